@@ -1,10 +1,10 @@
 use cxx::{CxxString, CxxVector, UniquePtr, Pin};
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs::{File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::sync::{Arc, Mutex, RwLock};
-use parking_lot::{Mutex as PMutex, RwLock as PRwLock};
+use parking_lot::{Condvar, Mutex as PMutex, ReentrantMutex, ReentrantMutexGuard, RwLock as PRwLock};
 use memmap2::{MmapMut, MmapOptions};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use uuid::Uuid;
@@ -13,6 +13,8 @@ use crc::CRC_32_ISO_HDLC;
 use lru::LruCache;
 use snappy;
 use md4::{Md4, Digest}; // Added for idTech4 checksum
+use sha2::Sha256;
+use zstd;
 
 const MAGIC: [u8; 8] = [0x55, 0xAA, 0xFE, 0xED, 0xFA, 0xCE, 0xDA, 0x7A];
 const PAGE_SIZE: u64 = 4096; // idTech4-aligned (HDD)
@@ -20,17 +22,111 @@ const PAGE_HEADER_SIZE: u64 = 32; // crc(4) + version(4) + prev/next(8+8) + flag
 const FREE_LIST_HEADER_SIZE: u64 = 12; // next(8) + used(4)
 const FREE_LIST_ENTRIES_PER_PAGE: usize = ((PAGE_SIZE - PAGE_HEADER_SIZE - FREE_LIST_HEADER_SIZE) / 8) as usize; // ~1010
 const MAX_PAGES: i64 = i64::MAX;
+const FIRST_PAGE_ID: i64 = 1; // page 0 holds the database header and is never allocated
 const MAX_DOCUMENT_SIZE: u64 = 256 * 1024 * 1024;
 const BATCH_GROW_PAGES: u64 = 16;
 const PAGE_CACHE_SIZE: usize = 2048;
+const PAGE_CACHE_SHARDS: usize = 16; // the page cache is split by page id so lookups of different pages rarely contend
+const PAGE_LOCK_STRIPES: usize = 64; // page ids share a stripe lock when equal modulo this
+const DIRECT_IO_ALIGNMENT: usize = 4096; // block size direct IO buffers, offsets and lengths are aligned to
+#[cfg(target_os = "linux")]
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024; // transparent huge page size on x86-64 and most arm64 kernels
+const WRITE_BACK_BATCH_BYTES: u64 = 1024 * 1024; // written bytes gathered before their write-back is started
+#[cfg(unix)]
+const MMAP_RESERVE_BYTES: u64 = 64 * 1024 * 1024; // least a mapping covers, so small databases grow a while before remapping
+const PREFAULT_CHUNK_BYTES: u64 = 64 * 1024 * 1024; // touched per hold of the mapping lock, and between progress reports
 const PATH_CACHE_SIZE: usize = 1024;
+const SEARCH_PAGE_SIZE: usize = 4096; // paths search_paths gathers per hold of the path order
+const TRIE_CACHE_SIZE: usize = 4096;
+const PATH_SNAPSHOT_BUDGET: u64 = 16 * 1024 * 1024; // bytes the sorted path snapshot may hold on to
+const INDEX_LOG_THRESHOLD: usize = 1024; // index log records kept before they are folded into the B-tree
+const MAINTENANCE_FRAGMENTATION_PERCENT: u32 = 25; // reclaimable slab space that makes maintenance repack
+const MAINTENANCE_FREE_PERCENT: u32 = 25; // free pages, as a share of the file, that make maintenance truncate
+const MAINTENANCE_SLICE_PAGES: usize = 64; // slab pages one maintenance thread slice reads
+const MAINTENANCE_PURGE_BATCH: usize = 16; // expired documents purged between budget checks
 const VERSIONS_TO_KEEP: i32 = 2;
+const LONG_TRANSACTION_MS: u64 = 30_000; // open transactions older than this are flagged in the stats
+const CURSOR_TIMEOUT_MS: u64 = 300_000; // read cursors unused for this long are closed
 const MAX_CONSECUTIVE_EMPTY_FREE_LIST: i64 = 5;
+const MAX_PATH_LENGTH: usize = 256; // MAX_OSPATH
+const MAX_PATH_COMPONENT_LENGTH: usize = 128;
+const DB_HEADER_SIZE: usize = 192; // MAGIC(8) + index/trie/free_list/path_hash roots (4 * (8+4)) + segment size(8) + count(4) + format version(2) + extended header(54) + dedup/tag/secondary/compression rule/index log roots (5 * (8+4)) + document count(8)
+const SEGMENT_LAYOUT_OFFSET: usize = 56;
+const FORMAT_VERSION_OFFSET: usize = 68;
+const EXTENDED_HEADER_OFFSET: usize = 70; // created(8) + creator(32) + page size(4) + codec(1) + checksum(1) + critical/optional features(4+4)
+const DEDUP_ROOT_OFFSET: usize = 124;
+const TAG_ROOT_OFFSET: usize = 136;
+const SECONDARY_ROOT_OFFSET: usize = 148;
+const RULES_ROOT_OFFSET: usize = 160;
+const INDEX_LOG_ROOT_OFFSET: usize = 172;
+const DOCUMENT_COUNT_OFFSET: usize = 184;
+const FORMAT_VERSION: u16 = 17; // bump on any layout change and register a migration from the previous version
+const CREATOR_LENGTH: usize = 32;
+const DEFAULT_CREATOR: &str = "StreamDB";
+const CODEC_NONE: u8 = ffi::PageCodec::None.repr;
+const CODEC_SNAPPY: u8 = ffi::PageCodec::Snappy.repr;
+const CODEC_ZSTD: u8 = ffi::PageCodec::Zstd.repr;
+const CHECKSUM_CRC32: u8 = 0;
+const CHUNK_MIN_SIZE: usize = 2 * 1024;
+const CHUNK_MAX_SIZE: usize = 64 * 1024;
+const CHUNK_BOUNDARY_MASK: u64 = !0 << 51; // the 13 high bits; a hash with all of them clear ends a chunk, every 8KB on average
+const PATCH_MAGIC: [u8; 8] = *b"SDBPATCH";
+const PATCH_VERSION: u16 = 1;
+const PATCH_OP_PUT: u8 = 1;
+const PATCH_OP_REMOVE: u8 = 2;
+const PATCH_OP_DELTA: u8 = 3; // new contents spliced from the old: kept prefix, replaced middle, kept suffix
+// Critical features change how the file must be read: a reader that lacks one must refuse the file.
+// Optional features are informational and unknown ones are ignored.
+const FEATURE_SEGMENTED: u32 = 0x1; // 0x2 (encrypted) and 0x4 (case-insensitive paths) are reserved
+const KNOWN_CRITICAL_FEATURES: u32 = FEATURE_SEGMENTED;
+const FEATURE_NORMALIZED_PATHS: u32 = 0x1; // optional
+const DEFAULT_SEGMENT_SIZE: u64 = 2 * 1024 * 1024 * 1024; // stays under FAT32's 4GB file limit
+const PATH_HASH_BUCKETS: usize = 256;
+const PATH_HASH_ENTRIES_PER_PAGE: usize = 128; // hash(8) + uuid(16) each, leaves headroom for snappy expansion
+const EVENT_QUEUE_CAPACITY: usize = 4096; // undrained events beyond this drop the oldest
+const MIRROR_QUEUE_CAPACITY: usize = 4096; // changed paths waiting for the mirror; overflowing forces a resync
+const MIRROR_CHECK_MS: u64 = 5_000; // idle time after which the mirror thread compares content hashes
+const MAX_TAG_LENGTH: usize = 64;
+const DICTIONARY_PATH_PREFIX: &str = "_streamdb/dictionaries/"; // followed by the dictionary id, 1-255
+const DICTIONARY_THRESHOLD: u64 = 4096; // documents smaller than this use the newest dictionary
+const MAX_DICTIONARY_SIZE: usize = 112 * 1024;
+const SLAB_SLOT_SHIFT: u32 = 48; // a slab record's address is its page id with slot + 1 in the bits from here up
+const SLAB_FREE_SLOT: u16 = u16::MAX; // slot table length marking a freed slot
+const TRIE_FORWARD_MARKER: i32 = -1; // in place of an edge length: the node moved to the page that follows
+const INDEX_LEAF: u8 = 0; // first payload byte of a document index page
+const INDEX_BRANCH: u8 = 1;
+const INDEX_NODE_HEADER_SIZE: usize = 5; // kind byte and entry count
+const INDEX_MAX_DEPTH: usize = 32; // deeper than any real index; a walk past it is following a cycle
+const MAX_TAGS_PER_DOCUMENT: usize = 32;
+const DOCUMENT_READONLY: u32 = ffi::DocumentFlag::Readonly.repr as u32;
+const DOCUMENT_PRECACHE: u32 = ffi::DocumentFlag::Precache.repr as u32;
+const KNOWN_DOCUMENT_FLAGS: u32 = DOCUMENT_READONLY | DOCUMENT_PRECACHE;
+
+// Enters a tracing span for the rest of the enclosing scope when built with the "trace" feature.
+// Without it the macro expands to () and its field expressions are never evaluated.
+macro_rules! trace_span {
+    ($name:literal $(, $field:ident = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "trace")]
+        let span = tracing::debug_span!($name $(, $field = $value)*).entered();
+        #[cfg(not(feature = "trace"))]
+        let span = ();
+        span
+    }};
+}
 
 #[derive(Clone, Debug)]
 pub struct CacheStats {
     hits: usize,
     misses: usize,
+    bypassed: usize,
+}
+
+// How a page read uses the page cache
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PageCaching {
+    Fill, // look up, promote on a hit, insert on a miss
+    Own, // neither consult nor fill: the page has a cache of its own
+    Peek, // look up without promoting and never insert, so a one-shot scan leaves hot pages resident
 }
 
 #[derive(Clone)]
@@ -40,9 +136,40 @@ struct Config {
     max_pages: i64,
     max_document_size: u64,
     use_compression: bool,
+    codec: u8, // CODEC_* used when compressing without a rule for the path
+    compression_level: i32, // 0 is the codec's default
     page_cache_size: usize,
     path_cache_size: usize,
+    trie_cache_size: usize, // trie nodes, cached apart from pages
+    path_snapshot_budget: u64, // largest sorted path snapshot kept between calls; 0 keeps none
     versions_to_keep: i32,
+    path_policy: ffi::PathPolicy,
+    durable_writes: bool, // flush the mapping after every write; off for disposable databases
+    lock_timeout_ms: u64, // how long open waits for another process's lock; 0 fails at once
+    read_only: bool, // open without write access, sharing the file lock with other readers
+    segment_size: u64, // split new databases into files of this size; 0 keeps a single file
+    creator: String, // recorded in the header of new databases
+    io_mode: ffi::IoMode, // whether to map the file; FileOnly routes every access through the storage
+    huge_pages: bool, // ask for transparent huge pages behind the mapping
+    prefault: ffi::Prefault, // pages to fault in at open and whenever the file is mapped anew
+    hide_expired: bool, // lookups report expired documents as missing until they are purged
+    secure_delete: bool, // zero every page before it is freed
+    dictionary_threshold: u64, // 0 never uses dictionaries
+    slab_threshold: u64, // documents smaller than this share slab pages; 0 turns slabs off
+    index_log_threshold: usize, // metadata changes logged before folding them into the index; 0 never logs
+    truncate_on_close: bool, // give free pages at the end of the file back on close
+    maintenance_interval_ms: u64, // how often the maintenance thread wakes; 0 runs no thread
+    maintenance_fragmentation_percent: u32,
+    maintenance_free_percent: u32,
+    long_transaction_ms: u64, // age at which get_transaction_stats flags an open transaction
+    cursor_timeout_ms: u64, // idle time after which a read cursor is closed and its pins released
+    decompress_threads: usize, // threads batch reads decompress pages on; 0 decompresses inline
+    admin: bool, // allows raw page reads for dump tooling
+    direct_io: bool, // bypass the OS page cache in the file backend when the filesystem allows
+    sync_policy: ffi::SyncPolicy,
+    compression_rules: Vec<ffi::CompressionRule>, // replaces the stored rules when non-empty
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Arc<PMutex<FaultSchedule>>>,
 }
 
 impl Default for Config {
@@ -53,10 +180,360 @@ impl Default for Config {
             max_pages: MAX_PAGES,
             max_document_size: MAX_DOCUMENT_SIZE,
             use_compression: true,
+            codec: CODEC_SNAPPY,
+            compression_level: 0,
+            page_cache_size: PAGE_CACHE_SIZE,
+            path_cache_size: PATH_CACHE_SIZE,
+            trie_cache_size: TRIE_CACHE_SIZE,
+            path_snapshot_budget: PATH_SNAPSHOT_BUDGET,
+            versions_to_keep: VERSIONS_TO_KEEP,
+            path_policy: ffi::PathPolicy::default(),
+            durable_writes: true,
+            lock_timeout_ms: 0,
+            read_only: false,
+            segment_size: 0,
+            creator: DEFAULT_CREATOR.to_string(),
+            io_mode: ffi::IoMode::Auto,
+            huge_pages: false,
+            prefault: ffi::Prefault::None,
+            hide_expired: false,
+            secure_delete: false,
+            dictionary_threshold: DICTIONARY_THRESHOLD,
+            slab_threshold: 0,
+            index_log_threshold: INDEX_LOG_THRESHOLD,
+            truncate_on_close: false,
+            maintenance_interval_ms: 0,
+            maintenance_fragmentation_percent: MAINTENANCE_FRAGMENTATION_PERCENT,
+            maintenance_free_percent: MAINTENANCE_FREE_PERCENT,
+            long_transaction_ms: LONG_TRANSACTION_MS,
+            cursor_timeout_ms: CURSOR_TIMEOUT_MS,
+            decompress_threads: Config::default_decompress_threads(),
+            admin: false,
+            direct_io: false,
+            sync_policy: ffi::SyncPolicy::Auto,
+            compression_rules: Vec::new(),
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+        }
+    }
+}
+
+impl Config {
+    /// One fewer than the cores available, leaving the calling thread its own.
+    fn default_decompress_threads() -> usize {
+        std::thread::available_parallelism().map_or(0, |cores| cores.get() - 1)
+    }
+}
+
+impl Default for ffi::PathPolicy {
+    fn default() -> Self {
+        ffi::PathPolicy {
+            max_path_length: MAX_PATH_LENGTH,
+            max_component_length: MAX_PATH_COMPONENT_LENGTH,
+            allow_spaces: true,
+            allow_non_ascii: false,
+            normalize: false,
+        }
+    }
+}
+
+impl Default for ffi::StreamDbOptions {
+    fn default() -> Self {
+        ffi::StreamDbOptions {
+            use_compression: true,
+            quick_mode: false,
             page_cache_size: PAGE_CACHE_SIZE,
             path_cache_size: PATH_CACHE_SIZE,
+            trie_cache_size: TRIE_CACHE_SIZE,
+            path_snapshot_budget: PATH_SNAPSHOT_BUDGET,
             versions_to_keep: VERSIONS_TO_KEEP,
+            path_policy: ffi::PathPolicy::default(),
+            durable_writes: true,
+            lock_timeout_ms: 0,
+            read_only: false,
+            segmented: false,
+            segment_size: 0,
+            use_mmap: true,
+            io_mode: ffi::IoMode::Auto,
+            huge_pages: false,
+            prefault: ffi::Prefault::None,
+            creator: DEFAULT_CREATOR.to_string(),
+            hide_expired: false,
+            secure_delete: false,
+            compression_rules: Vec::new(),
+            codec: ffi::PageCodec::Snappy,
+            compression_level: 0,
+            dictionary_threshold: DICTIONARY_THRESHOLD,
+            slab_threshold: 0,
+            index_log_threshold: INDEX_LOG_THRESHOLD,
+            truncate_on_close: false,
+            maintenance_interval_ms: 0,
+            maintenance_fragmentation_percent: MAINTENANCE_FRAGMENTATION_PERCENT,
+            maintenance_free_percent: MAINTENANCE_FREE_PERCENT,
+            decompress_threads: Config::default_decompress_threads(),
+            admin: false,
+            direct_io: false,
+            sync_policy: ffi::SyncPolicy::Auto,
+            mirror_path: String::new(),
+            mirror_fallback: false,
+        }
+    }
+}
+
+// Builder-style setters for Rust callers; C++ fills the shared struct from create_options() directly.
+impl ffi::StreamDbOptions {
+    pub fn use_compression(mut self, enabled: bool) -> Self {
+        self.use_compression = enabled;
+        self
+    }
+
+    pub fn quick_mode(mut self, enabled: bool) -> Self {
+        self.quick_mode = enabled;
+        self
+    }
+
+    pub fn trie_cache_size(mut self, nodes: usize) -> Self {
+        self.trie_cache_size = nodes;
+        self
+    }
+
+    /// Bytes get_all_paths_sorted may keep its snapshot in, counting each path's text and
+    /// string header. A larger snapshot is rebuilt on every call; 0 never keeps one.
+    pub fn path_snapshot_budget(mut self, bytes: u64) -> Self {
+        self.path_snapshot_budget = bytes;
+        self
+    }
+
+    pub fn cache_sizes(mut self, page_cache_size: usize, path_cache_size: usize) -> Self {
+        self.page_cache_size = page_cache_size;
+        self.path_cache_size = path_cache_size;
+        self
+    }
+
+    pub fn versions_to_keep(mut self, versions: i32) -> Self {
+        self.versions_to_keep = versions;
+        self
+    }
+
+    pub fn path_policy(mut self, policy: ffi::PathPolicy) -> Self {
+        self.path_policy = policy;
+        self
+    }
+
+    pub fn durable_writes(mut self, enabled: bool) -> Self {
+        self.durable_writes = enabled;
+        self
+    }
+
+    pub fn lock_timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.lock_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Opens an existing database without write access. Readers share the file lock, so any
+    /// number of them open it at once, but none alongside a writer; writes fail with PermissionDenied.
+    pub fn read_only(mut self, enabled: bool) -> Self {
+        self.read_only = enabled;
+        self
+    }
+
+    /// Splits a new database into files of segment_size bytes; 0 picks the 2GB default.
+    pub fn segmented(mut self, segment_size: u64) -> Self {
+        self.segmented = true;
+        self.segment_size = segment_size;
+        self
+    }
+
+    pub fn use_mmap(mut self, enabled: bool) -> Self {
+        self.use_mmap = enabled;
+        self
+    }
+
+    /// Auto maps the file when pages are at least 4KB; MmapPreferred maps it whatever the page
+    /// size; FileOnly never does, for platforms whose mapping misbehaves. Segmented and direct IO
+    /// databases are never mapped. use_mmap(false) wins over any mode.
+    pub fn io_mode(mut self, mode: ffi::IoMode) -> Self {
+        self.io_mode = mode;
+        self
+    }
+
+    /// Overwrites every page with zeros before it goes on the free list, so deleted documents,
+    /// superseded versions and the pages vacuum retires leave nothing readable in the file.
+    /// Costs a page write per freed page.
+    pub fn secure_delete(mut self, enabled: bool) -> Self {
+        self.secure_delete = enabled;
+        self
+    }
+
+    /// Asks Linux to back the mapping with transparent huge pages, easing TLB pressure on
+    /// multi-gigabyte files. Silently does nothing where the kernel, filesystem or platform
+    /// declines; get_db_info reports whether it took.
+    pub fn huge_pages(mut self, enabled: bool) -> Self {
+        self.huge_pages = enabled;
+        self
+    }
+
+    /// Faults pages in at open so the first read of an asset does not stall on the disk:
+    /// Metadata touches the header, index, trie and free list, Full the whole file. Open takes
+    /// longer; a prefault that fails is given up on without failing the open.
+    pub fn prefault(mut self, prefault: ffi::Prefault) -> Self {
+        self.prefault = prefault;
+        self
+    }
+
+    pub fn creator(mut self, creator: &str) -> Self {
+        self.creator = creator.to_string();
+        self
+    }
+
+    pub fn hide_expired(mut self, enabled: bool) -> Self {
+        self.hide_expired = enabled;
+        self
+    }
+
+    /// Codec and level for compressed writes without a rule; level 0 is the codec's default.
+    pub fn compression(mut self, codec: ffi::PageCodec, level: i32) -> Self {
+        self.codec = codec;
+        self.compression_level = level;
+        self
+    }
+
+    /// Documents smaller than bytes are compressed with the newest dictionary when their codec is zstd.
+    pub fn dictionary_threshold(mut self, bytes: u64) -> Self {
+        self.dictionary_threshold = bytes;
+        self
+    }
+
+    /// Documents smaller than bytes are packed into shared slab pages instead of a page each.
+    pub fn slab_threshold(mut self, bytes: u64) -> Self {
+        self.slab_threshold = bytes;
+        self
+    }
+
+    /// Metadata-only index changes are appended to a log until it holds records entries, then
+    /// folded into the index. 0 writes every change to the index directly.
+    pub fn index_log_threshold(mut self, records: usize) -> Self {
+        self.index_log_threshold = records;
+        self
+    }
+
+    /// Shrinks the file on close when pages at its end are free.
+    pub fn truncate_on_close(mut self, enabled: bool) -> Self {
+        self.truncate_on_close = enabled;
+        self
+    }
+
+    /// Runs a maintenance thread that wakes every interval_ms and, when a threshold is passed,
+    /// does one bounded slice of work: purging expired documents, repacking slabs once
+    /// fragmentation_percent of their space is reclaimable, truncating the file once
+    /// free_percent of its pages are free. Only databases opened through open_db_with_options
+    /// get the thread, since it needs the database at a fixed address.
+    pub fn maintenance(mut self, interval_ms: u64, fragmentation_percent: u32, free_percent: u32) -> Self {
+        self.maintenance_interval_ms = interval_ms;
+        self.maintenance_fragmentation_percent = fragmentation_percent;
+        self.maintenance_free_percent = free_percent;
+        self
+    }
+
+    /// Threads get_many and prefetch decompress pages on. 0 decompresses on the calling thread.
+    pub fn decompress_threads(mut self, threads: usize) -> Self {
+        self.decompress_threads = threads;
+        self
+    }
+
+    /// Opens the file for direct IO (O_DIRECT on Linux, FILE_FLAG_NO_BUFFERING on Windows) so
+    /// pages are cached once, by the page cache, instead of again by the OS. Implies use_mmap off.
+    /// Where the platform or filesystem refuses, the database opens with buffered IO.
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    /// How syncs reach the disk. Auto uses fdatasync and falls back to a full fsync only when
+    /// the file length changed since the last one; Full always fsyncs, for filesystems where
+    /// fdatasync is not trusted.
+    pub fn sync_policy(mut self, policy: ffi::SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Allows read_raw_page_admin, which hands out pages exactly as stored.
+    pub fn admin(mut self, enabled: bool) -> Self {
+        self.admin = enabled;
+        self
+    }
+
+    /// Replicates committed changes into a second database at path, on a background thread, so
+    /// only databases opened through open_db_with_options are kept up to date. With fallback,
+    /// a primary that fails to open or whose index cannot be read is set aside and the mirror is
+    /// opened instead, and a document whose pages fail their checks is read from the mirror.
+    pub fn mirror(mut self, path: &str, fallback: bool) -> Self {
+        self.mirror_path = path.to_string();
+        self.mirror_fallback = fallback;
+        self
+    }
+
+    pub fn compression_rule(mut self, extension: &str, codec: ffi::PageCodec) -> Self {
+        self.compression_rules.push(ffi::CompressionRule { extension: extension.to_string(), codec });
+        self
+    }
+
+    pub fn open(&self, path: &Path) -> io::Result<StreamDb> {
+        StreamDb::open_path_with_options(path, self)
+    }
+
+    fn to_config(&self) -> io::Result<Config> {
+        let segment_size = if !self.segmented {
+            0
+        } else {
+            let segment_size = if self.segment_size == 0 { DEFAULT_SEGMENT_SIZE } else { self.segment_size };
+            let segment_size = segment_size / PAGE_SIZE * PAGE_SIZE;
+            if segment_size < (DB_HEADER_SIZE as u64).max(PAGE_SIZE) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Segment size smaller than a page"));
+            }
+            segment_size
+        };
+        if self.page_cache_size == 0 || self.path_cache_size == 0 || self.trie_cache_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cache sizes must be non-zero"));
         }
+        StreamDb::validate_compression_level(self.codec.repr, self.compression_level)?;
+        if self.maintenance_fragmentation_percent > 100 || self.maintenance_free_percent > 100 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Maintenance thresholds are percentages"));
+        }
+        Ok(Config {
+            use_compression: self.use_compression,
+            codec: self.codec.repr,
+            compression_level: self.compression_level,
+            page_cache_size: self.page_cache_size,
+            path_cache_size: self.path_cache_size,
+            trie_cache_size: self.trie_cache_size,
+            path_snapshot_budget: self.path_snapshot_budget,
+            versions_to_keep: self.versions_to_keep,
+            path_policy: self.path_policy.clone(),
+            durable_writes: self.durable_writes,
+            lock_timeout_ms: self.lock_timeout_ms,
+            read_only: self.read_only,
+            segment_size,
+            io_mode: if self.use_mmap && !self.direct_io { self.io_mode } else { ffi::IoMode::FileOnly },
+            huge_pages: self.huge_pages,
+            prefault: self.prefault,
+            creator: self.creator.clone(),
+            hide_expired: self.hide_expired,
+            secure_delete: self.secure_delete,
+            dictionary_threshold: self.dictionary_threshold,
+            slab_threshold: self.slab_threshold,
+            index_log_threshold: self.index_log_threshold,
+            truncate_on_close: self.truncate_on_close,
+            maintenance_interval_ms: self.maintenance_interval_ms,
+            maintenance_fragmentation_percent: self.maintenance_fragmentation_percent,
+            maintenance_free_percent: self.maintenance_free_percent,
+            decompress_threads: self.decompress_threads,
+            admin: self.admin,
+            direct_io: self.direct_io,
+            sync_policy: self.sync_policy,
+            compression_rules: self.compression_rules.clone(),
+            ..Default::default()
+        })
     }
 }
 
@@ -68,20 +545,214 @@ struct PageHeader {
     next_page_id: i64,
     flags: u8,
     data_length: i32,
-    padding: [u8; 3],
+    padding: [u8; 3], // compressed pages: codec (0 from before v10 means snappy), level as i8, zstd dictionary id or 0; otherwise zero
+}
+
+// The page type and chain links a page is written with; write_raw_page fills in the rest of
+// the header from the payload it stores
+#[derive(Clone, Copy)]
+struct PageLinks {
+    flags: u8, // FLAG_* type bits; FLAG_COMPRESSED is added by the write when it applies
+    prev_page_id: i64,
+    next_page_id: i64,
+}
+
+impl PageLinks {
+    /// A page of the given type that is not part of a chain.
+    fn single(flags: u8) -> PageLinks {
+        PageLinks { flags, prev_page_id: -1, next_page_id: -1 }
+    }
+}
+
+/// The file's mapping: memmap2's, or on Linux with huge pages asked for, one placed on a
+/// HUGE_PAGE_SIZE boundary so file offsets line up with huge pages, which memmap2 cannot ask for.
+enum FileMapping {
+    Standard(MmapMut),
+    #[cfg(target_os = "linux")]
+    Aligned(AlignedMapping),
+}
+
+impl FileMapping {
+    fn flush(&self) -> io::Result<()> {
+        match self {
+            FileMapping::Standard(mmap) => mmap.flush(),
+            #[cfg(target_os = "linux")]
+            FileMapping::Aligned(mapping) => mapping.flush_range(0, mapping.len),
+        }
+    }
+
+    fn flush_range(&self, offset: usize, len: usize) -> io::Result<()> {
+        match self {
+            FileMapping::Standard(mmap) => mmap.flush_range(offset, len),
+            #[cfg(target_os = "linux")]
+            FileMapping::Aligned(mapping) => mapping.flush_range(offset, len),
+        }
+    }
+}
+
+impl std::ops::Deref for FileMapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileMapping::Standard(mmap) => mmap,
+            #[cfg(target_os = "linux")]
+            FileMapping::Aligned(mapping) => unsafe { std::slice::from_raw_parts(mapping.ptr, mapping.len) },
+        }
+    }
+}
+
+impl std::ops::DerefMut for FileMapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            FileMapping::Standard(mmap) => mmap,
+            #[cfg(target_os = "linux")]
+            FileMapping::Aligned(mapping) => unsafe { std::slice::from_raw_parts_mut(mapping.ptr, mapping.len) },
+        }
+    }
+}
+
+// A mapping of a file's first len bytes starting on a HUGE_PAGE_SIZE boundary. mmap only
+// promises OS page alignment, so HUGE_PAGE_SIZE more is reserved, the file mapped over the first
+// boundary inside the reservation, and the slack either side given back.
+#[cfg(target_os = "linux")]
+struct AlignedMapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is plain shared memory; the lock around FileMapping orders access to it
+#[cfg(target_os = "linux")]
+unsafe impl Send for AlignedMapping {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for AlignedMapping {}
+
+#[cfg(target_os = "linux")]
+impl AlignedMapping {
+    /// Maps shared, or private for a read-only handle, which cannot back a shared writable mapping.
+    fn map(file: &File, len: usize, shared: bool) -> io::Result<AlignedMapping> {
+        use std::os::unix::io::AsRawFd;
+        let os_page = Self::os_page_size();
+        let span = len.checked_add(os_page - 1).map(|len| len / os_page * os_page)
+            .and_then(|len| len.checked_add(HUGE_PAGE_SIZE))
+            .ok_or_else(StreamDb::too_large)?;
+        unsafe {
+            let reserved = libc::mmap(std::ptr::null_mut(), span, libc::PROT_NONE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1, 0);
+            if reserved == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            let start = reserved as usize;
+            let aligned = start.next_multiple_of(HUGE_PAGE_SIZE);
+            let sharing = if shared { libc::MAP_SHARED } else { libc::MAP_PRIVATE };
+            let mapped = libc::mmap(aligned as *mut libc::c_void, len, libc::PROT_READ | libc::PROT_WRITE, sharing | libc::MAP_FIXED, file.as_raw_fd(), 0);
+            if mapped == libc::MAP_FAILED {
+                let error = io::Error::last_os_error();
+                libc::munmap(reserved, span);
+                return Err(error);
+            }
+            let end = (aligned + len).next_multiple_of(os_page);
+            if aligned > start {
+                libc::munmap(reserved, aligned - start);
+            }
+            if start + span > end {
+                libc::munmap(end as *mut libc::c_void, start + span - end);
+            }
+            Ok(AlignedMapping { ptr: aligned as *mut u8, len })
+        }
+    }
+
+    fn os_page_size() -> usize {
+        (unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).max(1) as usize
+    }
+
+    /// Writes back offset..offset+len; msync wants the start on an OS page boundary.
+    fn flush_range(&self, offset: usize, len: usize) -> io::Result<()> {
+        let start = offset / Self::os_page_size() * Self::os_page_size();
+        if unsafe { libc::msync(self.ptr.add(start) as *mut libc::c_void, offset - start + len, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AlignedMapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A page payload at the API boundary: borrowed straight from the mapping when the page is
+/// stored uncompressed, owned otherwise. A mapped view holds the mapping's read lock, which is
+/// what close (or anything that replaces the mapping) takes exclusively, so it cannot outlive
+/// the mapping. Drop views before writing: writes through the mapping take the same lock.
+enum PageView<'a> {
+    Owned(Vec<u8>),
+    Mapped(parking_lot::RwLockReadGuard<'a, Option<FileMapping>>, std::ops::Range<usize>),
+}
+
+impl std::ops::Deref for PageView<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PageView::Owned(data) => data,
+            PageView::Mapped(mmap, range) => &mmap.as_ref().unwrap()[range.clone()],
+        }
+    }
 }
 
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
 const FLAG_DATA_PAGE: u8 = 0x01;
 const FLAG_TRIE_PAGE: u8 = 0x02;
 const FLAG_FREE_LIST_PAGE: u8 = 0x04;
 const FLAG_INDEX_PAGE: u8 = 0x08;
+const FLAG_HASH_PAGE: u8 = 0x10;
+const FLAG_APPEND_PAGE: u8 = 0x20; // data page written by an append handle
+const FLAG_COMPRESSED: u8 = 0x40; // payload is compressed with the codec in padding[0]; readers go by this, not the config
+const FLAG_SLAB_PAGE: u8 = 0x80; // uncompressed SlabPage holding small documents
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 struct Document {
     id: Uuid,
     first_page_id: i64,
     current_version: i32,
-    paths: Vec<String>,
+    checksum: u32, // CRC32 of the current version's full contents
+    paths: Vec<PathBinding>,
+    previous_versions: Vec<VersionedLink>, // retained older chains, oldest first
+    expires_at: u64, // unix time after which the document may be purged; 0 never expires
+    tags: BTreeSet<String>,
+    size: u64, // logical length of the current version
+    modified: u64, // unix time of the last write; 0 if unknown (written before v7)
+    flags: u32, // DocumentFlag bits
+    page_count: u32, // pages in the current version's chain; 0 for empty and slab-stored documents
+}
+
+impl Document {
+    fn has_path(&self, path: &str) -> bool {
+        self.paths.iter().any(|binding| binding.path == path)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    /// Refuses changes to a READONLY document unless forced.
+    fn check_writable(&self, force: bool) -> io::Result<()> {
+        if self.flags & DOCUMENT_READONLY != 0 && !force {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Document is read-only"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct PathBinding {
+    path: String,
+    addon: bool,
+    priority: i32, // among documents claiming the same path, the highest priority wins
+    lang: String, // empty for the default binding; otherwise only used while lang is active
 }
 
 #[derive(Clone)]
@@ -93,6 +764,216 @@ struct ReverseTrieNode {
     children: BTreeMap<char, i64>, // Optimized: BTreeMap for persistence
 }
 
+// A path glob compiled by PathPattern::compile. Paths are matched a component at a time, so
+// * and ? never cross a '/', while a ** component spans any number of directories.
+struct PathPattern {
+    segments: Vec<PatternSegment>,
+}
+
+enum PatternSegment {
+    AnyDirectories, // **: zero or more whole components
+    Component(Vec<GlobToken>),
+}
+
+enum GlobToken {
+    Literal(char),
+    AnyChar, // ?
+    AnyRun, // *
+    Class { negated: bool, ranges: Vec<(char, char)> }, // [a-z0-9], [!.]
+}
+
+// Where one path stands against a PathPattern. NoMatchUnder(end) rules out, besides the path,
+// every path starting with path[..end], which ends at the '/' after a directory.
+enum PatternMatch {
+    Match,
+    NoMatch,
+    NoMatchUnder(usize),
+}
+
+impl GlobToken {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            GlobToken::Literal(literal) => *literal == c,
+            GlobToken::AnyChar | GlobToken::AnyRun => true,
+            GlobToken::Class { negated, ranges } => ranges.iter().any(|&(low, high)| low <= c && c <= high) != *negated,
+        }
+    }
+}
+
+impl PathPattern {
+    /// Compiles a glob. A pattern with a leading '/', or a '/' between two components, is
+    /// anchored at the root; any other matches its component at any depth. A trailing '/'
+    /// matches everything below, and '\' makes the next character literal.
+    fn compile(pattern: &str) -> io::Result<PathPattern> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid pattern: {}", reason));
+        let body = pattern.strip_prefix('/').unwrap_or(pattern);
+        let mut segments = Vec::new();
+        let mut tokens = Vec::new();
+        let mut trailing_separator = false;
+        let mut chars = body.chars();
+        while let Some(c) = chars.next() {
+            trailing_separator = c == '/';
+            match c {
+                '/' => Self::end_component(&mut segments, std::mem::take(&mut tokens)),
+                '\\' => tokens.push(GlobToken::Literal(chars.next().ok_or_else(|| invalid("trailing escape"))?)),
+                '?' => tokens.push(GlobToken::AnyChar),
+                '*' => tokens.push(GlobToken::AnyRun),
+                '[' => {
+                    let negated = chars.as_str().starts_with('!');
+                    if negated {
+                        chars.next();
+                    }
+                    let mut ranges = Vec::new();
+                    loop {
+                        let low = match chars.next() {
+                            Some(']') if !ranges.is_empty() => break,
+                            Some('\\') => chars.next(),
+                            other => other,
+                        }.ok_or_else(|| invalid("unterminated character class"))?;
+                        let mut high = low;
+                        let rest = chars.as_str();
+                        if rest.starts_with('-') && rest.len() > 1 && !rest[1..].starts_with(']') {
+                            chars.next();
+                            high = match chars.next() {
+                                Some('\\') => chars.next(),
+                                other => other,
+                            }.ok_or_else(|| invalid("unterminated character class"))?;
+                            if high < low {
+                                return Err(invalid("reversed character range"));
+                            }
+                        }
+                        ranges.push((low, high));
+                    }
+                    tokens.push(GlobToken::Class { negated, ranges });
+                }
+                c => tokens.push(GlobToken::Literal(c)),
+            }
+        }
+        Self::end_component(&mut segments, tokens);
+        if segments.is_empty() {
+            return Err(invalid("empty pattern"));
+        }
+        if body.len() == pattern.len() && segments.len() == 1 {
+            segments.insert(0, PatternSegment::AnyDirectories);
+        }
+        if trailing_separator {
+            segments.push(PatternSegment::AnyDirectories);
+        }
+        Ok(PathPattern { segments })
+    }
+
+    fn end_component(segments: &mut Vec<PatternSegment>, tokens: Vec<GlobToken>) {
+        if tokens.is_empty() {
+            return;
+        }
+        if matches!(tokens.as_slice(), [GlobToken::AnyRun, GlobToken::AnyRun]) {
+            segments.push(PatternSegment::AnyDirectories);
+        } else {
+            segments.push(PatternSegment::Component(tokens));
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        matches!(self.check(path), PatternMatch::Match)
+    }
+
+    /// Steps through path's components, tracking every segment the pattern could be at.
+    /// Once none is left with a component still to match, nothing under the directory can match.
+    fn check(&self, path: &str) -> PatternMatch {
+        let mut states = self.closure(vec![0]);
+        let mut end = 0;
+        let mut components = path.split('/').peekable();
+        while let Some(component) = components.next() {
+            end += component.len() + 1;
+            let mut next = Vec::new();
+            for &state in &states {
+                match self.segments.get(state) {
+                    Some(PatternSegment::AnyDirectories) => next.push(state),
+                    Some(PatternSegment::Component(tokens)) if Self::component_matches(tokens, component) => next.push(state + 1),
+                    _ => {}
+                }
+            }
+            states = self.closure(next);
+            if components.peek().is_some() && !states.iter().any(|&state| state < self.segments.len()) {
+                return PatternMatch::NoMatchUnder(end);
+            }
+        }
+        if states.contains(&self.segments.len()) {
+            PatternMatch::Match
+        } else {
+            PatternMatch::NoMatch
+        }
+    }
+
+    /// Adds the states reachable by letting a ** match no components.
+    fn closure(&self, mut states: Vec<usize>) -> Vec<usize> {
+        let mut i = 0;
+        while i < states.len() {
+            let state = states[i];
+            if matches!(self.segments.get(state), Some(PatternSegment::AnyDirectories)) && !states.contains(&(state + 1)) {
+                states.push(state + 1);
+            }
+            i += 1;
+        }
+        states.sort_unstable();
+        states.dedup();
+        states
+    }
+
+    /// Matches one component, going back to the last * whenever a token fails.
+    fn component_matches(tokens: &[GlobToken], name: &str) -> bool {
+        let name: Vec<char> = name.chars().collect();
+        let (mut t, mut n) = (0, 0);
+        let mut last_run: Option<(usize, usize)> = None; // token after the *, and where in name it took over
+        while n < name.len() {
+            match tokens.get(t) {
+                Some(GlobToken::AnyRun) => {
+                    t += 1;
+                    last_run = Some((t, n));
+                    continue;
+                }
+                Some(token) if token.matches(name[n]) => {
+                    t += 1;
+                    n += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match last_run {
+                Some((after, from)) => {
+                    t = after;
+                    n = from + 1;
+                    last_run = Some((after, from + 1));
+                }
+                None => return false,
+            }
+        }
+        tokens[t..].iter().all(|token| matches!(token, GlobToken::AnyRun))
+    }
+
+    /// The path prefix spelled out by the pattern's leading literal components, which every
+    /// match starts with.
+    fn literal_prefix(&self) -> String {
+        let mut prefix = String::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                PatternSegment::Component(tokens) if tokens.iter().all(|token| matches!(token, GlobToken::Literal(_))) => {
+                    prefix.extend(tokens.iter().filter_map(|token| match token {
+                        GlobToken::Literal(c) => Some(*c),
+                        _ => None,
+                    }));
+                    if i + 1 < self.segments.len() {
+                        prefix.push('/');
+                    }
+                }
+                _ => break,
+            }
+        }
+        prefix
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
 struct VersionedLink {
     page_id: i64,
     version: i32,
@@ -101,805 +982,14798 @@ struct VersionedLink {
 struct Transaction {
     writes: VecDeque<(i64, Vec<u8>, i32)>, // page_id, data, version
     frees: Vec<i64>,
+    documents: Vec<StagedDocument>, // published together by a single index write at commit
+    changes: Vec<StagedChange>, // applied by the same index write, after the documents
+    started: std::time::Instant,
+    started_unix_ms: u64,
+    begun_after: u64, // CommitHistory::sequence at begin; later commits may conflict with this one
 }
 
-#[cxx::bridge]
-mod ffi {
-    #[derive(Clone, Debug)]
-    struct CacheStats {
-        hits: usize,
-        misses: usize,
+// Paths published by recent commits, kept while an open transaction began before them, so a
+// commit can tell whether another transaction wrote the same path first
+#[derive(Default)]
+struct CommitHistory {
+    sequence: u64, // commits that published documents so far
+    commits: VecDeque<(u64, BTreeSet<String>)>,
+}
+
+// Totals behind get_transaction_stats, since open
+#[derive(Default)]
+struct TransactionTotals {
+    begun: u64,
+    committed: u64,
+    failed: u64, // commits that returned an error
+    rolled_back: u64,
+    pages_committed: u64,
+    last_commit_pages: u64,
+    max_commit_pages: u64,
+}
+
+impl Transaction {
+    fn new(begun_after: u64) -> Self {
+        Transaction {
+            writes: VecDeque::new(),
+            frees: Vec::new(),
+            documents: Vec::new(),
+            changes: Vec::new(),
+            started: std::time::Instant::now(),
+            started_unix_ms: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+            begun_after,
+        }
     }
 
-    unsafe extern "C++" {
-        include!("framework/Common.h");
-        type idCommon;
-        fn commonPrintf(self: &idCommon, fmt: &CxxString);
+    /// Every path the commit would change, for conflict checks.
+    fn paths(&self) -> impl Iterator<Item = &String> {
+        self.documents.iter().map(|staged| &staged.path).chain(self.changes.iter().flat_map(|change| match change {
+            StagedChange::Delete { path, .. } => vec![path],
+            StagedChange::Rename { from, to } => vec![from, to],
+        }))
     }
+}
 
-    extern "Rust" {
-        type StreamDb;
+// A path change staged in a transaction. Paths resolve at commit, against what is committed
+// by then, so a change cannot target a document staged in the same transaction.
+enum StagedChange {
+    Delete { path: String, force: bool, secure: bool }, // secure zeroes the document's chains as they are freed
+    Rename { from: String, to: String },
+}
 
-        fn open_db(path: &CxxString, use_compression: bool, quick_mode: bool) -> Result<UniquePtr<StreamDb>>;
-        fn close_db(self: Pin<&mut StreamDb>);
-        fn write_document(self: Pin<&mut StreamDb>, path: &CxxString, data: &CxxVector<u8>) -> Result<Uuid>;
-        fn get(self: &StreamDb, path: &CxxString) -> Result<CxxVector<u8>>;
-        fn search_paths(self: &StreamDb, prefix: &CxxString) -> Result<CxxVector<CxxString>>;
-        fn delete_by_path(self: Pin<&mut StreamDb>, path: &CxxString) -> Result<()>;
-        fn get_checksum(self: &StreamDb) -> u32;
-        fn set_quick_mode(self: Pin<&mut StreamDb>, enabled: bool);
-        fn get_cache_stats(self: &StreamDb) -> CacheStats;
-        fn start_stream(self: &StreamDb, path: &CxxString) -> Result<i64>;
-        fn next_stream_chunk(self: &StreamDb, stream_id: i64) -> Result<CxxVector<u8>>;
-        fn end_stream(self: Pin<&mut StreamDb>, stream_id: i64);
-        fn bind_addon_path(self: Pin<&mut StreamDb>, path: &CxxString, addon: bool) -> Result<()>;
-        fn begin_transaction(self: Pin<&mut StreamDb>) -> Result<i64>;
-        fn commit_transaction(self: Pin<&mut StreamDb>, tx_id: i64) -> Result<()>;
-        fn rollback_transaction(self: Pin<&mut StreamDb>, tx_id: i64) -> Result<()>;
-    }
+// A document whose chain is written but not yet reachable from the index
+struct StagedDocument {
+    path: String,
+    first_page_id: i64,
+    checksum: u32,
+    size: u64,
 }
 
-pub struct StreamDb {
-    config: Config,
-    file: PMutex<File>,
-    mmap: PRwLock<Option<MmapMut>>,
-    current_size: PMutex<u64>,
-    document_index_root: PRwLock<VersionedLink>,
-    trie_root: PRwLock<VersionedLink>,
-    free_list_root: PRwLock<VersionedLink>,
-    page_cache: PMutex<LruCache<i64, Vec<u8>>>,
-    path_cache: PMutex<LruCache<String, Uuid>>,
-    cache_stats: PMutex<CacheStats>,
-    quick_mode: Arc<std::sync::atomic::AtomicBool>,
-    transactions: PMutex<Vec<Transaction>>,
+struct StreamHandle {
+    document_id: Uuid,
+    first_page_id: i64, // identifies the pinned chain
+    next_page_id: i64,
+    bytes_read: u64,
+    chunks_read: u64,
+    expected_checksum: u32,
+    running_checksum: Option<crc::Digest<'static, u32>>, // None once the stream is no longer sequential
+    stale: bool, // set when the file was reloaded; the chain may no longer exist
+    chunk_size: usize, // 0 delivers one page payload per chunk
+    buffered: Vec<u8>, // read ahead but not yet delivered
 }
 
-impl StreamDb {
-    pub fn open_db(path: &CxxString, use_compression: bool, quick_mode: bool) -> Result<UniquePtr<StreamDb>, std::io::Error> {
-        let config = Config { use_compression, ..Default::default() };
-        let file = OpenOptions::new().read(true).write(true).create(true).open(path.to_string_lossy())?;
-        let mmap = if config.page_size >= 4096 {
-            Some(unsafe { MmapOptions::new().len(config.page_size as usize * config.max_pages as usize).map_mut(&file)? })
-        } else {
-            None
+impl StreamHandle {
+    fn is_finished(&self) -> bool {
+        self.next_page_id == -1 && self.buffered.is_empty()
+    }
+}
+
+// Pages of an append-mode document. Only synced pages are reachable from the index;
+// full pages written since the last sync wait in pending until sync_append links them.
+struct AppendHandle {
+    document_id: Uuid,
+    first_page_id: i64,
+    last_sealed_page_id: i64, // last full page reachable on disk, -1 if none
+    synced_tail_page_id: i64, // partial page reachable on disk, replaced (never rewritten) on sync
+    pending_pages: Vec<i64>, // full pages written since the last sync, linked among themselves
+    tail: Vec<u8>, // bytes after the last full page, synced or not
+    sealed_checksum: crc::Digest<'static, u32>, // covers every full page, pending or not
+    length: u64, // document length including everything appended, synced or not
+    codec: u8, // from the compression rules for the document's path
+    dirty: bool,
+}
+
+// Byte-addressed backing store for pages when the file is not memory-mapped
+trait Storage: Send + Sync {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()>;
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()>;
+    fn len(&self) -> io::Result<u64>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync(&self) -> io::Result<()>;
+    /// Syncs file contents but not metadata such as the length, like fdatasync.
+    fn sync_data(&self) -> io::Result<()> {
+        self.sync()
+    }
+    /// Starts writing a range back to disk without waiting for it, so a later sync has less
+    /// left to do. Only a hint; backends without a way to ask ignore it.
+    fn start_write_back(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+    /// (segment size, segment count) as recorded in the header; (0, 0) for a single file.
+    fn segment_layout(&self) -> (u64, u32) {
+        (0, 0)
+    }
+    fn direct_io(&self) -> bool {
+        false
+    }
+}
+
+// Unix reads and writes at an offset without touching the shared file position, so page IO
+// on different pages runs in parallel; elsewhere each seek and transfer holds position.
+struct FileStorage {
+    file: File,
+    #[cfg(not(unix))]
+    position: PMutex<()>,
+}
+
+impl FileStorage {
+    fn new(file: File) -> Self {
+        FileStorage {
+            file,
+            #[cfg(not(unix))]
+            position: PMutex::new(()),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, buffer, offset)
+    }
+
+    #[cfg(not(unix))]
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let _position = self.position.lock();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buffer)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(&self.file, data, offset)
+    }
+
+    #[cfg(not(unix))]
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let _position = self.position.lock();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn start_write_back(&self, offset: u64, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let result = unsafe { libc::sync_file_range(self.file.as_raw_fd(), offset as i64, len as i64, libc::SYNC_FILE_RANGE_WRITE) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// A zeroed heap buffer aligned for direct IO, its length a whole number of blocks
+struct AlignedBuffer {
+    ptr: std::ptr::NonNull<u8>,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuffer {
+    fn zeroed(len: usize) -> AlignedBuffer {
+        let len = len.max(1).div_ceil(DIRECT_IO_ALIGNMENT) * DIRECT_IO_ALIGNMENT;
+        let layout = std::alloc::Layout::from_size_align(len, DIRECT_IO_ALIGNMENT).expect("direct IO buffer layout");
+        let ptr = std::ptr::NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl std::ops::Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl std::ops::DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+// A single file opened for direct IO, bypassing the OS cache. Every transfer goes through an
+// aligned buffer covering whole blocks; writes that cover part of a block read it first.
+// Only opened where the platform has direct IO.
+#[cfg_attr(not(any(target_os = "linux", windows)), allow(dead_code))]
+struct DirectStorage {
+    file: File,
+    writes: PMutex<()>, // a read-modify-write of a block, or a length fixed up after one, must not interleave with another write
+}
+
+impl DirectStorage {
+    /// Fails where the platform has no direct IO or the filesystem refuses it, for the caller
+    /// to fall back to buffered IO.
+    #[cfg(any(target_os = "linux", windows))]
+    fn open(path: &Path) -> io::Result<DirectStorage> {
+        let mut options = StreamDb::db_open_options();
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_DIRECT);
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+            options.custom_flags(FILE_FLAG_NO_BUFFERING);
+        }
+        let storage = DirectStorage { file: options.open(path)?, writes: PMutex::new(()) };
+        // Some filesystems take the flag but fail the IO; find out now rather than mid-write
+        storage.read_blocks(0, &mut AlignedBuffer::zeroed(DIRECT_IO_ALIGNMENT))?;
+        Ok(storage)
+    }
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    fn open(_path: &Path) -> io::Result<DirectStorage> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Direct IO is not supported on this platform"))
+    }
+
+    /// The aligned offset and length of the blocks covering offset..offset+len.
+    fn covering_blocks(offset: u64, len: usize) -> (u64, usize) {
+        let align = DIRECT_IO_ALIGNMENT as u64;
+        let start = offset / align * align;
+        let end = (offset + len as u64).div_ceil(align) * align;
+        (start, (end - start) as usize)
+    }
+
+    /// Fills buffer from an aligned offset; whatever lies past the end of the file stays zero.
+    /// Returns how many bytes the file held.
+    fn read_blocks(&self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buffer.len() {
+            #[cfg(unix)]
+            let read = std::os::unix::fs::FileExt::read_at(&self.file, &mut buffer[done..], offset + done as u64);
+            #[cfg(windows)]
+            let read = std::os::windows::fs::FileExt::seek_read(&self.file, &mut buffer[done..], offset + done as u64);
+            match read {
+                Ok(n) => {
+                    done += n;
+                    // A short read ends at the end of the file, which need not be block aligned
+                    if n == 0 || n % DIRECT_IO_ALIGNMENT != 0 {
+                        return Ok(done);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(done)
+    }
+
+    fn write_blocks(&self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::FileExt::write_all_at(&self.file, buffer, offset);
+        #[cfg(windows)]
+        {
+            let mut done = 0;
+            while done < buffer.len() {
+                match std::os::windows::fs::FileExt::seek_write(&self.file, &buffer[done..], offset + done as u64) {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "Direct write made no progress")),
+                    Ok(n) => done += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+impl Storage for DirectStorage {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let (start, len) = Self::covering_blocks(offset, buffer.len());
+        let mut blocks = AlignedBuffer::zeroed(len);
+        let available = self.read_blocks(start, &mut blocks)?;
+        let skip = (offset - start) as usize;
+        // Like read_exact_at: a read running past the end of the file fails rather than yield zeros
+        if skip + buffer.len() > available {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Read past the end of the file"));
+        }
+        buffer.copy_from_slice(&blocks[skip..skip + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let (start, len) = Self::covering_blocks(offset, data.len());
+        let mut blocks = AlignedBuffer::zeroed(len);
+        let skip = (offset - start) as usize;
+        let _writes = self.writes.lock();
+        if skip != 0 || data.len() != len {
+            self.read_blocks(start, &mut blocks)?;
+        }
+        blocks[skip..skip + data.len()].copy_from_slice(data);
+        let file_len = self.len()?;
+        self.write_blocks(start, &blocks)?;
+        // Whole blocks may have run past the end of the data; keep the length the caller would get
+        let end = offset + data.len() as u64;
+        if start + len as u64 > file_len.max(end) {
+            self.file.set_len(file_len.max(end))?;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn direct_io(&self) -> bool {
+        true
+    }
+}
+
+// Pages spread over <path>, <path>.001, <path>.002, ... each at most segment_size bytes.
+// segment_size is a multiple of the page size, so no page straddles two segments.
+struct SegmentedStorage {
+    base_path: std::path::PathBuf,
+    segment_size: u64,
+    segments: PMutex<Vec<File>>, // segments[0] is the primary file
+}
+
+impl SegmentedStorage {
+    fn segment_path(base_path: &Path, segment: usize) -> std::path::PathBuf {
+        let mut name = base_path.as_os_str().to_owned();
+        name.push(format!(".{:03}", segment));
+        std::path::PathBuf::from(name)
+    }
+
+    /// Opens the primary file's existing segments. Every segment the header records must be
+    /// present, and all but the last must be full.
+    fn open(primary: File, base_path: &Path, segment_size: u64, recorded_count: u32) -> io::Result<SegmentedStorage> {
+        let mut segments = vec![primary];
+        loop {
+            let path = Self::segment_path(base_path, segments.len());
+            match StreamDb::db_open_options().open(&path) {
+                Ok(file) => segments.push(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if (segments.len() as u32) < recorded_count {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Missing database segment"));
+        }
+        for segment in &segments[..segments.len() - 1] {
+            if segment.metadata()?.len() != segment_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Database segment is truncated"));
+            }
+        }
+        Ok(SegmentedStorage { base_path: base_path.to_path_buf(), segment_size, segments: PMutex::new(segments) })
+    }
+
+    fn segment_for(&self, offset: u64, len: usize) -> io::Result<(usize, u64)> {
+        let segment = (offset / self.segment_size) as usize;
+        let local = offset % self.segment_size;
+        if local + len as u64 > self.segment_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Access spans database segments"));
+        }
+        Ok((segment, local))
+    }
+
+    /// Adds segments up to the one holding the byte before end, filling those before it to
+    /// segment_size. Nothing is ever shortened, so writers growing the file cannot undo each other.
+    fn grow_to(&self, segments: &mut Vec<File>, end: u64) -> io::Result<()> {
+        let needed = end.max(1).div_ceil(self.segment_size) as usize;
+        while segments.len() < needed {
+            let path = Self::segment_path(&self.base_path, segments.len());
+            segments.push(StreamDb::db_open_options().create(true).open(path)?);
+        }
+        for segment in &segments[..needed - 1] {
+            if segment.metadata()?.len() < self.segment_size {
+                segment.set_len(self.segment_size)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SegmentedStorage {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let (segment, local) = self.segment_for(offset, buffer.len())?;
+        let mut segments = self.segments.lock();
+        let file = segments.get_mut(segment).ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Read past the last segment"))?;
+        file.seek(SeekFrom::Start(local))?;
+        file.read_exact(buffer)
+    }
+
+    // Keeps the default sync_data: a write here may create or extend a segment, which only a
+    // full sync is sure to persist
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let (segment, local) = self.segment_for(offset, data.len())?;
+        // Checked and grown under one hold of the lock, so a concurrent writer's growth stands
+        let mut segments = self.segments.lock();
+        if segment >= segments.len() {
+            self.grow_to(&mut segments, offset + data.len() as u64)?;
+        }
+        let file = &mut segments[segment];
+        file.seek(SeekFrom::Start(local))?;
+        file.write_all(data)?;
+        file.flush()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        let segments = self.segments.lock();
+        let last = segments.last().unwrap().metadata()?.len();
+        Ok((segments.len() as u64 - 1) * self.segment_size + last)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut segments = self.segments.lock();
+        let needed = (len.max(1) + self.segment_size - 1) / self.segment_size;
+        while (segments.len() as u64) < needed {
+            let path = Self::segment_path(&self.base_path, segments.len());
+            segments.push(StreamDb::db_open_options().create(true).open(path)?);
+        }
+        // Segments wholly past the new end go; the primary file always stays
+        while segments.len() as u64 > needed && segments.len() > 1 {
+            segments.pop();
+            std::fs::remove_file(Self::segment_path(&self.base_path, segments.len()))?;
+        }
+        for (i, segment) in segments.iter().enumerate() {
+            let segment_len = (len - (i as u64 * self.segment_size).min(len)).min(self.segment_size);
+            if segment.metadata()?.len() != segment_len {
+                segment.set_len(segment_len)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        for segment in self.segments.lock().iter() {
+            segment.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn segment_layout(&self) -> (u64, u32) {
+        (self.segment_size, self.segments.lock().len() as u32)
+    }
+}
+
+/// Programmable storage failures for crash-consistency testing. Writes are counted from 1
+/// across the whole database; once a fault fires with halt set, every later write and sync
+/// fails too, as if the process had died at that point.
+#[cfg(feature = "fault-injection")]
+#[derive(Clone, Debug, Default)]
+pub struct FaultSchedule {
+    pub fail_write: Option<u64>, // fail the Nth write
+    pub fail_page: Option<i64>, // fail any write touching this page
+    pub short_write: Option<u64>, // write only the first half of the Nth write, then fail
+    pub fail_reads: bool, // return EIO from every read
+    pub read_delay_ms: u64, // sleep before every read, to make slow IO visible in latency reports
+    pub halt: bool,
+    pub writes: u64, // writes seen so far, to size a sweep over a commit sequence
+    pub full_syncs: u64, // syncs, data syncs and write-back hints seen so far, to check which primitive a path uses
+    pub data_syncs: u64,
+    pub write_backs: u64,
+    pub fired: bool,
+}
+
+#[cfg(feature = "fault-injection")]
+struct FaultyStorage {
+    inner: Box<dyn Storage>,
+    page_size: u64,
+    schedule: Arc<PMutex<FaultSchedule>>,
+}
+
+#[cfg(feature = "fault-injection")]
+impl FaultyStorage {
+    fn injected(what: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, format!("Injected {} fault", what))
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+impl Storage for FaultyStorage {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let (fail_reads, read_delay_ms) = {
+            let schedule = self.schedule.lock();
+            (schedule.fail_reads, schedule.read_delay_ms)
         };
-        let mut db = StreamDb {
-            config,
-            file: PMutex::new(file),
-            mmap: PRwLock::new(mmap),
-            current_size: PMutex::new(0),
-            document_index_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
-            trie_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
-            free_list_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
-            page_cache: PMutex::new(LruCache::new(config.page_cache_size)),
-            path_cache: PMutex::new(LruCache::new(config.path_cache_size)),
-            cache_stats: PMutex::new(CacheStats { hits: 0, misses: 0 }),
-            quick_mode: Arc::new(std::sync::atomic::AtomicBool::new(quick_mode)),
-            transactions: PMutex::new(Vec::new()),
+        if fail_reads {
+            return Err(io::Error::from_raw_os_error(5)); // EIO
+        }
+        if read_delay_ms != 0 {
+            std::thread::sleep(std::time::Duration::from_millis(read_delay_ms));
+        }
+        self.inner.read_at(offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut schedule = self.schedule.lock();
+        if schedule.fired && schedule.halt {
+            return Err(Self::injected("write"));
+        }
+        schedule.writes += 1;
+        let first_page = (offset / self.page_size) as i64;
+        let last_page = ((offset + data.len().max(1) as u64 - 1) / self.page_size) as i64;
+        if schedule.fail_write == Some(schedule.writes)
+            || schedule.fail_page.is_some_and(|page_id| (first_page..=last_page).contains(&page_id)) {
+            schedule.fired = true;
+            return Err(Self::injected("write"));
+        }
+        if schedule.short_write == Some(schedule.writes) {
+            schedule.fired = true;
+            self.inner.write_at(offset, &data[..data.len() / 2])?;
+            return Err(Self::injected("short write"));
+        }
+        self.inner.write_at(offset, data)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let schedule = self.schedule.lock();
+        if schedule.fired && schedule.halt {
+            return Err(Self::injected("set_len"));
+        }
+        self.inner.set_len(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut schedule = self.schedule.lock();
+        if schedule.fired && schedule.halt {
+            return Err(Self::injected("sync"));
+        }
+        schedule.full_syncs += 1;
+        self.inner.sync()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let mut schedule = self.schedule.lock();
+        if schedule.fired && schedule.halt {
+            return Err(Self::injected("sync"));
+        }
+        schedule.data_syncs += 1;
+        self.inner.sync_data()
+    }
+
+    fn start_write_back(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.schedule.lock().write_backs += 1;
+        self.inner.start_write_back(offset, len)
+    }
+
+    fn segment_layout(&self) -> (u64, u32) {
+        self.inner.segment_layout()
+    }
+
+    fn direct_io(&self) -> bool {
+        self.inner.direct_io()
+    }
+}
+
+// Sorted (key, document) views of the index for range queries; None when that index is not enabled.
+// Rewritten together with the index so a published index never disagrees with them.
+#[derive(Clone, Default, PartialEq)]
+struct SecondaryIndexes {
+    by_size: Option<BTreeSet<(u64, Uuid)>>,
+    by_modified: Option<BTreeSet<(u64, Uuid)>>,
+}
+
+impl SecondaryIndexes {
+    fn rebuilt(&self, index: &BTreeMap<Uuid, Document>) -> SecondaryIndexes {
+        SecondaryIndexes {
+            by_size: self.by_size.as_ref().map(|_| index.values().map(|doc| (doc.size, doc.id)).collect()),
+            by_modified: self.by_modified.as_ref().map(|_| index.values().map(|doc| (doc.modified, doc.id)).collect()),
+        }
+    }
+
+    fn get(&self, kind: ffi::SecondaryIndexKind) -> Option<&BTreeSet<(u64, Uuid)>> {
+        match kind {
+            ffi::SecondaryIndexKind::Size => self.by_size.as_ref(),
+            _ => self.by_modified.as_ref(),
+        }
+    }
+
+    fn slot(&mut self, kind: ffi::SecondaryIndexKind) -> &mut Option<BTreeSet<(u64, Uuid)>> {
+        match kind {
+            ffi::SecondaryIndexKind::Size => &mut self.by_size,
+            _ => &mut self.by_modified,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_size.is_none() && self.by_modified.is_none()
+    }
+
+    // Presence mask, then each enabled index as a count and sorted (key, uuid) entries
+    fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        buffer.write_u8(self.by_size.is_some() as u8 | (self.by_modified.is_some() as u8) << 1)?;
+        for entries in [&self.by_size, &self.by_modified].into_iter().flatten() {
+            buffer.write_i32::<LittleEndian>(entries.len() as i32)?;
+            for (key, id) in entries {
+                buffer.write_u64::<LittleEndian>(*key)?;
+                buffer.write_all(id.as_bytes())?;
+            }
+        }
+        Ok(buffer)
+    }
+
+    fn deserialize(data: &[u8]) -> io::Result<SecondaryIndexes> {
+        let mut reader = Cursor::new(data);
+        let mask = reader.read_u8()?;
+        let mut indexes = SecondaryIndexes::default();
+        for (bit, slot) in [(1u8, &mut indexes.by_size), (2u8, &mut indexes.by_modified)] {
+            if mask & bit == 0 {
+                continue;
+            }
+            let count = StreamDb::read_count(&mut reader, 24, "secondary index")?;
+            let mut entries = BTreeSet::new();
+            for _ in 0..count {
+                let key = reader.read_u64::<LittleEndian>()?;
+                let mut id_bytes = [0u8; 16];
+                reader.read_exact(&mut id_bytes)?;
+                entries.insert((key, Uuid::from_bytes(id_bytes)));
+            }
+            *slot = Some(entries);
+        }
+        Ok(indexes)
+    }
+}
+
+// A FLAG_SLAB_PAGE payload: a u16 slot count and a table of (u16 offset, u16 length) slots at
+// the front, records packed down from the end. Records never move once written, so a slot's
+// offset can be handed out as an extent; the space of freed records is only reused once the
+// records below it are gone too, or when vacuum repacks the page.
+struct SlabPage {
+    data: Vec<u8>,
+}
+
+impl SlabPage {
+    fn new(capacity: usize) -> SlabPage {
+        SlabPage { data: vec![0; capacity] }
+    }
+
+    fn from_payload(data: Vec<u8>) -> io::Result<SlabPage> {
+        let slab = SlabPage { data };
+        if slab.data.len() < 2 || slab.table_end(slab.slot_count()) > slab.data.len() {
+            return Err(StreamDb::corrupt("slab page"));
+        }
+        let table_end = slab.table_end(slab.slot_count());
+        for slot in 0..slab.slot_count() {
+            if let Some((offset, length)) = slab.slot(slot) {
+                if offset < table_end || offset + length > slab.data.len() {
+                    return Err(StreamDb::corrupt("slab page"));
+                }
+            }
+        }
+        Ok(slab)
+    }
+
+    fn slot_count(&self) -> usize {
+        u16::from_le_bytes([self.data[0], self.data[1]]) as usize
+    }
+
+    fn table_end(&self, slots: usize) -> usize {
+        2 + 4 * slots
+    }
+
+    /// (offset, length) of a live slot.
+    fn slot(&self, slot: usize) -> Option<(usize, usize)> {
+        if slot >= self.slot_count() {
+            return None;
+        }
+        let entry = &self.data[2 + 4 * slot..6 + 4 * slot];
+        match u16::from_le_bytes([entry[2], entry[3]]) {
+            SLAB_FREE_SLOT => None,
+            length => Some((u16::from_le_bytes([entry[0], entry[1]]) as usize, length as usize)),
+        }
+    }
+
+    fn record(&self, slot: usize) -> Option<&[u8]> {
+        self.slot(slot).map(|(offset, length)| &self.data[offset..offset + length])
+    }
+
+    fn set_slot(&mut self, slot: usize, offset: u16, length: u16) {
+        self.data[2 + 4 * slot..4 + 4 * slot].copy_from_slice(&offset.to_le_bytes());
+        self.data[4 + 4 * slot..6 + 4 * slot].copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn records_start(&self) -> usize {
+        (0..self.slot_count()).filter_map(|slot| self.slot(slot)).map(|(offset, _)| offset).min().unwrap_or(self.data.len())
+    }
+
+    /// Stores record in the first free slot, or a new one. None if it does not fit.
+    fn insert(&mut self, record: &[u8]) -> Option<usize> {
+        let count = self.slot_count();
+        let slot = (0..count).find(|&slot| self.slot(slot).is_none()).unwrap_or(count);
+        let records_start = self.records_start();
+        if self.table_end(count.max(slot + 1)) + record.len() > records_start {
+            return None;
+        }
+        let offset = records_start - record.len();
+        self.data[offset..records_start].copy_from_slice(record);
+        if slot == count {
+            self.data[..2].copy_from_slice(&(count as u16 + 1).to_le_bytes());
+        }
+        self.set_slot(slot, offset as u16, record.len() as u16);
+        Some(slot)
+    }
+
+    /// Rewrites a live slot's record: in place if it is no longer than before, otherwise in
+    /// free space. False if it does not fit, leaving the page unchanged. Moves the record, so
+    /// only for slabs that hand out no extents.
+    fn replace(&mut self, slot: usize, record: &[u8]) -> bool {
+        let (offset, length) = match self.slot(slot) {
+            Some(span) => span,
+            None => return false,
         };
-        db.initialize()?;
-        Ok(cxx::UniquePtr::new(db))
+        let offset = if record.len() <= length {
+            offset
+        } else {
+            let records_start = self.records_start();
+            if self.table_end(self.slot_count()) + record.len() > records_start {
+                return false;
+            }
+            records_start - record.len()
+        };
+        self.data[offset..offset + record.len()].copy_from_slice(record);
+        self.set_slot(slot, offset as u16, record.len() as u16);
+        true
+    }
+
+    /// Frees slot. Trailing free slots are dropped from the table since nothing addresses them.
+    fn remove(&mut self, slot: usize) -> bool {
+        if self.slot(slot).is_none() {
+            return false;
+        }
+        self.set_slot(slot, 0, SLAB_FREE_SLOT);
+        let mut count = self.slot_count();
+        while count > 0 && self.slot(count - 1).is_none() {
+            count -= 1;
+        }
+        self.data[..2].copy_from_slice(&(count as u16).to_le_bytes());
+        true
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slot_count() == 0
+    }
+
+    /// Bytes held by freed slots and records that vacuum would get back.
+    fn reclaimable(&self) -> usize {
+        let live: Vec<_> = (0..self.slot_count()).filter_map(|slot| self.slot(slot)).collect();
+        let used = self.data.len() - self.records_start() + self.table_end(self.slot_count());
+        used - live.iter().map(|(_, length)| length).sum::<usize>() - self.table_end(live.len())
+    }
+}
+
+// What a slab page holds. Trie slabs are FLAG_TRIE_PAGE as well and bypass the page cache:
+// their nodes are cached on their own, so lookups don't evict document pages.
+#[derive(Clone, Copy, PartialEq)]
+enum SlabKind {
+    Document,
+    Trie,
+}
+
+impl SlabKind {
+    fn flags(self) -> u8 {
+        match self {
+            SlabKind::Document => FLAG_SLAB_PAGE,
+            SlabKind::Trie => FLAG_SLAB_PAGE | FLAG_TRIE_PAGE,
+        }
+    }
+}
+
+// A page of the document index B-tree. Branch keys[i] is the smallest key under children[i + 1].
+enum IndexNode {
+    Leaf(BTreeMap<Uuid, Document>),
+    Branch { keys: Vec<Uuid>, children: Vec<i64> },
+}
+
+// Walks the document index depth first, yielding each leaf's entries in key order
+struct IndexLeaves<'a> {
+    db: &'a StreamDb,
+    pending: Vec<(i64, usize)>, // page id and depth
+}
+
+impl Iterator for IndexLeaves<'_> {
+    type Item = io::Result<BTreeMap<Uuid, Document>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((page_id, depth)) = self.pending.pop() {
+            let node = if depth > INDEX_MAX_DEPTH { Err(StreamDb::corrupt("document index")) } else { self.db.read_index_node(page_id) };
+            match node {
+                Ok(IndexNode::Leaf(entries)) => return Some(Ok(entries)),
+                Ok(IndexNode::Branch { children, .. }) => self.pending.extend(children.into_iter().rev().map(|child| (child, depth + 1))),
+                Err(e) => {
+                    self.pending.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+// Index entries changed since the B-tree was last folded, as appended to the index log.
+// read_index applies them over what the B-tree holds.
+#[derive(Default)]
+struct IndexLog {
+    tail: Option<(i64, Vec<u8>)>, // the chain's last page and the records on it
+    records: usize,
+    entries: BTreeMap<Uuid, Document>, // latest logged state of each document
+}
+
+// Foreground writes and maintenance slices take turns through gate; between slices the
+// maintenance thread sleeps on wake. Document writes lay down their pages before taking the
+// gate, sharing layout; work that moves or truncates pages holds layout exclusively first.
+#[derive(Default)]
+struct Maintenance {
+    layout: PRwLock<()>,
+    gate: ReentrantMutex<()>, // held for each foreground write and each maintenance slice
+    paused: std::sync::atomic::AtomicBool,
+    stopping: PMutex<bool>,
+    wake: Condvar,
+    thread: PMutex<Option<std::thread::JoinHandle<()>>>,
+    progress: PMutex<MaintenanceProgress>,
+}
+
+// How far the current maintenance pass got. A pass purges expired documents, measures slab
+// fragmentation, repacks if it is over the threshold, then truncates; slices whose budget
+// runs out leave it here for the next one.
+#[derive(Default)]
+struct MaintenanceProgress {
+    step: MaintenanceStep,
+    slab_pages: Vec<i64>, // to measure, as of the start of the measuring step
+    scanned: usize, // slab_pages measured so far
+    reclaimable: u64, // bytes found reclaimable on them
+    repack_from: i64, // first slab page the repack has yet to look at
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum MaintenanceStep {
+    #[default]
+    Purge,
+    Measure,
+    Repack,
+    Truncate,
+}
+
+// Bounds a maintenance slice: by time when pumped from a frame loop, by pages read on the
+// maintenance thread. Checked between pages, so a slice overruns by at most one page's work
+// and the index write that ends a repack.
+struct WorkBudget {
+    deadline: Option<std::time::Instant>,
+    pages_left: usize,
+}
+
+impl WorkBudget {
+    fn unlimited() -> Self {
+        WorkBudget { deadline: None, pages_left: usize::MAX }
+    }
+
+    fn pages(pages: usize) -> Self {
+        WorkBudget { deadline: None, pages_left: pages }
+    }
+
+    fn millis(max_millis: u64) -> Self {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(max_millis);
+        WorkBudget { deadline: Some(deadline), pages_left: usize::MAX }
+    }
+
+    fn spent(&self) -> bool {
+        self.pages_left == 0 || self.deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline)
+    }
+
+    fn charge(&mut self) {
+        self.pages_left = self.pages_left.saturating_sub(1);
+    }
+}
+
+// The database as its maintenance thread sees it. shutdown joins the thread before the
+// database can be dropped, so the pointer is valid for as long as the thread runs.
+struct MaintainedDb(*const StreamDb);
+
+unsafe impl Send for MaintainedDb {}
+
+impl MaintainedDb {
+    fn run(self) {
+        unsafe { &*self.0 }.maintenance_loop()
+    }
+}
+
+// A document's contents on their way in: their SHA-256 for dedup, and the chain holding them
+// if it was written before the gate was taken
+struct DocumentContents {
+    hash: Option<[u8; 32]>,
+    written: Option<i64>,
+}
+
+impl DocumentContents {
+    fn hash(data: &[u8], dedup: bool) -> Self {
+        let hash = if dedup && !data.is_empty() { Some(Sha256::digest(data).into()) } else { None };
+        DocumentContents { hash, written: None }
+    }
+}
+
+// A compressed page read on the calling thread, for a decompression thread to finish
+struct CompressedPage {
+    page_id: i64,
+    generation: u64, // of the page when it was read, for the cache key
+    header: PageHeader,
+    payload: Vec<u8>,
+}
+
+// Gear hash table for content-defined chunking, filled by splitmix64 so every build cuts the same chunks
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+// One operation of a patch made by create_patch
+enum PatchOp {
+    Put { path: String, data: Vec<u8> },
+    Remove { path: String },
+    Delta { path: String, base_checksum: u32, prefix: u64, suffix: u64, middle: Vec<u8> },
+}
+
+// The second database of mirror mode and the queue of changed paths its thread copies over
+struct Mirror {
+    db: StreamDb,
+    sender: PMutex<Option<std::sync::mpsc::SyncSender<String>>>, // None until the thread runs and once it stops
+    thread: PMutex<Option<std::thread::JoinHandle<()>>>,
+    overflowed: std::sync::atomic::AtomicBool, // changes were dropped; the next idle check resyncs
+    diverged: std::sync::atomic::AtomicBool, // as of the last idle check or failed copy
+    fallback: bool, // documents whose chains are corrupt in the primary are read from here
+}
+
+struct MirroredDb(*const StreamDb);
+
+unsafe impl Send for MirroredDb {}
+
+impl MirroredDb {
+    fn run(self, receiver: std::sync::mpsc::Receiver<String>) {
+        unsafe { &*self.0 }.mirror_loop(receiver)
+    }
+}
+
+// Change notifications queued for the engine to drain; nothing is queued until recording is on
+struct EventLog {
+    recording: std::sync::atomic::AtomicBool,
+    queue: PMutex<VecDeque<ffi::DocumentEvent>>,
+    dropped: std::sync::atomic::AtomicU64,
+}
+
+const LATENCY_BUCKETS: usize = 32;
+
+// Counts of operations by duration: bucket i holds durations under 2^(i+1) microseconds
+struct LatencyHistogram {
+    buckets: [std::sync::atomic::AtomicU64; LATENCY_BUCKETS],
+    max_us: std::sync::atomic::AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        LatencyHistogram {
+            buckets: std::array::from_fn(|_| std::sync::atomic::AtomicU64::new(0)),
+            max_us: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, started: std::time::Instant) {
+        let us = started.elapsed().as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (63 - (us | 1).leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.max_us.fetch_max(us, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Percentiles are reported as the upper bound of the bucket they fall in.
+    fn summary(&self) -> ffi::LatencySummary {
+        let counts: Vec<u64> = self.buckets.iter().map(|b| b.load(std::sync::atomic::Ordering::Relaxed)).collect();
+        let count: u64 = counts.iter().sum();
+        let percentile = |fraction: f64| {
+            let target = ((count as f64 * fraction).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (i, &n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= target {
+                    return (2u64 << i) - 1;
+                }
+            }
+            0
+        };
+        let max_us = self.max_us.load(std::sync::atomic::Ordering::Relaxed);
+        ffi::LatencySummary {
+            count,
+            p50_us: if count == 0 { 0 } else { percentile(0.5).min(max_us) },
+            p99_us: if count == 0 { 0 } else { percentile(0.99).min(max_us) },
+            max_us,
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, std::sync::atomic::Ordering::Relaxed);
+        }
+        self.max_us.store(0, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TimedOp {
+    PageRead,
+    DocumentGet,
+    Write,
+    Flush,
+}
+
+impl TimedOp {
+    const ALL: [TimedOp; 4] = [TimedOp::PageRead, TimedOp::DocumentGet, TimedOp::Write, TimedOp::Flush];
+
+    fn name(self) -> &'static str {
+        match self {
+            TimedOp::PageRead => "page read",
+            TimedOp::DocumentGet => "document get",
+            TimedOp::Write => "write",
+            TimedOp::Flush => "flush",
+        }
+    }
+
+    fn tunable(self) -> &'static str {
+        match self {
+            TimedOp::PageRead => "slow_read_ms",
+            TimedOp::DocumentGet => "slow_get_ms",
+            TimedOp::Write => "slow_write_ms",
+            TimedOp::Flush => "slow_flush_ms",
+        }
+    }
+}
+
+const SLOW_OP_DEFAULT_MS: [u64; 4] = [20, 50, 50, 100]; // indexed by TimedOp
+const SLOW_OP_WARNING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5); // per operation type
+
+// What a slow-operation warning names; formatted only when a warning is actually logged
+enum OpDetail<'a> {
+    None,
+    Page(i64),
+    Path(&'a str),
+}
+
+struct LatencyStats {
+    enabled: std::sync::atomic::AtomicBool,
+    page_reads: LatencyHistogram,
+    document_gets: LatencyHistogram,
+    writes: LatencyHistogram,
+    flushes: LatencyHistogram,
+    slow_threshold_ms: [std::sync::atomic::AtomicU64; 4], // 0 turns the warning off
+    slow_last_warned: PMutex<[Option<std::time::Instant>; 4]>,
+    slow_suppressed: [std::sync::atomic::AtomicU64; 4],
+    log_sink: std::sync::atomic::AtomicPtr<ffi::idCommon>, // null until the engine installs one
+    #[cfg(feature = "fault-injection")]
+    captured_warnings: PMutex<Option<Vec<String>>>, // when Some, warnings are kept here instead of logged
+}
+
+impl LatencyStats {
+    fn new() -> Self {
+        LatencyStats {
+            enabled: std::sync::atomic::AtomicBool::new(false),
+            page_reads: LatencyHistogram::new(),
+            document_gets: LatencyHistogram::new(),
+            writes: LatencyHistogram::new(),
+            flushes: LatencyHistogram::new(),
+            slow_threshold_ms: SLOW_OP_DEFAULT_MS.map(std::sync::atomic::AtomicU64::new),
+            slow_last_warned: PMutex::new([None; 4]),
+            slow_suppressed: std::array::from_fn(|_| std::sync::atomic::AtomicU64::new(0)),
+            log_sink: std::sync::atomic::AtomicPtr::new(std::ptr::null_mut()),
+            #[cfg(feature = "fault-injection")]
+            captured_warnings: PMutex::new(None),
+        }
+    }
+
+    /// Whether slow-operation warnings have somewhere to go.
+    fn has_sink(&self) -> bool {
+        #[cfg(feature = "fault-injection")]
+        if self.captured_warnings.lock().is_some() {
+            return true;
+        }
+        !self.log_sink.load(std::sync::atomic::Ordering::Relaxed).is_null()
+    }
+
+    fn histogram(&self, op: TimedOp) -> &LatencyHistogram {
+        match op {
+            TimedOp::PageRead => &self.page_reads,
+            TimedOp::DocumentGet => &self.document_gets,
+            TimedOp::Write => &self.writes,
+            TimedOp::Flush => &self.flushes,
+        }
+    }
+
+    /// Times op until the returned timer drops. Without histograms or a log sink nothing is measured.
+    fn time<'a>(&'a self, op: TimedOp, detail: OpDetail<'a>) -> LatencyTimer<'a> {
+        let timing = self.enabled.load(std::sync::atomic::Ordering::Relaxed) || self.has_sink();
+        LatencyTimer { stats: self, op, detail, started: if timing { Some(std::time::Instant::now()) } else { None } }
+    }
+
+    /// Logs op through the engine if it ran past its threshold, at most once per interval per
+    /// operation type; warnings held back meanwhile are counted in the next one.
+    fn check_slow(&self, op: TimedOp, detail: &OpDetail, elapsed: std::time::Duration) {
+        let threshold_ms = self.slow_threshold_ms[op as usize].load(std::sync::atomic::Ordering::Relaxed);
+        if threshold_ms == 0 || elapsed < std::time::Duration::from_millis(threshold_ms) || !self.has_sink() {
+            return;
+        }
+        {
+            let mut last_warned = self.slow_last_warned.lock();
+            match last_warned[op as usize] {
+                Some(at) if at.elapsed() < SLOW_OP_WARNING_INTERVAL => {
+                    self.slow_suppressed[op as usize].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    return;
+                }
+                _ => last_warned[op as usize] = Some(std::time::Instant::now()),
+            }
+        }
+        let target = match detail {
+            OpDetail::None => String::new(),
+            OpDetail::Page(page_id) => format!(" of page {}", page_id),
+            OpDetail::Path(path) => format!(" of {}", path),
+        };
+        let mut message = format!("StreamDB: slow {}{} took {}ms (threshold {}ms)",
+            op.name(), target, elapsed.as_millis(), threshold_ms);
+        let suppressed = self.slow_suppressed[op as usize].swap(0, std::sync::atomic::Ordering::Relaxed);
+        if suppressed != 0 {
+            message.push_str(&format!(", {} more since the last warning", suppressed));
+        }
+        message.push('\n');
+        #[cfg(feature = "fault-injection")]
+        if let Some(captured) = self.captured_warnings.lock().as_mut() {
+            captured.push(message);
+            return;
+        }
+        let sink = self.log_sink.load(std::sync::atomic::Ordering::Relaxed);
+        if sink.is_null() {
+            return;
+        }
+        cxx::let_cxx_string!(message = message);
+        // The sink is valid from set_log_sink until the engine clears it
+        unsafe { &*sink }.commonPrintf(&message);
+    }
+}
+
+// Records when dropped, so every return path of the timed scope is counted
+struct LatencyTimer<'a> {
+    stats: &'a LatencyStats,
+    op: TimedOp,
+    detail: OpDetail<'a>,
+    started: Option<std::time::Instant>,
+}
+
+impl Drop for LatencyTimer<'_> {
+    fn drop(&mut self) {
+        if let Some(started) = self.started {
+            if self.stats.enabled.load(std::sync::atomic::Ordering::Relaxed) {
+                self.stats.histogram(self.op).record(started);
+            }
+            self.stats.check_slow(self.op, &self.detail, started.elapsed());
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TrackedLock {
+    Layout,
+    Gate,
+    Allocation,
+    PageStripes,
+    PageCache,
+    Mmap,
+}
+
+impl TrackedLock {
+    const ALL: [TrackedLock; 6] = [TrackedLock::Layout, TrackedLock::Gate, TrackedLock::Allocation,
+        TrackedLock::PageStripes, TrackedLock::PageCache, TrackedLock::Mmap];
+
+    /// Position in the lock order documented on StreamDb; locks are taken in increasing rank.
+    fn rank(self) -> u8 {
+        match self {
+            TrackedLock::Layout => 0,
+            TrackedLock::Gate => 1,
+            TrackedLock::Allocation => 2,
+            TrackedLock::PageStripes => 3,
+            TrackedLock::Mmap => 4,
+            TrackedLock::PageCache => 5,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TrackedLock::Layout => "layout",
+            TrackedLock::Gate => "write gate",
+            TrackedLock::Allocation => "allocation",
+            TrackedLock::PageStripes => "page stripes",
+            TrackedLock::PageCache => "page cache",
+            TrackedLock::Mmap => "mmap",
+        }
+    }
+}
+
+#[derive(Default)]
+struct LockCounters {
+    acquisitions: std::sync::atomic::AtomicU64,
+    contended: std::sync::atomic::AtomicU64,
+    wait_us: std::sync::atomic::AtomicU64,
+    max_wait_us: std::sync::atomic::AtomicU64,
+}
+
+// Contention on the locks writers and page IO queue on, indexed by TrackedLock
+#[derive(Default)]
+struct LockStats {
+    enabled: std::sync::atomic::AtomicBool,
+    counters: [LockCounters; 6],
+}
+
+impl LockStats {
+    /// Acquires with acquire. With tracking on, try_acquire is attempted first, and only an
+    /// acquisition it cannot make immediately is timed as a wait.
+    /// Debug builds check the lock order first, so taking a lock out of order panics on the
+    /// spot instead of deadlocking only when another thread happens to race it.
+    fn acquire<G>(&self, lock: TrackedLock, try_acquire: impl FnOnce() -> Option<G>, acquire: impl FnOnce() -> G) -> Ordered<G> {
+        let rank = LockRank::enter(self, lock);
+        Ordered { guard: self.timed(lock, try_acquire, acquire), _rank: rank }
+    }
+
+    fn timed<G>(&self, lock: TrackedLock, try_acquire: impl FnOnce() -> Option<G>, acquire: impl FnOnce() -> G) -> G {
+        if !self.enabled.load(std::sync::atomic::Ordering::Relaxed) {
+            return acquire();
+        }
+        let counters = &self.counters[lock as usize];
+        counters.acquisitions.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if let Some(guard) = try_acquire() {
+            return guard;
+        }
+        let started = std::time::Instant::now();
+        let guard = acquire();
+        let waited_us = started.elapsed().as_micros().min(u64::MAX as u128) as u64;
+        counters.contended.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        counters.wait_us.fetch_add(waited_us, std::sync::atomic::Ordering::Relaxed);
+        counters.max_wait_us.fetch_max(waited_us, std::sync::atomic::Ordering::Relaxed);
+        guard
+    }
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    // Tracked locks this thread holds, oldest first, with the LockStats of their database
+    static HELD_LOCKS: std::cell::RefCell<Vec<(usize, TrackedLock)>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+// Marks a tracked lock as held by this thread until dropped. Only debug builds keep the record.
+struct LockRank {
+    #[cfg(debug_assertions)]
+    entry: (usize, TrackedLock),
+}
+
+impl LockRank {
+    /// Panics if this thread holds a lock of the same database that comes later in the order.
+    /// The same lock again is allowed: the gate is reentrant, and page stripes and cache shards
+    /// are never held two at a time by one path. Other databases' locks (a mirror's) do not count.
+    #[cfg(debug_assertions)]
+    fn enter(stats: &LockStats, lock: TrackedLock) -> LockRank {
+        let entry = (stats as *const LockStats as usize, lock);
+        HELD_LOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(&(_, later)) = held.iter().find(|(db, taken)| *db == entry.0 && taken.rank() > lock.rank()) {
+                panic!("lock order violated: {} taken while holding {}", lock.name(), later.name());
+            }
+            held.push(entry);
+        });
+        LockRank { entry }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn enter(_stats: &LockStats, _lock: TrackedLock) -> LockRank {
+        LockRank {}
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for LockRank {
+    fn drop(&mut self) {
+        HELD_LOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            // Guards need not drop in the order they were taken
+            if let Some(position) = held.iter().rposition(|&(db, lock)| db == self.entry.0 && lock.rank() == self.entry.1.rank()) {
+                held.remove(position);
+            }
+        });
+    }
+}
+
+// A tracked lock's guard. The guard drops before the rank, so the record outlives the lock.
+struct Ordered<G> {
+    guard: G,
+    _rank: LockRank,
+}
+
+impl<G> Ordered<G> {
+    /// Converts the guard, such as downgrading a write guard, keeping its place in the order.
+    fn map<H>(self, convert: impl FnOnce(G) -> H) -> Ordered<H> {
+        Ordered { guard: convert(self.guard), _rank: self._rank }
+    }
+}
+
+impl<G> std::ops::Deref for Ordered<G> {
+    type Target = G;
+
+    fn deref(&self) -> &G {
+        &self.guard
+    }
+}
+
+impl<G> std::ops::DerefMut for Ordered<G> {
+    fn deref_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+// Open streams on a chain; freeing a pinned chain is deferred until the last stream ends
+struct ChainPin {
+    streams: usize,
+    pending_free: bool,
+}
+
+// Every page's summary as of open_page_iterator, handed out in batches.
+struct PageIterator {
+    pages: Vec<ffi::PageInfo>,
+    position: usize,
+}
+
+// The documents under a prefix as of open_cursor. Each one's chain is pinned, so deletes,
+// rewrites and vacuum can neither free nor move it before the cursor is closed.
+struct ReadCursor {
+    entries: Vec<(String, Document)>, // in path order
+    position: usize,
+    last_used: std::time::Instant,
+}
+
+#[cxx::bridge]
+mod ffi {
+    #[derive(Clone, Debug)]
+    struct CacheStats {
+        hits: usize,
+        misses: usize,
+        bypassed: usize, // misses read without being cached, by scans and bypass_cache reads
+    }
+
+    /// Per-call read settings for get_with_options and get_many_with_options.
+    #[derive(Clone, Debug, Default)]
+    struct ReadOptions {
+        bypass_cache: bool, // serve hits but leave the cache's contents and order untouched
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum TrieViolationKind {
+        DanglingChild,
+        UnreadableNode,
+        SelfPageMismatch,
+        ParentMismatch,
+        SiblingEdgeConflict,
+        CyclicReference,
+        OrphanDocument,
+        UnreachablePath,
+    }
+
+    #[derive(Clone, Debug)]
+    struct TrieViolation {
+        kind: TrieViolationKind,
+        page_id: i64,
+        path_fragment: String,
+    }
+
+    #[derive(Clone, Debug)]
+    struct TrieReport {
+        nodes_checked: u64,
+        paths_checked: u64,
+        violations: Vec<TrieViolation>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct VerifyReport {
+        pages_checked: u64,
+        corrupt_pages: Vec<i64>,
+        index_ok: bool,
+        trie: TrieReport,
+        paths_restored: u64,
+        missing_dictionaries: Vec<u8>, // referenced by compressed pages but not stored
+        document_count_ok: bool, // the header's document count matches the index
+        size_mismatches: Vec<String>, // documents whose stored size or page count disagrees with their chain (deep only)
+    }
+
+    /// One page as open_page_iterator saw it. A page whose header does not parse has
+    /// flags, version and data_length 0 and crc_ok false.
+    #[derive(Clone, Debug)]
+    struct PageInfo {
+        page_id: i64,
+        flags: u8, // FLAG_* bits of the header
+        version: i32,
+        data_length: i32,
+        crc_ok: bool, // payload matches the header's CRC; free list pages carry none and report true
+    }
+
+    #[derive(Clone, Debug)]
+    struct DocumentInfo {
+        path: String,
+        uuid: String,
+        size: u64,
+        version: i32,
+        expires_at: u64, // 0 if the document never expires
+        flags: u32, // DocumentFlag bits
+        page_count: u32, // pages the current version occupies; 0 for slab-stored documents
+    }
+
+    /// One result of search_documents: a document and its paths under the prefix, all of them
+    /// when grouped and otherwise one per result.
+    #[derive(Clone, Debug)]
+    struct DocumentPaths {
+        uuid: String,
+        paths: Vec<String>, // in order
+    }
+
+    /// A document's footprint in top_documents_by_physical_size.
+    #[derive(Clone, Debug)]
+    struct PhysicalSize {
+        path: String, // the document's first path
+        logical_size: u64,
+        physical_size: u64, // stored payload plus page headers
+        compression_ratio: f64, // logical over physical size; 0 for an empty document
+    }
+
+    /// One path's entry in export_manifest. stored_bytes is 0 unless physical stats were asked for.
+    #[derive(Clone, Debug)]
+    struct ManifestEntry {
+        path: String,
+        uuid: String,
+        size: u64,
+        version: i32,
+        modified: u64, // unix time of the last write; 0 if unknown
+        checksum: u32, // CRC32 of the full contents
+        flags: u32, // DocumentFlag bits
+        stored_bytes: u64, // payload bytes on disk, after compression
+    }
+
+    #[derive(Clone, Debug)]
+    struct Manifest {
+        entries: Vec<ManifestEntry>, // in path order
+        page_count: u64, // 0 unless physical stats were asked for
+        file_bytes: u64, // the same
+    }
+
+    /// A path that differs between two databases; the size on the side it is missing from is 0.
+    #[derive(Clone, Debug)]
+    struct DocumentChange {
+        path: String,
+        old_size: u64,
+        new_size: u64,
+    }
+
+    #[derive(Clone, Debug)]
+    struct DbDiff {
+        added: Vec<DocumentChange>, // each list in path order
+        removed: Vec<DocumentChange>,
+        modified: Vec<DocumentChange>, // checksum or size differs
+    }
+
+    /// A content-defined chunk of a document; chunk boundaries follow the contents, so an edit
+    /// changes only the chunks around it.
+    #[derive(Clone, Debug)]
+    struct ChunkSignature {
+        offset: u64,
+        length: u64,
+        hash: Vec<u8>, // SHA-256 of the chunk
+    }
+
+    /// A chunk fetched for sync_document_from; index is its position in the signature list.
+    #[derive(Clone, Debug)]
+    struct ChunkData {
+        index: u64,
+        data: Vec<u8>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct MirrorStatus {
+        mirrored: bool, // a mirror thread is copying committed changes
+        diverged: bool, // the mirror's contents differed at the last idle check, or a copy failed
+        overflowed: bool, // changes were dropped from the queue; a resync is pending
+        serving_mirror: bool, // the primary failed and this handle opened the mirror instead
+    }
+
+    #[derive(Clone, Debug)]
+    struct DocumentData {
+        path: String,
+        data: Vec<u8>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct PurgeReport {
+        documents: u64,
+        bytes_reclaimed: u64,
+    }
+
+    #[derive(Clone, Debug)]
+    struct VacuumReport {
+        records_moved: u64,
+        pages_freed: u64, // net of the pages the moved records now occupy
+        pages_truncated: u64, // free pages cut from the end of the file afterwards
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct MaintenanceReport {
+        documents_purged: u64,
+        slab_pages_scanned: u64, // measuring fragmentation
+        records_moved: u64,
+        pages_freed: u64,
+        pages_truncated: u64,
+        pass_completed: bool, // false when the budget ran out mid-pass; the next slice resumes it
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    struct Extent {
+        file_offset: u64,
+        length: u64,
+    }
+
+    /// How a database file was created, from its extended header.
+    #[derive(Clone, Debug)]
+    struct DbInfo {
+        format_version: u16,
+        created_unix_secs: u64,
+        creator: String,
+        page_size: u32,
+        codec: String, // "none", "snappy" or "zstd"
+        checksum_algorithm: String, // "crc32"
+        critical_features: u32,
+        optional_features: u32,
+        huge_pages: bool, // the mapping is huge-page aligned and the kernel accepted the advice
+    }
+
+    #[derive(Clone, Debug)]
+    struct DocumentExtents {
+        eligible: bool, // false when pages are compressed; extents is then empty
+        version_stamp: u64,
+        extents: Vec<Extent>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct PathPolicy {
+        max_path_length: usize,
+        max_component_length: usize,
+        allow_spaces: bool,
+        allow_non_ascii: bool,
+        normalize: bool,
+    }
+
+    #[derive(Clone, Debug)]
+    struct PathClaim {
+        uuid: String,
+        addon: bool,
+        priority: i32,
+        lang: String,
+    }
+
+    #[derive(Clone, Debug)]
+    struct PathResolution {
+        uuid: String,
+        addon: bool,
+        priority: i32,
+        lang: String,
+        shadowed: Vec<PathClaim>,
+    }
+
+    /// One of the paths get_paths_for_uuid finds a document bound to.
+    #[derive(Clone, Debug)]
+    struct PathBindingInfo {
+        path: String,
+        addon: bool,
+        priority: i32,
+        lang: String, // empty for the default binding
+        resolved: bool, // path currently resolves to this document, rather than to a shadowing one
+    }
+
+    /// Everything open can be told. Start from create_options() and change only what differs;
+    /// fields added later get defaults there, so callers keep compiling.
+    #[derive(Clone, Debug)]
+    struct StreamDbOptions {
+        use_compression: bool,
+        quick_mode: bool,
+        page_cache_size: usize,
+        path_cache_size: usize,
+        trie_cache_size: usize, // trie nodes, sized apart from the page cache
+        path_snapshot_budget: u64, // bytes for get_all_paths_sorted's snapshot; 0 keeps none
+        versions_to_keep: i32,
+        path_policy: PathPolicy,
+        durable_writes: bool, // off for disposable databases
+        lock_timeout_ms: u64, // how long to wait for another process's lock; 0 fails at once
+        read_only: bool, // open an existing database for reading only, alongside other readers
+        segmented: bool, // split a new database into segment files
+        segment_size: u64, // 0 picks the 2GB default
+        use_mmap: bool,
+        io_mode: IoMode, // ignored when use_mmap is off
+        huge_pages: bool, // request transparent huge pages for the mapping (Linux only)
+        prefault: Prefault, // pages to fault in at open
+        creator: String, // recorded in the header of a new database
+        hide_expired: bool, // treat documents past their expiry as missing
+        secure_delete: bool, // zero pages as they are freed
+        compression_rules: Vec<CompressionRule>, // empty keeps the rules stored in the database
+        codec: PageCodec, // for compressed writes without a rule
+        compression_level: i32, // zstd: 1-22, 0 for its default; snappy has no levels and needs 0
+        dictionary_threshold: u64, // zstd documents below this size use the newest dictionary; 0 never does
+        slab_threshold: u64, // documents below this size share pages, stored uncompressed; 0 never does
+        index_log_threshold: usize, // metadata changes logged before the index is rewritten; 0 never logs
+        truncate_on_close: bool, // shrink the file on close when its last pages are free
+        maintenance_interval_ms: u64, // wake period of the maintenance thread; 0 runs none
+        maintenance_fragmentation_percent: u32, // reclaimable slab space that triggers a repack
+        maintenance_free_percent: u32, // free share of the file that triggers truncation
+        decompress_threads: usize, // for batch reads; defaults to one fewer than the cores, 0 decompresses inline
+        admin: bool, // allow read_raw_page_admin
+        direct_io: bool, // skip the OS page cache in the file backend; falls back to buffered IO where refused
+        sync_policy: SyncPolicy,
+        mirror_path: String, // second database that committed changes are copied to; empty for none
+        mirror_fallback: bool, // open the mirror when the primary fails to open or its index is unreadable, and read from it when a chain is corrupt
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum IoMode {
+        Auto = 0, // map the file when pages are at least 4KB
+        MmapPreferred = 1, // map the file whenever it can be
+        FileOnly = 2,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Prefault {
+        None = 0,
+        Metadata = 1, // header, index, trie and free list pages
+        Full = 2, // every page in the file
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum SyncPolicy {
+        Auto = 0, // fdatasync, or fsync when the file length changed since the last sync
+        Full = 1, // fsync every time
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum PageCodec {
+        None = 0,
+        Snappy = 1,
+        Zstd = 2,
+    }
+
+    /// How documents whose path ends in .extension are stored. Extensions are matched without
+    /// the dot and case-insensitively; anything without a rule follows use_compression.
+    #[derive(Clone, Debug)]
+    struct CompressionRule {
+        extension: String,
+        codec: PageCodec,
+    }
+
+    /// A setting the engine can expose as a cvar. Values are strings; kind is "int" or "bool".
+    #[derive(Clone, Debug)]
+    struct Tunable {
+        name: String,
+        kind: String,
+        current: String,
+        default_value: String,
+        runtime: bool, // false when a change only takes effect on the next open
+    }
+
+    #[derive(Clone, Debug)]
+    struct LatencySummary {
+        count: u64,
+        p50_us: u64,
+        p99_us: u64,
+        max_us: u64,
+    }
+
+    #[derive(Clone, Debug)]
+    struct LatencyReport {
+        page_reads: LatencySummary, // reads that missed the page cache
+        document_gets: LatencySummary,
+        writes: LatencySummary,
+        flushes: LatencySummary,
+    }
+
+    #[derive(Clone, Debug)]
+    struct LockStats {
+        name: String, // striped and sharded locks are reported as one
+        acquisitions: u64,
+        contended: u64, // acquisitions that had to wait
+        total_wait_us: u64,
+        max_wait_us: u64,
+    }
+
+    #[derive(Clone, Debug)]
+    struct CheckpointStats {
+        pages_flushed: u64,
+        log_bytes_reclaimed: u64,
+    }
+
+    #[derive(Clone, Debug)]
+    struct OpenTransaction {
+        tx_id: i64,
+        started_unix_ms: u64,
+        age_ms: u64,
+        staged_writes: u64, // pages, documents and path changes
+        staged_frees: u64,
+        long_open: bool, // older than the long_transaction_ms tunable
+    }
+
+    #[derive(Clone, Debug)]
+    struct TransactionStats {
+        begun: u64,
+        committed: u64,
+        failed: u64,
+        rolled_back: u64, // explicitly, or by closing with the transaction open
+        pages_committed: u64, // distinct pages each commit wrote, summed
+        last_commit_pages: u64,
+        max_commit_pages: u64,
+        open: Vec<OpenTransaction>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct DbStats {
+        open_streams: u64,
+        pinned_chains: u64,
+        pending_free_chains: u64,
+        dedup_bytes_saved: u64, // stored bytes not written again because another document shares them
+        document_count: u64,
+        direct_io: bool, // the file backend is doing direct IO
+    }
+
+    #[derive(Clone, Debug)]
+    struct StreamStats {
+        document_uuid: String,
+        bytes_read: u64,
+        chunks_read: u64,
+        finished: bool,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum StreamVerification {
+        Verified,
+        Corrupt,
+        Unverified,
+    }
+
+    /// Bits for set_flags. READONLY documents refuse writes, deletes and unbinds unless forced;
+    /// PRECACHE documents are listed by get_precache_list for loading at map start.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum DocumentFlag {
+        Readonly = 1,
+        Precache = 2,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum SecondaryIndexKind {
+        Size,
+        Modified,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum DocumentEventOp {
+        Write,
+        Delete,
+        Bind,
+        Unbind,
+    }
+
+    #[derive(Clone, Debug)]
+    struct DocumentEvent {
+        op: DocumentEventOp,
+        path: String,
+        uuid: String,
+    }
+
+    #[derive(Clone, Debug)]
+    struct EventBatch {
+        events: Vec<DocumentEvent>,
+        dropped: u64, // events lost to overflow since the last drain
+    }
+
+    // Same order as idFile's fsOrigin_t
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum SeekOrigin {
+        Cur,
+        End,
+        Set,
+    }
+
+    unsafe extern "C++" {
+        include!("framework/Common.h");
+        type idCommon;
+        fn commonPrintf(self: &idCommon, fmt: &CxxString);
+    }
+
+    extern "Rust" {
+        type StreamDb;
+        type StreamDbFile<'a>;
+
+        fn open_db(path: &CxxString, use_compression: bool, quick_mode: bool) -> Result<UniquePtr<StreamDb>>;
+        fn open_db_with_path_policy(path: &CxxString, use_compression: bool, quick_mode: bool, path_policy: PathPolicy) -> Result<UniquePtr<StreamDb>>;
+        fn open_db_wait(path: &CxxString, use_compression: bool, quick_mode: bool, lock_timeout_ms: u64) -> Result<UniquePtr<StreamDb>>;
+        fn open_db_segmented(path: &CxxString, use_compression: bool, quick_mode: bool, segment_size: u64) -> Result<UniquePtr<StreamDb>>;
+        fn open_temp(dir_hint: &CxxString) -> Result<UniquePtr<StreamDb>>;
+        fn create_options() -> StreamDbOptions;
+        fn open_db_with_options(path: &CxxString, options: &StreamDbOptions) -> Result<UniquePtr<StreamDb>>;
+        fn snapshot_to(self: &StreamDb, dest_path: &CxxString) -> Result<u64>;
+        fn checkpoint(self: &StreamDb) -> Result<CheckpointStats>;
+        fn release_lock(self: Pin<&mut StreamDb>) -> Result<()>;
+        fn reload_if_changed(self: Pin<&mut StreamDb>) -> Result<bool>;
+        fn close_db(self: Pin<&mut StreamDb>);
+        fn write_document(self: Pin<&mut StreamDb>, path: &CxxString, data: &CxxVector<u8>) -> Result<Uuid>;
+        fn write_document_ex(self: Pin<&mut StreamDb>, path: &CxxString, data: &CxxVector<u8>, overwrite: bool) -> Result<Uuid>;
+        fn write_document_with_dedup(self: Pin<&mut StreamDb>, path: &CxxString, data: &CxxVector<u8>, overwrite: bool, dedup: bool) -> Result<Uuid>;
+        fn get(self: &StreamDb, path: &CxxString) -> Result<CxxVector<u8>>;
+        fn search_paths(self: &StreamDb, prefix: &CxxString) -> Result<CxxVector<CxxString>>;
+        fn search_paths_page(self: &StreamDb, prefix: &CxxString, after: &CxxString, limit: usize) -> Result<Vec<String>>;
+        fn search_glob(self: &StreamDb, pattern: &CxxString) -> Result<Vec<String>>;
+        fn search_documents(self: &StreamDb, prefix: &CxxString, grouped: bool) -> Result<Vec<DocumentPaths>>;
+        fn get_all_paths_sorted(self: &StreamDb) -> Result<Vec<String>>;
+        fn get_paths_for_uuid(self: &StreamDb, uuid: &CxxString) -> Result<Vec<PathBindingInfo>>;
+        fn complete_path(self: &StreamDb, partial: &CxxString, max: usize) -> Result<Vec<String>>;
+        fn match_path(pattern: &CxxString, path: &CxxString) -> Result<bool>;
+        fn get_many(self: &StreamDb, paths: &Vec<String>) -> Result<Vec<DocumentData>>;
+        fn get_with_options(self: &StreamDb, path: &CxxString, options: &ReadOptions) -> Result<CxxVector<u8>>;
+        fn get_many_with_options(self: &StreamDb, paths: &Vec<String>, options: &ReadOptions) -> Result<Vec<DocumentData>>;
+        fn prefetch(self: &StreamDb, paths: &Vec<String>) -> Result<u64>;
+        fn delete_by_path(self: Pin<&mut StreamDb>, path: &CxxString) -> Result<()>;
+        fn delete_by_path_ex(self: Pin<&mut StreamDb>, path: &CxxString, force: bool) -> Result<()>;
+        fn delete_by_path_secure(self: Pin<&mut StreamDb>, path: &CxxString, force: bool) -> Result<()>;
+        fn rename_path(self: Pin<&mut StreamDb>, from: &CxxString, to: &CxxString) -> Result<()>;
+        fn write_document_forced(self: Pin<&mut StreamDb>, path: &CxxString, data: &CxxVector<u8>) -> Result<Uuid>;
+        fn set_flags(self: Pin<&mut StreamDb>, path: &CxxString, flags: u32) -> Result<()>;
+        fn get_precache_list(self: &StreamDb) -> Result<Vec<String>>;
+        fn get_compression_rules(self: &StreamDb) -> Vec<CompressionRule>;
+        fn set_compression_rules(self: Pin<&mut StreamDb>, rules: &Vec<CompressionRule>) -> Result<()>;
+        fn recompress(self: Pin<&mut StreamDb>, path: &CxxString, level: i32) -> Result<u64>;
+        fn train_dictionary(self: Pin<&mut StreamDb>, prefix: &CxxString, max_size: u64) -> Result<u8>;
+        fn install_dictionary(self: Pin<&mut StreamDb>, dictionary: &[u8]) -> Result<u8>;
+        fn set_expiry(self: Pin<&mut StreamDb>, path: &CxxString, unix_time: u64) -> Result<()>;
+        fn purge_expired(self: Pin<&mut StreamDb>, now: u64) -> Result<PurgeReport>;
+        fn vacuum(self: Pin<&mut StreamDb>) -> Result<VacuumReport>;
+        fn pause_maintenance(self: &StreamDb);
+        fn resume_maintenance(self: &StreamDb);
+        fn run_maintenance_slice(self: Pin<&mut StreamDb>, max_millis: u64) -> Result<MaintenanceReport>;
+        fn fold_index_log(self: Pin<&mut StreamDb>) -> Result<u64>;
+        fn add_tag(self: Pin<&mut StreamDb>, path: &CxxString, tag: &CxxString) -> Result<()>;
+        fn remove_tag(self: Pin<&mut StreamDb>, path: &CxxString, tag: &CxxString) -> Result<bool>;
+        fn get_tags(self: &StreamDb, path: &CxxString) -> Result<Vec<String>>;
+        fn find_by_tag(self: &StreamDb, tag: &CxxString) -> Result<Vec<DocumentInfo>>;
+        fn create_secondary_index(self: Pin<&mut StreamDb>, kind: SecondaryIndexKind) -> Result<()>;
+        fn drop_secondary_index(self: Pin<&mut StreamDb>, kind: SecondaryIndexKind) -> Result<()>;
+        fn find_by_size_range(self: &StreamDb, min: u64, max: u64) -> Result<Vec<DocumentInfo>>;
+        fn find_modified_since(self: &StreamDb, since: u64) -> Result<Vec<DocumentInfo>>;
+        fn get_checksum(self: &StreamDb) -> u32;
+        fn format_version(self: &StreamDb) -> u16;
+        fn get_db_info(self: &StreamDb) -> Result<DbInfo>;
+        fn set_quick_mode(self: Pin<&mut StreamDb>, enabled: bool);
+        fn set_io_mode(self: Pin<&mut StreamDb>, mode: IoMode) -> Result<bool>;
+        fn prefault(self: &StreamDb, prefault: Prefault) -> Result<u64>;
+        fn set_latency_tracking(self: &StreamDb, enabled: bool);
+        fn get_latency_report(self: &StreamDb) -> LatencyReport;
+        fn reset_latency_stats(self: &StreamDb);
+        fn set_lock_tracking(self: &StreamDb, enabled: bool);
+        fn get_lock_stats(self: &StreamDb) -> Vec<LockStats>;
+        fn reset_lock_stats(self: &StreamDb);
+        unsafe fn set_log_sink(self: &StreamDb, common: *const idCommon);
+        fn set_event_recording(self: &StreamDb, enabled: bool);
+        fn drain_events(self: &StreamDb) -> EventBatch;
+        fn list_tunables(self: &StreamDb) -> Vec<Tunable>;
+        fn set_tunable(self: Pin<&mut StreamDb>, name: &CxxString, value: &CxxString) -> Result<()>;
+        fn get_cache_stats(self: &StreamDb) -> CacheStats;
+        fn start_stream(self: &StreamDb, path: &CxxString) -> Result<i64>;
+        fn start_stream_with_chunk_size(self: &StreamDb, path: &CxxString, chunk_size: usize) -> Result<i64>;
+        fn next_stream_chunk(self: &StreamDb, stream_id: i64) -> Result<CxxVector<u8>>;
+        unsafe fn read_into(self: &StreamDb, path: &CxxString, offset: u64, dst: *mut u8, dst_len: usize) -> Result<usize>;
+        unsafe fn stream_read_into(self: &StreamDb, stream_id: i64, dst: *mut u8, dst_len: usize) -> Result<usize>;
+        fn end_stream(self: Pin<&mut StreamDb>, stream_id: i64);
+        fn open_append(self: Pin<&mut StreamDb>, path: &CxxString) -> Result<i64>;
+        fn append(self: Pin<&mut StreamDb>, handle: i64, data: &[u8]) -> Result<()>;
+        fn sync_append(self: Pin<&mut StreamDb>, handle: i64) -> Result<()>;
+        fn close_append(self: Pin<&mut StreamDb>, handle: i64) -> Result<()>;
+        fn bind_addon_path(self: Pin<&mut StreamDb>, path: &CxxString, addon: bool) -> Result<()>;
+        fn unbind_addon_path(self: Pin<&mut StreamDb>, path: &CxxString, delete_if_last: bool) -> Result<()>;
+        fn add_path(self: Pin<&mut StreamDb>, source: &CxxString, path: &CxxString, shadow: bool) -> Result<()>;
+        fn remove_path(self: Pin<&mut StreamDb>, path: &CxxString, delete_if_last: bool) -> Result<()>;
+        fn bind_path_layer(self: Pin<&mut StreamDb>, path: &CxxString, source: &CxxString, addon: bool, priority: i32) -> Result<()>;
+        fn resolve_path(self: &StreamDb, path: &CxxString) -> Result<PathResolution>;
+        fn bind_localized_path(self: Pin<&mut StreamDb>, path: &CxxString, lang: &CxxString, source: &CxxString) -> Result<()>;
+        fn set_active_language(self: Pin<&mut StreamDb>, lang: &CxxString) -> Result<()>;
+        fn get_active_language(self: &StreamDb) -> String;
+        fn search_language_bindings(self: &StreamDb, prefix: &CxxString, lang: &CxxString) -> Result<Vec<DocumentInfo>>;
+        fn get_db_stats(self: &StreamDb) -> DbStats;
+        fn get_mirror_status(self: &StreamDb) -> MirrorStatus;
+        fn resync_mirror(self: &StreamDb) -> Result<u64>;
+        fn export_manifest(self: &StreamDb, include_physical: bool) -> Result<Manifest>;
+        fn get_physical_size(self: &StreamDb, path: &CxxString) -> Result<u64>;
+        fn top_documents_by_physical_size(self: &StreamDb, n: usize) -> Result<Vec<PhysicalSize>>;
+        fn diff_db(self: &StreamDb, other_path: &CxxString) -> Result<DbDiff>;
+        fn content_hash(self: &StreamDb) -> Result<Vec<u8>>;
+        fn create_patch(self: &StreamDb, new_path: &CxxString) -> Result<Vec<u8>>;
+        fn get_chunk_signatures(self: &StreamDb, path: &CxxString) -> Result<Vec<ChunkSignature>>;
+        fn missing_chunks(self: &StreamDb, path: &CxxString, signatures: &Vec<ChunkSignature>) -> Result<Vec<u64>>;
+        fn sync_document_from(self: Pin<&mut StreamDb>, path: &CxxString, signatures: &Vec<ChunkSignature>, chunks: &Vec<ChunkData>) -> Result<u64>;
+        fn apply_patch(self: Pin<&mut StreamDb>, patch: &CxxVector<u8>) -> Result<u64>;
+        fn get_transaction_stats(self: &StreamDb) -> TransactionStats;
+        fn get_stream_stats(self: &StreamDb, stream_id: i64) -> Result<StreamStats>;
+        fn finish_stream(self: Pin<&mut StreamDb>, stream_id: i64) -> Result<StreamVerification>;
+        fn begin_transaction(self: Pin<&mut StreamDb>) -> Result<i64>;
+        fn commit_transaction(self: Pin<&mut StreamDb>, tx_id: i64) -> Result<()>;
+        fn rollback_transaction(self: Pin<&mut StreamDb>, tx_id: i64) -> Result<()>;
+        fn begin_save_session(self: Pin<&mut StreamDb>) -> Result<i64>;
+        fn save_session_write(self: Pin<&mut StreamDb>, session_id: i64, path: &CxxString, data: &CxxVector<u8>) -> Result<()>;
+        fn save_session_delete(self: Pin<&mut StreamDb>, session_id: i64, path: &CxxString, force: bool) -> Result<()>;
+        fn save_session_rename(self: Pin<&mut StreamDb>, session_id: i64, from: &CxxString, to: &CxxString) -> Result<()>;
+        fn commit_save_session(self: Pin<&mut StreamDb>, session_id: i64) -> Result<()>;
+        fn abort_save_session(self: Pin<&mut StreamDb>, session_id: i64) -> Result<()>;
+        fn rebuild_trie(self: Pin<&mut StreamDb>) -> Result<u64>;
+        fn check_trie(self: &StreamDb) -> Result<TrieReport>;
+        fn verify_db(self: &StreamDb, deep: bool) -> Result<VerifyReport>;
+        fn repair_db(self: Pin<&mut StreamDb>) -> Result<VerifyReport>;
+        fn open_page_iterator(self: &StreamDb) -> Result<i64>;
+        fn page_iterator_next(self: &StreamDb, iterator_id: i64, max_pages: usize) -> Result<Vec<PageInfo>>;
+        fn close_page_iterator(self: &StreamDb, iterator_id: i64);
+        fn read_raw_page_admin(self: &StreamDb, page_id: i64) -> Result<Vec<u8>>;
+        fn count_paths(self: &StreamDb, prefix: &CxxString) -> Result<u64>;
+        fn document_count(self: &StreamDb) -> Result<u64>;
+        fn stat(self: &StreamDb, path: &CxxString) -> Result<DocumentInfo>;
+        fn search_paths_detailed(self: &StreamDb, prefix: &CxxString) -> Result<Vec<DocumentInfo>>;
+        fn get_extents(self: &StreamDb, path: &CxxString) -> Result<DocumentExtents>;
+        fn validate_extents(self: &StreamDb, path: &CxxString, version_stamp: u64) -> Result<bool>;
+        fn open_file<'a>(self: &'a StreamDb, path: &CxxString) -> Result<Box<StreamDbFile<'a>>>;
+        fn open_cursor(self: &StreamDb, prefix: &CxxString) -> Result<i64>;
+        fn cursor_next(self: &StreamDb, cursor_id: i64, max_entries: usize) -> Result<Vec<DocumentInfo>>;
+        fn cursor_read(self: &StreamDb, cursor_id: i64, path: &CxxString) -> Result<CxxVector<u8>>;
+        fn close_cursor(self: &StreamDb, cursor_id: i64);
+        fn read<'a>(self: &mut StreamDbFile<'a>, buf: &mut [u8]) -> Result<usize>;
+        fn seek<'a>(self: &mut StreamDbFile<'a>, offset: i64, origin: SeekOrigin) -> Result<u64>;
+        fn tell<'a>(self: &StreamDbFile<'a>) -> u64;
+        fn length<'a>(self: &StreamDbFile<'a>) -> u64;
+        fn timestamp<'a>(self: &StreamDbFile<'a>) -> u64;
+    }
+}
+
+// Lock order: a thread holding one of these takes only locks further down the list.
+//   maintenance.layout, maintenance.gate, index_log / open_slab / open_trie_slab, path_order, allocation,
+//   current_size, free_list_root, a page_locks stripe, mmap, page_generations, a page_cache shard
+// file, cache_stats, path_snapshot and the other counters are leaves: nothing else is taken while one is held.
+// The locks named by TrackedLock are taken through LockStats::acquire, which checks this order
+// in debug builds. The index itself has no lock of its own: index changes are made under the gate.
+pub struct StreamDb {
+    config: Config,
+    file: PMutex<File>, // primary file: header, locking and timestamps
+    storage: Box<dyn Storage>,
+    mmap: PRwLock<Option<FileMapping>>,
+    current_size: PMutex<u64>,
+    file_len: std::sync::atomic::AtomicU64, // bytes the storage holds; the mapping may run past them, so accesses are checked against this
+    allocation: PMutex<i64>, // consecutive allocations that found the free list empty; held while allocating or freeing pages
+    page_locks: Vec<PRwLock<()>>, // PAGE_LOCK_STRIPES stripes: shared while reading a page, exclusive while rewriting it
+    document_index_root: PRwLock<VersionedLink>,
+    trie_root: PRwLock<VersionedLink>,
+    free_list_root: PRwLock<VersionedLink>,
+    path_hash_root: PRwLock<VersionedLink>,
+    dedup_root: PRwLock<VersionedLink>, // chain holding the content hash table
+    dedup_table: PRwLock<HashMap<[u8; 32], i64>>, // SHA-256 of a document's contents -> its chain
+    tag_root: PRwLock<VersionedLink>, // chain holding the tag table
+    tag_table: PRwLock<BTreeMap<String, BTreeSet<Uuid>>>, // tag -> documents carrying it
+    secondary_root: PRwLock<VersionedLink>, // chain holding the secondary indexes
+    secondary: PRwLock<SecondaryIndexes>,
+    rules_root: PRwLock<VersionedLink>, // chain holding the compression rules
+    index_log_root: PRwLock<VersionedLink>, // chain of index entries not yet folded into the B-tree
+    index_log: PMutex<IndexLog>,
+    document_count: std::sync::atomic::AtomicU64, // live documents, persisted by write_roots
+    index_format: std::sync::atomic::AtomicU16, // format version index entries on disk are laid out in, and the one write_roots stamps; older only until migrated
+    compression_rules: PRwLock<BTreeMap<String, u8>>, // lowercase extension -> CODEC_*
+    dictionaries: PRwLock<BTreeMap<u8, Arc<Vec<u8>>>>, // loaded from the documents under DICTIONARY_PATH_PREFIX
+    path_hash_buckets: PRwLock<Vec<i64>>,
+    page_cache: Vec<PMutex<LruCache<(i64, u64), Vec<u8>>>>, // PAGE_CACHE_SHARDS shards, keyed by page id and generation
+    page_generations: PMutex<HashMap<i64, u64>>, // bumped on every rewrite or free; absent means 0
+    path_cache: PMutex<LruCache<String, Uuid>>,
+    path_cache_epoch: std::sync::atomic::AtomicU64, // bumped under path_cache by every trie change, so a lookup that raced one does not cache what it found
+    path_order: PRwLock<Option<BTreeMap<String, Uuid>>>, // every resolved path in order, for the prefix queries a reversed trie cannot answer; built on first use
+    path_generation: std::sync::atomic::AtomicU64, // bumped after every change to the path order, so a snapshot taken before one is known to be stale
+    path_snapshot: PMutex<Option<(u64, Arc<Vec<String>>)>>, // the sorted paths as of a path generation, within path_snapshot_budget
+    index_cache: PRwLock<Option<((VersionedLink, VersionedLink), BTreeMap<Uuid, Document>)>>, // the index as of the index and log roots it was read under
+    trie_cache: PMutex<LruCache<i64, ReverseTrieNode>>, // keyed by node address
+    cache_stats: PMutex<CacheStats>,
+    quick_mode: Arc<std::sync::atomic::AtomicBool>,
+    transactions: PMutex<HashMap<i64, Transaction>>, // ids come from next_stream_id
+    transaction_totals: PMutex<TransactionTotals>,
+    commit_history: PMutex<CommitHistory>,
+    commit_pages: PMutex<Option<HashSet<i64>>>, // pages written so far by the commit in progress
+    streams: PRwLock<HashMap<i64, Arc<PMutex<StreamHandle>>>>, // table lock is held only to look up a handle
+    next_stream_id: std::sync::atomic::AtomicI64,
+    chain_pins: PMutex<HashMap<i64, ChainPin>>,
+    wipe_chains: PMutex<HashSet<i64>>, // chain heads a secure delete released, zeroed whenever they are freed
+    cursors: PMutex<HashMap<i64, ReadCursor>>, // ids come from next_stream_id
+    page_iterators: PMutex<HashMap<i64, PageIterator>>, // ids come from next_stream_id
+    open_slab: PMutex<i64>, // slab page new records go to, -1 for none yet; held while any slab page changes
+    open_trie_slab: PMutex<i64>, // the same for trie nodes
+    appends: PMutex<HashMap<i64, AppendHandle>>, // ids come from next_stream_id
+    active_language: PRwLock<String>, // runtime setting, not persisted
+    dirty_pages: PMutex<HashSet<i64>>, // written since the last checkpoint fsync
+    huge_pages: std::sync::atomic::AtomicBool, // the current mapping took the huge page advice
+    resized: std::sync::atomic::AtomicBool, // the file length changed since the last sync, so the next must be a full one
+    write_back: PMutex<Option<std::ops::Range<u64>>>, // span of storage writes whose write-back has not been started
+    loaded_header: PMutex<Vec<u8>>, // header as last loaded or written by this instance
+    closed: std::sync::atomic::AtomicBool,
+    lock_released: std::sync::atomic::AtomicBool, // release_lock gave up the file lock and reload_if_changed has not taken it back
+    maintenance: Maintenance,
+    latency: LatencyStats,
+    lock_stats: LockStats,
+    events: EventLog,
+    mirror: Option<Mirror>,
+    serving_mirror: bool, // opened from the mirror because the primary failed
+}
+
+impl StreamDb {
+    pub fn create_options() -> ffi::StreamDbOptions {
+        ffi::StreamDbOptions::default()
+    }
+
+    /// Whether path matches the glob pattern, by the rules search_glob uses.
+    pub fn match_path(pattern: &CxxString, path: &CxxString) -> Result<bool, std::io::Error> {
+        let pattern = PathPattern::compile(pattern.to_string_lossy().as_ref())?;
+        Ok(pattern.matches(path.to_string_lossy().as_ref()))
+    }
+
+    pub fn open_db_with_options(path: &CxxString, options: &ffi::StreamDbOptions) -> Result<UniquePtr<StreamDb>, std::io::Error> {
+        let db = cxx::UniquePtr::new(Self::open_path_with_options(Path::new(path.to_string_lossy().as_ref()), options)?);
+        db.start_maintenance()?;
+        db.start_mirror()?;
+        Ok(db)
+    }
+
+    fn open_path_with_options(path: &Path, options: &ffi::StreamDbOptions) -> io::Result<StreamDb> {
+        if options.mirror_path.is_empty() {
+            return Self::open_single(path, options);
+        }
+        let mut mirror_options = options.clone();
+        mirror_options.mirror_path.clear();
+        mirror_options.maintenance_interval_ms = 0;
+        let mirror_path = Path::new(&options.mirror_path);
+        let primary = Self::open_single(path, options);
+        if options.mirror_fallback {
+            // Only the index is walked: a full page scan on every open would cost as much as
+            // reading the whole file, and damaged chains are caught when they are read
+            let healthy = primary.as_ref().is_ok_and(|db| db.verify_db(false).is_ok_and(|report| report.index_ok));
+            if !healthy {
+                drop(primary);
+                let mut db = Self::open_single(mirror_path, &mirror_options)?;
+                db.serving_mirror = true;
+                return Ok(db);
+            }
+        }
+        let mut db = primary?;
+        db.mirror = Some(Mirror {
+            db: Self::open_single(mirror_path, &mirror_options)?,
+            sender: PMutex::new(None),
+            thread: PMutex::new(None),
+            overflowed: std::sync::atomic::AtomicBool::new(false),
+            diverged: std::sync::atomic::AtomicBool::new(false),
+            fallback: options.mirror_fallback,
+        });
+        Ok(db)
+    }
+
+    fn open_single(path: &Path, options: &ffi::StreamDbOptions) -> io::Result<StreamDb> {
+        let config = options.to_config()?;
+        let path = Self::db_path(path.to_string_lossy().as_ref());
+        let mut open_options = Self::db_open_options();
+        if config.read_only {
+            open_options.write(false);
+        } else {
+            open_options.create(true);
+        }
+        let file = open_options.open(&path)?;
+        let db = Self::open_file_with_config(file, &path, config, options.quick_mode)?;
+        db.prefault_with_progress(db.config.prefault, &mut |_, _| {}).unwrap_or(0);
+        Ok(db)
+    }
+
+    pub fn open_db(path: &CxxString, use_compression: bool, quick_mode: bool) -> Result<UniquePtr<StreamDb>, std::io::Error> {
+        Self::open_db_with_options(path, &Self::create_options().use_compression(use_compression).quick_mode(quick_mode))
+    }
+
+    pub fn open_db_with_path_policy(path: &CxxString, use_compression: bool, quick_mode: bool, path_policy: ffi::PathPolicy) -> Result<UniquePtr<StreamDb>, std::io::Error> {
+        Self::open_db_with_options(path, &Self::create_options().use_compression(use_compression).quick_mode(quick_mode).path_policy(path_policy))
+    }
+
+    /// Like open_db, but if another process holds the database, waits up to lock_timeout_ms for it.
+    pub fn open_db_wait(path: &CxxString, use_compression: bool, quick_mode: bool, lock_timeout_ms: u64) -> Result<UniquePtr<StreamDb>, std::io::Error> {
+        Self::open_db_with_options(path, &Self::create_options().use_compression(use_compression).quick_mode(quick_mode).lock_timeout_ms(lock_timeout_ms))
+    }
+
+    /// Creates a database split into segment files of at most segment_size bytes (0 for the 2GB
+    /// default), for filesystems such as FAT32 that cannot hold one large file. Whether a database
+    /// is segmented is fixed at creation: an existing database opens with the layout in its header.
+    pub fn open_db_segmented(path: &CxxString, use_compression: bool, quick_mode: bool, segment_size: u64) -> Result<UniquePtr<StreamDb>, std::io::Error> {
+        Self::open_db_with_options(path, &Self::create_options().use_compression(use_compression).quick_mode(quick_mode).segmented(segment_size))
+    }
+
+    /// Options for opening database files. On Windows other handles may read, write and delete
+    /// (rename) the file: exclusion comes from lock_file, and deny-sharing would break tools that
+    /// merely inspect the file. Unix has no share modes.
+    fn db_open_options() -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            const FILE_SHARE_ALL: u32 = 0x1 | 0x2 | 0x4; // read | write | delete
+            options.share_mode(FILE_SHARE_ALL);
+        }
+        options
+    }
+
+    /// The path to open for a database. On Windows, paths too long for MAX_PATH get the \\?\
+    /// prefix (made absolute first, since prefixed paths are not normalized); elsewhere unchanged.
+    fn db_path(path: &str) -> std::path::PathBuf {
+        #[cfg(windows)]
+        {
+            const MAX_DIR_PATH: usize = 248; // MAX_PATH less room for an 8.3 file name
+            if path.len() >= MAX_DIR_PATH && !path.starts_with(r"\\?\") {
+                if let Ok(absolute) = std::path::absolute(path) {
+                    let absolute = absolute.to_string_lossy().replace('/', r"\");
+                    return match absolute.strip_prefix(r"\\") {
+                        Some(unc) => std::path::PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+                        None => std::path::PathBuf::from(format!(r"\\?\{}", absolute)),
+                    };
+                }
+            }
+        }
+        std::path::PathBuf::from(path)
+    }
+
+    /// Opens a database whose storage fails according to schedule. The mapping is disabled so
+    /// that every page access passes through the fault layer; the schedule can be changed while open.
+    #[cfg(feature = "fault-injection")]
+    pub fn open_with_faults(path: &Path, use_compression: bool, schedule: Arc<PMutex<FaultSchedule>>) -> io::Result<StreamDb> {
+        let config = Config { use_compression, io_mode: ffi::IoMode::FileOnly, faults: Some(schedule), ..Default::default() };
+        let file = Self::db_open_options().create(true).open(path)?;
+        Self::open_file_with_config(file, path, config, false)
+    }
+
+    /// Segment layout from an existing database's header; (0, 0) for a new or single-file database.
+    fn recorded_segment_layout(mut file: &File) -> io::Result<(u64, u32)> {
+        let mut header = vec![0u8; DB_HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        if file.metadata()?.len() < DB_HEADER_SIZE as u64 {
+            return Ok((0, 0));
+        }
+        file.read_exact(&mut header)?;
+        if header[..MAGIC.len()] != MAGIC {
+            return Ok((0, 0)); // initialize reports the bad magic
+        }
+        let mut reader = Cursor::new(&header[SEGMENT_LAYOUT_OFFSET..FORMAT_VERSION_OFFSET]);
+        Ok((reader.read_u64::<LittleEndian>()?, reader.read_u32::<LittleEndian>()?))
+    }
+
+    /// Takes the advisory lock: shared for readers, exclusive otherwise. A lock still held by
+    /// another open after timeout_ms fails with ResourceBusy and "Database already locked by
+    /// another process", which C++ callers see as the exception's message.
+    fn lock_file(file: &File, shared: bool, timeout_ms: u64) -> io::Result<()> {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms);
+        loop {
+            let locked = if shared { file.try_lock_shared() } else { file.try_lock() };
+            match locked {
+                Ok(()) => return Ok(()),
+                Err(std::fs::TryLockError::WouldBlock) if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(std::fs::TryLockError::WouldBlock) => {
+                    return Err(io::Error::new(io::ErrorKind::ResourceBusy, "Database already locked by another process"));
+                }
+                Err(std::fs::TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Creates a scratch database in dir_hint (or the system temp directory when empty). The file
+    /// is deleted by the OS once closed, even if the process dies, and writes skip durability flushes.
+    pub fn open_temp(dir_hint: &CxxString) -> Result<UniquePtr<StreamDb>, std::io::Error> {
+        let dir = match dir_hint.to_string_lossy() {
+            hint if hint.is_empty() => std::env::temp_dir(),
+            hint => Path::new(hint.as_ref()).to_path_buf(),
+        };
+        let path = dir.join(format!("streamdb-{}-{}.tmp", std::process::id(), Uuid::new_v4().simple()));
+        let path = Self::db_path(path.to_string_lossy().as_ref());
+        let mut options = Self::db_open_options();
+        options.create_new(true);
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+            options.custom_flags(FILE_FLAG_DELETE_ON_CLOSE);
+        }
+        let file = options.open(&path)?;
+        #[cfg(unix)]
+        std::fs::remove_file(&path)?; // the open handle keeps the data alive until close
+        let config = Self::create_options().durable_writes(false).to_config()?;
+        Ok(cxx::UniquePtr::new(Self::open_file_with_config(file, &path, config, false)?))
+    }
+
+    fn open_file_with_config(file: File, path: &Path, config: Config, quick_mode: bool) -> io::Result<StreamDb> {
+        Self::lock_file(&file, config.read_only, config.lock_timeout_ms)?;
+        let (segment_size, segment_count) = Self::recorded_segment_layout(&file)?;
+        let storage: Box<dyn Storage> = if segment_size != 0 {
+            Box::new(SegmentedStorage::open(file.try_clone()?, path, segment_size, segment_count)?)
+        } else if config.segment_size != 0 && file.metadata()?.len() == 0 {
+            Box::new(SegmentedStorage::open(file.try_clone()?, path, config.segment_size, 0)?)
+        } else if config.direct_io {
+            match DirectStorage::open(path) {
+                Ok(storage) => Box::new(storage),
+                Err(_) => Box::new(FileStorage::new(file.try_clone()?)), // refused: buffered IO it is
+            }
+        } else {
+            Box::new(FileStorage::new(file.try_clone()?))
+        };
+        #[cfg(feature = "fault-injection")]
+        let storage: Box<dyn Storage> = match &config.faults {
+            Some(schedule) => Box::new(FaultyStorage { inner: storage, page_size: config.page_size, schedule: schedule.clone() }),
+            None => storage,
+        };
+        let storage_len = storage.len()?;
+        let mmap = Self::map_file(&file, &config, config.io_mode, storage.as_ref(), storage_len);
+        let huge_pages = config.huge_pages && mmap.as_ref().is_some_and(Self::advise_huge_pages);
+        let (page_cache_size, path_cache_size, page_size) = (config.page_cache_size, config.path_cache_size, config.page_size);
+        let mut db = StreamDb {
+            config,
+            file: PMutex::new(file),
+            storage,
+            mmap: PRwLock::new(mmap),
+            current_size: PMutex::new(Self::whole_pages(storage_len, page_size)),
+            file_len: std::sync::atomic::AtomicU64::new(storage_len),
+            allocation: PMutex::new(0),
+            page_locks: (0..PAGE_LOCK_STRIPES).map(|_| PRwLock::new(())).collect(),
+            document_index_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            trie_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            free_list_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            path_hash_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            dedup_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            dedup_table: PRwLock::new(HashMap::new()),
+            tag_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            tag_table: PRwLock::new(BTreeMap::new()),
+            secondary_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            secondary: PRwLock::new(SecondaryIndexes::default()),
+            rules_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            index_log_root: PRwLock::new(VersionedLink { page_id: -1, version: 0 }),
+            index_log: PMutex::new(IndexLog::default()),
+            document_count: std::sync::atomic::AtomicU64::new(0),
+            index_format: std::sync::atomic::AtomicU16::new(FORMAT_VERSION),
+            compression_rules: PRwLock::new(BTreeMap::new()),
+            dictionaries: PRwLock::new(BTreeMap::new()),
+            path_hash_buckets: PRwLock::new(Vec::new()),
+            page_cache: (0..PAGE_CACHE_SHARDS).map(|_| PMutex::new(LruCache::new(page_cache_size.div_ceil(PAGE_CACHE_SHARDS)))).collect(),
+            page_generations: PMutex::new(HashMap::new()),
+            path_cache: PMutex::new(LruCache::new(path_cache_size)),
+            path_cache_epoch: std::sync::atomic::AtomicU64::new(0),
+            path_order: PRwLock::new(None),
+            path_generation: std::sync::atomic::AtomicU64::new(0),
+            path_snapshot: PMutex::new(None),
+            index_cache: PRwLock::new(None),
+            trie_cache: PMutex::new(LruCache::new(config.trie_cache_size)),
+            cache_stats: PMutex::new(CacheStats { hits: 0, misses: 0, bypassed: 0 }),
+            quick_mode: Arc::new(std::sync::atomic::AtomicBool::new(quick_mode)),
+            transactions: PMutex::new(HashMap::new()),
+            transaction_totals: PMutex::new(TransactionTotals::default()),
+            commit_history: PMutex::new(CommitHistory::default()),
+            commit_pages: PMutex::new(None),
+            streams: PRwLock::new(HashMap::new()),
+            next_stream_id: std::sync::atomic::AtomicI64::new(1),
+            chain_pins: PMutex::new(HashMap::new()),
+            wipe_chains: PMutex::new(HashSet::new()),
+            cursors: PMutex::new(HashMap::new()),
+            page_iterators: PMutex::new(HashMap::new()),
+            open_slab: PMutex::new(-1),
+            open_trie_slab: PMutex::new(-1),
+            appends: PMutex::new(HashMap::new()),
+            active_language: PRwLock::new(String::new()),
+            dirty_pages: PMutex::new(HashSet::new()),
+            huge_pages: std::sync::atomic::AtomicBool::new(huge_pages),
+            resized: std::sync::atomic::AtomicBool::new(false),
+            write_back: PMutex::new(None),
+            loaded_header: PMutex::new(Vec::new()),
+            closed: std::sync::atomic::AtomicBool::new(false),
+            lock_released: std::sync::atomic::AtomicBool::new(false),
+            maintenance: Maintenance::default(),
+            latency: LatencyStats::new(),
+            lock_stats: LockStats::default(),
+            events: EventLog {
+                recording: std::sync::atomic::AtomicBool::new(false),
+                queue: PMutex::new(VecDeque::new()),
+                dropped: std::sync::atomic::AtomicU64::new(0),
+            },
+            mirror: None,
+            serving_mirror: false,
+        };
+        db.initialize()?;
+        Ok(db)
+    }
+
+    fn initialize(&mut self) -> io::Result<()> {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = vec![0u8; DB_HEADER_SIZE]; // MAGIC + roots
+        if file.read(&mut header)? == 0 {
+            if self.config.read_only {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "No database to open read-only"));
+            }
+            // New DB: Write header
+            let mut writer = BufWriter::new(Vec::new());
+            writer.write_all(&MAGIC)?;
+            writer.write_i64::<LittleEndian>(-1)?; // index_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_i64::<LittleEndian>(-1)?; // trie_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_i64::<LittleEndian>(-1)?; // free_list_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_i64::<LittleEndian>(-1)?; // path_hash_root
+            writer.write_i32::<LittleEndian>(0)?;
+            let (segment_size, segment_count) = self.storage.segment_layout();
+            writer.write_u64::<LittleEndian>(segment_size)?;
+            writer.write_u32::<LittleEndian>(segment_count)?;
+            writer.write_u16::<LittleEndian>(FORMAT_VERSION)?;
+            let created = Self::unix_now();
+            writer.write_all(&self.extended_header(created, &self.config.creator)?)?;
+            writer.write_i64::<LittleEndian>(-1)?; // dedup_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_i64::<LittleEndian>(-1)?; // tag_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_i64::<LittleEndian>(-1)?; // secondary_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_i64::<LittleEndian>(-1)?; // rules_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_i64::<LittleEndian>(-1)?; // index_log_root
+            writer.write_i32::<LittleEndian>(0)?;
+            writer.write_u64::<LittleEndian>(0)?; // document_count
+            file.write_all(&writer.into_inner()?)?;
+            file.flush()?;
+            drop(file);
+            self.storage_written_to(DB_HEADER_SIZE as u64);
+        } else {
+            let version = self.load_roots(&header)?;
+            drop(file);
+            self.index_format.store(version, std::sync::atomic::Ordering::SeqCst);
+            if self.config.read_only && version < FORMAT_VERSION {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Database needs upgrading; open it writable first"));
+            }
+            self.migrate(version)?;
+        }
+        if self.config.read_only {
+            // Repairs need a writer: a reader takes the file as the last writer left it
+            self.load_index_log()?;
+        } else {
+            self.recover()?;
+        }
+        self.load_path_hash_buckets()?;
+        self.load_dedup_table()?;
+        self.load_tag_table()?;
+        self.load_secondary_indexes()?;
+        self.load_compression_rules()?;
+        // A reader writes nothing, so the stored rules, or the defaults where none are, stand
+        if !self.config.read_only {
+            if !self.config.compression_rules.is_empty() {
+                let rules = self.config.compression_rules.clone();
+                self.store_compression_rules(&rules)?;
+            } else if self.rules_root.read().page_id == -1 {
+                // Pin the defaults in the file so later changes to them don't affect this database
+                self.store_compression_rules(&Self::default_compression_rules())?;
+            }
+        }
+        self.load_dictionaries()?;
+        if !self.config.read_only {
+            self.recover_append_documents()?;
+        }
+        let mut header = vec![0u8; DB_HEADER_SIZE];
+        self.read_bytes_at(0, &mut header)?;
+        *self.loaded_header.lock() = header;
+        Ok(())
+    }
+
+    /// Loads the roots from a header and returns its format version. Versions newer than this
+    /// build understands are refused before anything is read from them.
+    fn load_roots(&self, header: &[u8]) -> io::Result<u16> {
+        // MAGIC is a byte sequence, so compare bytes rather than a host-order integer
+        if header.len() < DB_HEADER_SIZE || header[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid DB magic"));
+        }
+        let version = Self::header_format_version(header);
+        if version > FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                format!("Unsupported database format version {} (this build reads up to {})", version, FORMAT_VERSION)));
+        }
+        if version >= 3 {
+            let unknown = Self::parse_db_info(header)?.critical_features & !KNOWN_CRITICAL_FEATURES;
+            if unknown != 0 {
+                return Err(io::Error::new(io::ErrorKind::Unsupported,
+                    format!("Database requires unsupported features 0x{:x}", unknown)));
+            }
+        }
+        let mut reader = Cursor::new(&header[MAGIC.len()..]);
+        for link in [&self.document_index_root, &self.trie_root, &self.free_list_root, &self.path_hash_root] {
+            *link.write() = VersionedLink {
+                page_id: reader.read_i64::<LittleEndian>()?,
+                version: reader.read_i32::<LittleEndian>()?,
+            };
+        }
+        // Roots added after v3 are absent (-1) in files older than the version that introduced them
+        for (link, offset, since) in [(&self.dedup_root, DEDUP_ROOT_OFFSET, 4), (&self.tag_root, TAG_ROOT_OFFSET, 6),
+            (&self.secondary_root, SECONDARY_ROOT_OFFSET, 7), (&self.rules_root, RULES_ROOT_OFFSET, 9),
+            (&self.index_log_root, INDEX_LOG_ROOT_OFFSET, 15)] {
+            *link.write() = if version >= since {
+                let mut reader = Cursor::new(&header[offset..]);
+                VersionedLink { page_id: reader.read_i64::<LittleEndian>()?, version: reader.read_i32::<LittleEndian>()? }
+            } else {
+                VersionedLink { page_id: -1, version: 0 }
+            };
+        }
+        // Counted by the v16 migration for older files
+        if version >= 16 {
+            let count = Cursor::new(&header[DOCUMENT_COUNT_OFFSET..]).read_u64::<LittleEndian>()?;
+            self.document_count.store(count, std::sync::atomic::Ordering::SeqCst);
+        }
+        Ok(version)
+    }
+
+    /// The extended header for this database as configured: page size, codec and features come
+    /// from the open configuration and storage, creation time and creator from the caller.
+    fn extended_header(&self, created_unix_secs: u64, creator: &str) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        buffer.write_u64::<LittleEndian>(created_unix_secs)?;
+        // Truncated on a character boundary so the stored creator stays valid UTF-8
+        let mut end = creator.len().min(CREATOR_LENGTH);
+        while !creator.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut creator_bytes = [0u8; CREATOR_LENGTH];
+        creator_bytes[..end].copy_from_slice(&creator.as_bytes()[..end]);
+        buffer.write_all(&creator_bytes)?;
+        buffer.write_u32::<LittleEndian>(self.config.page_size as u32)?;
+        buffer.write_u8(self.default_codec())?;
+        buffer.write_u8(CHECKSUM_CRC32)?;
+        let mut critical = 0;
+        if self.storage.segment_layout().0 != 0 {
+            critical |= FEATURE_SEGMENTED;
+        }
+        let mut optional = 0;
+        if self.config.path_policy.normalize {
+            optional |= FEATURE_NORMALIZED_PATHS;
+        }
+        buffer.write_u32::<LittleEndian>(critical)?;
+        buffer.write_u32::<LittleEndian>(optional)?;
+        Ok(buffer)
+    }
+
+    fn parse_db_info(header: &[u8]) -> io::Result<ffi::DbInfo> {
+        let mut reader = Cursor::new(&header[EXTENDED_HEADER_OFFSET..DEDUP_ROOT_OFFSET]);
+        let created_unix_secs = reader.read_u64::<LittleEndian>()?;
+        let mut creator_bytes = [0u8; CREATOR_LENGTH];
+        reader.read_exact(&mut creator_bytes)?;
+        let creator_len = creator_bytes.iter().position(|&b| b == 0).unwrap_or(CREATOR_LENGTH);
+        let creator = String::from_utf8_lossy(&creator_bytes[..creator_len]).into_owned();
+        let page_size = reader.read_u32::<LittleEndian>()?;
+        let codec = match reader.read_u8()? {
+            CODEC_NONE => "none".to_string(),
+            CODEC_SNAPPY => "snappy".to_string(),
+            CODEC_ZSTD => "zstd".to_string(),
+            other => format!("unknown ({})", other),
+        };
+        let checksum_algorithm = match reader.read_u8()? {
+            CHECKSUM_CRC32 => "crc32".to_string(),
+            other => format!("unknown ({})", other),
+        };
+        Ok(ffi::DbInfo {
+            format_version: Self::header_format_version(header),
+            created_unix_secs,
+            creator,
+            page_size,
+            codec,
+            checksum_algorithm,
+            critical_features: reader.read_u32::<LittleEndian>()?,
+            optional_features: reader.read_u32::<LittleEndian>()?,
+            huge_pages: false,
+        })
+    }
+
+    fn get_db_info(&self) -> io::Result<ffi::DbInfo> {
+        self.ensure_open()?;
+        let mut info = Self::parse_db_info(&self.loaded_header.lock())?;
+        info.huge_pages = self.huge_pages.load(std::sync::atomic::Ordering::SeqCst);
+        Ok(info)
+    }
+
+    /// Files written before the version field existed read as 0 there and are version 1.
+    fn header_format_version(header: &[u8]) -> u16 {
+        let version = u16::from_le_bytes([header[FORMAT_VERSION_OFFSET], header[FORMAT_VERSION_OFFSET + 1]]);
+        version.max(1)
+    }
+
+    /// Runs the registered migrations from version up to FORMAT_VERSION, in order. Each step
+    /// converts the file in place and stamps the version it converted to, so a crash between
+    /// steps leaves a file the next open carries on migrating from where it stopped.
+    fn migrate(&self, mut version: u16) -> io::Result<()> {
+        const MIGRATIONS: &[(u16, fn(&StreamDb) -> io::Result<()>)] = &[
+            (1, StreamDb::migrate_v1_to_v2),
+            (2, StreamDb::migrate_v2_to_v3),
+            (3, StreamDb::migrate_v3_to_v4),
+            (4, StreamDb::migrate_v4_to_v5),
+            (5, StreamDb::migrate_v5_to_v6),
+            (6, StreamDb::migrate_v6_to_v7),
+            (7, StreamDb::migrate_v7_to_v8),
+            (8, StreamDb::migrate_v8_to_v9),
+            (9, StreamDb::migrate_v9_to_v10),
+            (10, StreamDb::migrate_v10_to_v11),
+            (11, StreamDb::migrate_v11_to_v12),
+            (12, StreamDb::migrate_v12_to_v13),
+            (13, StreamDb::migrate_v13_to_v14),
+            (14, StreamDb::migrate_v14_to_v15),
+            (15, StreamDb::migrate_v15_to_v16),
+            (16, StreamDb::migrate_v16_to_v17),
+        ];
+        while version < FORMAT_VERSION {
+            let (_, step) = MIGRATIONS.iter().find(|(from, _)| *from == version)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, format!("No migration from database format version {}", version)))?;
+            step(self)?;
+            version += 1;
+        }
+        Ok(())
+    }
+
+    /// v2 adds the format version to the header; rewriting the roots stamps it.
+    fn migrate_v1_to_v2(&self) -> io::Result<()> {
+        self.write_roots_as(2)?;
+        self.sync_storage()
+    }
+
+    /// v3 adds the extended header. The original creation time and creator are unknown.
+    fn migrate_v2_to_v3(&self) -> io::Result<()> {
+        self.write_bytes_at(EXTENDED_HEADER_OFFSET as u64, &self.extended_header(0, "unknown (migrated)")?)?;
+        self.write_roots_as(3)?;
+        self.sync_storage()
+    }
+
+    /// v4 adds the dedup table root; existing documents start out unshared and unhashed.
+    fn migrate_v3_to_v4(&self) -> io::Result<()> {
+        self.write_roots_as(4)?;
+        self.sync_storage()
+    }
+
+    /// v5 adds an expiry time to each index entry; existing documents never expire.
+    fn migrate_v4_to_v5(&self) -> io::Result<()> {
+        self.rewrite_flat_index(4, |_| Ok(()))
+    }
+
+    /// v6 adds tags to each index entry and the tag table root; existing documents are untagged.
+    fn migrate_v5_to_v6(&self) -> io::Result<()> {
+        self.rewrite_flat_index(5, |_| Ok(()))
+    }
+
+    /// v7 adds size and modification time to each index entry, plus the secondary index root.
+    /// Sizes are measured from the chains; modification times of existing documents are unknown.
+    fn migrate_v6_to_v7(&self) -> io::Result<()> {
+        self.rewrite_flat_index(6, |doc| {
+            doc.size = self.document_size(doc)?;
+            Ok(())
+        })
+    }
+
+    /// v8 adds document flags to each index entry; existing documents have none.
+    fn migrate_v7_to_v8(&self) -> io::Result<()> {
+        self.rewrite_flat_index(7, |_| Ok(()))
+    }
+
+    /// v9 records compression per page and adds the compression rule root. Older files were
+    /// compressed as a whole according to the open flag, so every page written through the
+    /// compressing path is marked; free list pages never were.
+    fn migrate_v8_to_v9(&self) -> io::Result<()> {
+        if self.config.use_compression {
+            for page_id in FIRST_PAGE_ID..self.page_count() {
+                let mut header = match self.read_page_header(page_id) {
+                    Ok(header) => header,
+                    Err(_) => continue,
+                };
+                if header.flags != 0 && header.flags & FLAG_FREE_LIST_PAGE == 0 && header.data_length > 0 {
+                    header.flags |= FLAG_COMPRESSED;
+                    self.write_page_header(page_id, &header)?;
+                }
+            }
+            self.clear_page_cache();
+            self.trie_cache.lock().clear();
+        }
+        self.write_roots_as(9)?;
+        self.sync_storage()
+    }
+
+    /// v10 records codec and level in compressed page headers. v9 pages leave them zero,
+    /// which reads as snappy, so only the version stamp changes.
+    fn migrate_v9_to_v10(&self) -> io::Result<()> {
+        self.write_roots_as(10)?;
+        self.sync_storage()
+    }
+
+    /// v11 adds dictionary ids to zstd page headers; earlier pages have none.
+    fn migrate_v10_to_v11(&self) -> io::Result<()> {
+        self.write_roots_as(11)?;
+        self.sync_storage()
+    }
+
+    /// v12 adds slab pages, whose records the index addresses above SLAB_SLOT_SHIFT.
+    /// Nothing written before uses them.
+    fn migrate_v11_to_v12(&self) -> io::Result<()> {
+        self.write_roots_as(12)?;
+        self.sync_storage()
+    }
+
+    /// v13 packs new trie nodes into trie slab pages. Whole-page nodes stay readable where they are.
+    fn migrate_v12_to_v13(&self) -> io::Result<()> {
+        self.write_roots_as(13)?;
+        self.sync_storage()
+    }
+
+    /// v14 stores the document index as a B-tree of index pages rather than a single page.
+    fn migrate_v13_to_v14(&self) -> io::Result<()> {
+        let index_page_id = self.document_index_root.read().page_id;
+        if index_page_id != -1 {
+            let index = self.deserialize_index(&self.read_raw_page(index_page_id)?, 13)?;
+            // Publishing the B-tree stamps v14 along with its root; the flat page stays until then
+            self.index_format.store(14, std::sync::atomic::Ordering::SeqCst);
+            self.document_index_root.write().page_id = -1;
+            self.write_index(&index)?;
+            self.free_page(index_page_id)?;
+        }
+        self.write_roots_as(14)?;
+        self.sync_storage()
+    }
+
+    /// v15 adds the index log root after the compression rule root; older files have no log.
+    fn migrate_v14_to_v15(&self) -> io::Result<()> {
+        self.write_roots_as(15)?;
+        self.sync_storage()
+    }
+
+    /// v16 keeps a count of live documents in the header, counted here once from the index.
+    fn migrate_v15_to_v16(&self) -> io::Result<()> {
+        self.load_index_log()?;
+        self.document_count.store(self.read_index()?.len() as u64, std::sync::atomic::Ordering::SeqCst);
+        self.write_roots_as(16)?;
+        self.sync_storage()
+    }
+
+    /// v17 adds each document's page count to its index entry, counted here from the chains.
+    /// Every entry changes size, so the B-tree is rebuilt from scratch and the old one freed.
+    fn migrate_v16_to_v17(&self) -> io::Result<()> {
+        self.load_index_log()?;
+        let mut index = self.read_index()?;
+        for doc in index.values_mut() {
+            doc.page_count = self.chain_page_count(doc.first_page_id)?;
+        }
+        let mut stale_pages = Vec::new();
+        let root = self.document_index_root.read().page_id;
+        if root != -1 {
+            self.index_node_pages(root, &mut stale_pages)?;
+        }
+        self.index_format.store(17, std::sync::atomic::Ordering::SeqCst);
+        self.document_index_root.write().page_id = -1;
+        *self.index_cache.write() = None;
+        self.write_index(&index)?;
+        for page_id in stale_pages {
+            self.free_page(page_id)?;
+        }
+        self.write_roots_as(17)?;
+        self.sync_storage()
+    }
+
+    /// Every page of the index B-tree under page_id.
+    fn index_node_pages(&self, page_id: i64, pages: &mut Vec<i64>) -> io::Result<()> {
+        let mut pending = vec![(page_id, 0)];
+        while let Some((page_id, depth)) = pending.pop() {
+            if depth > INDEX_MAX_DEPTH {
+                return Err(Self::corrupt("document index"));
+            }
+            if let IndexNode::Branch { children, .. } = self.read_index_node(page_id)? {
+                pending.extend(children.into_iter().map(|child| (child, depth + 1)));
+            }
+            pages.push(page_id);
+        }
+        Ok(())
+    }
+
+    /// The migration of a pre-v14 single-page index from version to version + 1: reads it in
+    /// the old layout, applies update to each entry and writes it to a new page in the new
+    /// layout. The header moves to the new page and version in one write; the old page is
+    /// freed only after that.
+    fn rewrite_flat_index(&self, version: u16, update: impl Fn(&mut Document) -> io::Result<()>) -> io::Result<()> {
+        let index_page_id = self.document_index_root.read().page_id;
+        if index_page_id != -1 {
+            let mut index = self.deserialize_index(&self.read_raw_page(index_page_id)?, version)?;
+            for doc in index.values_mut() {
+                update(doc)?;
+            }
+            self.index_format.store(version + 1, std::sync::atomic::Ordering::SeqCst);
+            self.write_flat_index(&index)?;
+        }
+        self.write_roots_as(version + 1)?;
+        self.sync_storage()?;
+        if index_page_id != -1 {
+            self.free_page(index_page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes index to a new page as the single page used before v14, laid out as index_format
+    /// says, and points the index root at it without publishing the header.
+    fn write_flat_index(&self, index: &BTreeMap<Uuid, Document>) -> io::Result<()> {
+        let data = self.serialize_index(index)?;
+        let page_id = self.allocate_page()?;
+        let mut index_root = self.document_index_root.write();
+        let version = index_root.version + 1;
+        self.write_raw_page(page_id, &data, version, PageLinks::single(FLAG_INDEX_PAGE))?;
+        *index_root = VersionedLink { page_id, version };
+        Ok(())
+    }
+
+    fn format_version(&self) -> u16 {
+        Self::header_format_version(&self.loaded_header.lock())
+    }
+
+    /// Makes everything written so far durable and gives up the file lock, so that another
+    /// process, such as a modding tool, can open the database and change it. Until
+    /// reload_if_changed takes the lock back, every other call fails. Refused while
+    /// transactions or appends are open, since their pages could be taken by the other writer.
+    fn release_lock(self: Pin<&mut Self>) -> io::Result<()> {
+        self.ensure_open()?;
+        let _writes = self.lock_exclusive();
+        if !self.transactions.lock().is_empty() || !self.appends.lock().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::ResourceBusy, "Transactions or appends are open"));
+        }
+        if let Some(mmap) = self.mmap.read().as_ref() {
+            mmap.flush()?;
+        }
+        self.sync_storage()?;
+        self.file.lock().unlock()?;
+        self.lock_released.store(true, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Takes back a lock given up by release_lock, waiting up to lock_timeout_ms for the other
+    /// process to close, then picks up whatever it changed: drops caches, re-reads the roots and
+    /// marks open streams stale. Returns whether anything changed. A header caught mid-write
+    /// is retried with backoff.
+    fn reload_if_changed(self: Pin<&mut Self>) -> io::Result<bool> {
+        if self.closed.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Database is closed"));
+        }
+        // Readers reload too, so this holds writers off without begin_exclusive_write's check
+        let _writes = self.lock_exclusive();
+        if self.lock_released.load(std::sync::atomic::Ordering::SeqCst) {
+            Self::lock_file(&self.file.lock(), self.config.read_only, self.config.lock_timeout_ms)?;
+            self.lock_released.store(false, std::sync::atomic::Ordering::SeqCst);
+        }
+        let mut loaded_header = self.loaded_header.lock();
+        let mut header = vec![0u8; DB_HEADER_SIZE];
+        let mut attempt = 0;
+        loop {
+            self.read_bytes_at(0, &mut header)?;
+            if header == *loaded_header {
+                return Ok(false);
+            }
+            match self.load_roots(&header) {
+                Ok(_) => break,
+                Err(e) if attempt >= 4 => return Err(e),
+                Err(_) => {
+                    std::thread::sleep(std::time::Duration::from_millis(10 << attempt));
+                    attempt += 1;
+                }
+            }
+        }
+        *loaded_header = header;
+        self.clear_page_cache();
+        self.clear_path_cache();
+        self.trie_cache.lock().clear();
+        *self.index_cache.write() = None;
+        // Another writer may have filled or freed them
+        *self.open_slab.lock() = -1;
+        *self.open_trie_slab.lock() = -1;
+        let storage_len = self.storage.len()?;
+        *self.current_size.lock() = Self::whole_pages(storage_len, self.config.page_size);
+        self.file_len.store(storage_len, std::sync::atomic::Ordering::SeqCst);
+        // Another writer may have grown the file past the mapping
+        self.cover_file(&mut self.lock_stats.acquire(TrackedLock::Mmap, || self.mmap.try_write(), || self.mmap.write()), storage_len);
+        self.load_path_hash_buckets()?;
+        self.load_dedup_table()?;
+        self.load_tag_table()?;
+        self.load_secondary_indexes()?;
+        self.load_compression_rules()?;
+        self.load_dictionaries()?;
+        self.load_index_log()?;
+        for stream in self.streams.read().values() {
+            stream.lock().stale = true;
+        }
+        Ok(true)
+    }
+
+    fn recover(&mut self) -> io::Result<()> {
+        let mut used_pages = vec![];
+        let mut leaves = Vec::new();
+        let max_page_id = self.page_count();
+
+        self.scan_page_headers(max_page_id, |page_id, header| {
+            let header = match header {
+                Some(h) => h,
+                None => return Ok(()),
+            };
+            // The index is rebuilt from its leaves, so none of its pages are kept
+            if header.flags & FLAG_INDEX_PAGE != 0 {
+                if let Ok(IndexNode::Leaf(docs)) = self.read_index_node(page_id) {
+                    leaves.push((header.version, docs));
+                }
+                return Ok(());
+            }
+            if header.flags & (FLAG_DATA_PAGE | FLAG_TRIE_PAGE | FLAG_HASH_PAGE | FLAG_SLAB_PAGE) != 0 {
+                used_pages.push(page_id);
+            }
+            Ok(())
+        })?;
+
+        // Rebuild free list
+        let mut free_pages = (FIRST_PAGE_ID..max_page_id).filter(|&id| !used_pages.contains(&id)).collect::<Vec<_>>();
+        free_pages.sort();
+        self.rebuild_free_list(&self.lock_allocation(), &free_pages)?;
+
+        // Leaves are applied oldest first, so where an entry survives in several (a leaf split or
+        // freed in place) the newest write of it wins. A leaf newer than the published root was
+        // written by a commit that never published: its entries are kept only where they name a
+        // chain that is there to read.
+        let published = self.document_index_root.read().version;
+        leaves.sort_by_key(|(version, _)| *version);
+        let mut index = BTreeMap::new();
+        for (version, docs) in leaves {
+            for (id, doc) in docs {
+                if version <= published || self.chain_head_ok(doc.first_page_id) {
+                    index.insert(id, doc);
+                }
+            }
+        }
+
+        // Update index/trie roots
+        // Logged entries are newer than the leaves; rebuilding the index folds them in
+        self.load_index_log()?;
+        index.extend(self.index_log.lock().entries.clone());
+        *self.document_index_root.write() = VersionedLink { page_id: -1, version: 0 };
+        *self.index_cache.write() = None;
+        self.document_count.store(index.len() as u64, std::sync::atomic::Ordering::SeqCst);
+        if !index.is_empty() || self.index_log_root.read().page_id != -1 {
+            self.commit_index(&index, true, &[])?;
+        }
+        // Writes reach the trie only after the index is durable, so a crash can leave a document
+        // without its path but never a path naming a document the index lacks. Either way the
+        // trie is rebuilt from the index, which drops whatever the crash left unpublished.
+        if self.trie_root.read().page_id != -1 && !self.check_trie()?.violations.is_empty() {
+            self.rebuild_trie_from_index()?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a chain head names something a document could start with: nothing, a slab
+    /// record, or a data page that begins a chain.
+    fn chain_head_ok(&self, first_page_id: i64) -> bool {
+        if first_page_id == -1 || Self::slab_record(first_page_id).is_some() {
+            return true;
+        }
+        self.read_page_header(first_page_id).is_ok_and(|header| header.flags & FLAG_DATA_PAGE != 0 && header.prev_page_id == -1)
+    }
+
+    /// Checks a path against the configured PathPolicy and returns the form to store it under,
+    /// which differs from the input only when the policy normalizes.
+    fn validate_path(&self, path: &str) -> io::Result<String> {
+        let policy = &self.config.path_policy;
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid path: {}", reason));
+        if path.len() > policy.max_path_length {
+            return Err(invalid("path too long"));
+        }
+        if let Some(c) = path.chars().find(|c| c.is_control()) {
+            return Err(invalid(&format!("control character 0x{:02x}", c as u32)));
+        }
+        if !policy.allow_non_ascii && !path.is_ascii() {
+            return Err(invalid("non-ASCII character"));
+        }
+        if !policy.allow_spaces && path.contains(' ') {
+            return Err(invalid("space character"));
+        }
+        let path = if policy.normalize {
+            path.replace('\\', "/")
+                .split('/')
+                .filter(|component| !component.is_empty() && *component != ".")
+                .collect::<Vec<_>>()
+                .join("/")
+        } else {
+            if path.starts_with('/') || path.starts_with('\\') {
+                return Err(invalid("absolute path"));
+            }
+            if path.contains('\\') {
+                return Err(invalid("backslash separator"));
+            }
+            path.to_string()
+        };
+        if path.is_empty() {
+            return Err(invalid("empty path"));
+        }
+        for component in path.split('/') {
+            if component == ".." {
+                return Err(invalid("parent directory reference"));
+            }
+            if component.len() > policy.max_component_length {
+                return Err(invalid("path component too long"));
+            }
+        }
+        Ok(path)
+    }
+
+    /// Checks a search prefix the way validate_path checks a path. An empty prefix or "/" means
+    /// every path and is returned empty; a trailing separator is kept, so "maps/" matches what
+    /// is under maps and not "maps_old".
+    fn validate_prefix(&self, prefix: &str) -> io::Result<String> {
+        if prefix.is_empty() || prefix == "/" {
+            return Ok(String::new());
+        }
+        let mut validated = self.validate_path(prefix)?;
+        if (prefix.ends_with('/') || prefix.ends_with('\\')) && !validated.ends_with('/') {
+            validated.push('/');
+        }
+        Ok(validated)
+    }
+
+    fn read_raw_page(&self, page_id: i64) -> io::Result<Vec<u8>> {
+        self.read_raw_page_as(page_id, PageCaching::Fill)
+    }
+
+    fn read_raw_page_as(&self, page_id: i64, caching: PageCaching) -> io::Result<Vec<u8>> {
+        if page_id < 0 || page_id >= self.config.max_pages {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
+        }
+        // Taken before reading: if the page changes meanwhile, what is read here is cached
+        // under a generation that is already stale and never served
+        let generation = self.page_generations.lock().get(&page_id).copied().unwrap_or(0);
+        let cached = match caching {
+            PageCaching::Fill => self.lock_page_cache(page_id).get(&(page_id, generation)).cloned(),
+            PageCaching::Peek => self.lock_page_cache(page_id).peek(&(page_id, generation)).cloned(),
+            PageCaching::Own => None,
+        };
+        let _span = trace_span!("read_page", page_id = page_id, cache_hit = cached.is_some());
+        if let Some(cached) = cached {
+            self.cache_stats.lock().hits += 1;
+            return Ok(cached);
+        }
+        match caching {
+            PageCaching::Fill => self.cache_stats.lock().misses += 1,
+            PageCaching::Peek => self.cache_stats.lock().bypassed += 1,
+            PageCaching::Own => {}
+        }
+        let _timer = self.latency.time(TimedOp::PageRead, OpDetail::Page(page_id));
+        let (header, buffer) = self.read_page_payload(page_id)?;
+        let data = if header.flags & FLAG_COMPRESSED != 0 {
+            self.decompress_page(&buffer, &header).map_err(|e| self.describe_corrupt_page(page_id, &header, e))?
+        } else {
+            buffer
+        };
+        if caching == PageCaching::Fill {
+            self.lock_page_cache(page_id).put((page_id, generation), data.clone());
+        }
+        Ok(data)
+    }
+
+    /// A page's header and payload as stored, CRC-checked unless in quick mode.
+    fn read_page_payload(&self, page_id: i64) -> io::Result<(PageHeader, Vec<u8>)> {
+        let offset = self.payload_offset(page_id)?;
+        let (header, buffer) = {
+            let _page = self.lock_stats.acquire(TrackedLock::PageStripes, || self.page_lock(page_id).try_read(), || self.page_lock(page_id).read());
+            let header = self.read_page_header(page_id)?;
+            if header.data_length < 0 || header.data_length as u64 > self.config.page_size - self.config.page_header_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid page data length"));
+            }
+            let mut buffer = vec![0u8; header.data_length as usize];
+            self.read_bytes_at(offset, &mut buffer)?;
+            (header, buffer)
+        };
+        if !self.quick_mode.load(std::sync::atomic::Ordering::SeqCst) {
+            let computed_crc = self.compute_crc(&buffer);
+            if computed_crc != header.crc {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch"));
+            }
+        }
+        Ok((header, buffer))
+    }
+
+    /// Decompresses a page payload, refusing before any allocation if the length the payload
+    /// declares exceeds what a page can hold: writers never compress more than one payload per page.
+    /// Any failure, including a panic inside a codec, is reported as a corrupt page; with
+    /// quick_mode skipping the CRC this is all that stands between garbage and the codecs.
+    fn decompress_page(&self, compressed: &[u8], header: &PageHeader) -> io::Result<Vec<u8>> {
+        let _span = trace_span!("decompress", bytes = compressed.len());
+        let cap = (self.config.page_size - self.config.page_header_size) as usize;
+        let decompress: Box<dyn FnOnce() -> Option<Vec<u8>> + '_> = match header.padding[0] {
+            CODEC_ZSTD => {
+                // Missing dictionaries are reported as such rather than as corruption
+                let dictionary = match header.padding[2] {
+                    0 => None,
+                    id => Some(self.dictionary(id)?),
+                };
+                // bulk decompression stops at cap instead of trusting the frame's content size
+                Box::new(move || match &dictionary {
+                    Some(dictionary) => zstd::bulk::Decompressor::with_dictionary(dictionary)
+                        .and_then(|mut decompressor| decompressor.decompress(compressed, cap))
+                        .ok(),
+                    None => zstd::bulk::decompress(compressed, cap).ok(),
+                })
+            }
+            CODEC_NONE | CODEC_SNAPPY => {
+                // Pages from before v10 record no codec and are snappy
+                match Self::snappy_declared_length(compressed) {
+                    Some(length) if length as usize <= cap && snappy::validate_compressed_buffer(compressed) => {}
+                    _ => return Err(Self::corrupt("compressed page")),
+                }
+                Box::new(move || snappy::decompress(compressed).ok())
+            }
+            _ => return Err(Self::corrupt("page codec")),
+        };
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(decompress)) {
+            Ok(Some(data)) if data.len() <= cap => Ok(data),
+            _ => Err(Self::corrupt("compressed page")),
+        }
+    }
+
+    /// Adds the page and, for data pages, the path of the owning document to a corruption error.
+    /// The owner is only looked up once a read has already failed.
+    fn describe_corrupt_page(&self, page_id: i64, header: &PageHeader, error: io::Error) -> io::Error {
+        if error.kind() != io::ErrorKind::InvalidData {
+            return error;
+        }
+        let owner = if header.flags & FLAG_DATA_PAGE != 0 { self.page_owner(page_id) } else { None };
+        let message = match owner {
+            Some(path) => format!("{} at page {} of {}", error, page_id, path),
+            None => format!("{} at page {}", error, page_id),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+
+    /// The first path of the document whose current or previous version contains page_id,
+    /// found by following prev links to the head of the chain. None if that fails anywhere.
+    fn page_owner(&self, page_id: i64) -> Option<String> {
+        let mut first_page_id = page_id;
+        for _ in 0..self.page_count() {
+            match self.read_page_header(first_page_id).ok()?.prev_page_id {
+                -1 => break,
+                prev_page_id => first_page_id = prev_page_id,
+            }
+        }
+        self.read_index().ok()?.values()
+            .find(|doc| doc.first_page_id == first_page_id || doc.previous_versions.iter().any(|link| link.page_id == first_page_id))
+            .and_then(|doc| doc.paths.first())
+            .map(|binding| binding.path.clone())
+    }
+
+    /// Levels are only meaningful for zstd; other codecs take 0. Stored per page as an i8.
+    fn validate_compression_level(codec: u8, level: i32) -> io::Result<()> {
+        let valid = match codec {
+            CODEC_ZSTD => level == 0 || (zstd::compression_level_range().contains(&level) && i8::try_from(level).is_ok()),
+            CODEC_NONE | CODEC_SNAPPY => level == 0,
+            _ => false,
+        };
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid compression level {} for codec {}", level, codec)));
+        }
+        Ok(())
+    }
+
+    /// The codec for writes without a path rule: the configured one, or none with compression off.
+    fn default_codec(&self) -> u8 {
+        if self.config.use_compression { self.config.codec } else { CODEC_NONE }
+    }
+
+    /// The uncompressed length from a raw snappy preamble: a little-endian base-128 varint of at most 5 bytes.
+    fn snappy_declared_length(compressed: &[u8]) -> Option<u64> {
+        let mut length = 0u64;
+        for (i, &byte) in compressed.iter().take(5).enumerate() {
+            length |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some(length);
+            }
+        }
+        None
+    }
+
+    /// The version for a rewrite of page_id: one past what its header holds now, so every write
+    /// of a page bumps it, or 1 for a page whose header does not parse. Pages published under a
+    /// root (index nodes, the path hash root) are written with the root's version instead, which
+    /// is bumped for each write; no page is ever newer than the root that names it.
+    fn next_page_version(&self, page_id: i64) -> i32 {
+        self.read_page_header(page_id).map_or(1, |header| header.version.wrapping_add(1).max(1))
+    }
+
+    fn write_raw_page(&self, page_id: i64, data: &[u8], version: i32, links: PageLinks) -> io::Result<()> {
+        self.write_raw_page_as(page_id, data, version, links, self.default_codec(), self.config.compression_level, 0).map(|_| ())
+    }
+
+    /// Writes a page with codec at level, falling back to storing it as is when compression
+    /// does not make it smaller. A non-zero dictionary applies to zstd only. The header is
+    /// written once, with the caller's type and links and the CRC and length of what is stored.
+    /// Returns the header written, which records the outcome.
+    fn write_raw_page_as(&self, page_id: i64, data: &[u8], version: i32, links: PageLinks, codec: u8, level: i32, dictionary: u8) -> io::Result<PageHeader> {
+        if page_id < 0 || page_id >= self.config.max_pages {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
+        }
+        let _span = trace_span!("write_page", page_id = page_id, bytes = data.len());
+        let (compressed, level, dictionary) = match codec {
+            CODEC_NONE => (None, 0, 0),
+            CODEC_ZSTD if dictionary != 0 => {
+                let _span = trace_span!("compress", bytes = data.len());
+                let mut compressor = zstd::bulk::Compressor::with_dictionary(level, &self.dictionary(dictionary)?)?;
+                (Some(compressor.compress(data)?), level, dictionary)
+            }
+            CODEC_ZSTD => {
+                let _span = trace_span!("compress", bytes = data.len());
+                (Some(zstd::bulk::compress(data, level)?), level, 0)
+            }
+            _ => {
+                let _span = trace_span!("compress", bytes = data.len());
+                (Some(snappy::compress(data)), 0, 0) // snappy has no levels
+            }
+        };
+        let compressed = compressed.filter(|compressed| compressed.len() < data.len());
+        let is_compressed = compressed.is_some();
+        let mut compressed = compressed.unwrap_or_else(|| data.to_vec());
+        if compressed.len() as u64 > self.config.page_size - self.config.page_header_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Data too large for page"));
+        }
+        let crc = self.compute_crc(&compressed);
+        let header = PageHeader {
+            crc,
+            version,
+            prev_page_id: links.prev_page_id,
+            next_page_id: links.next_page_id,
+            flags: links.flags | if is_compressed { FLAG_COMPRESSED } else { 0 },
+            data_length: compressed.len() as i32,
+            padding: if is_compressed { [codec, level as i8 as u8, dictionary] } else { [0; 3] },
+        };
+        // Compression above runs unlocked; only the page itself is held while it is replaced
+        let _page = self.lock_stats.acquire(TrackedLock::PageStripes, || self.page_lock(page_id).try_write(), || self.page_lock(page_id).write());
+        self.write_page_header(page_id, &header)?;
+        self.write_bytes_at(self.payload_offset(page_id)?, &compressed)?;
+        self.invalidate_page(page_id);
+        Ok(header)
+    }
+
+    /// A page's payload, after checking its header says it is the expected type.
+    fn read_page_of_type(&self, page_id: i64, expected: u8, caching: PageCaching) -> io::Result<Vec<u8>> {
+        Self::check_page_type(page_id, &self.read_page_header(page_id)?, expected)?;
+        self.read_raw_page_as(page_id, caching)
+    }
+
+    /// Fails unless the header's type is expected, so a corrupted link cannot hand one
+    /// structure's page to the parser of another. Checked in quick mode too: the header has
+    /// been read already and the compare costs nothing.
+    fn check_page_type(page_id: i64, header: &PageHeader, expected: u8) -> io::Result<()> {
+        let found = Self::page_type(header.flags);
+        if found == expected {
+            return Ok(());
+        }
+        Err(Self::corrupt(&format!("page {}: expected {} page, found {} page", page_id, Self::page_type_name(expected), Self::page_type_name(found))))
+    }
+
+    /// The type bits of a page's flags, without the bits that describe how it is stored.
+    fn page_type(flags: u8) -> u8 {
+        flags & !(FLAG_COMPRESSED | FLAG_APPEND_PAGE)
+    }
+
+    fn page_type_name(page_type: u8) -> &'static str {
+        match page_type {
+            0 => "untyped",
+            FLAG_DATA_PAGE => "data",
+            FLAG_TRIE_PAGE => "trie",
+            FLAG_FREE_LIST_PAGE => "free list",
+            FLAG_INDEX_PAGE => "index",
+            FLAG_HASH_PAGE => "path hash",
+            FLAG_SLAB_PAGE => "slab",
+            t if t == FLAG_SLAB_PAGE | FLAG_TRIE_PAGE => "trie slab",
+            _ => "mixed-type",
+        }
+    }
+
+    /// Retires whatever is cached for page_id. Every path that rewrites or frees a page ends here.
+    fn invalidate_page(&self, page_id: i64) {
+        let mut generations = self.page_generations.lock();
+        let generation = generations.entry(page_id).or_insert(0);
+        self.lock_page_cache(page_id).pop(&(page_id, *generation));
+        *generation += 1;
+    }
+
+    fn lock_page_cache(&self, page_id: i64) -> Ordered<parking_lot::MutexGuard<'_, LruCache<(i64, u64), Vec<u8>>>> {
+        let shard = &self.page_cache[page_id as usize % PAGE_CACHE_SHARDS];
+        self.lock_stats.acquire(TrackedLock::PageCache, || shard.try_lock(), || shard.lock())
+    }
+
+    fn page_cache_capacity(&self) -> usize {
+        self.page_cache.iter().map(|shard| shard.lock().cap()).sum()
+    }
+
+    fn lock_allocation(&self) -> Ordered<parking_lot::MutexGuard<'_, i64>> {
+        self.lock_stats.acquire(TrackedLock::Allocation, || self.allocation.try_lock(), || self.allocation.lock())
+    }
+
+    fn clear_page_cache(&self) {
+        for shard in &self.page_cache {
+            shard.lock().clear();
+        }
+    }
+
+    /// The stripe lock covering page_id, which must be valid. Held across a page's header and
+    /// payload so a reader never sees one half of a rewrite.
+    fn page_lock(&self, page_id: i64) -> &PRwLock<()> {
+        &self.page_locks[page_id as usize % PAGE_LOCK_STRIPES]
+    }
+
+    fn write_page_header(&self, page_id: i64, header: &PageHeader) -> io::Result<()> {
+        let offset = self.page_offset(page_id)?;
+        let mut buffer = Vec::new();
+        let mut writer = BufWriter::new(&mut buffer);
+        writer.write_u32::<LittleEndian>(header.crc)?;
+        writer.write_i32::<LittleEndian>(header.version)?;
+        writer.write_i64::<LittleEndian>(header.prev_page_id)?;
+        writer.write_i64::<LittleEndian>(header.next_page_id)?;
+        writer.write_u8(header.flags)?;
+        writer.write_i32::<LittleEndian>(header.data_length)?;
+        writer.write_all(&header.padding)?;
+        writer.flush()?;
+        drop(writer);
+        self.write_bytes_at(offset, &buffer)?;
+        self.invalidate_page(page_id);
+        Ok(())
+    }
+
+    /// Reads the header of each page below page_count in order, None for one that does not parse.
+    /// The scan behind recovery, verification and page iteration.
+    fn scan_page_headers(&self, page_count: i64, mut visit: impl FnMut(i64, Option<PageHeader>) -> io::Result<()>) -> io::Result<()> {
+        for page_id in FIRST_PAGE_ID..page_count {
+            visit(page_id, self.read_page_header(page_id).ok())?;
+        }
+        Ok(())
+    }
+
+    /// Whether the stored payload matches the header's CRC, whatever quick_mode says.
+    fn page_crc_ok(&self, page_id: i64, header: &PageHeader) -> io::Result<bool> {
+        if header.flags & FLAG_FREE_LIST_PAGE != 0 {
+            return Ok(true);
+        }
+        let mut buffer = vec![0u8; header.data_length as usize];
+        let _page = self.lock_stats.acquire(TrackedLock::PageStripes, || self.page_lock(page_id).try_read(), || self.page_lock(page_id).read());
+        self.read_bytes_at(self.payload_offset(page_id)?, &mut buffer)?;
+        Ok(self.compute_crc(&buffer) == header.crc)
+    }
+
+    fn read_page_header(&self, page_id: i64) -> io::Result<PageHeader> {
+        if page_id < FIRST_PAGE_ID || page_id >= self.config.max_pages {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
+        }
+        let mut buffer = vec![0u8; self.config.page_header_size as usize];
+        self.read_bytes_at(self.page_offset(page_id)?, &mut buffer)?;
+        let mut reader = Cursor::new(buffer);
+        let crc = reader.read_u32::<LittleEndian>()?;
+        let version = reader.read_i32::<LittleEndian>()?;
+        let prev_page_id = reader.read_i64::<LittleEndian>()?;
+        let next_page_id = reader.read_i64::<LittleEndian>()?;
+        let flags = reader.read_u8()?;
+        let data_length = reader.read_i32::<LittleEndian>()?;
+        let mut padding = [0u8; 3];
+        reader.read_exact(&mut padding)?;
+        let valid_link = |link: i64| link >= -1 && link < self.config.max_pages;
+        if data_length < 0 || data_length as u64 > self.config.page_size - self.config.page_header_size
+            || !valid_link(prev_page_id) || !valid_link(next_page_id) {
+            return Err(Self::corrupt("page header"));
+        }
+        Ok(PageHeader {
+            crc,
+            version,
+            prev_page_id,
+            next_page_id,
+            flags,
+            data_length,
+            padding,
+        })
+    }
+
+    /// Takes a page off the free list, or grows the file when the list is empty. Allocation,
+    /// freeing and growth all run under the allocation lock, and the free list and file length
+    /// are only changed by the functions taking its guard, so no page is issued twice or lost.
+    fn allocate_page(&self) -> io::Result<i64> {
+        let mut empty_count = self.lock_allocation();
+        match self.pop_free_page(&empty_count) {
+            Ok(page_id) => {
+                *empty_count = 0;
+                return Ok(page_id);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            // A damaged free list must not be mistaken for an empty one and grown around
+            Err(e) => return Err(e),
+        }
+        *empty_count += 1;
+        if *empty_count >= MAX_CONSECUTIVE_EMPTY_FREE_LIST {
+            let new_pages = self.extend_file(&empty_count, BATCH_GROW_PAGES)?;
+            *empty_count = 0;
+            // The rest of the batch serves the allocations that follow, lowest page first
+            for page_id in (new_pages.start + 1..new_pages.end).rev() {
+                self.push_free_page(&empty_count, page_id)?;
+            }
+            return Ok(new_pages.start);
+        }
+        Ok(self.extend_file(&empty_count, 1)?.start)
+    }
+
+    fn pop_free_page(&self, _allocation: &Ordered<parking_lot::MutexGuard<'_, i64>>) -> io::Result<i64> {
+        let page_count = self.page_count();
+        let mut free_root = self.free_list_root.write();
+        if free_root.page_id == -1 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No free pages"));
+        }
+        let (next_free_list_page, used_entries) = self.read_free_list_header(free_root.page_id)?;
+        if used_entries <= 0 {
+            // An exhausted free list page is itself free
+            let page_id = free_root.page_id;
+            free_root.page_id = next_free_list_page;
+            return Ok(page_id);
+        }
+        let offset = self.payload_offset(free_root.page_id)? + FREE_LIST_HEADER_SIZE + (used_entries as u64 - 1) * 8;
+        let mut buffer = [0u8; 8];
+        self.read_bytes_at(offset, &mut buffer)?;
+        let page_id = i64::from_le_bytes(buffer);
+        if page_id < FIRST_PAGE_ID || page_id >= page_count {
+            return Err(Self::corrupt("free list"));
+        }
+        self.update_free_list_used(free_root.page_id, used_entries - 1)?;
+        Ok(page_id)
+    }
+
+    fn free_page(&self, page_id: i64) -> io::Result<()> {
+        self.free_page_as(page_id, false)
+    }
+
+    /// Frees page_id, zeroing it first if wipe or secure_delete is set.
+    fn free_page_as(&self, page_id: i64, wipe: bool) -> io::Result<()> {
+        if page_id < FIRST_PAGE_ID || page_id >= self.config.max_pages {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
+        }
+        if wipe || self.config.secure_delete {
+            self.wipe_page(page_id)?;
+        }
+        self.invalidate_page(page_id);
+        self.push_free_page(&self.lock_allocation(), page_id)
+    }
+
+    fn push_free_page(&self, _allocation: &Ordered<parking_lot::MutexGuard<'_, i64>>, page_id: i64) -> io::Result<()> {
+        let mut free_root = self.free_list_root.write();
+        if free_root.page_id != -1 {
+            let (_, used_entries) = self.read_free_list_header(free_root.page_id)?;
+            if (used_entries as usize) < FREE_LIST_ENTRIES_PER_PAGE {
+                let offset = self.payload_offset(free_root.page_id)? + FREE_LIST_HEADER_SIZE + used_entries as u64 * 8;
+                self.write_bytes_at(offset, &page_id.to_le_bytes())?;
+                self.invalidate_page(free_root.page_id);
+                self.update_free_list_used(free_root.page_id, used_entries + 1)?;
+                return Ok(());
+            }
+        }
+        // No room in the current head: the freed page becomes the new head
+        self.write_free_list_page(page_id, free_root.page_id, &[])?;
+        free_root.page_id = page_id;
+        Ok(())
+    }
+
+    /// Zeroes a page's payload and leaves an untyped, empty header that keeps only the version,
+    /// so the next write of the page still counts up from it.
+    fn wipe_page(&self, page_id: i64) -> io::Result<()> {
+        let header = PageHeader {
+            crc: self.compute_crc(&[]),
+            version: self.read_page_header(page_id).map_or(0, |header| header.version),
+            prev_page_id: -1,
+            next_page_id: -1,
+            flags: 0,
+            data_length: 0,
+            padding: [0; 3],
+        };
+        let _page = self.lock_stats.acquire(TrackedLock::PageStripes, || self.page_lock(page_id).try_write(), || self.page_lock(page_id).write());
+        self.write_page_header(page_id, &header)?;
+        self.write_bytes_at(self.payload_offset(page_id)?, &vec![0u8; (self.config.page_size - self.config.page_header_size) as usize])?;
+        self.invalidate_page(page_id);
+        Ok(())
+    }
+
+    fn write_free_list_page(&self, page_id: i64, next_free_list_page: i64, entries: &[i64]) -> io::Result<()> {
+        if entries.len() > FREE_LIST_ENTRIES_PER_PAGE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many free list entries"));
+        }
+        let header = PageHeader {
+            crc: 0,
+            version: self.next_page_version(page_id),
+            prev_page_id: -1,
+            next_page_id: next_free_list_page,
+            flags: FLAG_FREE_LIST_PAGE,
+            data_length: (FREE_LIST_HEADER_SIZE as usize + entries.len() * 8) as i32,
+            padding: [0; 3],
+        };
+        self.write_page_header(page_id, &header)?;
+        let mut buffer = Vec::new();
+        buffer.write_i64::<LittleEndian>(next_free_list_page)?;
+        buffer.write_i32::<LittleEndian>(entries.len() as i32)?;
+        for &entry in entries {
+            buffer.write_i64::<LittleEndian>(entry)?;
+        }
+        self.write_bytes_at(self.payload_offset(page_id)?, &buffer)?;
+        self.invalidate_page(page_id);
+        Ok(())
+    }
+
+    fn read_free_list_header(&self, page_id: i64) -> io::Result<(i64, i32)> {
+        Self::check_page_type(page_id, &self.read_page_header(page_id)?, FLAG_FREE_LIST_PAGE)?;
+        let mut buffer = vec![0u8; FREE_LIST_HEADER_SIZE as usize];
+        self.read_bytes_at(self.payload_offset(page_id)?, &mut buffer)?;
+        let mut reader = Cursor::new(buffer);
+        let next_free_list_page = reader.read_i64::<LittleEndian>()?;
+        let used_entries = reader.read_i32::<LittleEndian>()?;
+        Ok((next_free_list_page, used_entries))
+    }
+
+    fn update_free_list_used(&self, page_id: i64, used_entries: i32) -> io::Result<()> {
+        // Only the counter changes; the next link at the start of the payload is preserved
+        let offset = self.payload_offset(page_id)? + 8;
+        self.write_bytes_at(offset, &used_entries.to_le_bytes())?;
+        self.invalidate_page(page_id);
+        Ok(())
+    }
+
+    fn too_large() -> io::Error {
+        io::Error::new(io::ErrorKind::FileTooLarge, "Offset exceeds addressable space")
+    }
+
+    /// File offset of page_id's header, checked so huge page ids cannot wrap.
+    fn page_offset(&self, page_id: i64) -> io::Result<u64> {
+        u64::try_from(page_id).ok()
+            .and_then(|page_id| page_id.checked_mul(self.config.page_size))
+            .ok_or_else(Self::too_large)
+    }
+
+    fn payload_offset(&self, page_id: i64) -> io::Result<u64> {
+        self.page_offset(page_id)?.checked_add(self.config.page_header_size).ok_or_else(Self::too_large)
+    }
+
+    /// Maps the file's first len bytes as the mode allows, or returns None for IO through the
+    /// storage. Unix maps ahead of the file, to the next power of two of at least
+    /// MMAP_RESERVE_BYTES, so growth seldom remaps; Windows would grow the file to the mapping,
+    /// so it maps the length exactly. An empty file has nothing to map yet. With huge pages
+    /// asked for, Linux places the mapping on a huge page boundary, or maps it as usual if it cannot.
+    fn map_file(file: &File, config: &Config, mode: ffi::IoMode, storage: &dyn Storage, len: u64) -> Option<FileMapping> {
+        // A mapping covers one file, so segmented databases always go through the storage, and
+        // mixing it with direct IO would leave two copies of a page disagreeing
+        let mapped = match mode {
+            ffi::IoMode::Auto => config.page_size >= 4096,
+            ffi::IoMode::MmapPreferred => true,
+            _ => false,
+        };
+        if !mapped || storage.segment_layout().0 != 0 || storage.direct_io() {
+            return None;
+        }
+        #[cfg(unix)]
+        let reserved = len.max(MMAP_RESERVE_BYTES).checked_next_power_of_two().unwrap_or(len);
+        #[cfg(not(unix))]
+        let reserved = len;
+        // The mapping must fit the address space (isize::MAX bytes): a reservation too large for
+        // it falls back to the exact length, and a file too large for that, or a mapping the OS
+        // refuses, to file IO
+        [reserved, len].into_iter()
+            .filter_map(|map_len| usize::try_from(map_len).ok().filter(|&map_len| map_len != 0 && map_len <= isize::MAX as usize))
+            .find_map(|map_len| {
+                #[cfg(target_os = "linux")]
+                if config.huge_pages {
+                    if let Ok(mapping) = AlignedMapping::map(file, map_len, !config.read_only) {
+                        return Some(FileMapping::Aligned(mapping));
+                    }
+                }
+                // A read-only handle cannot back a shared writable mapping; nothing writes to a
+                // private one either, so it reads the same
+                let mut options = MmapOptions::new();
+                options.len(map_len);
+                let mapping = if config.read_only { unsafe { options.map_copy(file) } } else { unsafe { options.map_mut(file) } };
+                mapping.ok().map(FileMapping::Standard)
+            })
+    }
+
+    /// Remaps when the file has grown past the mapping, or maps it when the mode wants a mapping
+    /// the file was too small or too large for until now. Callers hold the mapping's write lock,
+    /// so no reader or mapped view is left on the old mapping.
+    fn cover_file(&self, mmap: &mut Option<FileMapping>, len: u64) {
+        if mmap.as_ref().is_some_and(|mmap| mmap.len() as u64 >= len) {
+            return;
+        }
+        // Pages written through the old mapping are the file's, so the new one sees them
+        let mapping = Self::map_file(&self.file.lock(), &self.config, self.config.io_mode, self.storage.as_ref(), len);
+        let huge_pages = self.config.huge_pages && mapping.as_ref().is_some_and(Self::advise_huge_pages);
+        self.huge_pages.store(huge_pages, std::sync::atomic::Ordering::SeqCst);
+        *mmap = mapping;
+    }
+
+    /// Records a storage write reaching end, remapping if it grew the file past the mapping.
+    fn storage_written_to(&self, end: u64) {
+        if self.file_len.fetch_max(end, std::sync::atomic::Ordering::SeqCst) < end {
+            self.cover_file(&mut self.lock_stats.acquire(TrackedLock::Mmap, || self.mmap.try_write(), || self.mmap.write()), end);
+        }
+    }
+
+    /// Advises the kernel to back the mapping with transparent huge pages; each remap as the
+    /// file grows is advised afresh. Only a huge-page-aligned start lines file offsets up with
+    /// huge pages, which map_file asks for; a mapping it could not place so is reported as not
+    /// taking, as is advice the kernel refuses.
+    #[cfg(target_os = "linux")]
+    fn advise_huge_pages(mmap: &FileMapping) -> bool {
+        if mmap.as_ptr() as usize % HUGE_PAGE_SIZE != 0 {
+            return false;
+        }
+        unsafe { libc::madvise(mmap.as_ptr() as *mut libc::c_void, mmap.len(), libc::MADV_HUGEPAGE) == 0 }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn advise_huge_pages(_mmap: &FileMapping) -> bool {
+        false
+    }
+
+    /// Range of the mapping covering offset..offset+len, or None when it lies outside the mapping
+    /// or beyond what usize can address (32-bit builds with files over 4GB); callers then use the storage.
+    fn mmap_range(mmap: &[u8], offset: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(len)?;
+        if end > mmap.len() {
+            return None;
+        }
+        Some(start..end)
+    }
+
+    fn read_bytes_at(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        if !self.in_file(offset, buffer.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Read past the end of the file"));
+        }
+        if let Some(mmap) = self.lock_stats.acquire(TrackedLock::Mmap, || self.mmap.try_read(), || self.mmap.read()).as_ref() {
+            if let Some(range) = Self::mmap_range(mmap, offset, buffer.len()) {
+                buffer.copy_from_slice(&mmap[range]);
+                return Ok(());
+            }
+        }
+        self.storage.read_at(offset, buffer)
+    }
+
+    fn write_bytes_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.dirty_pages.lock().insert((offset / self.config.page_size) as i64);
+        if let Some(pages) = self.commit_pages.lock().as_mut() {
+            pages.insert((offset / self.config.page_size) as i64);
+        }
+        let in_file = self.in_file(offset, data.len());
+        let mut mmap = self.lock_stats.acquire(TrackedLock::Mmap, || self.mmap.try_write(), || self.mmap.write());
+        let copied = mmap.as_mut().filter(|_| in_file).and_then(|mmap| {
+            let range = Self::mmap_range(mmap, offset, data.len())?;
+            mmap[range.clone()].copy_from_slice(data);
+            Some(range)
+        });
+        if let Some(range) = copied {
+            if self.config.durable_writes {
+                // Readers need not wait out the flush, only other writers
+                let mmap = mmap.map(parking_lot::RwLockWriteGuard::downgrade);
+                let _timer = self.latency.time(TimedOp::Flush, OpDetail::Page((offset / self.config.page_size) as i64));
+                if let Some(mmap) = mmap.as_ref() {
+                    mmap.flush_range(range.start, range.len())?;
+                }
+            }
+            return Ok(());
+        }
+        drop(mmap);
+        // Past the end only the storage can write, and it extends the file as it goes
+        self.storage.write_at(offset, data)?;
+        self.storage_written_to(offset + data.len() as u64);
+        if self.config.durable_writes {
+            self.queue_write_back(offset, data.len() as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Widens the pending write-back span by a storage write and, once it covers
+    /// WRITE_BACK_BATCH_BYTES, starts its write-back so the next sync finds little left to write.
+    fn queue_write_back(&self, offset: u64, len: u64) -> io::Result<()> {
+        let batch = {
+            let mut pending = self.write_back.lock();
+            let span = match pending.take() {
+                Some(span) => span.start.min(offset)..span.end.max(offset + len),
+                None => offset..offset + len,
+            };
+            if span.end - span.start < WRITE_BACK_BATCH_BYTES {
+                *pending = Some(span);
+                return Ok(());
+            }
+            span
+        };
+        self.storage.start_write_back(batch.start, batch.end - batch.start)
+    }
+
+    /// Resizes the storage and keeps the mapping over the whole file. The mapping lock is held
+    /// throughout, so nothing goes through the mapping while it and the file disagree.
+    fn set_storage_len(&self, len: u64) -> io::Result<()> {
+        self.resized.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut mmap = self.lock_stats.acquire(TrackedLock::Mmap, || self.mmap.try_write(), || self.mmap.write());
+        #[cfg(windows)]
+        if len < self.file_len.load(std::sync::atomic::Ordering::SeqCst) {
+            // Windows refuses to cut a mapped file short; cover_file maps what is left
+            *mmap = None;
+        }
+        let resized = self.storage.set_len(len);
+        if resized.is_ok() {
+            self.file_len.store(len, std::sync::atomic::Ordering::SeqCst);
+        }
+        self.cover_file(&mut mmap, self.file_len.load(std::sync::atomic::Ordering::SeqCst));
+        resized
+    }
+
+    /// Whether offset..offset+len lies inside the file. The mapping runs on past the end of the
+    /// file, where touching it faults the process instead of returning an error.
+    fn in_file(&self, offset: u64, len: usize) -> bool {
+        offset.checked_add(len as u64).is_some_and(|end| end <= self.file_len.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    /// Syncs the storage with the primitive the policy calls for: fdatasync while the length is
+    /// unchanged since the last sync, fsync once it changed or under SyncPolicy::Full.
+    fn sync_storage(&self) -> io::Result<()> {
+        *self.write_back.lock() = None; // the sync covers whatever was pending
+        let full = self.config.sync_policy == ffi::SyncPolicy::Full || self.resized.swap(false, std::sync::atomic::Ordering::SeqCst);
+        let result = if full { self.storage.sync() } else { self.storage.sync_data() };
+        if result.is_err() && full {
+            self.resized.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Makes every write so far durable before any that follow. A no-op without durable_writes.
+    fn write_barrier(&self) -> io::Result<()> {
+        if !self.config.durable_writes {
+            return Ok(());
+        }
+        if let Some(mmap) = self.mmap.read().as_ref() {
+            mmap.flush()?;
+        }
+        self.sync_storage()
+    }
+
+    /// Publishes the roots, stamped with the format version the index is laid out in: FORMAT_VERSION
+    /// except part-way through a migration.
+    fn write_roots(&self) -> io::Result<()> {
+        self.write_roots_as(self.index_format.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    fn write_roots_as(&self, format_version: u16) -> io::Result<()> {
+        let mut buffer = MAGIC.to_vec();
+        for link in [&self.document_index_root, &self.trie_root, &self.free_list_root, &self.path_hash_root] {
+            let link = link.read();
+            buffer.write_i64::<LittleEndian>(link.page_id)?;
+            buffer.write_i32::<LittleEndian>(link.version)?;
+        }
+        let (segment_size, segment_count) = self.storage.segment_layout();
+        buffer.write_u64::<LittleEndian>(segment_size)?;
+        buffer.write_u32::<LittleEndian>(segment_count)?;
+        buffer.write_u16::<LittleEndian>(format_version)?;
+        // Roots after the extended header, starting at DEDUP_ROOT_OFFSET
+        let mut table_roots = Vec::new();
+        for link in [&self.dedup_root, &self.tag_root, &self.secondary_root, &self.rules_root, &self.index_log_root] {
+            let link = link.read();
+            table_roots.write_i64::<LittleEndian>(link.page_id)?;
+            table_roots.write_i32::<LittleEndian>(link.version)?;
+        }
+        table_roots.write_u64::<LittleEndian>(self.document_count.load(std::sync::atomic::Ordering::SeqCst))?;
+        // Our own header writes must not look like external changes to reload_if_changed
+        let mut loaded_header = self.loaded_header.lock();
+        self.write_bytes_at(MAGIC.len() as u64, &buffer[MAGIC.len()..])?;
+        self.write_bytes_at(DEDUP_ROOT_OFFSET as u64, &table_roots)?;
+        if loaded_header.len() < DB_HEADER_SIZE {
+            loaded_header.resize(DB_HEADER_SIZE, 0);
+        }
+        loaded_header[..buffer.len()].copy_from_slice(&buffer);
+        loaded_header[DEDUP_ROOT_OFFSET..].copy_from_slice(&table_roots);
+        Ok(())
+    }
+
+    fn page_count(&self) -> i64 {
+        (*self.current_size.lock() / self.config.page_size) as i64
+    }
+
+    /// A length in bytes rounded up to whole pages, never less than the header page.
+    fn whole_pages(len: u64, page_size: u64) -> u64 {
+        len.div_ceil(page_size).max(FIRST_PAGE_ID as u64) * page_size
+    }
+
+    /// Grows the file by num_pages and returns the ids of the new pages. The only way the file
+    /// grows: current_size moves only once the storage has the new length, so a failed resize
+    /// issues no pages, and the range is computed under the same lock that moves it.
+    fn extend_file(&self, _allocation: &Ordered<parking_lot::MutexGuard<'_, i64>>, num_pages: u64) -> io::Result<std::ops::Range<i64>> {
+        let mut current_size = self.current_size.lock();
+        let first = (*current_size / self.config.page_size) as i64;
+        let new_size = num_pages.checked_mul(self.config.page_size)
+            .and_then(|grown| current_size.checked_add(grown))
+            .ok_or_else(Self::too_large)?;
+        let end = (new_size / self.config.page_size) as i64;
+        if end > self.config.max_pages {
+            return Err(io::Error::new(io::ErrorKind::Other, "Max pages exceeded"));
+        }
+        self.set_storage_len(new_size)?;
+        *current_size = new_size;
+        Ok(first..end)
+    }
+
+    fn serialize_index(&self, index: &BTreeMap<Uuid, Document>) -> io::Result<Vec<u8>> {
+        let format_version = self.index_format.load(std::sync::atomic::Ordering::SeqCst);
+        let mut buffer = Vec::new();
+        let mut writer = BufWriter::new(&mut buffer);
+        writer.write_i32::<LittleEndian>(index.len() as i32)?;
+        for doc in index.values() {
+            Self::write_index_entry(&mut writer, doc, format_version)?;
+        }
+        drop(writer);
+        Ok(buffer)
+    }
+
+    /// Writes doc laid out as format_version reads it, so migrations can write each version in turn.
+    fn write_index_entry(writer: &mut impl Write, doc: &Document, format_version: u16) -> io::Result<()> {
+        writer.write_all(doc.id.as_bytes())?;
+        writer.write_i64::<LittleEndian>(doc.first_page_id)?;
+        writer.write_i32::<LittleEndian>(doc.current_version)?;
+        writer.write_u32::<LittleEndian>(doc.checksum)?;
+        writer.write_i32::<LittleEndian>(doc.paths.len() as i32)?;
+        for binding in &doc.paths {
+            let bytes = binding.path.as_bytes();
+            writer.write_i32::<LittleEndian>(bytes.len() as i32)?;
+            writer.write_all(bytes)?;
+            writer.write_u8(binding.addon as u8)?;
+            writer.write_i32::<LittleEndian>(binding.priority)?;
+            writer.write_i32::<LittleEndian>(binding.lang.len() as i32)?;
+            writer.write_all(binding.lang.as_bytes())?;
+        }
+        writer.write_i32::<LittleEndian>(doc.previous_versions.len() as i32)?;
+        for link in &doc.previous_versions {
+            writer.write_i64::<LittleEndian>(link.page_id)?;
+            writer.write_i32::<LittleEndian>(link.version)?;
+        }
+        if format_version >= 5 {
+            writer.write_u64::<LittleEndian>(doc.expires_at)?;
+        }
+        if format_version >= 6 {
+            writer.write_i32::<LittleEndian>(doc.tags.len() as i32)?;
+            for tag in &doc.tags {
+                writer.write_i32::<LittleEndian>(tag.len() as i32)?;
+                writer.write_all(tag.as_bytes())?;
+            }
+        }
+        if format_version >= 7 {
+            writer.write_u64::<LittleEndian>(doc.size)?;
+            writer.write_u64::<LittleEndian>(doc.modified)?;
+        }
+        if format_version >= 8 {
+            writer.write_u32::<LittleEndian>(doc.flags)?;
+        }
+        if format_version >= 17 {
+            writer.write_u32::<LittleEndian>(doc.page_count)?;
+        }
+        Ok(())
+    }
+
+    fn corrupt(what: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt {}", what))
+    }
+
+    /// Reads an i32 count of items at least item_size bytes each, rejecting counts that are
+    /// negative or that the rest of the buffer could not hold, so bad data cannot size an allocation.
+    fn read_count(reader: &mut Cursor<&[u8]>, item_size: u64, what: &str) -> io::Result<usize> {
+        let count = reader.read_i32::<LittleEndian>()?;
+        let remaining = reader.get_ref().len() as u64 - reader.position().min(reader.get_ref().len() as u64);
+        if count < 0 || count as u64 * item_size > remaining {
+            return Err(Self::corrupt(what));
+        }
+        Ok(count as usize)
+    }
+
+    fn read_string(reader: &mut Cursor<&[u8]>, what: &str) -> io::Result<String> {
+        let len = Self::read_count(reader, 1, what)?;
+        let mut bytes = vec![0u8; len];
+        reader.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| Self::corrupt(what))
+    }
+
+    /// Parses an index written by the given format version, so migrations can read old layouts.
+    fn deserialize_index(&self, data: &[u8], format_version: u16) -> io::Result<BTreeMap<Uuid, Document>> {
+        let mut index = BTreeMap::new();
+        let mut reader = Cursor::new(data);
+        let entry_size = match format_version {
+            0..=4 => 40,
+            5 => 48,
+            6 => 52,
+            7 => 68,
+            8..=16 => 72,
+            _ => 76,
+        };
+        let count = Self::read_count(&mut reader, entry_size, "document index")?;
+        for _ in 0..count {
+            let doc = Self::read_index_entry(&mut reader, format_version)?;
+            index.insert(doc.id, doc);
+        }
+        Ok(index)
+    }
+
+    fn read_index_entry(reader: &mut Cursor<&[u8]>, format_version: u16) -> io::Result<Document> {
+        let mut id_bytes = [0u8; 16];
+        reader.read_exact(&mut id_bytes)?;
+        let id = Uuid::from_bytes(id_bytes);
+        let first_page_id = reader.read_i64::<LittleEndian>()?;
+        let current_version = reader.read_i32::<LittleEndian>()?;
+        let checksum = reader.read_u32::<LittleEndian>()?;
+        let path_count = Self::read_count(reader, 13, "document index")?;
+        let mut paths = Vec::with_capacity(path_count);
+        for _ in 0..path_count {
+            let path = Self::read_string(reader, "document index path")?;
+            let addon = reader.read_u8()? != 0;
+            let priority = reader.read_i32::<LittleEndian>()?;
+            let lang = Self::read_string(reader, "document index language")?;
+            paths.push(PathBinding { path, addon, priority, lang });
+        }
+        let version_count = Self::read_count(reader, 12, "document index")?;
+        let mut previous_versions = Vec::with_capacity(version_count);
+        for _ in 0..version_count {
+            previous_versions.push(VersionedLink {
+                page_id: reader.read_i64::<LittleEndian>()?,
+                version: reader.read_i32::<LittleEndian>()?,
+            });
+        }
+        let expires_at = if format_version >= 5 { reader.read_u64::<LittleEndian>()? } else { 0 };
+        let mut tags = BTreeSet::new();
+        if format_version >= 6 {
+            let tag_count = Self::read_count(reader, 4, "document index")?;
+            for _ in 0..tag_count {
+                tags.insert(Self::read_string(reader, "document index tag")?);
+            }
+        }
+        let (size, modified) = if format_version >= 7 {
+            (reader.read_u64::<LittleEndian>()?, reader.read_u64::<LittleEndian>()?)
+        } else {
+            (0, 0)
+        };
+        let flags = if format_version >= 8 { reader.read_u32::<LittleEndian>()? } else { 0 };
+        let page_count = if format_version >= 17 { reader.read_u32::<LittleEndian>()? } else { 0 };
+        Ok(Document { id, first_page_id, current_version, checksum, paths, previous_versions, expires_at, tags, size, modified, flags, page_count })
+    }
+
+    fn serialize_trie_node(&self, node: &ReverseTrieNode) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut writer = BufWriter::new(&mut buffer);
+        let edge_bytes = node.edge.as_bytes();
+        writer.write_i32::<LittleEndian>(edge_bytes.len() as i32)?;
+        writer.write_all(edge_bytes)?;
+        writer.write_i64::<LittleEndian>(node.parent_page_id)?;
+        writer.write_i64::<LittleEndian>(node.self_page_id)?;
+        writer.write_i32::<LittleEndian>(if node.document_id.is_some() { 1 } else { 0 })?;
+        if let Some(id) = node.document_id {
+            writer.write_all(id.as_bytes())?;
+        }
+        writer.write_i32::<LittleEndian>(node.children.len() as i32)?;
+        for (ch, child_id) in &node.children {
+            writer.write_u8(*ch as u8)?;
+            writer.write_i64::<LittleEndian>(*child_id)?;
+        }
+        Ok(buffer)
+    }
+
+    fn deserialize_trie_node(&self, data: &[u8]) -> io::Result<ReverseTrieNode> {
+        let mut reader = Cursor::new(data);
+        let edge = Self::read_string(&mut reader, "trie edge")?;
+        let parent_page_id = reader.read_i64::<LittleEndian>()?;
+        let self_page_id = reader.read_i64::<LittleEndian>()?;
+        let has_doc = reader.read_i32::<LittleEndian>()?;
+        let document_id = if has_doc != 0 {
+            let mut id_bytes = [0u8; 16];
+            reader.read_exact(&mut id_bytes)?;
+            Some(Uuid::from_bytes(id_bytes))
+        } else {
+            None
+        };
+        let child_count = Self::read_count(&mut reader, 9, "trie node")?;
+        let mut children = BTreeMap::new();
+        for _ in 0..child_count {
+            let ch = reader.read_u8()? as char;
+            let child_id = reader.read_i64::<LittleEndian>()?;
+            children.insert(ch, child_id);
+        }
+        Ok(ReverseTrieNode { edge, parent_page_id, self_page_id, document_id, children })
+    }
+
+    fn compute_crc(&self, data: &[u8]) -> u32 {
+        CRC32.checksum(data)
+    }
+
+    /// MD4 of the first 32 header bytes, folded to 32 bits by XORing its four little-endian words
+    /// so the value is the same on every host.
+    fn get_checksum(&self) -> u32 {
+        let mut hasher = Md4::new();
+        let mut header = vec![0u8; 32];
+        self.read_bytes_at(0, &mut header).unwrap_or(());
+        hasher.update(&header);
+        hasher.finalize()
+            .chunks_exact(4)
+            .fold(0u32, |folded, word| folded ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+    }
+
+    fn write_document(&mut self, path: &CxxString, data: &CxxVector<u8>) -> io::Result<Uuid> {
+        self.write_document_unordered(path.to_string_lossy().as_ref(), data.as_slice(), true, true, false)
+    }
+
+    fn write_document_ex(self: Pin<&mut Self>, path: &CxxString, data: &CxxVector<u8>, overwrite: bool) -> io::Result<Uuid> {
+        self.write_document_unordered(path.to_string_lossy().as_ref(), data.as_slice(), overwrite, true, false)
+    }
+
+    /// Like write_document_ex; with dedup off the document always gets its own copy of the pages
+    /// even if identical contents are already stored.
+    fn write_document_with_dedup(self: Pin<&mut Self>, path: &CxxString, data: &CxxVector<u8>, overwrite: bool, dedup: bool) -> io::Result<Uuid> {
+        self.write_document_unordered(path.to_string_lossy().as_ref(), data.as_slice(), overwrite, dedup, false)
+    }
+
+    /// Overwrites path even if its document is READONLY; the flags carry over to the new version.
+    fn write_document_forced(self: Pin<&mut Self>, path: &CxxString, data: &CxxVector<u8>) -> io::Result<Uuid> {
+        self.write_document_unordered(path.to_string_lossy().as_ref(), data.as_slice(), true, true, true)
+    }
+
+    /// store_document for the write_document calls. The chain is compressed and written before
+    /// the gate is taken, so writers to unrelated paths overlap their page IO and queue only for
+    /// the index commit. Contents the dedup table already holds are left for the commit to share.
+    fn write_document_unordered(&self, path: &str, data: &[u8], overwrite: bool, dedup: bool, force: bool) -> io::Result<Uuid> {
+        let _layout = self.begin_layout_write()?;
+        let rust_path = self.validate_path(path)?;
+        let _timer = self.latency.time(TimedOp::Write, OpDetail::Path(&rust_path));
+        let _span = trace_span!("write_document", path = rust_path.as_str(), bytes = data.len());
+        let mut contents = DocumentContents::hash(data, dedup);
+        let known = contents.hash.is_some_and(|hash| self.dedup_table.read().contains_key(&hash));
+        if !known {
+            contents.written = Some(self.write_document_chain(&rust_path, data, self.config.compression_level)?);
+        }
+        let _writes = self.begin_write()?;
+        self.commit_document(&rust_path, data, contents, overwrite, force)
+    }
+
+    /// Writes data under path. An existing document at path is updated in place: it keeps its
+    /// uuid, gets a new chain and version, and older chains are retained up to versions_to_keep.
+    /// With dedup, contents already stored by another document share that document's chain.
+    fn store_document(&self, path: &str, data: &[u8], overwrite: bool, dedup: bool, force: bool) -> io::Result<Uuid> {
+        let _timer = self.latency.time(TimedOp::Write, OpDetail::Path(path));
+        let _span = trace_span!("write_document", path = path, bytes = data.len());
+        self.commit_document(path, data, DocumentContents::hash(data, dedup), overwrite, force)
+    }
+
+    /// The existing document at path, if any, checked for being overwritable, and the index to change.
+    fn overwrite_target(&self, path: &str, overwrite: bool, force: bool) -> io::Result<(Option<Uuid>, BTreeMap<Uuid, Document>)> {
+        let existing = match self.get_document_id_by_path(path) {
+            Ok(id) => Some(id),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if existing.is_some() && !overwrite {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Path already exists"));
+        }
+        let index = self.read_index()?;
+        if let Some(doc) = existing.and_then(|id| index.get(&id)) {
+            doc.check_writable(force)?;
+        }
+        Ok((existing, index))
+    }
+
+    /// The part of a document write made under the gate. A chain already written for contents
+    /// that turns out to be unneeded, because the write is refused or the same contents were
+    /// stored meanwhile, is freed.
+    fn commit_document(&self, path: &str, data: &[u8], contents: DocumentContents, overwrite: bool, force: bool) -> io::Result<Uuid> {
+        let (existing, mut index) = match self.overwrite_target(path, overwrite, force) {
+            Ok(target) => target,
+            Err(e) => {
+                if let Some(first_page_id) = contents.written {
+                    self.free_chain(first_page_id)?;
+                }
+                return Err(e);
+            }
+        };
+        let content_hash = contents.hash;
+        let shared = content_hash.and_then(|hash| self.dedup_table.read().get(&hash).copied())
+            .filter(|&first_page_id| Self::chain_referenced(&index, first_page_id));
+        let first_page_id = match (shared, contents.written) {
+            (Some(first_page_id), written) => {
+                if let Some(unused) = written {
+                    self.free_chain(unused)?;
+                }
+                first_page_id
+            }
+            (None, Some(first_page_id)) => first_page_id,
+            (None, None) => self.write_document_chain(path, data, self.config.compression_level)?,
+        };
+        let checksum = self.compute_crc(data);
+        let mut stale_chains = Vec::new();
+        let (id, created) = self.apply_document(&mut index, existing, path, first_page_id, checksum, data.len() as u64, &mut stale_chains)?;
+        let new_paths = if created { vec![(path.to_string(), id)] } else { Vec::new() };
+        self.commit_index(&index, false, &new_paths)?;
+        if let (Some(hash), None) = (content_hash, shared) {
+            self.dedup_table.write().insert(hash, first_page_id);
+            self.write_dedup_table()?;
+        }
+        for page_id in stale_chains {
+            self.release_chain(&index, page_id)?;
+        }
+        self.path_cache.lock().put(path.to_string(), id);
+        if Self::dictionary_id(path).is_some() {
+            self.load_dictionaries()?;
+        }
+        self.emit_event(ffi::DocumentEventOp::Write, path, id);
+        Ok(id)
+    }
+
+    /// Points path at a freshly written chain in index, either as a new version of the existing
+    /// document or as a new document. Nothing is visible until index is written. Returns the
+    /// document and whether it is new, in which case the caller binds path in the trie as part
+    /// of writing the index.
+    fn apply_document(&self, index: &mut BTreeMap<Uuid, Document>, existing: Option<Uuid>, path: &str, first_page_id: i64, checksum: u32, size: u64, stale_chains: &mut Vec<i64>) -> io::Result<(Uuid, bool)> {
+        match existing.and_then(|id| index.get_mut(&id)) {
+            Some(doc) => {
+                doc.previous_versions.push(VersionedLink { page_id: doc.first_page_id, version: doc.current_version });
+                let keep = (self.config.versions_to_keep - 1).max(0) as usize;
+                while doc.previous_versions.len() > keep {
+                    stale_chains.push(doc.previous_versions.remove(0).page_id);
+                }
+                doc.first_page_id = first_page_id;
+                doc.current_version += 1;
+                doc.checksum = checksum;
+                doc.expires_at = 0; // new contents start without an expiry
+                doc.size = size;
+                doc.page_count = self.chain_page_count(first_page_id)?;
+                doc.modified = Self::unix_now();
+                Ok((doc.id, false))
+            }
+            None => {
+                let id = Uuid::new_v4();
+                index.insert(id, Document {
+                    id,
+                    first_page_id,
+                    current_version: 0,
+                    checksum,
+                    paths: vec![PathBinding { path: path.to_string(), addon: false, priority: 0, lang: String::new() }],
+                    previous_versions: Vec::new(),
+                    expires_at: 0,
+                    tags: BTreeSet::new(),
+                    size,
+                    modified: Self::unix_now(),
+                    flags: 0,
+                    page_count: self.chain_page_count(first_page_id)?,
+                });
+                Ok((id, true))
+            }
+        }
+    }
+
+    fn write_chain(&self, data: &[u8], codec: u8) -> io::Result<i64> {
+        self.write_chain_at(data, codec, self.config.compression_level, 0)
+    }
+
+    /// Writes document contents with the codec for path. Small zstd documents use the newest dictionary.
+    /// Documents under slab_threshold become slab records instead, stored uncompressed.
+    fn write_document_chain(&self, path: &str, data: &[u8], level: i32) -> io::Result<i64> {
+        if !data.is_empty() && (data.len() as u64) < self.config.slab_threshold && Self::dictionary_id(path).is_none() {
+            if let Some(address) = self.insert_slab_record(SlabKind::Document, data)? {
+                return Ok(address);
+            }
+        }
+        let codec = self.codec_for_path(path);
+        let dictionary = if codec == CODEC_ZSTD && (data.len() as u64) < self.config.dictionary_threshold {
+            self.dictionaries.read().keys().next_back().copied().unwrap_or(0)
+        } else {
+            0
+        };
+        self.write_chain_at(data, codec, level, dictionary)
+    }
+
+    /// Writes data as a chain of fresh pages and returns its first page, -1 for no data. Each
+    /// page is allocated once, as the previous page's next link, so the links name exactly the
+    /// pages written. If a write fails, every page allocated for the chain goes back to the free list.
+    fn write_chain_at(&self, data: &[u8], codec: u8, level: i32, dictionary: u8) -> io::Result<i64> {
+        let mut allocated = Vec::new();
+        let result = self.write_chain_pages(data, codec, level, dictionary, &mut allocated);
+        if result.is_err() {
+            // Nothing links to a partial chain, so it would otherwise be lost until a repair
+            for page_id in allocated {
+                self.free_page(page_id).unwrap_or(());
+            }
+        }
+        result
+    }
+
+    fn write_chain_pages(&self, data: &[u8], codec: u8, level: i32, dictionary: u8, allocated: &mut Vec<i64>) -> io::Result<i64> {
+        if data.is_empty() {
+            return Ok(-1);
+        }
+        let first_page_id = self.allocate_page()?;
+        allocated.push(first_page_id);
+        let mut page_id = first_page_id;
+        let mut prev_page_id = -1;
+        let mut data_remaining = data;
+        while !data_remaining.is_empty() {
+            let chunk_size = std::cmp::min(data_remaining.len(), (self.config.page_size - self.config.page_header_size) as usize);
+            let chunk = &data_remaining[..chunk_size];
+            data_remaining = &data_remaining[chunk_size..];
+            let next_page_id = if data_remaining.is_empty() { -1 } else { self.allocate_page()? };
+            if next_page_id != -1 {
+                allocated.push(next_page_id);
+            }
+            let links = PageLinks { flags: FLAG_DATA_PAGE, prev_page_id, next_page_id };
+            self.write_raw_page_as(page_id, chunk, self.next_page_version(page_id), links, codec, level, dictionary)?;
+            prev_page_id = page_id;
+            page_id = next_page_id;
+        }
+        Ok(first_page_id)
+    }
+
+    fn free_chain(&self, first_page_id: i64) -> io::Result<()> {
+        if let Some(pin) = self.chain_pins.lock().get_mut(&first_page_id) {
+            pin.pending_free = true;
+            return Ok(());
+        }
+        let wipe = self.wipe_chains.lock().remove(&first_page_id);
+        if let Some((page_id, slot)) = Self::slab_record(first_page_id) {
+            return self.free_slab_record(SlabKind::Document, page_id, slot, wipe);
+        }
+        let mut current_page_id = first_page_id;
+        while current_page_id != -1 {
+            let header = self.read_page_header(current_page_id)?;
+            self.free_page_as(current_page_id, wipe)?;
+            current_page_id = header.next_page_id;
+        }
+        Ok(())
+    }
+
+    /// The document for a lookup. With hide_expired set, documents past their expiry read as
+    /// missing even before purge_expired removes them.
+    fn visible_document<'a>(&self, index: &'a BTreeMap<Uuid, Document>, id: Uuid) -> io::Result<&'a Document> {
+        index.get(&id)
+            .filter(|doc| !self.config.hide_expired || !doc.is_expired(Self::unix_now()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))
+    }
+
+    fn unix_now() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+    }
+
+    /// Whether any document in index still uses the chain, as its current or a retained version.
+    fn chain_referenced(index: &BTreeMap<Uuid, Document>, first_page_id: i64) -> bool {
+        first_page_id != -1 && index.values().any(|doc| doc.first_page_id == first_page_id
+            || doc.previous_versions.iter().any(|link| link.page_id == first_page_id))
+    }
+
+    /// Frees a chain the updated index no longer references. Chains shared through dedup are
+    /// kept until their last reference goes, and then leave the dedup table too.
+    fn release_chain(&self, index: &BTreeMap<Uuid, Document>, first_page_id: i64) -> io::Result<()> {
+        if Self::chain_referenced(index, first_page_id) {
+            return Ok(());
+        }
+        let removed = {
+            let mut table = self.dedup_table.write();
+            let before = table.len();
+            table.retain(|_, chain| *chain != first_page_id);
+            table.len() != before
+        };
+        if removed {
+            self.write_dedup_table()?;
+        }
+        self.free_chain(first_page_id)
+    }
+
+    /// Gives a document a private copy of its chain if that chain is shared or could become
+    /// shared through the dedup table, so it can be modified in place (append mode).
+    /// Slab records always get a chain of their own.
+    fn unshare_chain(&self, id: Uuid) -> io::Result<()> {
+        let mut index = self.read_index()?;
+        let first_page_id = index.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?.first_page_id;
+        let shared = index.values().filter(|doc| doc.first_page_id == first_page_id).count() > 1;
+        let slab = Self::slab_record(first_page_id).is_some();
+        if first_page_id == -1 || (!slab && !shared && !self.dedup_table.read().values().any(|&chain| chain == first_page_id)) {
+            return Ok(());
+        }
+        let codec = index[&id].paths.first().map_or(self.default_codec(), |binding| self.codec_for_path(&binding.path));
+        let copy = self.write_chain(&self.read_chain(first_page_id)?, codec)?;
+        let doc = index.get_mut(&id).unwrap();
+        doc.first_page_id = copy;
+        doc.page_count = self.chain_page_count(copy)?;
+        self.write_index(&index)?;
+        self.release_chain(&index, first_page_id)
+    }
+
+    /// The slab page and slot an index address refers to, if it is a slab record rather than
+    /// a chain head. Page ids never reach SLAB_SLOT_SHIFT bits, so chain heads read as None.
+    fn slab_record(address: i64) -> Option<(i64, usize)> {
+        match address >> SLAB_SLOT_SHIFT {
+            slot if address > 0 && slot > 0 => Some((address & ((1 << SLAB_SLOT_SHIFT) - 1), slot as usize - 1)),
+            _ => None,
+        }
+    }
+
+    fn slab_address(page_id: i64, slot: usize) -> i64 {
+        page_id | ((slot as i64 + 1) << SLAB_SLOT_SHIFT)
+    }
+
+    fn read_slab_page(&self, page_id: i64, kind: SlabKind) -> io::Result<SlabPage> {
+        Self::check_page_type(page_id, &self.read_page_header(page_id)?, kind.flags())?;
+        SlabPage::from_payload(self.read_raw_page_as(page_id, if kind == SlabKind::Document { PageCaching::Fill } else { PageCaching::Own })?)
+    }
+
+    fn write_slab_page(&self, page_id: i64, slab: &SlabPage, kind: SlabKind) -> io::Result<()> {
+        self.write_raw_page_as(page_id, &slab.data, self.next_page_version(page_id), PageLinks::single(kind.flags()), CODEC_NONE, 0, 0).map(|_| ())
+    }
+
+    fn open_slab_of(&self, kind: SlabKind) -> &PMutex<i64> {
+        match kind {
+            SlabKind::Document => &self.open_slab,
+            SlabKind::Trie => &self.open_trie_slab,
+        }
+    }
+
+    /// Stores data as a record in the open slab page of its kind, starting a new page when it
+    /// is full. Returns the record's address, or None if data is too large for any slab page.
+    fn insert_slab_record(&self, kind: SlabKind, data: &[u8]) -> io::Result<Option<i64>> {
+        let capacity = (self.config.page_size - self.config.page_header_size) as usize;
+        // Offsets and lengths are u16, which also bounds the page sizes slabs work with
+        if data.len() + 6 > capacity || capacity > SLAB_FREE_SLOT as usize {
+            return Ok(None);
+        }
+        let mut open_slab = self.open_slab_of(kind).lock();
+        if *open_slab != -1 {
+            let mut slab = self.read_slab_page(*open_slab, kind)?;
+            if let Some(slot) = slab.insert(data) {
+                self.write_slab_page(*open_slab, &slab, kind)?;
+                return Ok(Some(Self::slab_address(*open_slab, slot)));
+            }
+        }
+        let page_id = self.allocate_page()?;
+        let mut slab = SlabPage::new(capacity);
+        let slot = slab.insert(data).unwrap();
+        self.write_slab_page(page_id, &slab, kind)?;
+        *open_slab = page_id;
+        Ok(Some(Self::slab_address(page_id, slot)))
+    }
+
+    /// Frees a record, and its page along with the last one. With wipe or secure_delete the
+    /// record's bytes are zeroed, as the page is not rewritten until vacuum repacks it.
+    fn free_slab_record(&self, kind: SlabKind, page_id: i64, slot: usize, wipe: bool) -> io::Result<()> {
+        let mut open_slab = self.open_slab_of(kind).lock();
+        let mut slab = self.read_slab_page(page_id, kind)?;
+        if wipe || self.config.secure_delete {
+            if let Some((offset, length)) = slab.slot(slot) {
+                slab.data[offset..offset + length].fill(0);
+            }
+        }
+        if !slab.remove(slot) {
+            return Err(Self::corrupt("slab slot"));
+        }
+        if !slab.is_empty() {
+            return self.write_slab_page(page_id, &slab, kind);
+        }
+        if *open_slab == page_id {
+            *open_slab = -1;
+        }
+        self.free_page_as(page_id, wipe)
+    }
+
+    /// Repacks slab pages with reclaimable space: their records move into fresh slab pages,
+    /// the index and dedup table follow, and the old pages are freed. Pages holding a record
+    /// that a stream has pinned or an open transaction has staged are left alone.
+    fn vacuum(self: Pin<&mut Self>) -> io::Result<ffi::VacuumReport> {
+        let _writes = self.begin_exclusive_write()?;
+        let (mut report, _) = self.vacuum_slabs(0, &mut WorkBudget::unlimited())?;
+        report.pages_truncated = self.truncate_free_tail()?;
+        Ok(report)
+    }
+
+    /// Slab pages holding the index's records, with the addresses of the records on each.
+    fn document_slabs(index: &BTreeMap<Uuid, Document>) -> BTreeMap<i64, BTreeSet<i64>> {
+        let mut slabs: BTreeMap<i64, BTreeSet<i64>> = BTreeMap::new();
+        for doc in index.values() {
+            for address in std::iter::once(doc.first_page_id).chain(doc.previous_versions.iter().map(|link| link.page_id)) {
+                if let Some((page_id, _)) = Self::slab_record(address) {
+                    slabs.entry(page_id).or_default().insert(address);
+                }
+            }
+        }
+        slabs
+    }
+
+    /// vacuum without the truncation, over the slab pages from page from on and for as long
+    /// as budget lasts. Also returns the page to resume from if the budget ran out first.
+    fn vacuum_slabs(&self, from: i64, budget: &mut WorkBudget) -> io::Result<(ffi::VacuumReport, Option<i64>)> {
+        let mut index = self.read_index()?;
+        let slabs = Self::document_slabs(&index);
+        let mut busy: HashSet<i64> = self.chain_pins.lock().keys().copied().collect();
+        busy.extend(self.transactions.lock().values().flat_map(|tx| tx.documents.iter().map(|doc| doc.first_page_id)));
+        let busy_pages: HashSet<i64> = busy.into_iter().filter_map(Self::slab_record).map(|(page_id, _)| page_id).collect();
+        // Moved records go to fresh pages, never onto a page about to be freed
+        *self.open_slab.lock() = -1;
+        let mut moved = HashMap::new();
+        let mut emptied = Vec::new();
+        let mut resume_from = None;
+        for (&page_id, addresses) in slabs.range(from..) {
+            if budget.spent() {
+                resume_from = Some(page_id);
+                break;
+            }
+            if busy_pages.contains(&page_id) {
+                continue;
+            }
+            let slab = self.read_slab_page(page_id, SlabKind::Document)?;
+            budget.charge();
+            if slab.reclaimable() == 0 {
+                continue;
+            }
+            for &address in addresses {
+                let (_, slot) = Self::slab_record(address).unwrap();
+                let record = slab.record(slot).ok_or_else(|| Self::corrupt("slab slot"))?;
+                let new_address = self.insert_slab_record(SlabKind::Document, record)?.unwrap();
+                moved.insert(address, new_address);
+            }
+            emptied.push(page_id);
+        }
+        if emptied.is_empty() {
+            return Ok((ffi::VacuumReport { records_moved: 0, pages_freed: 0, pages_truncated: 0 }, resume_from));
+        }
+        let remap = |address: &mut i64| {
+            if let Some(&new_address) = moved.get(&*address) {
+                *address = new_address;
+            }
+        };
+        for doc in index.values_mut() {
+            remap(&mut doc.first_page_id);
+            doc.previous_versions.iter_mut().for_each(|link| remap(&mut link.page_id));
+        }
+        self.write_index(&index)?;
+        let dedup_changed = {
+            let mut table = self.dedup_table.write();
+            let mut changed = false;
+            for chain in table.values_mut() {
+                if moved.contains_key(&*chain) {
+                    remap(chain);
+                    changed = true;
+                }
+            }
+            changed
+        };
+        if dedup_changed {
+            self.write_dedup_table()?;
+        }
+        for &page_id in &emptied {
+            self.free_page(page_id)?;
+        }
+        let new_pages: HashSet<i64> = moved.values().filter_map(|&address| Self::slab_record(address)).map(|(page_id, _)| page_id).collect();
+        let report = ffi::VacuumReport {
+            records_moved: moved.len() as u64,
+            pages_freed: emptied.len().saturating_sub(new_pages.len()) as u64,
+            pages_truncated: 0,
+        };
+        Ok((report, resume_from))
+    }
+
+    /// Every page on the free list, the list's own pages included.
+    fn free_list_pages(&self, _allocation: &Ordered<parking_lot::MutexGuard<'_, i64>>) -> io::Result<Vec<i64>> {
+        let mut pages = Vec::new();
+        let mut page_id = self.free_list_root.read().page_id;
+        while page_id != -1 {
+            let (next_free_list_page, used_entries) = self.read_free_list_header(page_id)?;
+            if used_entries < 0 || used_entries as usize > FREE_LIST_ENTRIES_PER_PAGE {
+                return Err(Self::corrupt("free list"));
+            }
+            let mut entries = vec![0u8; used_entries as usize * 8];
+            self.read_bytes_at(self.payload_offset(page_id)? + FREE_LIST_HEADER_SIZE, &mut entries)?;
+            pages.extend(entries.chunks_exact(8).map(|entry| i64::from_le_bytes(entry.try_into().unwrap())));
+            pages.push(page_id);
+            page_id = next_free_list_page;
+        }
+        Ok(pages)
+    }
+
+    /// Replaces the free list with one holding pages, which must be sorted.
+    fn rebuild_free_list(&self, _allocation: &Ordered<parking_lot::MutexGuard<'_, i64>>, pages: &[i64]) -> io::Result<()> {
+        let mut free_root = self.free_list_root.write();
+        let mut next_free_list_page = -1;
+        for chunk in pages.chunks(FREE_LIST_ENTRIES_PER_PAGE + 1).rev() {
+            self.write_free_list_page(chunk[0], next_free_list_page, &chunk[1..])?;
+            next_free_list_page = chunk[0];
+        }
+        free_root.page_id = next_free_list_page;
+        Ok(())
+    }
+
+    /// Cuts the run of free pages at the end of the file off: they leave the free list, which
+    /// is rewritten from the rest, and the file shrinks. Returns the number of pages cut.
+    /// Chains that are pinned by a snapshot or an open stream only reach the free list once
+    /// released, so nothing still readable is ever cut. set_storage_len keeps the mapping over
+    /// what is left, and nothing reads past page_count().
+    fn truncate_free_tail(&self) -> io::Result<u64> {
+        let allocation = self.lock_allocation();
+        let mut current_size = self.current_size.lock();
+        let page_count = (*current_size / self.config.page_size) as i64;
+        let mut free: Vec<i64> = self.free_list_pages(&allocation)?.into_iter().filter(|&page_id| page_id < page_count).collect();
+        free.sort_unstable();
+        free.dedup();
+        // Page 0 stays: the database header lives at the start of the file
+        let mut new_count = page_count;
+        while new_count > FIRST_PAGE_ID && free.last() == Some(&(new_count - 1)) {
+            free.pop();
+            new_count -= 1;
+        }
+        if new_count == page_count {
+            return Ok(0);
+        }
+        self.rebuild_free_list(&allocation, &free)?;
+        // The shorter list is published before the pages it no longer names disappear
+        self.write_roots()?;
+        self.write_barrier()?;
+        let new_size = new_count as u64 * self.config.page_size;
+        self.set_storage_len(new_size)?;
+        *current_size = new_size;
+        for page_id in new_count..page_count {
+            self.invalidate_page(page_id);
+        }
+        self.dirty_pages.lock().retain(|&page_id| page_id < new_count);
+        Ok((page_count - new_count) as u64)
+    }
+
+    /// Starts the maintenance thread when the options ask for one. The database must be at
+    /// its final address, inside the UniquePtr handed to the caller.
+    fn start_maintenance(&self) -> io::Result<()> {
+        if self.config.maintenance_interval_ms == 0 || self.config.read_only {
+            return Ok(());
+        }
+        let db = MaintainedDb(self);
+        let thread = std::thread::Builder::new()
+            .name("streamdb-maintenance".to_string())
+            .spawn(move || db.run())?;
+        *self.maintenance.thread.lock() = Some(thread);
+        Ok(())
+    }
+
+    fn stop_maintenance(&self) {
+        *self.maintenance.stopping.lock() = true;
+        self.maintenance.wake.notify_all();
+        let thread = self.maintenance.thread.lock().take();
+        if let Some(thread) = thread {
+            thread.join().unwrap_or(());
+        }
+    }
+
+    /// Starts copying committed changes to the mirror, if there is one. The database must be at
+    /// its final address, inside the UniquePtr handed to the caller.
+    fn start_mirror(&self) -> io::Result<()> {
+        let mirror = match &self.mirror {
+            Some(mirror) => mirror,
+            None => return Ok(()),
+        };
+        let (sender, receiver) = std::sync::mpsc::sync_channel(MIRROR_QUEUE_CAPACITY);
+        *mirror.sender.lock() = Some(sender);
+        // The mirror may be new or stale, so the first idle check brings it up to date
+        mirror.overflowed.store(true, std::sync::atomic::Ordering::SeqCst);
+        let db = MirroredDb(self);
+        let thread = std::thread::Builder::new()
+            .name("streamdb-mirror".to_string())
+            .spawn(move || db.run(receiver))?;
+        *mirror.thread.lock() = Some(thread);
+        Ok(())
+    }
+
+    /// Lets the mirror thread copy what is already queued, then stops it.
+    fn stop_mirror(&self) {
+        if let Some(mirror) = &self.mirror {
+            mirror.sender.lock().take();
+            let thread = mirror.thread.lock().take();
+            if let Some(thread) = thread {
+                thread.join().unwrap_or(());
+            }
+        }
+    }
+
+    /// Body of the mirror thread: copies each changed path, and when idle resyncs after an
+    /// overflow or compares content hashes to detect divergence.
+    fn mirror_loop(&self, receiver: std::sync::mpsc::Receiver<String>) {
+        let mirror = match &self.mirror {
+            Some(mirror) => mirror,
+            None => return,
+        };
+        let interval = std::time::Duration::from_millis(MIRROR_CHECK_MS);
+        loop {
+            match receiver.recv_timeout(interval) {
+                Ok(path) => {
+                    if self.mirror_path_change(&mirror.db, &path).is_err() {
+                        mirror.diverged.store(true, std::sync::atomic::Ordering::SeqCst);
+                    }
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                    if mirror.overflowed.load(std::sync::atomic::Ordering::SeqCst) {
+                        self.resync_mirror().unwrap_or(0);
+                        continue;
+                    }
+                    // Failures count as divergence, since the mirror cannot be shown to match
+                    let matches = self.content_hash().ok().is_some_and(|hash| mirror.db.content_hash().is_ok_and(|mirrored| mirrored == hash));
+                    mirror.diverged.store(!matches, std::sync::atomic::Ordering::SeqCst);
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Makes path in mirror match path here: the same contents, or absent.
+    fn mirror_path_change(&self, mirror: &StreamDb, path: &str) -> io::Result<()> {
+        match self.read_document_as(path, PageCaching::Peek) {
+            Ok(data) => mirror.write_document_unordered(path, &data, true, true, true).map(|_| ()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let _writes = mirror.begin_write()?;
+                match mirror.commit_changes(vec![StagedChange::Delete { path: path.to_string(), force: true, secure: false }]) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Copies every path whose contents differ from the primary to the mirror and removes the
+    /// ones the primary no longer has. Returns the number of paths changed in the mirror.
+    fn resync_mirror(&self) -> io::Result<u64> {
+        self.ensure_open()?;
+        let mirror = self.mirror.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No mirror configured"))?;
+        // Changes committed from here on are queued again, so none is lost to the clear
+        mirror.overflowed.store(false, std::sync::atomic::Ordering::SeqCst);
+        let diff = Self::diff_manifests(mirror.db.export_manifest(false)?.entries, self.export_manifest(false)?.entries);
+        let mut changed = 0u64;
+        for change in diff.added.iter().chain(&diff.modified).chain(&diff.removed) {
+            if let Err(e) = self.mirror_path_change(&mirror.db, &change.path) {
+                mirror.diverged.store(true, std::sync::atomic::Ordering::SeqCst);
+                return Err(e);
+            }
+            changed += 1;
+        }
+        mirror.diverged.store(false, std::sync::atomic::Ordering::SeqCst);
+        Ok(changed)
+    }
+
+    fn get_mirror_status(&self) -> ffi::MirrorStatus {
+        ffi::MirrorStatus {
+            mirrored: self.mirror.as_ref().is_some_and(|mirror| mirror.thread.lock().is_some()),
+            diverged: self.mirror.as_ref().is_some_and(|mirror| mirror.diverged.load(std::sync::atomic::Ordering::SeqCst)),
+            overflowed: self.mirror.as_ref().is_some_and(|mirror| mirror.overflowed.load(std::sync::atomic::Ordering::SeqCst)),
+            serving_mirror: self.serving_mirror,
+        }
+    }
+
+    /// Body of the maintenance thread: a slice of work per interval unless paused or stopping.
+    fn maintenance_loop(&self) {
+        let interval = std::time::Duration::from_millis(self.config.maintenance_interval_ms);
+        loop {
+            {
+                let mut stopping = self.maintenance.stopping.lock();
+                if !*stopping {
+                    self.maintenance.wake.wait_for(&mut stopping, interval);
+                }
+                if *stopping {
+                    return;
+                }
+            }
+            let _layout = self.lock_stats.acquire(TrackedLock::Layout, || self.maintenance.layout.try_write(), || self.maintenance.layout.write());
+            let _writes = self.lock_gate();
+            // Checked under the gate, so pause_maintenance returning means no slice is running
+            if self.maintenance.paused.load(std::sync::atomic::Ordering::SeqCst) {
+                continue;
+            }
+            // Failures are left for foreground calls to report; the next wake starts a new pass
+            self.maintenance_slice(&mut WorkBudget::pages(MAINTENANCE_SLICE_PAGES)).unwrap_or_default();
+        }
+    }
+
+    /// Runs maintenance from the engine's frame loop, for ports that cannot spawn threads: the
+    /// same passes as the maintenance thread, stopped between pages once max_millis have passed
+    /// and resumed where they stopped on the next call. Does nothing while maintenance is paused.
+    fn run_maintenance_slice(self: Pin<&mut Self>, max_millis: u64) -> io::Result<ffi::MaintenanceReport> {
+        let _writes = self.begin_exclusive_write()?;
+        if self.maintenance.paused.load(std::sync::atomic::Ordering::SeqCst) {
+            return Ok(ffi::MaintenanceReport::default());
+        }
+        self.maintenance_slice(&mut WorkBudget::millis(max_millis))
+    }
+
+    /// Advances the current maintenance pass until budget is spent or the pass completes.
+    /// The caller holds the gate.
+    fn maintenance_slice(&self, budget: &mut WorkBudget) -> io::Result<ffi::MaintenanceReport> {
+        self.ensure_open()?;
+        self.expire_cursors();
+        let mut progress = self.maintenance.progress.lock();
+        let mut report = ffi::MaintenanceReport::default();
+        while !budget.spent() && !report.pass_completed {
+            if let Err(e) = self.maintenance_step(&mut progress, budget, &mut report) {
+                *progress = MaintenanceProgress::default();
+                return Err(e);
+            }
+        }
+        Ok(report)
+    }
+
+    fn maintenance_step(&self, progress: &mut MaintenanceProgress, budget: &mut WorkBudget, report: &mut ffi::MaintenanceReport) -> io::Result<()> {
+        match progress.step {
+            MaintenanceStep::Purge => {
+                let purged = self.purge_expired_up_to(Self::unix_now(), MAINTENANCE_PURGE_BATCH)?.documents;
+                budget.charge();
+                report.documents_purged += purged;
+                if purged < MAINTENANCE_PURGE_BATCH as u64 {
+                    progress.slab_pages = Self::document_slabs(&self.read_index()?).into_keys().collect();
+                    progress.step = MaintenanceStep::Measure;
+                }
+            }
+            MaintenanceStep::Measure => match progress.slab_pages.get(progress.scanned) {
+                Some(&page_id) => {
+                    // Foreground writes may have freed the page since the list was taken
+                    if let Ok(slab) = self.read_slab_page(page_id, SlabKind::Document) {
+                        progress.reclaimable += slab.reclaimable() as u64;
+                    }
+                    budget.charge();
+                    progress.scanned += 1;
+                    report.slab_pages_scanned += 1;
+                }
+                None => {
+                    let capacity = progress.slab_pages.len() as u64 * (self.config.page_size - self.config.page_header_size);
+                    let fragmented = capacity != 0
+                        && progress.reclaimable * 100 / capacity >= self.config.maintenance_fragmentation_percent as u64;
+                    progress.step = if fragmented { MaintenanceStep::Repack } else { MaintenanceStep::Truncate };
+                }
+            },
+            MaintenanceStep::Repack => {
+                let (vacuumed, resume_from) = self.vacuum_slabs(progress.repack_from, budget)?;
+                report.records_moved += vacuumed.records_moved;
+                report.pages_freed += vacuumed.pages_freed;
+                match resume_from {
+                    Some(page_id) => progress.repack_from = page_id,
+                    None => progress.step = MaintenanceStep::Truncate,
+                }
+            }
+            MaintenanceStep::Truncate => {
+                let free_percent = self.free_list_pages(&self.lock_allocation())?.len() as u64 * 100 / self.page_count().max(1) as u64;
+                if free_percent >= self.config.maintenance_free_percent as u64 {
+                    report.pages_truncated += self.truncate_free_tail()?;
+                }
+                *progress = MaintenanceProgress::default();
+                report.pass_completed = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps maintenance slices from starting, e.g. during level loads. Returns once any slice
+    /// already running has finished.
+    fn pause_maintenance(&self) {
+        self.maintenance.paused.store(true, std::sync::atomic::Ordering::SeqCst);
+        drop(self.maintenance.gate.lock());
+    }
+
+    fn resume_maintenance(&self) {
+        self.maintenance.paused.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn load_dedup_table(&self) -> io::Result<()> {
+        let root_page_id = self.dedup_root.read().page_id;
+        let mut table = self.dedup_table.write();
+        table.clear();
+        if root_page_id == -1 {
+            return Ok(());
+        }
+        let data = self.read_chain(root_page_id)?;
+        let mut reader = Cursor::new(data.as_slice());
+        let count = Self::read_count(&mut reader, 40, "dedup table")?;
+        for _ in 0..count {
+            let mut hash = [0u8; 32];
+            reader.read_exact(&mut hash)?;
+            table.insert(hash, reader.read_i64::<LittleEndian>()?);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the dedup table to a new chain and publishes it through the header.
+    fn write_dedup_table(&self) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        {
+            let table = self.dedup_table.read();
+            buffer.write_i32::<LittleEndian>(table.len() as i32)?;
+            for (hash, &first_page_id) in table.iter() {
+                buffer.write_all(hash)?;
+                buffer.write_i64::<LittleEndian>(first_page_id)?;
+            }
+        }
+        self.publish_table(&self.dedup_root, &buffer)
+    }
+
+    /// Writes a table to a new chain, points root at it through the header and frees the old chain.
+    fn publish_table(&self, root: &PRwLock<VersionedLink>, data: &[u8]) -> io::Result<()> {
+        let first_page_id = self.write_chain(data, self.default_codec())?;
+        let old_page_id = {
+            let mut root = root.write();
+            let old_page_id = root.page_id;
+            *root = VersionedLink { page_id: first_page_id, version: root.version + 1 };
+            old_page_id
+        };
+        self.write_roots()?;
+        if old_page_id != -1 {
+            self.free_chain(old_page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Built-in rules for idTech4 assets: media formats that are already compressed are stored
+    /// as is, text formats that compress well always get compressed.
+    fn default_compression_rules() -> Vec<ffi::CompressionRule> {
+        let rule = |extension: &str, codec| ffi::CompressionRule { extension: extension.to_string(), codec };
+        let stored = ["ogg", "jpg", "jpeg", "png", "roq", "pk4", "zip", "mp3"];
+        let compressed = ["map", "mtr", "def", "script", "gui", "guide", "cfg", "txt", "lang", "md5mesh", "md5anim", "md5camera", "lwo", "ase", "proc", "cm", "aas48", "aas96", "af", "pda", "skin", "sndshd", "fx", "particle"];
+        stored.iter().map(|extension| rule(extension, ffi::PageCodec::None))
+            .chain(compressed.iter().map(|extension| rule(extension, ffi::PageCodec::Snappy)))
+            .collect()
+    }
+
+    /// The codec for data written under path: the rule for its extension if there is one,
+    /// otherwise the database default.
+    fn codec_for_path(&self, path: &str) -> u8 {
+        if Self::dictionary_id(path).is_some() {
+            return CODEC_NONE; // dictionaries are read to decompress, so never compressed with one
+        }
+        let name = path.rsplit('/').next().unwrap_or(path);
+        name.rsplit_once('.')
+            .and_then(|(_, extension)| self.compression_rules.read().get(&extension.to_ascii_lowercase()).copied())
+            .unwrap_or_else(|| self.default_codec())
+    }
+
+    /// Loads the stored rules, falling back to the built-in defaults until some are stored.
+    fn load_compression_rules(&self) -> io::Result<()> {
+        let root_page_id = self.rules_root.read().page_id;
+        let mut rules = BTreeMap::new();
+        if root_page_id == -1 {
+            for rule in Self::default_compression_rules() {
+                rules.insert(rule.extension, rule.codec.repr);
+            }
+        } else {
+            let data = self.read_chain(root_page_id)?;
+            let mut reader = Cursor::new(data.as_slice());
+            let count = Self::read_count(&mut reader, 5, "compression rules")?;
+            for _ in 0..count {
+                let extension = Self::read_string(&mut reader, "compression rules")?;
+                let codec = reader.read_u8()?;
+                if codec != CODEC_NONE && codec != CODEC_SNAPPY && codec != CODEC_ZSTD {
+                    return Err(Self::corrupt("compression rules"));
+                }
+                rules.insert(extension, codec);
+            }
+        }
+        *self.compression_rules.write() = rules;
+        Ok(())
+    }
+
+    fn store_compression_rules(&self, rules: &[ffi::CompressionRule]) -> io::Result<()> {
+        let mut table = BTreeMap::new();
+        for rule in rules {
+            let extension = rule.extension.trim_start_matches('.').to_ascii_lowercase();
+            if extension.is_empty() || extension.contains('/') {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid extension: {:?}", rule.extension)));
+            }
+            if ![ffi::PageCodec::None, ffi::PageCodec::Snappy, ffi::PageCodec::Zstd].contains(&rule.codec) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unknown codec"));
+            }
+            table.insert(extension, rule.codec.repr);
+        }
+        let mut buffer = Vec::new();
+        buffer.write_i32::<LittleEndian>(table.len() as i32)?;
+        for (extension, &codec) in &table {
+            buffer.write_i32::<LittleEndian>(extension.len() as i32)?;
+            buffer.write_all(extension.as_bytes())?;
+            buffer.write_u8(codec)?;
+        }
+        *self.compression_rules.write() = table;
+        self.publish_table(&self.rules_root, &buffer)
+    }
+
+    fn get_compression_rules(&self) -> Vec<ffi::CompressionRule> {
+        self.compression_rules.read().iter()
+            .map(|(extension, &codec)| ffi::CompressionRule {
+                extension: extension.clone(),
+                codec: match codec {
+                    CODEC_SNAPPY => ffi::PageCodec::Snappy,
+                    CODEC_ZSTD => ffi::PageCodec::Zstd,
+                    _ => ffi::PageCodec::None,
+                },
+            })
+            .collect()
+    }
+
+    /// Rewrites the document's current version with the codec its path's rule selects, at level.
+    /// Contents, checksum and version are unchanged. Returns the stored size afterwards.
+    fn recompress(self: Pin<&mut Self>, path: &CxxString, level: i32) -> io::Result<u64> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let codec = self.codec_for_path(&rust_path);
+        Self::validate_compression_level(codec, level)?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let mut index = self.read_index()?;
+        let doc = index.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        doc.check_writable(false)?;
+        let old_page_id = doc.first_page_id;
+        if old_page_id == -1 {
+            return Ok(0);
+        }
+        let new_page_id = self.write_document_chain(&rust_path, &self.read_chain(old_page_id)?, level)?;
+        let doc = index.get_mut(&id).unwrap();
+        doc.first_page_id = new_page_id;
+        doc.page_count = self.chain_page_count(new_page_id)?;
+        self.write_index(&index)?;
+        self.release_chain(&index, old_page_id)?;
+        self.chain_stored_bytes(new_page_id)
+    }
+
+    /// The dictionary id a reserved dictionary path names, if it is one.
+    fn dictionary_id(path: &str) -> Option<u8> {
+        path.strip_prefix(DICTIONARY_PATH_PREFIX)?.parse::<u8>().ok().filter(|&id| id != 0)
+    }
+
+    fn dictionary(&self, id: u8) -> io::Result<Arc<Vec<u8>>> {
+        self.dictionaries.read().get(&id).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Compression dictionary {} is missing", id)))
+    }
+
+    fn load_dictionaries(&self) -> io::Result<()> {
+        let mut dictionaries = BTreeMap::new();
+        for doc in self.read_index()?.values() {
+            for binding in &doc.paths {
+                if let Some(id) = Self::dictionary_id(&binding.path) {
+                    dictionaries.insert(id, Arc::new(self.read_chain(doc.first_page_id)?));
+                }
+            }
+        }
+        *self.dictionaries.write() = dictionaries;
+        Ok(())
+    }
+
+    /// Stores a precomputed zstd dictionary as a read-only document under DICTIONARY_PATH_PREFIX.
+    /// It becomes the one used for small documents from now on. Returns its id.
+    fn install_dictionary(self: Pin<&mut Self>, dictionary: &[u8]) -> io::Result<u8> {
+        let _writes = self.begin_write()?;
+        if dictionary.is_empty() || dictionary.len() > MAX_DICTIONARY_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid dictionary size"));
+        }
+        let id = match self.dictionaries.read().keys().next_back() {
+            Some(&u8::MAX) => return Err(io::Error::new(io::ErrorKind::Other, "No dictionary ids left")),
+            Some(&last) => last + 1,
+            None => 1,
+        };
+        let path = format!("{}{}", DICTIONARY_PATH_PREFIX, id);
+        let mut index = self.read_index()?;
+        let first_page_id = self.write_chain(dictionary, CODEC_NONE)?;
+        let mut stale_chains = Vec::new();
+        let (doc_id, _) = self.apply_document(&mut index, None, &path, first_page_id, self.compute_crc(dictionary), dictionary.len() as u64, &mut stale_chains)?;
+        // Pages compressed with it are unreadable without it
+        index.get_mut(&doc_id).unwrap().flags = DOCUMENT_READONLY;
+        self.commit_index(&index, false, &[(path.clone(), doc_id)])?;
+        self.path_cache.lock().put(path, doc_id);
+        self.dictionaries.write().insert(id, Arc::new(dictionary.to_vec()));
+        Ok(id)
+    }
+
+    /// Trains a dictionary of at most max_size bytes on the small documents under prefix
+    /// (all of them for an empty prefix) and installs it. Returns its id.
+    fn train_dictionary(self: Pin<&mut Self>, prefix: &CxxString, max_size: u64) -> io::Result<u8> {
+        let _writes = self.begin_write()?;
+        let prefix = prefix.to_string_lossy();
+        let mut samples = Vec::new();
+        for doc in self.read_index()?.values() {
+            if doc.first_page_id == -1 || doc.size >= self.config.dictionary_threshold {
+                continue;
+            }
+            if doc.paths.iter().any(|binding| binding.path.starts_with(prefix.as_ref()) && Self::dictionary_id(&binding.path).is_none()) {
+                samples.push(self.read_chain_as(doc.first_page_id, PageCaching::Peek)?);
+            }
+        }
+        let max_size = usize::try_from(max_size).unwrap_or(usize::MAX).min(MAX_DICTIONARY_SIZE);
+        let dictionary = zstd::dict::from_samples(&samples, max_size)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Dictionary training failed: {}", e)))?;
+        self.install_dictionary(&dictionary)
+    }
+
+    /// Replaces the compression rules. Affects later writes only; existing pages keep the
+    /// codec recorded in their flags.
+    fn set_compression_rules(self: Pin<&mut Self>, rules: &Vec<ffi::CompressionRule>) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        self.store_compression_rules(rules)
+    }
+
+    fn load_tag_table(&self) -> io::Result<()> {
+        let root_page_id = self.tag_root.read().page_id;
+        let mut table = self.tag_table.write();
+        table.clear();
+        if root_page_id == -1 {
+            return Ok(());
+        }
+        let data = self.read_chain(root_page_id)?;
+        let mut reader = Cursor::new(data.as_slice());
+        let count = Self::read_count(&mut reader, 8, "tag table")?;
+        for _ in 0..count {
+            let tag = Self::read_string(&mut reader, "tag table")?;
+            let id_count = Self::read_count(&mut reader, 16, "tag table")?;
+            let mut ids = BTreeSet::new();
+            for _ in 0..id_count {
+                let mut id_bytes = [0u8; 16];
+                reader.read_exact(&mut id_bytes)?;
+                ids.insert(Uuid::from_bytes(id_bytes));
+            }
+            table.insert(tag, ids);
+        }
+        Ok(())
+    }
+
+    fn write_tag_table(&self) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        {
+            let table = self.tag_table.read();
+            buffer.write_i32::<LittleEndian>(table.len() as i32)?;
+            for (tag, ids) in table.iter() {
+                buffer.write_i32::<LittleEndian>(tag.len() as i32)?;
+                buffer.write_all(tag.as_bytes())?;
+                buffer.write_i32::<LittleEndian>(ids.len() as i32)?;
+                for id in ids {
+                    buffer.write_all(id.as_bytes())?;
+                }
+            }
+        }
+        self.publish_table(&self.tag_root, &buffer)
+    }
+
+    /// Drops deleted documents from the tag table.
+    fn untag_documents<'a>(&self, docs: impl IntoIterator<Item = &'a Document>) -> io::Result<()> {
+        let mut changed = false;
+        {
+            let mut table = self.tag_table.write();
+            for doc in docs {
+                for tag in &doc.tags {
+                    if let Some(ids) = table.get_mut(tag) {
+                        changed |= ids.remove(&doc.id);
+                        if ids.is_empty() {
+                            table.remove(tag);
+                        }
+                    }
+                }
+            }
+        }
+        if changed {
+            self.write_tag_table()?;
+        }
+        Ok(())
+    }
+
+    fn validate_tag(tag: &str) -> io::Result<()> {
+        if tag.is_empty() || tag.len() > MAX_TAG_LENGTH || tag.chars().any(|c| c.is_control()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid tag: {:?}", tag)));
+        }
+        Ok(())
+    }
+
+    fn add_tag(self: Pin<&mut Self>, path: &CxxString, tag: &CxxString) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let tag = tag.to_string_lossy();
+        Self::validate_tag(&tag)?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        if doc.tags.contains(tag.as_ref()) {
+            return Ok(());
+        }
+        if doc.tags.len() >= MAX_TAGS_PER_DOCUMENT {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many tags on document"));
+        }
+        doc.tags.insert(tag.to_string());
+        self.write_index(&index)?;
+        self.tag_table.write().entry(tag.to_string()).or_default().insert(id);
+        self.write_tag_table()
+    }
+
+    /// Returns whether the document carried the tag.
+    fn remove_tag(self: Pin<&mut Self>, path: &CxxString, tag: &CxxString) -> io::Result<bool> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let tag = tag.to_string_lossy();
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        if !doc.tags.remove(tag.as_ref()) {
+            return Ok(false);
+        }
+        self.write_index(&index)?;
+        {
+            let mut table = self.tag_table.write();
+            if let Some(ids) = table.get_mut(tag.as_ref()) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    table.remove(tag.as_ref());
+                }
+            }
+        }
+        self.write_tag_table()?;
+        Ok(true)
+    }
+
+    /// Replaces the document's flags with a mask of DocumentFlag bits. Works on READONLY
+    /// documents, so clearing the flag is how they are made writable again.
+    fn set_flags(self: Pin<&mut Self>, path: &CxxString, flags: u32) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        if flags & !KNOWN_DOCUMENT_FLAGS != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown document flags 0x{:x}", flags & !KNOWN_DOCUMENT_FLAGS)));
+        }
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        if doc.flags == flags {
+            return Ok(());
+        }
+        doc.flags = flags;
+        self.write_index(&index)
+    }
+
+    /// Every path bound to a PRECACHE document, sorted, for the engine to load at map start.
+    fn get_precache_list(&self) -> io::Result<Vec<String>> {
+        self.ensure_open()?;
+        let now = Self::unix_now();
+        let paths: BTreeSet<String> = self.read_index()?.values()
+            .filter(|doc| doc.flags & DOCUMENT_PRECACHE != 0)
+            .filter(|doc| !self.config.hide_expired || !doc.is_expired(now))
+            .flat_map(|doc| doc.paths.iter().map(|binding| binding.path.clone()))
+            .collect();
+        Ok(paths.into_iter().collect())
+    }
+
+    fn get_tags(&self, path: &CxxString) -> io::Result<Vec<String>> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        Ok(self.visible_document(&index, id)?.tags.iter().cloned().collect())
+    }
+
+    /// Documents carrying tag, each reported under its first bound path.
+    fn find_by_tag(&self, tag: &CxxString) -> io::Result<Vec<ffi::DocumentInfo>> {
+        self.ensure_open()?;
+        let ids = match self.tag_table.read().get(tag.to_string_lossy().as_ref()) {
+            Some(ids) => ids.clone(),
+            None => return Ok(Vec::new()),
+        };
+        let index = self.read_index()?;
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Ok(doc) = self.visible_document(&index, id) {
+                if let Some(binding) = doc.paths.first() {
+                    results.push(self.document_info(&binding.path, doc)?);
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    fn write_index(&self, index: &BTreeMap<Uuid, Document>) -> io::Result<()> {
+        self.commit_index(index, false, &[])
+    }
+
+    /// Brings the stored index in line with index. Changes that leave every document's chain
+    /// where it was (times, flags, tags, path bindings) are appended to the index log while it
+    /// is under index_log_threshold. Anything else, or fold, rewrites the B-tree leaves holding
+    /// the changed and logged entries, along with the branches above any that split or empty,
+    /// and empties the log.
+    ///
+    /// Each step is durable before the next starts, so a crash leaves a prefix of them:
+    /// the data pages index names, then the index pages, then new_paths bound in the trie, then
+    /// the roots published in the header. Pages the update makes unreachable are freed last.
+    fn commit_index(&self, index: &BTreeMap<Uuid, Document>, fold: bool, new_paths: &[(String, Uuid)]) -> io::Result<()> {
+        self.write_barrier()?;
+        let current = self.read_index()?;
+        let changed: Vec<&Document> = index.values().filter(|doc| current.get(&doc.id) != Some(*doc)).collect();
+        let removed: Vec<Uuid> = current.keys().filter(|id| !index.contains_key(id)).copied().collect();
+        let metadata_only = removed.is_empty()
+            && changed.iter().all(|doc| current.get(&doc.id).map_or(false, |old| old.first_page_id == doc.first_page_id));
+        let stale_secondary = self.stage_secondary_indexes(index)?;
+        let mut index_root = self.document_index_root.write();
+        let mut log = self.index_log.lock();
+        let mut stale_log = None;
+        if !fold && metadata_only && index_root.page_id != -1 && log.records + changed.len() <= self.config.index_log_threshold {
+            self.append_index_log(&mut log, &changed)?;
+        } else {
+            // The version doubles as a change sequence other processes can watch in the header
+            index_root.version += 1;
+            let version = index_root.version;
+            let mut root = index_root.page_id;
+            if root == -1 && !index.is_empty() {
+                // Built bottom up: one oversized leaf split into as many pages as it takes
+                root = self.allocate_page()?;
+                let siblings = self.store_index_node(root, IndexNode::Leaf(index.clone()), version)?;
+                self.grow_index(&mut root, Vec::new(), siblings, version)?;
+            } else if root != -1 {
+                let ids: BTreeSet<Uuid> = changed.iter().map(|doc| doc.id).chain(removed).chain(log.entries.keys().copied()).collect();
+                for id in &ids {
+                    match index.get(id) {
+                        Some(doc) => self.index_insert(&mut root, doc, version)?,
+                        None => self.index_remove(&mut root, id, version)?,
+                    }
+                }
+            }
+            index_root.page_id = root;
+            let mut log_root = self.index_log_root.write();
+            stale_log = Some(log_root.page_id).filter(|&page_id| page_id != -1);
+            *log_root = VersionedLink { page_id: -1, version: log_root.version + 1 };
+            *log = IndexLog::default();
+        }
+        *self.index_cache.write() = Some(((*index_root, *self.index_log_root.read()), index.clone()));
+        // Every write, delete, import and purge ends here, so the count is always the index's
+        self.document_count.store(index.len() as u64, std::sync::atomic::Ordering::SeqCst);
+        drop(log);
+        drop(index_root);
+        self.write_barrier()?;
+        if !new_paths.is_empty() {
+            for (path, id) in new_paths {
+                self.trie_insert(path, *id)?;
+            }
+            self.write_barrier()?;
+        }
+        // One header write publishes the index together with its log and secondary indexes
+        self.write_roots()?;
+        self.write_barrier()?;
+        if let Some(page_id) = stale_secondary {
+            self.free_chain(page_id)?;
+        }
+        if let Some(page_id) = stale_log {
+            self.free_chain(page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Appends docs to the index log, filling its last page before linking another. Each
+    /// record is an index entry; a later record for the same document supersedes earlier ones.
+    fn append_index_log(&self, log: &mut IndexLog, docs: &[&Document]) -> io::Result<()> {
+        let capacity = (self.config.page_size - self.config.page_header_size) as usize;
+        for doc in docs {
+            let mut record = Vec::new();
+            Self::write_index_entry(&mut record, doc, self.index_format.load(std::sync::atomic::Ordering::SeqCst))?;
+            // Held to what a leaf could take, so folding it in later cannot fail
+            if record.len() > capacity - INDEX_NODE_HEADER_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Document index entry too large"));
+            }
+            // Typed as data, so recovery keeps the log for load_index_log rather than parsing it as a node
+            let links = PageLinks::single(FLAG_DATA_PAGE);
+            match log.tail.as_mut() {
+                Some((page_id, records)) if records.len() + record.len() <= capacity => {
+                    records.extend_from_slice(&record);
+                    self.write_raw_page_as(*page_id, records, self.next_page_version(*page_id), links, CODEC_NONE, 0, 0)?;
+                }
+                tail => {
+                    let page_id = self.allocate_page()?;
+                    self.write_raw_page_as(page_id, &record, self.next_page_version(page_id), links, CODEC_NONE, 0, 0)?;
+                    match tail {
+                        Some((previous, _)) => {
+                            let header = self.read_page_header(*previous)?;
+                            self.write_page_header(*previous, &PageHeader { next_page_id: page_id, ..header })?;
+                        }
+                        None => self.index_log_root.write().page_id = page_id,
+                    }
+                    log.tail = Some((page_id, record));
+                }
+            }
+            log.records += 1;
+            log.entries.insert(doc.id, (*doc).clone());
+        }
+        // Moves the header on even when nothing was logged, as every index write does
+        self.index_log_root.write().version += 1;
+        Ok(())
+    }
+
+    fn load_index_log(&self) -> io::Result<()> {
+        let mut log = IndexLog::default();
+        let mut page_id = self.index_log_root.read().page_id;
+        while page_id != -1 {
+            let records = self.read_raw_page(page_id)?;
+            let mut reader = Cursor::new(&records[..]);
+            while (reader.position() as usize) < records.len() {
+                let doc = Self::read_index_entry(&mut reader, self.index_format.load(std::sync::atomic::Ordering::SeqCst))?;
+                log.entries.insert(doc.id, doc);
+                log.records += 1;
+            }
+            let next_page_id = self.read_page_header(page_id)?.next_page_id;
+            log.tail = Some((page_id, records));
+            page_id = next_page_id;
+        }
+        *self.index_log.lock() = log;
+        Ok(())
+    }
+
+    /// Folds the index log into the B-tree and empties it. Returns the number of records folded.
+    fn fold_index_log(self: Pin<&mut Self>) -> io::Result<u64> {
+        let _writes = self.begin_write()?;
+        let records = self.index_log.lock().records as u64;
+        if records != 0 {
+            self.commit_index(&self.read_index()?, true, &[])?;
+        }
+        Ok(records)
+    }
+
+    /// Brings the enabled secondary indexes in line with index and writes them to a new chain
+    /// for the caller to publish. Returns the chain that publishing makes unreachable.
+    fn stage_secondary_indexes(&self, index: &BTreeMap<Uuid, Document>) -> io::Result<Option<i64>> {
+        let mut secondary = self.secondary.write();
+        if secondary.is_empty() {
+            return Ok(None);
+        }
+        let rebuilt = secondary.rebuilt(index);
+        if rebuilt == *secondary {
+            return Ok(None);
+        }
+        let first_page_id = self.write_chain(&rebuilt.serialize()?, self.default_codec())?;
+        *secondary = rebuilt;
+        let mut root = self.secondary_root.write();
+        let old_page_id = root.page_id;
+        *root = VersionedLink { page_id: first_page_id, version: root.version + 1 };
+        Ok(Some(old_page_id).filter(|&page_id| page_id != -1))
+    }
+
+    fn load_secondary_indexes(&self) -> io::Result<()> {
+        let root_page_id = self.secondary_root.read().page_id;
+        *self.secondary.write() = if root_page_id == -1 {
+            SecondaryIndexes::default()
+        } else {
+            SecondaryIndexes::deserialize(&self.read_chain(root_page_id)?)?
+        };
+        Ok(())
+    }
+
+    /// Enables a secondary index, backfilling it from the current index. Enabling one that
+    /// already exists is a no-op.
+    fn create_secondary_index(self: Pin<&mut Self>, kind: ffi::SecondaryIndexKind) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        if self.secondary.read().get(kind).is_some() {
+            return Ok(());
+        }
+        let index = self.read_index()?;
+        let mut updated = self.secondary.read().clone();
+        *updated.slot(kind) = Some(BTreeSet::new());
+        self.publish_secondary_indexes(updated.rebuilt(&index))
+    }
+
+    fn drop_secondary_index(self: Pin<&mut Self>, kind: ffi::SecondaryIndexKind) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let mut updated = self.secondary.read().clone();
+        if updated.slot(kind).take().is_none() {
+            return Ok(());
+        }
+        self.publish_secondary_indexes(updated)
+    }
+
+    fn publish_secondary_indexes(&self, updated: SecondaryIndexes) -> io::Result<()> {
+        let first_page_id = if updated.is_empty() { -1 } else { self.write_chain(&updated.serialize()?, self.default_codec())? };
+        let old_page_id = {
+            let mut root = self.secondary_root.write();
+            let old_page_id = root.page_id;
+            *root = VersionedLink { page_id: first_page_id, version: root.version + 1 };
+            old_page_id
+        };
+        *self.secondary.write() = updated;
+        self.write_roots()?;
+        if old_page_id != -1 {
+            self.free_chain(old_page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Documents whose key falls in min..=max, in key order. Without the secondary index this
+    /// falls back to a scan of the main index.
+    fn find_by_key_range(&self, kind: ffi::SecondaryIndexKind, min: u64, max: u64) -> io::Result<Vec<ffi::DocumentInfo>> {
+        self.ensure_open()?;
+        let index = self.read_index()?;
+        let ids: Vec<Uuid> = match self.secondary.read().get(kind) {
+            Some(entries) if min <= max => entries.range((min, Uuid::nil())..=(max, Uuid::from_bytes([0xff; 16])))
+                .map(|&(_, id)| id)
+                .collect(),
+            Some(_) => Vec::new(),
+            None => {
+                let key = |doc: &Document| if kind == ffi::SecondaryIndexKind::Size { doc.size } else { doc.modified };
+                let mut matches: Vec<(u64, Uuid)> = index.values()
+                    .filter(|doc| (min..=max).contains(&key(doc)))
+                    .map(|doc| (key(doc), doc.id))
+                    .collect();
+                matches.sort_unstable();
+                matches.into_iter().map(|(_, id)| id).collect()
+            }
+        };
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Ok(doc) = self.visible_document(&index, id) {
+                if let Some(binding) = doc.paths.first() {
+                    results.push(self.document_info(&binding.path, doc)?);
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    fn find_by_size_range(&self, min: u64, max: u64) -> io::Result<Vec<ffi::DocumentInfo>> {
+        self.find_by_key_range(ffi::SecondaryIndexKind::Size, min, max)
+    }
+
+    /// Documents written at or after since (unix time), oldest first.
+    fn find_modified_since(&self, since: u64) -> io::Result<Vec<ffi::DocumentInfo>> {
+        self.find_by_key_range(ffi::SecondaryIndexKind::Modified, since, u64::MAX)
+    }
+
+    /// The whole index, with the index log applied. Served from memory while the index and log
+    /// roots are the ones it was last read or written under; any write, here or by another
+    /// process, moves one of their versions on.
+    fn read_index(&self) -> io::Result<BTreeMap<Uuid, Document>> {
+        let index_root = self.document_index_root.read();
+        let log = self.index_log.lock();
+        let roots = (*index_root, *self.index_log_root.read());
+        if let Some((cached_roots, index)) = self.index_cache.read().as_ref() {
+            if *cached_roots == roots {
+                return Ok(index.clone());
+            }
+        }
+        let mut index = self.load_index(index_root.page_id)?;
+        index.extend(log.entries.clone());
+        *self.index_cache.write() = Some((roots, index.clone()));
+        Ok(index)
+    }
+
+    /// One document's entry, without loading the whole index: from the cached index if it is
+    /// current, else from the index log or the one leaf id falls in.
+    fn lookup_document(&self, id: &Uuid) -> io::Result<Option<Document>> {
+        let index_root = self.document_index_root.read();
+        let log = self.index_log.lock();
+        let roots = (*index_root, *self.index_log_root.read());
+        if let Some((cached_roots, index)) = self.index_cache.read().as_ref() {
+            if *cached_roots == roots {
+                return Ok(index.get(id).cloned());
+            }
+        }
+        if let Some(doc) = log.entries.get(id) {
+            return Ok(Some(doc.clone()));
+        }
+        if index_root.page_id == -1 {
+            return Ok(None);
+        }
+        let (_, _, mut entries) = self.index_descend(index_root.page_id, id)?;
+        Ok(entries.remove(id))
+    }
+
+    /// Reads every leaf under root, checking that keys ascend from one leaf to the next.
+    fn load_index(&self, root: i64) -> io::Result<BTreeMap<Uuid, Document>> {
+        let mut index = BTreeMap::new();
+        for leaf in self.index_leaves(root) {
+            for (id, doc) in leaf? {
+                if index.keys().next_back().map_or(false, |last| *last >= id) {
+                    return Err(Self::corrupt("document index order"));
+                }
+                index.insert(id, doc);
+            }
+        }
+        Ok(index)
+    }
+
+    fn index_leaves(&self, root: i64) -> IndexLeaves<'_> {
+        IndexLeaves { db: self, pending: if root == -1 { Vec::new() } else { vec![(root, 0)] } }
+    }
+
+    fn read_index_node(&self, page_id: i64) -> io::Result<IndexNode> {
+        let data = self.read_page_of_type(page_id, FLAG_INDEX_PAGE, PageCaching::Fill)?;
+        match data.first() {
+            Some(&INDEX_LEAF) => Ok(IndexNode::Leaf(self.deserialize_index(&data[1..], self.index_format.load(std::sync::atomic::Ordering::SeqCst))?)),
+            Some(&INDEX_BRANCH) => {
+                let mut reader = Cursor::new(&data[1..]);
+                let count = Self::read_count(&mut reader, 8, "document index page")?;
+                if count == 0 {
+                    return Err(Self::corrupt("document index page"));
+                }
+                let mut keys = Vec::with_capacity(count - 1);
+                let mut children = vec![reader.read_i64::<LittleEndian>()?];
+                for _ in 1..count {
+                    let mut key = [0u8; 16];
+                    reader.read_exact(&mut key)?;
+                    keys.push(Uuid::from_bytes(key));
+                    children.push(reader.read_i64::<LittleEndian>()?);
+                }
+                Ok(IndexNode::Branch { keys, children })
+            }
+            _ => Err(Self::corrupt("document index page")),
+        }
+    }
+
+    fn write_index_node(&self, page_id: i64, node: &IndexNode, version: i32) -> io::Result<()> {
+        let data = match node {
+            IndexNode::Leaf(entries) => {
+                let mut data = vec![INDEX_LEAF];
+                data.extend_from_slice(&self.serialize_index(entries)?);
+                data
+            }
+            IndexNode::Branch { keys, children } => {
+                let mut data = vec![INDEX_BRANCH];
+                data.write_i32::<LittleEndian>(children.len() as i32)?;
+                data.write_i64::<LittleEndian>(children[0])?;
+                for (key, &child) in keys.iter().zip(&children[1..]) {
+                    data.write_all(key.as_bytes())?;
+                    data.write_i64::<LittleEndian>(child)?;
+                }
+                data
+            }
+        };
+        self.write_raw_page(page_id, &data, version, PageLinks::single(FLAG_INDEX_PAGE))
+    }
+
+    /// Where to cut a run of items of the given sizes so each piece fits capacity, aiming for
+    /// pieces of even size. Returns the index each piece starts at; a run that fits is one piece.
+    fn split_points(sizes: &[usize], capacity: usize) -> Vec<usize> {
+        let total: usize = sizes.iter().sum();
+        let target = total.div_ceil(total.div_ceil(capacity).max(1));
+        let mut starts = vec![0];
+        let mut filled = 0;
+        for (i, &size) in sizes.iter().enumerate() {
+            if filled > 0 && (filled + size > capacity || filled >= target) {
+                starts.push(i);
+                filled = 0;
+            }
+            filled += size;
+        }
+        starts
+    }
+
+    /// Writes node at page_id, splitting it across new pages when it does not fit in one.
+    /// Returns the pages split off, each with the smallest key under it, in key order.
+    fn store_index_node(&self, page_id: i64, node: IndexNode, version: i32) -> io::Result<Vec<(Uuid, i64)>> {
+        // Sized uncompressed, so a node that fits never depends on how well it compresses
+        let capacity = (self.config.page_size - self.config.page_header_size) as usize - INDEX_NODE_HEADER_SIZE;
+        let mut pieces = Vec::new();
+        match node {
+            IndexNode::Leaf(entries) => {
+                let mut sizes = Vec::with_capacity(entries.len());
+                for doc in entries.values() {
+                    let mut entry = Vec::new();
+                    Self::write_index_entry(&mut entry, doc, self.index_format.load(std::sync::atomic::Ordering::SeqCst))?;
+                    if entry.len() > capacity {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Document index entry too large"));
+                    }
+                    sizes.push(entry.len());
+                }
+                let mut entries: Vec<(Uuid, Document)> = entries.into_iter().collect();
+                for &start in Self::split_points(&sizes, capacity).iter().skip(1).rev() {
+                    let piece = entries.split_off(start);
+                    pieces.push((piece[0].0, IndexNode::Leaf(piece.into_iter().collect())));
+                }
+                pieces.push((Uuid::nil(), IndexNode::Leaf(entries.into_iter().collect())));
+            }
+            IndexNode::Branch { mut keys, mut children } => {
+                let sizes = vec![24; children.len()];
+                for &start in Self::split_points(&sizes, capacity).iter().skip(1).rev() {
+                    let piece_children = children.split_off(start);
+                    let piece_keys = keys.split_off(start);
+                    let separator = keys.pop().unwrap();
+                    pieces.push((separator, IndexNode::Branch { keys: piece_keys, children: piece_children }));
+                }
+                pieces.push((Uuid::nil(), IndexNode::Branch { keys, children }));
+            }
+        }
+        let (_, first) = pieces.pop().unwrap();
+        self.write_index_node(page_id, &first, version)?;
+        let mut siblings = Vec::with_capacity(pieces.len());
+        for (key, node) in pieces.into_iter().rev() {
+            let sibling = self.allocate_page()?;
+            self.write_index_node(sibling, &node, version)?;
+            siblings.push((key, sibling));
+        }
+        Ok(siblings)
+    }
+
+    /// Finds the leaf id belongs in. Returns the branches above it, each with the child
+    /// position taken, along with the leaf's page and entries.
+    fn index_descend(&self, root: i64, id: &Uuid) -> io::Result<(Vec<(i64, Vec<Uuid>, Vec<i64>, usize)>, i64, BTreeMap<Uuid, Document>)> {
+        let mut path = Vec::new();
+        let mut page_id = root;
+        while path.len() <= INDEX_MAX_DEPTH {
+            match self.read_index_node(page_id)? {
+                IndexNode::Leaf(entries) => return Ok((path, page_id, entries)),
+                IndexNode::Branch { keys, children } => {
+                    let position = keys.partition_point(|key| key <= id);
+                    let child = *children.get(position).ok_or_else(|| Self::corrupt("document index page"))?;
+                    path.push((page_id, keys, children, position));
+                    page_id = child;
+                }
+            }
+        }
+        Err(Self::corrupt("document index"))
+    }
+
+    /// Links the pages a node split into to the branches above it, splitting those in turn
+    /// as needed and putting a new root over an old one that split.
+    fn grow_index(&self, root: &mut i64, mut path: Vec<(i64, Vec<Uuid>, Vec<i64>, usize)>, mut siblings: Vec<(Uuid, i64)>, version: i32) -> io::Result<()> {
+        while !siblings.is_empty() {
+            let (page_id, mut keys, mut children, position) = match path.pop() {
+                Some(step) => step,
+                None => {
+                    let page_id = self.allocate_page()?;
+                    let step = (page_id, Vec::new(), vec![*root], 0);
+                    *root = page_id;
+                    step
+                }
+            };
+            for (offset, (key, child)) in siblings.into_iter().enumerate() {
+                keys.insert(position + offset, key);
+                children.insert(position + offset + 1, child);
+            }
+            siblings = self.store_index_node(page_id, IndexNode::Branch { keys, children }, version)?;
+        }
+        Ok(())
+    }
+
+    fn index_insert(&self, root: &mut i64, doc: &Document, version: i32) -> io::Result<()> {
+        let (path, page_id, mut entries) = self.index_descend(*root, &doc.id)?;
+        entries.insert(doc.id, doc.clone());
+        let siblings = self.store_index_node(page_id, IndexNode::Leaf(entries), version)?;
+        self.grow_index(root, path, siblings, version)
+    }
+
+    /// Removes id's entry. A leaf left empty is freed and unlinked from its branch, which is
+    /// freed in turn if that was its last child; underfull nodes are otherwise left as they are.
+    fn index_remove(&self, root: &mut i64, id: &Uuid, version: i32) -> io::Result<()> {
+        let (mut path, page_id, mut entries) = self.index_descend(*root, id)?;
+        if entries.remove(id).is_none() {
+            return Ok(());
+        }
+        if !entries.is_empty() {
+            // Smaller than before, so it still fits
+            self.store_index_node(page_id, IndexNode::Leaf(entries), version)?;
+            return Ok(());
+        }
+        self.free_page(page_id)?;
+        loop {
+            let (page_id, mut keys, mut children, position) = match path.pop() {
+                Some(step) => step,
+                None => {
+                    *root = -1;
+                    return Ok(());
+                }
+            };
+            children.remove(position);
+            if !keys.is_empty() {
+                keys.remove(position.saturating_sub(1));
+            }
+            if !children.is_empty() {
+                self.store_index_node(page_id, IndexNode::Branch { keys, children }, version)?;
+                break;
+            }
+            self.free_page(page_id)?;
+        }
+        // A root branch down to one child gives way to it
+        while let IndexNode::Branch { children, .. } = self.read_index_node(*root)? {
+            if children.len() != 1 {
+                break;
+            }
+            self.free_page(*root)?;
+            *root = children[0];
+        }
+        Ok(())
+    }
+
+    fn get(&self, path: &CxxString) -> io::Result<CxxVector<u8>> {
+        Ok(cxx::CxxVector::from(self.read_document(path.to_string_lossy().as_ref())?))
+    }
+
+    fn get_with_options(&self, path: &CxxString, options: &ffi::ReadOptions) -> io::Result<CxxVector<u8>> {
+        Ok(cxx::CxxVector::from(self.read_document_as(path.to_string_lossy().as_ref(), Self::read_caching(options))?))
+    }
+
+    fn read_caching(options: &ffi::ReadOptions) -> PageCaching {
+        if options.bypass_cache { PageCaching::Peek } else { PageCaching::Fill }
+    }
+
+    fn read_document(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.read_document_as(path, PageCaching::Fill)
+    }
+
+    fn read_document_as(&self, path: &str, caching: PageCaching) -> io::Result<Vec<u8>> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path)?;
+        let _timer = self.latency.time(TimedOp::DocumentGet, OpDetail::Path(&rust_path));
+        let _span = trace_span!("get_document", path = rust_path.as_str());
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        let doc = self.visible_document(&index, id)?;
+        match self.read_document_chain(doc, caching) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => match self.mirror.as_ref().filter(|mirror| mirror.fallback) {
+                Some(mirror) => {
+                    // The primary no longer matches the mirror, whether or not the mirror has the document
+                    mirror.diverged.store(true, std::sync::atomic::Ordering::SeqCst);
+                    mirror.db.read_document_as(&rust_path, caching).map_err(|_| e)
+                }
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    /// Reads the document's current version into a buffer reserved at its stored size, so a
+    /// large document is not regrown page by page. A chain that reassembles to any other
+    /// length is corrupt.
+    fn read_document_chain(&self, doc: &Document, caching: PageCaching) -> io::Result<Vec<u8>> {
+        // Bounded, so a damaged size cannot ask for more memory than any document may hold
+        let mut data = Vec::with_capacity(doc.size.min(self.config.max_document_size) as usize);
+        self.read_chain_into(doc.first_page_id, caching, &mut data).map_err(|e| self.name_document(&doc.id, e))?;
+        if data.len() as u64 != doc.size {
+            return Err(self.name_document(&doc.id, Self::corrupt("document size")));
+        }
+        Ok(data)
+    }
+
+    /// Adds the paths of the document id to an InvalidData error, so a report of damage says
+    /// which assets it hit. Other errors are returned as they are.
+    fn name_document(&self, id: &Uuid, error: io::Error) -> io::Error {
+        if error.kind() != io::ErrorKind::InvalidData {
+            return error;
+        }
+        let paths = match self.lookup_document(id) {
+            Ok(Some(doc)) => doc.paths.iter().map(|binding| binding.path.as_str()).collect::<Vec<_>>().join(", "),
+            _ => "unknown paths".to_string(),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} in document {} ({})", error, id, paths))
+    }
+
+    /// The paths the document is bound to, with each binding's layer and language, looked up
+    /// by uuid. Shadowed and inactive localized bindings are listed too, marked unresolved.
+    fn get_paths_for_uuid(&self, uuid: &CxxString) -> io::Result<Vec<ffi::PathBindingInfo>> {
+        self.ensure_open()?;
+        let id = Uuid::parse_str(&uuid.to_string_lossy()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid uuid"))?;
+        let doc = self.lookup_document(&id)?
+            .filter(|doc| !self.config.hide_expired || !doc.is_expired(Self::unix_now()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        Ok(doc.paths.iter().map(|binding| ffi::PathBindingInfo {
+            path: binding.path.clone(),
+            addon: binding.addon,
+            priority: binding.priority,
+            lang: binding.lang.clone(),
+            resolved: self.get_document_id_by_path(&binding.path).is_ok_and(|resolved| resolved == id),
+        }).collect())
+    }
+
+    /// Reads several documents, in the order given; a path that does not resolve fails the
+    /// call. Unlike get, compressed pages are first decompressed into the page cache on up to
+    /// decompress_threads threads, a window of documents at a time so the cache holds them.
+    fn get_many(&self, paths: &Vec<String>) -> io::Result<Vec<ffi::DocumentData>> {
+        self.get_many_with_options(paths, &ffi::ReadOptions::default())
+    }
+
+    /// get_many; with bypass_cache set, pages are decompressed on the calling thread as they
+    /// are read rather than staged through the cache.
+    fn get_many_with_options(&self, paths: &Vec<String>, options: &ffi::ReadOptions) -> io::Result<Vec<ffi::DocumentData>> {
+        self.ensure_open()?;
+        let caching = Self::read_caching(options);
+        let index = self.read_index()?;
+        let mut chains = Vec::with_capacity(paths.len());
+        for path in paths {
+            let rust_path = self.validate_path(path)?;
+            let id = self.get_document_id_by_path(&rust_path)?;
+            chains.push((rust_path, self.visible_document(&index, id)?));
+        }
+        let window_pages = (self.page_cache_capacity() / 2).max(1);
+        let mut documents = Vec::with_capacity(chains.len());
+        let mut start = 0;
+        while start < chains.len() {
+            let mut pending = Vec::new();
+            let mut end = start;
+            while end < chains.len() && (end == start || pending.len() < window_pages) {
+                if self.config.decompress_threads != 0 && caching == PageCaching::Fill {
+                    self.collect_compressed_pages(chains[end].1.first_page_id, &mut pending);
+                }
+                end += 1;
+            }
+            self.decompress_into_cache(&pending);
+            for (path, doc) in &chains[start..end] {
+                documents.push(ffi::DocumentData { path: path.clone(), data: self.read_document_chain(doc, caching)? });
+            }
+            start = end;
+        }
+        Ok(documents)
+    }
+
+    /// Decompresses the documents' compressed pages into the page cache ahead of their reads,
+    /// e.g. for the precache list at map start. Paths that do not resolve are skipped, and no
+    /// more pages are warmed than the cache can hold. Returns the number of pages warmed.
+    fn prefetch(&self, paths: &Vec<String>) -> io::Result<u64> {
+        self.ensure_open()?;
+        let index = self.read_index()?;
+        let capacity = self.page_cache_capacity();
+        let mut pending = Vec::new();
+        for path in paths {
+            if pending.len() >= capacity {
+                break;
+            }
+            let resolved = self.validate_path(path)
+                .and_then(|path| self.get_document_id_by_path(&path))
+                .and_then(|id| self.visible_document(&index, id).map(|doc| doc.first_page_id));
+            if let Ok(first_page_id) = resolved {
+                self.collect_compressed_pages(first_page_id, &mut pending);
+            }
+        }
+        pending.truncate(capacity);
+        Ok(self.decompress_into_cache(&pending))
+    }
+
+    /// Reads the chain's compressed pages that are not cached yet. Stops quietly at a page that
+    /// cannot be read: the ordinary read that follows reports it.
+    fn collect_compressed_pages(&self, first_page_id: i64, pending: &mut Vec<CompressedPage>) {
+        let mut page_id = first_page_id;
+        while page_id != -1 && Self::slab_record(page_id).is_none() {
+            let header = match self.read_page_header(page_id) {
+                Ok(header) => header,
+                Err(_) => return,
+            };
+            let generation = self.page_generations.lock().get(&page_id).copied().unwrap_or(0);
+            if header.flags & FLAG_COMPRESSED != 0 && !self.lock_page_cache(page_id).contains(&(page_id, generation)) {
+                match self.read_page_payload(page_id) {
+                    Ok((header, payload)) => pending.push(CompressedPage { page_id, generation, header, payload }),
+                    Err(_) => return,
+                }
+            }
+            page_id = header.next_page_id;
+        }
+    }
+
+    /// Decompresses pages into the page cache on up to decompress_threads threads, or inline
+    /// with none, returning how many succeeded. Failures are left for the ordinary read to report.
+    fn decompress_into_cache(&self, pending: &[CompressedPage]) -> u64 {
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let decompressed = std::sync::atomic::AtomicU64::new(0);
+        let work = || {
+            while let Some(page) = pending.get(next.fetch_add(1, std::sync::atomic::Ordering::Relaxed)) {
+                if let Ok(data) = self.decompress_page(&page.payload, &page.header) {
+                    self.lock_page_cache(page.page_id).put((page.page_id, page.generation), data);
+                    decompressed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        };
+        match self.config.decompress_threads.min(pending.len()) {
+            0 => work(),
+            threads => std::thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(&work);
+                }
+            }),
+        }
+        decompressed.into_inner()
+    }
+
+    fn read_chain(&self, first_page_id: i64) -> io::Result<Vec<u8>> {
+        self.read_chain_as(first_page_id, PageCaching::Fill)
+    }
+
+    fn read_chain_as(&self, first_page_id: i64, caching: PageCaching) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_chain_into(first_page_id, caching, &mut data)?;
+        Ok(data)
+    }
+
+    fn read_chain_into(&self, first_page_id: i64, caching: PageCaching, data: &mut Vec<u8>) -> io::Result<()> {
+        let mut current_page_id = first_page_id;
+        while current_page_id != -1 {
+            let (page, next_page_id) = self.view_page_as(current_page_id, caching)?;
+            data.extend_from_slice(&page);
+            current_page_id = next_page_id;
+        }
+        Ok(())
+    }
+
+    /// Turns a caller-provided buffer into a slice that lives only for the current call.
+    /// Safety: dst must be valid for dst_len writable bytes and not aliased for the duration of the call.
+    unsafe fn caller_buffer<'a>(dst: *mut u8, dst_len: usize) -> io::Result<&'a mut [u8]> {
+        if dst_len == 0 {
+            return Ok(&mut []);
+        }
+        if dst.is_null() || dst_len > isize::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid destination buffer"));
+        }
+        Ok(std::slice::from_raw_parts_mut(dst, dst_len))
+    }
+
+    /// A page payload and the next page in its chain. Uncompressed pages in the mapping are
+    /// borrowed rather than copied and bypass the page cache, which would only add copies;
+    /// everything else goes through read_raw_page.
+    fn view_page(&self, page_id: i64) -> io::Result<(PageView<'_>, i64)> {
+        self.view_page_as(page_id, PageCaching::Fill)
+    }
+
+    fn view_page_as(&self, page_id: i64, caching: PageCaching) -> io::Result<(PageView<'_>, i64)> {
+        if let Some((slab_page_id, slot)) = Self::slab_record(page_id) {
+            let slab = self.read_slab_page(slab_page_id, SlabKind::Document)?;
+            let record = slab.record(slot).ok_or_else(|| Self::corrupt("slab slot"))?;
+            return Ok((PageView::Owned(record.to_vec()), -1));
+        }
+        let header = self.read_page_header(page_id)?;
+        Self::check_page_type(page_id, &header, FLAG_DATA_PAGE)?;
+        if header.flags & FLAG_COMPRESSED == 0 {
+            let guard = self.mmap.read();
+            if let Some(mmap) = guard.as_ref() {
+                let length = header.data_length as usize;
+                if header.data_length < 0 || length as u64 > self.config.page_size - self.config.page_header_size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid page data length"));
+                }
+                let offset = self.payload_offset(page_id)?;
+                if let Some(range) = Self::mmap_range(mmap, offset, length).filter(|_| self.in_file(offset, length)) {
+                    if !self.quick_mode.load(std::sync::atomic::Ordering::SeqCst) && self.compute_crc(&mmap[range.clone()]) != header.crc {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch"));
+                    }
+                    return Ok((PageView::Mapped(guard, range), header.next_page_id));
+                }
+            }
+        }
+        Ok((PageView::Owned(self.read_raw_page_as(page_id, caching)?), header.next_page_id))
+    }
+
+    /// Copies a page payload, starting skip bytes in, into dst; for mapped pages this is the
+    /// only copy made. Returns (bytes copied, payload length, next page).
+    fn copy_page_into(&self, page_id: i64, skip: usize, dst: &mut [u8]) -> io::Result<(usize, usize, i64)> {
+        let (payload, next_page_id) = self.view_page(page_id)?;
+        let start = skip.min(payload.len());
+        let copied = (payload.len() - start).min(dst.len());
+        dst[..copied].copy_from_slice(&payload[start..start + copied]);
+        Ok((copied, payload.len(), next_page_id))
+    }
+
+    /// Reads up to dst_len bytes of a document, starting at offset, straight into a caller buffer.
+    /// Returns the number of bytes written; 0 at or past the end of the document.
+    /// Safety: dst must be valid for dst_len writable bytes and not aliased for the duration of the call.
+    unsafe fn read_into(&self, path: &CxxString, offset: u64, dst: *mut u8, dst_len: usize) -> io::Result<usize> {
+        self.ensure_open()?;
+        let dst = Self::caller_buffer(dst, dst_len)?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        let doc = self.visible_document(&index, id)?;
+        let mut skip = offset;
+        let mut written = 0;
+        let mut current_page_id = doc.first_page_id;
+        while current_page_id != -1 && written < dst.len() {
+            let skip_in_page = skip.min(usize::MAX as u64) as usize;
+            let (copied, length, next_page_id) = self.copy_page_into(current_page_id, skip_in_page, &mut dst[written..])?;
+            skip = skip.saturating_sub(length as u64);
+            written += copied;
+            current_page_id = next_page_id;
+        }
+        Ok(written)
+    }
+
+    /// Every path starting with prefix, in order; an empty prefix or "/" lists them all.
+    /// Each path appears once, so a document bound to several of them appears once for each;
+    /// search_documents can group them. Gathered a page at a time, so writers are not held
+    /// off for the whole listing.
+    fn search_paths(&self, prefix: &CxxString) -> io::Result<CxxVector<CxxString>> {
+        self.ensure_open()?;
+        let prefix = self.validate_prefix(prefix.to_string_lossy().as_ref())?;
+        let mut cxx_results = cxx::CxxVector::new();
+        let mut after = String::new();
+        loop {
+            let page = self.paths_page(&prefix, &after, SEARCH_PAGE_SIZE)?;
+            for (path, _) in &page {
+                cxx_results.push(cxx::CxxString::from(path.as_str()));
+            }
+            match page.last() {
+                Some((last, _)) if page.len() == SEARCH_PAGE_SIZE => after = last.clone(),
+                _ => break,
+            }
+        }
+        Ok(cxx_results)
+    }
+
+    /// At most limit paths starting with prefix that sort after after, in order. Pass the last
+    /// path of one page as after to get the next; an empty after starts from the beginning.
+    fn search_paths_page(&self, prefix: &CxxString, after: &CxxString, limit: usize) -> io::Result<Vec<String>> {
+        self.ensure_open()?;
+        let prefix = self.validate_prefix(prefix.to_string_lossy().as_ref())?;
+        let after = after.to_string_lossy();
+        Ok(self.paths_page(&prefix, &after, limit)?.into_iter().map(|(path, _)| path).collect())
+    }
+
+    /// Every path in order, for tab completion. Served from a snapshot that lasts until a
+    /// path is added, removed or rebound, so repeated calls do not walk the paths again. cxx
+    /// hands C++ an owned vector, so each call copies the snapshot; Rust callers share it
+    /// through all_paths_sorted instead.
+    fn get_all_paths_sorted(&self) -> io::Result<Vec<String>> {
+        Ok(self.all_paths_sorted()?.as_ref().clone())
+    }
+
+    /// The snapshot get_all_paths_sorted copies from, shared until a path changes.
+    pub fn all_paths_sorted(&self) -> io::Result<Arc<Vec<String>>> {
+        self.ensure_open()?;
+        self.path_snapshot()
+    }
+
+    /// Up to max paths starting with partial, in order, from the same snapshot as
+    /// get_all_paths_sorted. partial is checked and normalized like a search prefix, so it
+    /// matches the stored form of what was typed.
+    fn complete_path(&self, partial: &CxxString, max: usize) -> io::Result<Vec<String>> {
+        self.ensure_open()?;
+        let partial = self.validate_prefix(partial.to_string_lossy().as_ref())?;
+        let paths = self.path_snapshot()?;
+        let start = paths.partition_point(|path| path.as_str() < partial.as_str());
+        Ok(paths[start..].iter().take_while(|path| path.starts_with(partial.as_str())).take(max).cloned().collect())
+    }
+
+    /// The documents bound to paths starting with prefix. Flat results pair each path with its
+    /// document, like search_paths; grouped results give each document once, in the order of
+    /// its first path, with all of its paths under prefix.
+    fn search_documents(&self, prefix: &CxxString, grouped: bool) -> io::Result<Vec<ffi::DocumentPaths>> {
+        self.ensure_open()?;
+        let prefix = self.validate_prefix(prefix.to_string_lossy().as_ref())?;
+        let matches = self.paths_with_prefix(&prefix)?;
+        if !grouped {
+            return Ok(matches.into_iter().map(|(path, id)| ffi::DocumentPaths { uuid: id.to_string(), paths: vec![path] }).collect());
+        }
+        let mut results: Vec<ffi::DocumentPaths> = Vec::new();
+        let mut positions: HashMap<Uuid, usize> = HashMap::new();
+        for (path, id) in matches {
+            match positions.get(&id) {
+                Some(&position) => results[position].paths.push(path),
+                None => {
+                    positions.insert(id, results.len());
+                    results.push(ffi::DocumentPaths { uuid: id.to_string(), paths: vec![path] });
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Every path matching the glob pattern, in order: * and ? match within a component, ** any
+    /// number of whole components, and [a-z] or [!a-z] one character of a class.
+    fn search_glob(&self, pattern: &CxxString) -> io::Result<Vec<String>> {
+        self.ensure_open()?;
+        let pattern = PathPattern::compile(pattern.to_string_lossy().as_ref())?;
+        Ok(self.paths_matching(&pattern)?.into_iter().map(|(path, _)| path).collect())
+    }
+
+    /// Every resolved path starting with prefix, in order. The trie is keyed by reversed paths,
+    /// which suits lookups but not prefixes, so this reads the path order instead.
+    fn search_path_list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.ensure_open()?;
+        let prefix = self.validate_prefix(prefix)?;
+        Ok(self.paths_with_prefix(&prefix)?.into_iter().map(|(path, _)| path).collect())
+    }
+
+    /// Visits every terminal below page_id with its reversed path, reading one page per node.
+    fn trie_for_each_terminal(&self, page_id: i64, fragment: &mut String, f: &mut dyn FnMut(&str, Uuid)) -> io::Result<()> {
+        let node = self.read_trie_node(page_id)?;
+        let len = fragment.len();
+        fragment.push_str(&node.edge);
+        if let Some(id) = node.document_id {
+            f(fragment, id);
+        }
+        for &child_id in node.children.values() {
+            self.trie_for_each_terminal(child_id, fragment, f)?;
+        }
+        fragment.truncate(len);
+        Ok(())
+    }
+
+    fn count_paths(&self, prefix: &CxxString) -> io::Result<u64> {
+        self.ensure_open()?;
+        let prefix = self.validate_prefix(prefix.to_string_lossy().as_ref())?;
+        Ok(self.paths_with_prefix(&prefix)?.len() as u64)
+    }
+
+    /// Read from the header's count, without touching the index.
+    fn document_count(&self) -> io::Result<u64> {
+        self.ensure_open()?;
+        Ok(self.document_count.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    fn document_size(&self, doc: &Document) -> io::Result<u64> {
+        let mut size = 0u64;
+        let mut current_page_id = doc.first_page_id;
+        while current_page_id != -1 {
+            let (page, next_page_id) = self.view_page(current_page_id)?;
+            size += page.len() as u64;
+            current_page_id = next_page_id;
+        }
+        Ok(size)
+    }
+
+    fn document_info(&self, path: &str, doc: &Document) -> io::Result<ffi::DocumentInfo> {
+        Ok(ffi::DocumentInfo {
+            path: path.to_string(),
+            uuid: doc.id.to_string(),
+            size: doc.size,
+            version: doc.current_version,
+            expires_at: doc.expires_at,
+            flags: doc.flags,
+            page_count: doc.page_count,
+        })
+    }
+
+    /// Changes whenever the path is rewritten, deleted and recreated, or moved to another chain.
+    fn version_stamp(doc: &Document) -> u64 {
+        let mut bytes = doc.id.as_bytes().to_vec();
+        bytes.extend_from_slice(&doc.first_page_id.to_le_bytes());
+        bytes.extend_from_slice(&doc.current_version.to_le_bytes());
+        bytes.extend_from_slice(&doc.checksum.to_le_bytes());
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3))
+    }
+
+    /// Lists where a document's payload lives in the database file so callers can do their own IO.
+    /// Extents are not pinned: read them, then call validate_extents with the stamp to make sure
+    /// the document was not rewritten in the meantime.
+    fn get_extents(&self, path: &CxxString) -> io::Result<ffi::DocumentExtents> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        let doc = self.visible_document(&index, id)?;
+        if let Some((page_id, slot)) = Self::slab_record(doc.first_page_id) {
+            // Slab records are uncompressed and never move within their page
+            let (offset, length) = self.read_slab_page(page_id, SlabKind::Document)?.slot(slot).ok_or_else(|| Self::corrupt("slab slot"))?;
+            let extent = ffi::Extent { file_offset: self.payload_offset(page_id)? + offset as u64, length: length as u64 };
+            return Ok(ffi::DocumentExtents { eligible: true, version_stamp: Self::version_stamp(doc), extents: vec![extent] });
+        }
+        let mut extents = Vec::new();
+        // Only documents stored entirely uncompressed can be read straight from the file
+        let mut eligible = true;
+        let mut current_page_id = doc.first_page_id;
+        while current_page_id != -1 {
+            let header = self.read_page_header(current_page_id)?;
+            if header.flags & FLAG_COMPRESSED != 0 {
+                eligible = false;
+                extents.clear();
+                break;
+            }
+            if header.data_length > 0 {
+                extents.push(ffi::Extent {
+                    file_offset: self.payload_offset(current_page_id)?,
+                    length: header.data_length as u64,
+                });
+            }
+            current_page_id = header.next_page_id;
+        }
+        Ok(ffi::DocumentExtents { eligible, version_stamp: Self::version_stamp(doc), extents })
+    }
+
+    fn validate_extents(&self, path: &CxxString, version_stamp: u64) -> io::Result<bool> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = match self.get_document_id_by_path(&rust_path) {
+            Ok(id) => id,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        Ok(self.read_index()?.get(&id).map_or(false, |doc| Self::version_stamp(doc) == version_stamp))
+    }
+
+    fn stat(&self, path: &CxxString) -> io::Result<ffi::DocumentInfo> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        let doc = self.visible_document(&index, id)?;
+        self.document_info(&rust_path, doc)
+    }
+
+    /// Like search_paths, but resolves each match against the index in the same pass.
+    fn search_paths_detailed(&self, prefix: &CxxString) -> io::Result<Vec<ffi::DocumentInfo>> {
+        self.ensure_open()?;
+        let prefix = self.validate_prefix(prefix.to_string_lossy().as_ref())?;
+        let matches = self.paths_with_prefix(&prefix)?;
+        let index = self.read_index()?;
+        let mut results = Vec::with_capacity(matches.len());
+        for (path, id) in matches {
+            if let Some(doc) = index.get(&id) {
+                results.push(self.document_info(&path, doc)?);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Reads a node through the trie cache. Node addresses are records in trie slab pages, or
+    /// whole pages for nodes too large for a slab and for tries written before v13.
+    fn read_trie_node(&self, address: i64) -> io::Result<ReverseTrieNode> {
+        if let Some(node) = self.trie_cache.lock().get(&address) {
+            return Ok(node.clone());
+        }
+        let page_id = match Self::slab_record(address) {
+            Some((slab_page_id, slot)) => {
+                let slab = self.read_slab_page(slab_page_id, SlabKind::Trie)?;
+                let record = slab.record(slot).ok_or_else(|| Self::corrupt("trie slot"))?;
+                match Self::trie_forward(record) {
+                    Some(page_id) => page_id,
+                    None => {
+                        let node = self.deserialize_trie_node(record)?;
+                        self.trie_cache.lock().put(address, node.clone());
+                        return Ok(node);
+                    }
+                }
+            }
+            None => address,
+        };
+        let node = self.deserialize_trie_node(&self.read_page_of_type(page_id, FLAG_TRIE_PAGE, PageCaching::Own)?)?;
+        self.trie_cache.lock().put(address, node.clone());
+        Ok(node)
+    }
+
+    /// The page a slab record forwards to when its node outgrew the slab.
+    fn trie_forward(record: &[u8]) -> Option<i64> {
+        if record.len() == 12 && record[..4] == TRIE_FORWARD_MARKER.to_le_bytes() {
+            Some(i64::from_le_bytes(record[4..].try_into().unwrap()))
+        } else {
+            None
+        }
+    }
+
+    /// Writes a node at its address, which never changes: a node that outgrows its slab page
+    /// moves to a page of its own and leaves a forward in its slot.
+    fn write_trie_node(&self, node: &ReverseTrieNode) -> io::Result<()> {
+        let data = self.serialize_trie_node(node)?;
+        match Self::slab_record(node.self_page_id) {
+            Some((page_id, slot)) => {
+                let _open = self.open_trie_slab.lock();
+                let mut slab = self.read_slab_page(page_id, SlabKind::Trie)?;
+                let forward = Self::trie_forward(slab.record(slot).ok_or_else(|| Self::corrupt("trie slot"))?);
+                match forward {
+                    Some(target) => self.write_trie_page(target, &data)?,
+                    None if slab.replace(slot, &data) => self.write_slab_page(page_id, &slab, SlabKind::Trie)?,
+                    None => {
+                        let target = self.allocate_page()?;
+                        self.write_trie_page(target, &data)?;
+                        let mut record = TRIE_FORWARD_MARKER.to_le_bytes().to_vec();
+                        record.extend_from_slice(&target.to_le_bytes());
+                        // No node is shorter than a forward, so this always rewrites in place
+                        slab.replace(slot, &record);
+                        self.write_slab_page(page_id, &slab, SlabKind::Trie)?;
+                    }
+                }
+            }
+            None => self.write_trie_page(node.self_page_id, &data)?,
+        }
+        self.trie_cache.lock().put(node.self_page_id, node.clone());
+        Ok(())
+    }
+
+    /// Writes a node that has a page to itself.
+    fn write_trie_page(&self, page_id: i64, data: &[u8]) -> io::Result<()> {
+        self.write_raw_page(page_id, data, self.next_page_version(page_id), PageLinks::single(FLAG_TRIE_PAGE))
+    }
+
+    /// Allocates a node, reserving a slab slot the size it has now. Nothing points at it
+    /// until it and its parent are written.
+    fn new_trie_node(&self, edge: &str, parent_page_id: i64, document_id: Option<Uuid>) -> io::Result<ReverseTrieNode> {
+        let mut node = ReverseTrieNode {
+            edge: edge.to_string(),
+            parent_page_id,
+            self_page_id: -1,
+            document_id,
+            children: BTreeMap::new(),
+        };
+        node.self_page_id = match self.insert_slab_record(SlabKind::Trie, &self.serialize_trie_node(&node)?)? {
+            Some(address) => address,
+            None => self.allocate_page()?,
+        };
+        Ok(node)
+    }
+
+    fn free_trie_node(&self, address: i64) -> io::Result<()> {
+        self.trie_cache.lock().pop(&address);
+        match Self::slab_record(address) {
+            Some((page_id, slot)) => {
+                let forward = self.read_slab_page(page_id, SlabKind::Trie)?.record(slot).and_then(Self::trie_forward);
+                if let Some(target) = forward {
+                    self.free_page(target)?;
+                }
+                self.free_slab_record(SlabKind::Trie, page_id, slot, false)
+            }
+            None => self.free_page(address),
+        }
+    }
+
+    /// The pages a node occupies: its slab page and any page it was forwarded to, or its own page.
+    fn trie_node_pages(&self, address: i64, pages: &mut Vec<i64>) -> io::Result<()> {
+        match Self::slab_record(address) {
+            Some((page_id, slot)) => {
+                pages.push(page_id);
+                if let Some(target) = self.read_slab_page(page_id, SlabKind::Trie)?.record(slot).and_then(Self::trie_forward) {
+                    pages.push(target);
+                }
+            }
+            None => pages.push(address),
+        }
+        Ok(())
+    }
+
+    fn trie_insert(&self, path: &str, id: Uuid) -> io::Result<()> {
+        let mut root_page_id = self.trie_root.read().page_id;
+        if root_page_id == -1 {
+            let root = self.new_trie_node("", -1, None)?;
+            self.write_trie_node(&root)?;
+            root_page_id = root.self_page_id;
+            *self.trie_root.write() = VersionedLink { page_id: root_page_id, version: 0 };
+            self.write_roots()?;
+        }
+        // A change that failed partway leaves the path uncached, to be resolved afresh
+        let result = self.trie_insert_at(root_page_id, path, id).and_then(|_| self.path_hash_insert(path, id));
+        match result {
+            Ok(()) => self.update_path_cache(path, Some(id)),
+            Err(_) => self.clear_path_cache(),
+        }
+        result
+    }
+
+    /// Brings the path cache and path order in line with a trie change: path now resolves to
+    /// id, or to nothing. Every trie insert and delete ends here, so neither outlives the trie.
+    fn update_path_cache(&self, path: &str, id: Option<Uuid>) {
+        {
+            let mut path_cache = self.path_cache.lock();
+            self.path_cache_epoch.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            match id {
+                Some(id) => {
+                    path_cache.put(path.to_string(), id);
+                }
+                None => {
+                    path_cache.pop(path);
+                }
+            }
+        }
+        // Applied after the trie changed, so a build racing the change sees it or is corrected here
+        if let Some(order) = self.path_order.write().as_mut() {
+            match id {
+                Some(id) => order.insert(path.to_string(), id),
+                None => order.remove(path),
+            };
+        }
+        self.path_generation.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// For changes no single path describes: another process's writes, a rebuilt trie, or a
+    /// trie change that failed partway.
+    fn clear_path_cache(&self) {
+        {
+            let mut path_cache = self.path_cache.lock();
+            self.path_cache_epoch.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            path_cache.clear();
+        }
+        *self.path_order.write() = None;
+        self.path_generation.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Every resolved path in order, shared with other callers until a path changes. The
+    /// generation is read before the order, and bumped only after the order changes, so a
+    /// snapshot is never newer than its generation says.
+    fn path_snapshot(&self) -> io::Result<Arc<Vec<String>>> {
+        let generation = self.path_generation.load(std::sync::atomic::Ordering::SeqCst);
+        if let Some((snapshot_generation, snapshot)) = self.path_snapshot.lock().as_ref() {
+            if *snapshot_generation == generation {
+                return Ok(snapshot.clone());
+            }
+        }
+        let paths: Arc<Vec<String>> = Arc::new(self.with_path_order(|order| order.keys().cloned().collect())?);
+        let bytes: u64 = paths.iter().map(|path| (path.len() + std::mem::size_of::<String>()) as u64).sum();
+        *self.path_snapshot.lock() = if bytes <= self.config.path_snapshot_budget { Some((generation, paths.clone())) } else { None };
+        Ok(paths)
+    }
+
+    /// Runs f over every resolved path in order, first building the order from the trie if
+    /// it is not loaded. That walk reads the whole trie once; afterwards each trie change
+    /// keeps the order current, and queries read only the paths they return.
+    fn with_path_order<R>(&self, f: impl FnOnce(&BTreeMap<String, Uuid>) -> R) -> io::Result<R> {
+        if let Some(order) = self.path_order.read().as_ref() {
+            return Ok(f(order));
+        }
+        let mut order = self.path_order.write();
+        if order.is_none() {
+            let mut paths = BTreeMap::new();
+            let root_page_id = self.trie_root.read().page_id;
+            if root_page_id != -1 {
+                self.trie_for_each_terminal(root_page_id, &mut String::new(), &mut |reversed, id| {
+                    paths.insert(reversed.chars().rev().collect(), id);
+                })?;
+            }
+            *order = Some(paths);
+        }
+        let order = parking_lot::RwLockWriteGuard::downgrade(order);
+        Ok(f(order.as_ref().unwrap()))
+    }
+
+    /// The resolved paths starting with prefix, in order.
+    fn paths_with_prefix(&self, prefix: &str) -> io::Result<Vec<(String, Uuid)>> {
+        self.paths_page(prefix, "", usize::MAX)
+    }
+
+    /// At most limit of the resolved paths starting with prefix and sorting after after, in order.
+    fn paths_page(&self, prefix: &str, after: &str, limit: usize) -> io::Result<Vec<(String, Uuid)>> {
+        let start = if after >= prefix { std::ops::Bound::Excluded(after) } else { std::ops::Bound::Included(prefix) };
+        self.with_path_order(|order| order.range::<str, _>((start, std::ops::Bound::Unbounded))
+            .take_while(|(path, _)| path.starts_with(prefix))
+            .take(limit)
+            .map(|(path, id)| (path.clone(), *id))
+            .collect())
+    }
+
+    /// The resolved paths matching pattern, in order. Only paths under the pattern's literal
+    /// prefix are read, and a directory the pattern rules out is skipped whole.
+    fn paths_matching(&self, pattern: &PathPattern) -> io::Result<Vec<(String, Uuid)>> {
+        let prefix = pattern.literal_prefix();
+        self.with_path_order(|order| {
+            let mut results = Vec::new();
+            let mut from = prefix.clone();
+            loop {
+                let mut skip_to = None;
+                for (path, id) in order.range::<str, _>((std::ops::Bound::Included(from.as_str()), std::ops::Bound::Unbounded))
+                    .take_while(|(path, _)| path.starts_with(prefix.as_str()))
+                {
+                    match pattern.check(path) {
+                        PatternMatch::Match => results.push((path.clone(), *id)),
+                        PatternMatch::NoMatch => {}
+                        PatternMatch::NoMatchUnder(end) => {
+                            // '0' follows '/', so this sorts after every path under the directory
+                            skip_to = Some(format!("{}0", &path[..end - 1]));
+                            break;
+                        }
+                    }
+                }
+                match skip_to {
+                    Some(next) => from = next,
+                    None => return results,
+                }
+            }
+        })
+    }
+
+    fn trie_insert_at(&self, root_page_id: i64, path: &str, id: Uuid) -> io::Result<()> {
+        let reversed: String = path.chars().rev().collect();
+        let mut node = self.read_trie_node(root_page_id)?;
+        let mut remaining = reversed.as_str();
+        loop {
+            let common_prefix: usize = remaining.chars()
+                .zip(node.edge.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a.len_utf8())
+                .sum();
+            if common_prefix < node.edge.len() {
+                // Split: a new upper node takes this node's slot under its parent
+                let mut upper = self.new_trie_node(&node.edge[..common_prefix], node.parent_page_id, None)?;
+                let mut parent = self.read_trie_node(node.parent_page_id)?;
+                parent.children.insert(node.edge.chars().next().unwrap(), upper.self_page_id);
+                node.edge = node.edge[common_prefix..].to_string();
+                node.parent_page_id = upper.self_page_id;
+                upper.children.insert(node.edge.chars().next().unwrap(), node.self_page_id);
+                remaining = &remaining[common_prefix..];
+                if remaining.is_empty() {
+                    upper.document_id = Some(id);
+                } else {
+                    let leaf = self.new_trie_node(remaining, upper.self_page_id, Some(id))?;
+                    upper.children.insert(remaining.chars().next().unwrap(), leaf.self_page_id);
+                    self.write_trie_node(&leaf)?;
+                }
+                self.write_trie_node(&node)?;
+                self.write_trie_node(&upper)?;
+                self.write_trie_node(&parent)?;
+                return Ok(());
+            }
+            remaining = &remaining[common_prefix..];
+            if remaining.is_empty() {
+                node.document_id = Some(id);
+                return self.write_trie_node(&node);
+            }
+            let first_char = remaining.chars().next().unwrap();
+            match node.children.get(&first_char) {
+                Some(&child_id) => node = self.read_trie_node(child_id)?,
+                None => {
+                    let leaf = self.new_trie_node(remaining, node.self_page_id, Some(id))?;
+                    node.children.insert(first_char, leaf.self_page_id);
+                    self.write_trie_node(&leaf)?;
+                    return self.write_trie_node(&node);
+                }
+            }
+        }
+    }
+
+    fn trie_collect_pages(&self, page_id: i64, pages: &mut Vec<i64>) -> io::Result<()> {
+        let node = self.read_trie_node(page_id)?;
+        self.trie_node_pages(page_id, pages)?;
+        for &child_id in node.children.values() {
+            self.trie_collect_pages(child_id, pages)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the path trie from the paths recorded in the document index.
+    /// The new trie is built beside the old one and published by swapping
+    /// trie_root; old trie pages are then returned to the free list.
+    /// Returns the number of paths restored.
+    fn rebuild_trie(self: Pin<&mut Self>) -> io::Result<u64> {
+        let _writes = self.begin_write()?;
+        self.rebuild_trie_from_index()
+    }
+
+    fn rebuild_trie_from_index(&self) -> io::Result<u64> {
+        let index = self.read_index()?;
+        // Fresh slab pages, so none of the new nodes share a page with the old trie
+        *self.open_trie_slab.lock() = -1;
+        let root = self.new_trie_node("", -1, None)?;
+        self.write_trie_node(&root)?;
+        let mut restored = 0u64;
+        for doc in index.values() {
+            for binding in &doc.paths {
+                self.trie_insert_at(root.self_page_id, &binding.path, doc.id)?;
+                restored += 1;
+            }
+        }
+        let mut live_pages = Vec::new();
+        self.trie_collect_pages(root.self_page_id, &mut live_pages)?;
+        live_pages.sort_unstable();
+        // Find old trie pages by type rather than by walking the old trie, which may be unreadable
+        let mut stale_pages = Vec::new();
+        for page_id in FIRST_PAGE_ID..self.page_count() {
+            if live_pages.binary_search(&page_id).is_ok() {
+                continue;
+            }
+            if let Ok(header) = self.read_page_header(page_id) {
+                if header.flags & FLAG_TRIE_PAGE != 0 {
+                    stale_pages.push(page_id);
+                }
+            }
+        }
+        {
+            let mut trie_root = self.trie_root.write();
+            *trie_root = VersionedLink { page_id: root.self_page_id, version: trie_root.version + 1 };
+        }
+        self.write_roots()?;
+        for page_id in stale_pages {
+            self.free_page(page_id)?;
+        }
+        self.trie_cache.lock().clear();
+        // The old trie may have resolved paths the index no longer backs
+        self.clear_path_cache();
+        Ok(restored)
+    }
+
+    /// Walks the trie once and reports every structural inconsistency found,
+    /// then cross-checks terminals against the document index.
+    fn check_trie(&self) -> io::Result<ffi::TrieReport> {
+        self.ensure_open()?;
+        let index = self.read_index()?;
+        let mut report = ffi::TrieReport { nodes_checked: 0, paths_checked: 0, violations: Vec::new() };
+        let mut violation = |kind, page_id, fragment: &str| {
+            report.violations.push(ffi::TrieViolation { kind, page_id, path_fragment: fragment.chars().rev().collect() });
+        };
+        let mut reached: HashMap<String, Uuid> = HashMap::new();
+        let root_page_id = self.trie_root.read().page_id;
+        let mut visited = HashSet::new();
+        let mut nodes_checked = 0u64;
+        // (page_id, expected parent, key under the parent, reversed path up to and excluding this node)
+        let mut stack = if root_page_id == -1 { vec![] } else { vec![(root_page_id, -1i64, None::<char>, String::new())] };
+        while let Some((page_id, expected_parent, key, fragment)) = stack.pop() {
+            if !visited.insert(page_id) {
+                violation(ffi::TrieViolationKind::CyclicReference, page_id, &fragment);
+                continue;
+            }
+            match self.read_page_header(Self::slab_record(page_id).map_or(page_id, |(slab_page_id, _)| slab_page_id)) {
+                Ok(header) if header.flags & FLAG_TRIE_PAGE != 0 => {}
+                _ => {
+                    violation(ffi::TrieViolationKind::DanglingChild, page_id, &fragment);
+                    continue;
+                }
+            }
+            let node = match self.read_trie_node(page_id) {
+                Ok(node) => node,
+                Err(_) => {
+                    violation(ffi::TrieViolationKind::UnreadableNode, page_id, &fragment);
+                    continue;
+                }
+            };
+            nodes_checked += 1;
+            let fragment = format!("{}{}", fragment, node.edge);
+            if node.self_page_id != page_id {
+                violation(ffi::TrieViolationKind::SelfPageMismatch, page_id, &fragment);
+            }
+            if node.parent_page_id != expected_parent {
+                violation(ffi::TrieViolationKind::ParentMismatch, page_id, &fragment);
+            }
+            if key.is_some() && node.edge.chars().next() != key {
+                violation(ffi::TrieViolationKind::SiblingEdgeConflict, page_id, &fragment);
+            }
+            if let Some(id) = node.document_id {
+                let path: String = fragment.chars().rev().collect();
+                // Also orphaned: a path its document no longer binds, as a crash mid-rename leaves
+                if !index.get(&id).is_some_and(|doc| doc.paths.iter().any(|binding| binding.path == path)) {
+                    violation(ffi::TrieViolationKind::OrphanDocument, page_id, &fragment);
+                }
+                reached.insert(path, id);
+            }
+            for (&ch, &child_id) in &node.children {
+                stack.push((child_id, page_id, Some(ch), fragment.clone()));
+            }
+        }
+        let mut paths_checked = 0u64;
+        for doc in index.values() {
+            for binding in &doc.paths {
+                paths_checked += 1;
+                if reached.get(&binding.path) != Some(&doc.id) {
+                    violation(ffi::TrieViolationKind::UnreachablePath, -1, &binding.path.chars().rev().collect::<String>());
+                }
+            }
+        }
+        report.nodes_checked = nodes_checked;
+        report.paths_checked = paths_checked;
+        Ok(report)
+    }
+
+    fn verify_db(&self, deep: bool) -> io::Result<ffi::VerifyReport> {
+        self.ensure_open()?;
+        // Walked afresh rather than served from memory, so a damaged tree shows
+        let index_ok = self.load_index(self.document_index_root.read().page_id).is_ok();
+        let mut pages_checked = 0u64;
+        let mut corrupt_pages = Vec::new();
+        let mut missing_dictionaries = Vec::new();
+        if deep {
+            self.scan_page_headers(self.page_count(), |page_id, header| {
+                let header = match header {
+                    Some(header) => header,
+                    None => {
+                        corrupt_pages.push(page_id);
+                        return Ok(());
+                    }
+                };
+                if header.flags & (FLAG_DATA_PAGE | FLAG_TRIE_PAGE | FLAG_INDEX_PAGE | FLAG_HASH_PAGE | FLAG_SLAB_PAGE) == 0 {
+                    return Ok(());
+                }
+                pages_checked += 1;
+                if header.flags & FLAG_SLAB_PAGE != 0 {
+                    if SlabPage::from_payload(self.read_raw_page_as(page_id, PageCaching::Own)?).is_err() {
+                        corrupt_pages.push(page_id);
+                    }
+                    return Ok(());
+                }
+                let dictionary = header.padding[2];
+                if header.flags & FLAG_COMPRESSED != 0 && header.padding[0] == CODEC_ZSTD && dictionary != 0
+                    && !self.dictionaries.read().contains_key(&dictionary) {
+                    if !missing_dictionaries.contains(&dictionary) {
+                        missing_dictionaries.push(dictionary);
+                    }
+                    return Ok(());
+                }
+                if self.read_raw_page_as(page_id, PageCaching::Peek).is_err() {
+                    corrupt_pages.push(page_id);
+                }
+                Ok(())
+            })?;
+        }
+        let trie = if deep && index_ok {
+            self.check_trie()?
+        } else {
+            ffi::TrieReport { nodes_checked: 0, paths_checked: 0, violations: Vec::new() }
+        };
+        let document_count_ok = index_ok && self.read_index()?.len() as u64 == self.document_count.load(std::sync::atomic::Ordering::SeqCst);
+        let mut size_mismatches = Vec::new();
+        if deep && index_ok {
+            for doc in self.read_index()?.values() {
+                // Chains that cannot be read are already among the corrupt pages
+                if let Ok((size, page_count)) = self.measure_chain(doc.first_page_id) {
+                    if size != doc.size || page_count != doc.page_count {
+                        size_mismatches.push(doc.paths.first().map_or_else(|| doc.id.to_string(), |binding| binding.path.clone()));
+                    }
+                }
+            }
+        }
+        Ok(ffi::VerifyReport { pages_checked, corrupt_pages, index_ok, trie, paths_restored: 0, missing_dictionaries, document_count_ok, size_mismatches })
+    }
+
+    /// Opens an iterator over every page of the file as it is now. The pages are summarized under
+    /// the exclusive write locks, so no write lands partway through; later writes do not show.
+    fn open_page_iterator(&self) -> io::Result<i64> {
+        let pages = {
+            let _writes = self.begin_exclusive_write()?;
+            let mut pages = Vec::new();
+            self.scan_page_headers(self.page_count(), |page_id, header| {
+                pages.push(match header {
+                    Some(header) => ffi::PageInfo {
+                        page_id,
+                        flags: header.flags,
+                        version: header.version,
+                        data_length: header.data_length,
+                        crc_ok: self.page_crc_ok(page_id, &header)?,
+                    },
+                    None => ffi::PageInfo { page_id, flags: 0, version: 0, data_length: 0, crc_ok: false },
+                });
+                Ok(())
+            })?;
+            pages
+        };
+        let iterator_id = self.next_stream_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.page_iterators.lock().insert(iterator_id, PageIterator { pages, position: 0 });
+        Ok(iterator_id)
+    }
+
+    /// The next max_pages pages of the iterator in page order; empty once it is exhausted.
+    fn page_iterator_next(&self, iterator_id: i64, max_pages: usize) -> io::Result<Vec<ffi::PageInfo>> {
+        self.ensure_open()?;
+        let mut iterators = self.page_iterators.lock();
+        let iterator = iterators.get_mut(&iterator_id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Page iterator not found"))?;
+        let end = iterator.pages.len().min(iterator.position.saturating_add(max_pages));
+        let batch = iterator.pages[iterator.position..end].to_vec();
+        iterator.position = end;
+        Ok(batch)
+    }
+
+    fn close_page_iterator(&self, iterator_id: i64) {
+        self.page_iterators.lock().remove(&iterator_id);
+    }
+
+    /// A whole page, header included, exactly as stored. Only for databases opened with admin set.
+    fn read_raw_page_admin(&self, page_id: i64) -> io::Result<Vec<u8>> {
+        self.ensure_open()?;
+        if !self.config.admin {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Raw page reads need the admin option"));
+        }
+        if page_id < 0 || page_id >= self.page_count() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
+        }
+        let mut buffer = vec![0u8; self.config.page_size as usize];
+        let _page = self.lock_stats.acquire(TrackedLock::PageStripes, || self.page_lock(page_id).try_read(), || self.page_lock(page_id).read());
+        self.read_bytes_at(self.page_offset(page_id)?, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Runs a deep verify, then rebuilds the trie from the index if it is inconsistent,
+    /// recounts the documents if the header's count is off, and remeasures documents whose
+    /// stored size or page count disagrees with their chain.
+    fn repair_db(self: Pin<&mut Self>) -> io::Result<ffi::VerifyReport> {
+        let _writes = self.begin_write()?;
+        let mut report = self.verify_db(true)?;
+        if report.index_ok && !report.trie.violations.is_empty() {
+            report.paths_restored = self.rebuild_trie_from_index()?;
+        }
+        if report.index_ok && !report.document_count_ok {
+            self.document_count.store(self.read_index()?.len() as u64, std::sync::atomic::Ordering::SeqCst);
+            self.write_roots()?;
+        }
+        if !report.size_mismatches.is_empty() {
+            let mut index = self.read_index()?;
+            for doc in index.values_mut() {
+                if let Ok((size, page_count)) = self.measure_chain(doc.first_page_id) {
+                    doc.size = size;
+                    doc.page_count = page_count;
+                }
+            }
+            self.write_index(&index)?;
+        }
+        Ok(report)
+    }
+
+    fn delete_by_path(self: Pin<&mut Self>, path: &CxxString) -> io::Result<()> {
+        self.delete_by_path_ex(path, false)
+    }
+
+    /// Like delete_by_path; force also deletes READONLY documents.
+    fn delete_by_path_ex(self: Pin<&mut Self>, path: &CxxString, force: bool) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        self.commit_changes(vec![StagedChange::Delete { path: rust_path, force, secure: false }])
+    }
+
+    /// Like delete_by_path_ex, and zeroes the pages of the document's current and retained
+    /// versions as they are freed, whatever the database's secure_delete option says. A chain
+    /// still shared through dedup or held open by a stream is zeroed when it is finally freed.
+    fn delete_by_path_secure(self: Pin<&mut Self>, path: &CxxString, force: bool) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        self.commit_changes(vec![StagedChange::Delete { path: rust_path, force, secure: true }])
+    }
+
+    /// Moves the binding at from to to, which must not resolve yet; the document, its other
+    /// paths and its tags are unchanged.
+    fn rename_path(self: Pin<&mut Self>, from: &CxxString, to: &CxxString) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let from = self.validate_path(from.to_string_lossy().as_ref())?;
+        let to = self.validate_path(to.to_string_lossy().as_ref())?;
+        self.commit_changes(vec![StagedChange::Rename { from, to }])
+    }
+
+    /// Applies path changes as an implicit transaction, so they land whole or not at all.
+    fn commit_changes(&self, changes: Vec<StagedChange>) -> io::Result<()> {
+        let mut tx = Transaction::new(self.commit_history.lock().sequence);
+        tx.changes = changes;
+        let result = self.apply_transaction(tx);
+        self.prune_commit_history();
+        result
+    }
+
+    /// Sets when the document at path expires, as unix time; 0 clears the expiry.
+    /// Rewriting the document clears it as well.
+    fn set_expiry(self: Pin<&mut Self>, path: &CxxString, unix_time: u64) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        doc.expires_at = unix_time;
+        self.write_index(&index)
+    }
+
+    /// Deletes every document whose expiry is at or before now, with a single index write.
+    /// READONLY documents are skipped.
+    /// Bytes count the stored pages actually freed; chains still shared through dedup are not.
+    fn purge_expired(self: Pin<&mut Self>, now: u64) -> io::Result<ffi::PurgeReport> {
+        let _writes = self.begin_write()?;
+        self.purge_expired_up_to(now, usize::MAX)
+    }
+
+    /// purge_expired, deleting at most limit documents.
+    fn purge_expired_up_to(&self, now: u64, limit: usize) -> io::Result<ffi::PurgeReport> {
+        let mut index = self.read_index()?;
+        // READONLY documents are kept past their expiry; clear the flag to let them go
+        let expired: Vec<Uuid> = index.values()
+            .filter(|doc| doc.is_expired(now) && doc.flags & DOCUMENT_READONLY == 0)
+            .map(|doc| doc.id)
+            .take(limit)
+            .collect();
+        if expired.is_empty() {
+            return Ok(ffi::PurgeReport { documents: 0, bytes_reclaimed: 0 });
+        }
+        let removed: Vec<Document> = expired.iter().filter_map(|id| index.remove(id)).collect();
+        for doc in &removed {
+            for binding in &doc.paths {
+                self.release_binding(&index, &binding.path, doc.id)?;
+            }
+        }
+        self.write_index(&index)?;
+        self.untag_documents(&removed)?;
+        let mut bytes_reclaimed = 0;
+        let mut released = HashSet::new();
+        for doc in &removed {
+            let chains = std::iter::once(doc.first_page_id).chain(doc.previous_versions.iter().map(|link| link.page_id));
+            for first_page_id in chains {
+                if first_page_id == -1 || Self::chain_referenced(&index, first_page_id) || !released.insert(first_page_id) {
+                    continue;
+                }
+                bytes_reclaimed += self.chain_stored_bytes(first_page_id)?;
+                self.release_chain(&index, first_page_id)?;
+            }
+        }
+        for doc in &removed {
+            for binding in &doc.paths {
+                self.emit_event(ffi::DocumentEventOp::Delete, &binding.path, doc.id);
+            }
+        }
+        Ok(ffi::PurgeReport { documents: removed.len() as u64, bytes_reclaimed })
+    }
+
+    /// Queues a change notification when recording is on; otherwise costs one atomic load.
+    fn emit_event(&self, op: ffi::DocumentEventOp, path: &str, id: Uuid) {
+        if let Some(mirror) = &self.mirror {
+            if let Some(sender) = mirror.sender.lock().as_ref() {
+                if sender.try_send(path.to_string()).is_err() {
+                    mirror.overflowed.store(true, std::sync::atomic::Ordering::SeqCst);
+                }
+            }
+        }
+        if !self.events.recording.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        let mut queue = self.events.queue.lock();
+        if queue.len() >= EVENT_QUEUE_CAPACITY {
+            queue.pop_front();
+            self.events.dropped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        queue.push_back(ffi::DocumentEvent { op, path: path.to_string(), uuid: id.to_string() });
+    }
+
+    /// Starts or stops queueing change events. Stopping discards anything not yet drained.
+    fn set_event_recording(&self, enabled: bool) {
+        self.events.recording.store(enabled, std::sync::atomic::Ordering::Relaxed);
+        if !enabled {
+            self.events.queue.lock().clear();
+            self.events.dropped.store(0, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Takes every queued event, oldest first, for the engine to act on once per frame.
+    fn drain_events(&self) -> ffi::EventBatch {
+        let events = std::mem::take(&mut *self.events.queue.lock());
+        ffi::EventBatch {
+            events: events.into(),
+            dropped: self.events.dropped.swap(0, std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    /// Detaches path from document id. If id was the resolved winner, the best remaining
+    /// claimant in index (which must no longer list id's binding) takes over the path.
+    /// Resolves through the trie, so it may run before or after the updated index is written.
+    fn release_binding(&self, index: &BTreeMap<Uuid, Document>, path: &str, id: Uuid) -> io::Result<()> {
+        let resolved = self.get_document_id_by_path(path).ok();
+        self.path_cache.lock().pop(path);
+        if resolved != Some(id) {
+            return Ok(());
+        }
+        self.trie_delete(path)?;
+        if let Some((next_id, _)) = Self::path_claims(index, path, &self.active_language.read()).first() {
+            self.trie_insert(path, *next_id)?;
+        }
+        Ok(())
+    }
+
+    /// Every document binding path that applies under lang, in shadow order: bindings for lang
+    /// before default ones, then highest priority first. Other languages' bindings are left out.
+    fn path_claims(index: &BTreeMap<Uuid, Document>, path: &str, lang: &str) -> Vec<(Uuid, PathBinding)> {
+        let mut claims: Vec<(Uuid, PathBinding)> = index.values()
+            .filter_map(|doc| doc.paths.iter().find(|binding| binding.path == path).map(|binding| (doc.id, binding.clone())))
+            .filter(|(_, binding)| binding.lang.is_empty() || binding.lang == lang)
+            .collect();
+        claims.sort_by(|a, b| Self::claim_rank(&b.1, lang).cmp(&Self::claim_rank(&a.1, lang)));
+        claims
+    }
+
+    fn claim_rank(binding: &PathBinding, lang: &str) -> (bool, i32) {
+        (!binding.lang.is_empty() && binding.lang == lang, binding.priority)
+    }
+
+    fn trie_delete(&self, path: &str) -> io::Result<()> {
+        let root_page_id = self.trie_root.read().page_id;
+        if root_page_id == -1 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
+        }
+        let result = self.trie_delete_at(root_page_id, path).and_then(|_| self.path_hash_remove(path));
+        match result {
+            Ok(()) => self.update_path_cache(path, None),
+            Err(_) => self.clear_path_cache(),
+        }
+        result
+    }
+
+    /// Clears the terminal for path, then prunes upward: empty nodes are removed and
+    /// single-child nodes are merged into their child, so the trie stays compressed.
+    fn trie_delete_at(&self, root_page_id: i64, path: &str) -> io::Result<()> {
+        let reversed: String = path.chars().rev().collect();
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "Path not found");
+        let mut node = self.read_trie_node(root_page_id)?;
+        let mut remaining = reversed.as_str();
+        loop {
+            remaining = remaining.strip_prefix(node.edge.as_str()).ok_or_else(not_found)?;
+            if remaining.is_empty() {
+                break;
+            }
+            let child_id = *node.children.get(&remaining.chars().next().unwrap()).ok_or_else(not_found)?;
+            node = self.read_trie_node(child_id)?;
+        }
+        if node.document_id.take().is_none() {
+            return Err(not_found());
+        }
+        loop {
+            if node.self_page_id == root_page_id || node.document_id.is_some() || node.children.len() > 1 {
+                return self.write_trie_node(&node);
+            }
+            let mut parent = self.read_trie_node(node.parent_page_id)?;
+            let key = node.edge.chars().next().unwrap();
+            let merged = match node.children.values().next() {
+                Some(&child_id) => {
+                    let mut child = self.read_trie_node(child_id)?;
+                    child.edge = format!("{}{}", node.edge, child.edge);
+                    child.parent_page_id = node.parent_page_id;
+                    self.write_trie_node(&child)?;
+                    parent.children.insert(key, child_id);
+                    true
+                }
+                None => {
+                    parent.children.remove(&key);
+                    false
+                }
+            };
+            self.free_trie_node(node.self_page_id)?;
+            if merged {
+                return self.write_trie_node(&parent);
+            }
+            node = parent;
+        }
+    }
+
+    fn get_document_id_by_path(&self, path: &str) -> io::Result<Uuid> {
+        let path = self.validate_path(path)?;
+        let path = path.as_str();
+        if let Some(&id) = self.path_cache.lock().get(path) {
+            return Ok(id);
+        }
+        let epoch = self.path_cache_epoch.load(std::sync::atomic::Ordering::SeqCst);
+        if let Some(id) = self.path_hash_lookup(path)? {
+            let mut path_cache = self.path_cache.lock();
+            if self.path_cache_epoch.load(std::sync::atomic::Ordering::SeqCst) == epoch {
+                path_cache.put(path.to_string(), id);
+            }
+            return Ok(id);
+        }
+        let trie_root = self.trie_root.read();
+        if trie_root.page_id == -1 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
+        }
+        let _span = trace_span!("trie_lookup", path = path);
+        let reversed: String = path.chars().rev().collect();
+        let mut current_page_id = trie_root.page_id;
+        let mut remaining = reversed.as_str();
+        while !remaining.is_empty() {
+            let node = self.read_trie_node(current_page_id)?;
+            let edge = node.edge.as_str();
+            if remaining.starts_with(edge) {
+                remaining = &remaining[edge.len()..];
+                if remaining.is_empty() {
+                    return node.document_id.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Path not found"));
+                }
+                let first_char = remaining.chars().next().unwrap();
+                if let Some(&child_id) = node.children.get(&first_char) {
+                    current_page_id = child_id;
+                } else {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
+                }
+            } else {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
+            }
+        }
+        let node = self.read_trie_node(current_page_id)?;
+        node.document_id.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Path not found"))
+    }
+
+    fn path_hash(path: &str) -> u64 {
+        // FNV-1a: stable across runs and platforms, unlike std's RandomState
+        path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3))
+    }
+
+    fn load_path_hash_buckets(&self) -> io::Result<()> {
+        let root_page_id = self.path_hash_root.read().page_id;
+        let mut buckets = self.path_hash_buckets.write();
+        buckets.clear();
+        if root_page_id == -1 {
+            return Ok(());
+        }
+        let data = self.read_raw_page(root_page_id)?;
+        let mut reader = Cursor::new(data);
+        for _ in 0..PATH_HASH_BUCKETS {
+            buckets.push(reader.read_i64::<LittleEndian>()?);
+        }
+        Ok(())
+    }
+
+    fn write_path_hash_buckets(&self, buckets: &[i64]) -> io::Result<()> {
+        let mut root = self.path_hash_root.write();
+        let mut buffer = Vec::with_capacity(PATH_HASH_BUCKETS * 8);
+        for &bucket in buckets {
+            buffer.write_i64::<LittleEndian>(bucket)?;
+        }
+        if root.page_id == -1 {
+            root.page_id = self.allocate_page()?;
+        }
+        root.version += 1;
+        self.write_raw_page(root.page_id, &buffer, root.version, PageLinks::single(FLAG_HASH_PAGE))?;
+        drop(root);
+        // Published every time, so the header's link keeps up with the page's version
+        self.write_roots()?;
+        Ok(())
+    }
+
+    /// Reads every (hash, uuid) entry of a bucket, following its overflow chain.
+    fn read_path_hash_bucket(&self, bucket_page_id: i64) -> io::Result<(Vec<i64>, Vec<(u64, Uuid)>)> {
+        let mut pages = Vec::new();
+        let mut entries = Vec::new();
+        let mut page_id = bucket_page_id;
+        while page_id != -1 {
+            pages.push(page_id);
+            if pages.len() as i64 > self.config.max_pages {
+                return Err(Self::corrupt("path hash bucket chain"));
+            }
+            let page = self.read_raw_page(page_id)?;
+            let mut reader = Cursor::new(page.as_slice());
+            let next_page_id = reader.read_i64::<LittleEndian>()?;
+            let count = Self::read_count(&mut reader, 24, "path hash bucket")?;
+            for _ in 0..count {
+                let hash = reader.read_u64::<LittleEndian>()?;
+                let mut id_bytes = [0u8; 16];
+                reader.read_exact(&mut id_bytes)?;
+                entries.push((hash, Uuid::from_bytes(id_bytes)));
+            }
+            page_id = next_page_id;
+        }
+        Ok((pages, entries))
+    }
+
+    /// Rewrites a bucket's chain, reusing its existing pages and freeing any left over.
+    fn write_path_hash_bucket(&self, bucket: usize, mut pages: Vec<i64>, entries: &[(u64, Uuid)]) -> io::Result<()> {
+        let chunks: Vec<&[(u64, Uuid)]> = entries.chunks(PATH_HASH_ENTRIES_PER_PAGE).collect();
+        while pages.len() < chunks.len() {
+            pages.push(self.allocate_page()?);
+        }
+        for page_id in pages.drain(chunks.len()..) {
+            self.free_page(page_id)?;
+        }
+        // Write back to front so every next link points at an already written page
+        for (i, chunk) in chunks.iter().enumerate().rev() {
+            let mut buffer = Vec::new();
+            buffer.write_i64::<LittleEndian>(pages.get(i + 1).copied().unwrap_or(-1))?;
+            buffer.write_i32::<LittleEndian>(chunk.len() as i32)?;
+            for (hash, id) in chunk.iter() {
+                buffer.write_u64::<LittleEndian>(*hash)?;
+                buffer.write_all(id.as_bytes())?;
+            }
+            self.write_raw_page(pages[i], &buffer, self.next_page_version(pages[i]), PageLinks::single(FLAG_HASH_PAGE))?;
+        }
+        let head = pages.first().copied().unwrap_or(-1);
+        let mut buckets = self.path_hash_buckets.read().clone();
+        if buckets.is_empty() {
+            buckets = vec![-1; PATH_HASH_BUCKETS];
+        }
+        if buckets[bucket] != head {
+            buckets[bucket] = head;
+            self.write_path_hash_buckets(&buckets)?;
+            *self.path_hash_buckets.write() = buckets;
+        }
+        Ok(())
+    }
+
+    /// Exact-path lookup through the hash index. Only the bucket chain is read;
+    /// candidates are confirmed against their index entry to rule out collisions.
+    fn path_hash_lookup(&self, path: &str) -> io::Result<Option<Uuid>> {
+        let hash = Self::path_hash(path);
+        let bucket_page_id = match self.path_hash_buckets.read().get(hash as usize % PATH_HASH_BUCKETS) {
+            Some(&page_id) if page_id != -1 => page_id,
+            _ => return Ok(None),
+        };
+        let (_, entries) = self.read_path_hash_bucket(bucket_page_id)?;
+        let mut candidates = entries.into_iter().filter(|(h, _)| *h == hash).peekable();
+        if candidates.peek().is_none() {
+            return Ok(None);
+        }
+        let index = self.read_index()?;
+        Ok(candidates
+            .map(|(_, id)| id)
+            .find(|id| index.get(id).map_or(false, |doc| doc.has_path(path))))
+    }
+
+    fn path_hash_insert(&self, path: &str, id: Uuid) -> io::Result<()> {
+        let hash = Self::path_hash(path);
+        let bucket = hash as usize % PATH_HASH_BUCKETS;
+        let bucket_page_id = self.path_hash_buckets.read().get(bucket).copied().unwrap_or(-1);
+        let (pages, mut entries) = self.read_path_hash_bucket(bucket_page_id)?;
+        let index = self.read_index()?;
+        // The new binding supersedes whatever this path resolved to before, as in the trie
+        entries.retain(|(h, other)| {
+            *h != hash || (*other != id && !index.get(other).map_or(false, |doc| doc.has_path(path)))
+        });
+        entries.push((hash, id));
+        self.write_path_hash_bucket(bucket, pages, &entries)
+    }
+
+    fn path_hash_remove(&self, path: &str) -> io::Result<()> {
+        let hash = Self::path_hash(path);
+        let bucket = hash as usize % PATH_HASH_BUCKETS;
+        let bucket_page_id = self.path_hash_buckets.read().get(bucket).copied().unwrap_or(-1);
+        if bucket_page_id == -1 {
+            return Ok(());
+        }
+        let (pages, mut entries) = self.read_path_hash_bucket(bucket_page_id)?;
+        let index = self.read_index()?;
+        let before = entries.len();
+        // Drop entries for this path along with any whose document no longer exists
+        entries.retain(|(h, id)| {
+            *h != hash || index.get(id).map_or(false, |doc| !doc.has_path(path))
+        });
+        if entries.len() == before {
+            return Ok(());
+        }
+        self.write_path_hash_bucket(bucket, pages, &entries)
+    }
+
+    /// Opens a stream over the document's current chain. The chain stays readable until
+    /// end_stream, even if the document is rewritten or deleted in the meantime.
+    fn start_stream(&self, path: &CxxString) -> io::Result<i64> {
+        self.start_stream_with_chunk_size(path, 0)
+    }
+
+    /// chunk_size is the target size of each next_stream_chunk result: pages are coalesced
+    /// (or split) to approximate it. 0 keeps page granularity.
+    fn start_stream_with_chunk_size(&self, path: &CxxString, chunk_size: usize) -> io::Result<i64> {
+        self.open_stream(path.to_string_lossy().as_ref(), chunk_size)
+    }
+
+    fn open_stream(&self, path: &str, chunk_size: usize) -> io::Result<i64> {
+        self.ensure_open()?;
+        let id = self.get_document_id_by_path(path)?;
+        let index = self.read_index()?;
+        let doc = self.visible_document(&index, id)?;
+        self.pin_chain(doc.first_page_id);
+        let stream_id = self.next_stream_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.streams.write().insert(stream_id, Arc::new(PMutex::new(StreamHandle {
+            document_id: id,
+            first_page_id: doc.first_page_id,
+            next_page_id: doc.first_page_id,
+            bytes_read: 0,
+            chunks_read: 0,
+            expected_checksum: doc.checksum,
+            running_checksum: Some(CRC32.digest()),
+            stale: false,
+            chunk_size,
+            buffered: Vec::new(),
+        })));
+        Ok(stream_id)
+    }
+
+    fn stream_handle(&self, stream_id: i64) -> io::Result<Arc<PMutex<StreamHandle>>> {
+        self.streams.read().get(&stream_id).cloned().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid stream ID"))
+    }
+
+    fn next_stream_chunk(&self, stream_id: i64) -> io::Result<CxxVector<u8>> {
+        Ok(cxx::CxxVector::from(self.stream_chunk(stream_id)?))
+    }
+
+    fn stream_chunk(&self, stream_id: i64) -> io::Result<Vec<u8>> {
+        self.ensure_open()?;
+        let stream = self.stream_handle(stream_id)?;
+        let mut stream = stream.lock();
+        if stream.stale {
+            return Err(io::Error::new(io::ErrorKind::Other, "Stale stream"));
+        }
+        if stream.is_finished() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Stream ended"));
+        }
+        let target = if stream.chunk_size == 0 { 1 } else { stream.chunk_size };
+        let mut data = std::mem::take(&mut stream.buffered);
+        while data.len() < target && stream.next_page_id != -1 {
+            let (page, next_page_id) = self.view_page(stream.next_page_id)?;
+            data.extend_from_slice(&page);
+            stream.next_page_id = next_page_id;
+        }
+        if stream.chunk_size != 0 && data.len() > stream.chunk_size {
+            stream.buffered = data.split_off(stream.chunk_size);
+        }
+        stream.bytes_read += data.len() as u64;
+        stream.chunks_read += 1;
+        if let Some(digest) = stream.running_checksum.as_mut() {
+            digest.update(&data);
+        }
+        Ok(data)
+    }
+
+    /// Fills a caller buffer with the next bytes of the stream, ignoring chunk_size.
+    /// Returns the number of bytes written; 0 once the stream has ended.
+    /// Safety: dst must be valid for dst_len writable bytes and not aliased for the duration of the call.
+    unsafe fn stream_read_into(&self, stream_id: i64, dst: *mut u8, dst_len: usize) -> io::Result<usize> {
+        self.ensure_open()?;
+        let dst = Self::caller_buffer(dst, dst_len)?;
+        let stream = self.stream_handle(stream_id)?;
+        let mut stream = stream.lock();
+        if stream.stale {
+            return Err(io::Error::new(io::ErrorKind::Other, "Stale stream"));
+        }
+        let mut written = stream.buffered.len().min(dst.len());
+        dst[..written].copy_from_slice(&stream.buffered[..written]);
+        stream.buffered.drain(..written);
+        while written < dst.len() && stream.next_page_id != -1 {
+            let (payload, next_page_id) = self.view_page(stream.next_page_id)?;
+            let copied = payload.len().min(dst.len() - written);
+            dst[written..written + copied].copy_from_slice(&payload[..copied]);
+            // Whatever did not fit is kept for the next read
+            stream.buffered = payload[copied..].to_vec();
+            stream.next_page_id = next_page_id;
+            written += copied;
+        }
+        if written > 0 {
+            stream.bytes_read += written as u64;
+            stream.chunks_read += 1;
+            if let Some(digest) = stream.running_checksum.as_mut() {
+                digest.update(&dst[..written]);
+            }
+        }
+        Ok(written)
+    }
+
+    /// Ends the stream and checks everything it delivered against the document checksum.
+    /// Streams that were not read sequentially to the end report Unverified.
+    fn finish_stream(self: Pin<&mut Self>, stream_id: i64) -> io::Result<ffi::StreamVerification> {
+        let _writes = self.begin_write()?;
+        let stream = self.streams.write().remove(&stream_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid stream ID"))?;
+        let mut stream = stream.lock();
+        self.release_stream(&stream);
+        let digest = match stream.running_checksum.take() {
+            Some(digest) if stream.is_finished() => digest,
+            _ => return Ok(ffi::StreamVerification::Unverified),
+        };
+        Ok(if digest.finalize() == stream.expected_checksum {
+            ffi::StreamVerification::Verified
+        } else {
+            ffi::StreamVerification::Corrupt
+        })
+    }
+
+    fn get_stream_stats(&self, stream_id: i64) -> io::Result<ffi::StreamStats> {
+        self.ensure_open()?;
+        let stream = self.stream_handle(stream_id)?;
+        let stream = stream.lock();
+        Ok(ffi::StreamStats {
+            document_uuid: stream.document_id.to_string(),
+            bytes_read: stream.bytes_read,
+            chunks_read: stream.chunks_read,
+            finished: stream.is_finished(),
+        })
+    }
+
+    /// Ending an unknown or already ended stream is a no-op.
+    fn end_stream(self: Pin<&mut Self>, stream_id: i64) {
+        self.close_stream(stream_id)
+    }
+
+    fn close_stream(&self, stream_id: i64) {
+        let stream = self.streams.write().remove(&stream_id);
+        if let Some(stream) = stream {
+            self.release_stream(&stream.lock());
+        }
+    }
+
+    fn release_stream(&self, stream: &StreamHandle) {
+        self.unpin_chain(stream.first_page_id);
+    }
+
+    /// Opens a cursor over the documents whose paths start with prefix, as they are now.
+    /// Later writes, deletes and maintenance do not disturb it; close_cursor releases what it
+    /// holds, as does cursor_timeout_ms of disuse.
+    fn open_cursor(&self, prefix: &CxxString) -> io::Result<i64> {
+        self.ensure_open()?;
+        self.expire_cursors();
+        let prefix = self.validate_prefix(prefix.to_string_lossy().as_ref())?;
+        // Taken like a write, so no chain is freed between reading the index and pinning it
+        let _gate = self.maintenance.gate.lock();
+        let index = self.read_index()?;
+        let now = Self::unix_now();
+        let mut entries: Vec<(String, Document)> = index.values()
+            .filter(|doc| !self.config.hide_expired || !doc.is_expired(now))
+            .flat_map(|doc| doc.paths.iter().filter(|binding| binding.path.starts_with(prefix.as_str())).map(move |binding| (binding.path.clone(), doc.clone())))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, doc) in &entries {
+            self.pin_chain(doc.first_page_id);
+        }
+        let cursor_id = self.next_stream_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.cursors.lock().insert(cursor_id, ReadCursor { entries, position: 0, last_used: std::time::Instant::now() });
+        Ok(cursor_id)
+    }
+
+    /// The next max_entries documents of the cursor; empty once it is exhausted.
+    fn cursor_next(&self, cursor_id: i64, max_entries: usize) -> io::Result<Vec<ffi::DocumentInfo>> {
+        self.ensure_open()?;
+        self.expire_cursors();
+        let mut cursors = self.cursors.lock();
+        let cursor = cursors.get_mut(&cursor_id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Cursor not found"))?;
+        cursor.last_used = std::time::Instant::now();
+        let end = cursor.entries.len().min(cursor.position.saturating_add(max_entries));
+        let batch = cursor.entries[cursor.position..end].iter()
+            .map(|(path, doc)| self.document_info(path, doc))
+            .collect::<io::Result<Vec<_>>>()?;
+        cursor.position = end;
+        Ok(batch)
+    }
+
+    /// A document's contents as of the cursor's snapshot, whatever has happened to path since.
+    fn cursor_read(&self, cursor_id: i64, path: &CxxString) -> io::Result<CxxVector<u8>> {
+        self.ensure_open()?;
+        self.expire_cursors();
+        let path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let first_page_id = {
+            let mut cursors = self.cursors.lock();
+            let cursor = cursors.get_mut(&cursor_id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Cursor not found"))?;
+            cursor.last_used = std::time::Instant::now();
+            let entry = cursor.entries.binary_search_by(|(entry_path, _)| entry_path.as_str().cmp(&path))
+                .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "Path not in cursor"))?;
+            cursor.entries[entry].1.first_page_id
+        };
+        Ok(cxx::CxxVector::from(self.read_chain(first_page_id)?))
+    }
+
+    fn close_cursor(&self, cursor_id: i64) {
+        let cursor = self.cursors.lock().remove(&cursor_id);
+        if let Some(cursor) = cursor {
+            self.release_cursor(cursor);
+        }
+    }
+
+    fn release_cursor(&self, cursor: ReadCursor) {
+        for (_, doc) in cursor.entries {
+            self.unpin_chain(doc.first_page_id);
+        }
+    }
+
+    /// Closes cursors left unused for cursor_timeout_ms, so a forgotten one cannot hold
+    /// freed pages forever.
+    fn expire_cursors(&self) {
+        let timeout = std::time::Duration::from_millis(self.config.cursor_timeout_ms);
+        let expired: Vec<ReadCursor> = {
+            let mut cursors = self.cursors.lock();
+            let ids: Vec<i64> = cursors.iter().filter(|(_, cursor)| cursor.last_used.elapsed() > timeout).map(|(&id, _)| id).collect();
+            ids.iter().filter_map(|id| cursors.remove(id)).collect()
+        };
+        for cursor in expired {
+            self.release_cursor(cursor);
+        }
+    }
+
+    fn pin_chain(&self, first_page_id: i64) {
+        self.chain_pins.lock().entry(first_page_id).or_insert(ChainPin { streams: 0, pending_free: false }).streams += 1;
+    }
+
+    fn unpin_chain(&self, first_page_id: i64) {
+        if self.closed.load(std::sync::atomic::Ordering::SeqCst) {
+            return; // pins do not outlive the database; recovery reclaims anything left pending
+        }
+        let release = {
+            let mut pins = self.chain_pins.lock();
+            match pins.get_mut(&first_page_id) {
+                Some(pin) if pin.streams > 1 => {
+                    pin.streams -= 1;
+                    false
+                }
+                Some(_) => pins.remove(&first_page_id).map_or(false, |pin| pin.pending_free),
+                None => false,
+            }
+        };
+        if release {
+            // Unpinning cannot report errors; a failed free leaves the chain for recovery to reclaim
+            self.free_chain(first_page_id).unwrap_or(());
+        }
+    }
+
+    /// Opens a document as a seekable file. The page chain stays pinned until the file is dropped,
+    /// so the contents do not change underneath an open file even if the path is rewritten.
+    fn open_file<'a>(&'a self, path: &CxxString) -> io::Result<Box<StreamDbFile<'a>>> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        let doc = self.visible_document(&index, id)?;
+        self.pin_chain(doc.first_page_id);
+        let mut file = StreamDbFile {
+            db: self,
+            first_page_id: doc.first_page_id,
+            pages: Vec::new(),
+            length: 0,
+            position: 0,
+            buffer_index: None,
+            buffer: Vec::new(),
+        };
+        // Payload offsets are only known after decompression, so map them once up front
+        let mut current_page_id = doc.first_page_id;
+        while current_page_id != -1 {
+            let (page, next_page_id) = self.view_page(current_page_id)?;
+            file.pages.push((current_page_id, file.length));
+            file.length += page.len() as u64;
+            current_page_id = next_page_id;
+        }
+        Ok(Box::new(file))
+    }
+
+    /// Raw bytes per append page. With compression on, leaves room for the codec's worst-case
+    /// expansion (snappy's is the larger of the two).
+    fn append_page_capacity(&self, codec: u8) -> usize {
+        let capacity = (self.config.page_size - self.config.page_header_size) as usize;
+        if codec != CODEC_NONE {
+            (capacity - 32) * 6 / 7
+        } else {
+            capacity
+        }
+    }
+
+    fn write_append_page(&self, data: &[u8], prev_page_id: i64, codec: u8) -> io::Result<i64> {
+        let page_id = self.allocate_page()?;
+        let links = PageLinks { flags: FLAG_DATA_PAGE | FLAG_APPEND_PAGE, prev_page_id, next_page_id: -1 };
+        self.write_raw_page_as(page_id, data, self.next_page_version(page_id), links, codec, self.config.compression_level, 0)?;
+        Ok(page_id)
+    }
+
+    fn set_next_page(&self, page_id: i64, next_page_id: i64) -> io::Result<()> {
+        let mut header = self.read_page_header(page_id)?;
+        header.next_page_id = next_page_id;
+        self.write_page_header(page_id, &header)
+    }
+
+    /// Opens path for appending, creating an empty document if it does not exist.
+    /// Appended bytes are buffered and only full pages are written until sync_append.
+    fn open_append(self: Pin<&mut Self>, path: &CxxString) -> io::Result<i64> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = match self.get_document_id_by_path(&rust_path) {
+            Ok(id) => id,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.store_document(&rust_path, &[], false, false, false)?,
+            Err(e) => return Err(e),
+        };
+        self.read_index()?.get(&id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?
+            .check_writable(false)?;
+        self.unshare_chain(id)?;
+        let index = self.read_index()?;
+        let doc = index.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        let mut handle = AppendHandle {
+            document_id: id,
+            first_page_id: doc.first_page_id,
+            last_sealed_page_id: -1,
+            synced_tail_page_id: -1,
+            pending_pages: Vec::new(),
+            tail: Vec::new(),
+            sealed_checksum: CRC32.digest(),
+            length: 0,
+            codec: self.codec_for_path(&rust_path),
+            dirty: false,
+        };
+        // Existing contents: every page is sealed except a short last page, which becomes the tail
+        let capacity = self.append_page_capacity(handle.codec);
+        let mut current_page_id = doc.first_page_id;
+        while current_page_id != -1 {
+            let data = self.read_raw_page(current_page_id)?;
+            let next_page_id = self.read_page_header(current_page_id)?.next_page_id;
+            handle.length += data.len() as u64;
+            if next_page_id == -1 && data.len() < capacity {
+                handle.synced_tail_page_id = current_page_id;
+                handle.tail = data;
+            } else {
+                handle.sealed_checksum.update(&data);
+                handle.last_sealed_page_id = current_page_id;
+            }
+            current_page_id = next_page_id;
+        }
+        let append_id = self.next_stream_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.appends.lock().insert(append_id, handle);
+        Ok(append_id)
+    }
+
+    fn append(self: Pin<&mut Self>, handle: i64, data: &[u8]) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let mut appends = self.appends.lock();
+        let handle = appends.get_mut(&handle).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid append handle"))?;
+        let capacity = self.append_page_capacity(handle.codec);
+        handle.length += data.len() as u64;
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let take = (capacity - handle.tail.len()).min(remaining.len());
+            handle.tail.extend_from_slice(&remaining[..take]);
+            remaining = &remaining[take..];
+            handle.dirty = true;
+            if handle.tail.len() == capacity {
+                let prev_page_id = handle.pending_pages.last().copied().unwrap_or(handle.last_sealed_page_id);
+                let page_id = self.write_append_page(&handle.tail, prev_page_id, handle.codec)?;
+                if let Some(&last_pending) = handle.pending_pages.last() {
+                    self.set_next_page(last_pending, page_id)?;
+                }
+                handle.pending_pages.push(page_id);
+                handle.sealed_checksum.update(&handle.tail);
+                handle.tail.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes everything appended so far durable. The partial tail is written to a fresh page and
+    /// swapped in with a single link update, so a crash leaves either the old or the new tail.
+    fn sync_append(self: Pin<&mut Self>, handle: i64) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let mut appends = self.appends.lock();
+        let handle = appends.get_mut(&handle).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid append handle"))?;
+        self.sync_append_handle(handle)
+    }
+
+    fn sync_append_handle(&self, handle: &mut AppendHandle) -> io::Result<()> {
+        if !handle.dirty {
+            return Ok(());
+        }
+        let last_pending = handle.pending_pages.last().copied();
+        let new_tail_page_id = if handle.tail.is_empty() {
+            -1
+        } else {
+            self.write_append_page(&handle.tail, last_pending.unwrap_or(handle.last_sealed_page_id), handle.codec)?
+        };
+        if let Some(last_pending) = last_pending {
+            self.set_next_page(last_pending, new_tail_page_id)?;
+        }
+        let segment_start = handle.pending_pages.first().copied().unwrap_or(new_tail_page_id);
+        if handle.last_sealed_page_id == -1 {
+            handle.first_page_id = segment_start;
+        } else {
+            self.set_next_page(handle.last_sealed_page_id, segment_start)?;
+        }
+        let mut checksum = handle.sealed_checksum.clone();
+        checksum.update(&handle.tail);
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&handle.document_id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        doc.first_page_id = handle.first_page_id;
+        doc.current_version += 1; // appended contents are a new version, though the old one is not kept
+        doc.checksum = checksum.finalize();
+        doc.size = handle.length;
+        doc.page_count = self.chain_page_count(handle.first_page_id)?;
+        doc.modified = Self::unix_now();
+        let paths: Vec<String> = doc.paths.iter().map(|binding| binding.path.clone()).collect();
+        self.write_index(&index)?;
+        for path in &paths {
+            self.emit_event(ffi::DocumentEventOp::Write, path, handle.document_id);
+        }
+        if self.config.durable_writes {
+            self.sync_storage()?;
+        }
+        if handle.synced_tail_page_id != -1 {
+            self.free_page(handle.synced_tail_page_id)?;
+        }
+        if let Some(last_pending) = last_pending {
+            handle.last_sealed_page_id = last_pending;
+        }
+        handle.pending_pages.clear();
+        handle.synced_tail_page_id = new_tail_page_id;
+        handle.dirty = false;
+        Ok(())
+    }
+
+    fn close_append(self: Pin<&mut Self>, handle: i64) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let mut handle = self.appends.lock().remove(&handle).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid append handle"))?;
+        self.sync_append_handle(&mut handle)
+    }
+
+    /// Cuts append-mode chains at the first page that fails validation, dropping a torn tail
+    /// left by a crash mid-sync, and refreshes their checksums to match what survives.
+    fn recover_append_documents(&self) -> io::Result<()> {
+        let mut index = self.read_index()?;
+        let mut changed = false;
+        for doc in index.values_mut() {
+            if doc.first_page_id == -1 || Self::slab_record(doc.first_page_id).is_some()
+                || self.read_page_header(doc.first_page_id)?.flags & FLAG_APPEND_PAGE == 0 {
+                continue;
+            }
+            let mut checksum = CRC32.digest();
+            let mut size = 0;
+            let mut page_count = 0;
+            let mut prev_page_id = -1;
+            let mut current_page_id = doc.first_page_id;
+            while current_page_id != -1 {
+                let page = self.read_page_header(current_page_id).ok()
+                    .filter(|header| header.flags & FLAG_DATA_PAGE != 0 && header.prev_page_id == prev_page_id)
+                    .and_then(|header| self.read_raw_page(current_page_id).ok().map(|data| (header, data)));
+                match page {
+                    Some((header, data)) => {
+                        checksum.update(&data);
+                        size += data.len() as u64;
+                        page_count += 1;
+                        prev_page_id = current_page_id;
+                        current_page_id = header.next_page_id;
+                    }
+                    None => {
+                        if prev_page_id == -1 {
+                            doc.first_page_id = -1;
+                        } else {
+                            self.set_next_page(prev_page_id, -1)?;
+                        }
+                        changed = true;
+                        break;
+                    }
+                }
+            }
+            let checksum = checksum.finalize();
+            if checksum != doc.checksum || size != doc.size || page_count != doc.page_count {
+                doc.checksum = checksum;
+                doc.size = size;
+                doc.page_count = page_count;
+                changed = true;
+            }
+        }
+        if changed {
+            self.write_index(&index)?;
+        }
+        Ok(())
+    }
+
+    /// For each chain used as the current version of several documents, the stored size of
+    /// every use beyond the first.
+    fn dedup_bytes_saved(&self) -> io::Result<u64> {
+        let mut uses: HashMap<i64, u64> = HashMap::new();
+        for doc in self.read_index()?.values().filter(|doc| doc.first_page_id != -1) {
+            *uses.entry(doc.first_page_id).or_insert(0) += 1;
+        }
+        let mut saved = 0;
+        for (first_page_id, count) in uses.into_iter().filter(|&(_, count)| count > 1) {
+            saved += self.chain_stored_bytes(first_page_id)? * (count - 1);
+        }
+        Ok(saved)
+    }
+
+    /// Pages in the chain, read from the headers alone; 0 for a slab record, which has none of its own.
+    fn chain_page_count(&self, first_page_id: i64) -> io::Result<u32> {
+        if Self::slab_record(first_page_id).is_some() {
+            return Ok(0);
+        }
+        let mut pages = 0;
+        let mut current_page_id = first_page_id;
+        while current_page_id != -1 {
+            current_page_id = self.read_page_header(current_page_id)?.next_page_id;
+            pages += 1;
+        }
+        Ok(pages)
+    }
+
+    /// The logical size and page count of the chain, measured by reading it without filling the cache.
+    fn measure_chain(&self, first_page_id: i64) -> io::Result<(u64, u32)> {
+        let mut size = 0;
+        let mut current_page_id = first_page_id;
+        while current_page_id != -1 {
+            let (page, next_page_id) = self.view_page_as(current_page_id, PageCaching::Peek)?;
+            size += page.len() as u64;
+            current_page_id = next_page_id;
+        }
+        Ok((size, self.chain_page_count(first_page_id)?))
+    }
+
+    /// Payload bytes a chain occupies on disk, after compression.
+    fn chain_stored_bytes(&self, first_page_id: i64) -> io::Result<u64> {
+        if let Some((page_id, slot)) = Self::slab_record(first_page_id) {
+            return Ok(self.read_slab_page(page_id, SlabKind::Document)?.slot(slot).map_or(0, |(_, length)| length as u64));
+        }
+        let mut stored = 0;
+        let mut current_page_id = first_page_id;
+        while current_page_id != -1 {
+            let header = self.read_page_header(current_page_id)?;
+            stored += header.data_length as u64;
+            current_page_id = header.next_page_id;
+        }
+        Ok(stored)
+    }
+
+    /// Bytes a chain occupies on disk: its stored payload plus a header per page. Reads headers
+    /// only; a slab record counts its record, the slab page's header being shared.
+    fn chain_physical_bytes(&self, first_page_id: i64) -> io::Result<u64> {
+        if Self::slab_record(first_page_id).is_some() {
+            return self.chain_stored_bytes(first_page_id);
+        }
+        let mut physical = 0;
+        let mut current_page_id = first_page_id;
+        while current_page_id != -1 {
+            let header = self.read_page_header(current_page_id)?;
+            physical += header.data_length as u64 + self.config.page_header_size;
+            current_page_id = header.next_page_id;
+        }
+        Ok(physical)
+    }
+
+    /// On-disk bytes of the current version at path, compressed, with page headers.
+    fn get_physical_size(&self, path: &CxxString) -> io::Result<u64> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        self.chain_physical_bytes(self.visible_document(&index, id)?.first_page_id)
+    }
+
+    /// The n documents taking the most space on disk, biggest first, for finding the assets
+    /// that bloat a database. Page headers are read; payloads are not.
+    fn top_documents_by_physical_size(&self, n: usize) -> io::Result<Vec<ffi::PhysicalSize>> {
+        self.ensure_open()?;
+        let now = Self::unix_now();
+        let mut documents = Vec::new();
+        for doc in self.read_index()?.values().filter(|doc| !self.config.hide_expired || !doc.is_expired(now)) {
+            let physical_size = self.chain_physical_bytes(doc.first_page_id)?;
+            documents.push(ffi::PhysicalSize {
+                path: doc.paths.first().map_or_else(|| doc.id.to_string(), |binding| binding.path.clone()),
+                logical_size: doc.size,
+                physical_size,
+                compression_ratio: if physical_size == 0 { 0.0 } else { doc.size as f64 / physical_size as f64 },
+            });
+        }
+        documents.sort_by(|a, b| b.physical_size.cmp(&a.physical_size).then_with(|| a.path.cmp(&b.path)));
+        documents.truncate(n);
+        Ok(documents)
+    }
+
+    fn get_db_stats(&self) -> ffi::DbStats {
+        let pins = self.chain_pins.lock();
+        ffi::DbStats {
+            open_streams: self.streams.read().len() as u64,
+            pinned_chains: pins.len() as u64,
+            pending_free_chains: pins.values().filter(|pin| pin.pending_free).count() as u64,
+            dedup_bytes_saved: self.dedup_bytes_saved().unwrap_or(0),
+            document_count: self.document_count.load(std::sync::atomic::Ordering::SeqCst),
+            direct_io: self.storage.direct_io(),
+        }
+    }
+
+    /// Lists every path with its document's metadata, straight from the index: no payload is read.
+    /// With include_physical, each chain's page headers are walked for its stored size and the
+    /// file's page count and size are filled in.
+    fn export_manifest(&self, include_physical: bool) -> io::Result<ffi::Manifest> {
+        self.ensure_open()?;
+        let index = self.read_index()?;
+        let now = Self::unix_now();
+        let mut entries = Vec::new();
+        for doc in index.values().filter(|doc| !self.config.hide_expired || !doc.is_expired(now)) {
+            let stored_bytes = if include_physical && doc.first_page_id != -1 { self.chain_stored_bytes(doc.first_page_id)? } else { 0 };
+            for binding in &doc.paths {
+                entries.push(ffi::ManifestEntry {
+                    path: binding.path.clone(),
+                    uuid: doc.id.to_string(),
+                    size: doc.size,
+                    version: doc.current_version,
+                    modified: doc.modified,
+                    checksum: doc.checksum,
+                    flags: doc.flags,
+                    stored_bytes,
+                });
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let (page_count, file_bytes) = if include_physical {
+            (self.page_count() as u64, *self.current_size.lock())
+        } else {
+            (0, 0)
+        };
+        Ok(ffi::Manifest { entries, page_count, file_bytes })
+    }
+
+    /// What changed from this database to the one at other_path, compared path by path on size and
+    /// checksum. Only the two indexes are read, so page sizes and codecs may differ. The other
+    /// database is opened for the duration and must not be open elsewhere.
+    fn diff_db(&self, other_path: &CxxString) -> io::Result<ffi::DbDiff> {
+        self.ensure_open()?;
+        let other = Self::open_existing(other_path)?;
+        Ok(Self::diff_manifests(self.export_manifest(false)?.entries, other.export_manifest(false)?.entries))
+    }
+
+    /// Opens another database for comparison; unlike the open calls, never creates one.
+    fn open_existing(path: &CxxString) -> io::Result<StreamDb> {
+        let path = Path::new(path.to_string_lossy().as_ref()).to_path_buf();
+        if !path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Database to compare against not found"));
+        }
+        Self::open_path_with_options(&path, &Self::create_options())
+    }
+
+    fn diff_manifests(old: Vec<ffi::ManifestEntry>, new: Vec<ffi::ManifestEntry>) -> ffi::DbDiff {
+        let mut diff = ffi::DbDiff { added: Vec::new(), removed: Vec::new(), modified: Vec::new() };
+        let (mut old, mut new) = (old.into_iter().peekable(), new.into_iter().peekable());
+        // Both manifests are in path order, so one merge pass pairs them up
+        loop {
+            let order = match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) => a.path.cmp(&b.path),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => break,
+            };
+            match order {
+                std::cmp::Ordering::Less => {
+                    let entry = old.next().unwrap();
+                    diff.removed.push(ffi::DocumentChange { path: entry.path, old_size: entry.size, new_size: 0 });
+                }
+                std::cmp::Ordering::Greater => {
+                    let entry = new.next().unwrap();
+                    diff.added.push(ffi::DocumentChange { path: entry.path, old_size: 0, new_size: entry.size });
+                }
+                std::cmp::Ordering::Equal => {
+                    let (a, b) = (old.next().unwrap(), new.next().unwrap());
+                    if a.checksum != b.checksum || a.size != b.size {
+                        diff.modified.push(ffi::DocumentChange { path: a.path, old_size: a.size, new_size: b.size });
+                    }
+                }
+            }
+        }
+        diff
+    }
+
+    /// SHA-256 over every visible path with its document's checksum and size, in path order.
+    /// Equal for databases holding the same contents, whatever their page layout.
+    fn content_hash(&self) -> io::Result<Vec<u8>> {
+        self.ensure_open()?;
+        let entries = self.export_manifest(false)?.entries;
+        Ok(Self::hash_contents(entries.iter().map(|entry| (entry.path.as_str(), entry.checksum, entry.size))))
+    }
+
+    fn hash_contents<'a>(entries: impl Iterator<Item = (&'a str, u32, u64)>) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        for (path, checksum, size) in entries {
+            hasher.update((path.len() as u64).to_le_bytes());
+            hasher.update(path.as_bytes());
+            hasher.update(checksum.to_le_bytes());
+            hasher.update(size.to_le_bytes());
+        }
+        hasher.finalize().to_vec()
+    }
+
+    /// A patch taking this database's contents to those of the database at new_path, for
+    /// apply_patch. Changed documents are sent as a splice of the old contents when that is
+    /// less than half their size, whole otherwise.
+    fn create_patch(&self, new_path: &CxxString) -> io::Result<Vec<u8>> {
+        self.ensure_open()?;
+        let other = Self::open_existing(new_path)?;
+        let old = self.export_manifest(false)?.entries;
+        let new = other.export_manifest(false)?.entries;
+        let mut patch = PATCH_MAGIC.to_vec();
+        patch.write_u16::<LittleEndian>(PATCH_VERSION)?;
+        patch.extend_from_slice(&Self::hash_contents(old.iter().map(|entry| (entry.path.as_str(), entry.checksum, entry.size))));
+        patch.extend_from_slice(&Self::hash_contents(new.iter().map(|entry| (entry.path.as_str(), entry.checksum, entry.size))));
+        let diff = Self::diff_manifests(old, new);
+        patch.write_u32::<LittleEndian>((diff.removed.len() + diff.added.len() + diff.modified.len()) as u32)?;
+        for change in &diff.removed {
+            patch.write_u8(PATCH_OP_REMOVE)?;
+            Self::write_patch_bytes(&mut patch, change.path.as_bytes())?;
+        }
+        for change in &diff.added {
+            patch.write_u8(PATCH_OP_PUT)?;
+            Self::write_patch_bytes(&mut patch, change.path.as_bytes())?;
+            Self::write_patch_bytes(&mut patch, &other.read_document_as(&change.path, PageCaching::Peek)?)?;
+        }
+        for change in &diff.modified {
+            let old_data = self.read_document_as(&change.path, PageCaching::Peek)?;
+            let new_data = other.read_document_as(&change.path, PageCaching::Peek)?;
+            let prefix = old_data.iter().zip(&new_data).take_while(|(a, b)| a == b).count();
+            let suffix = old_data[prefix..].iter().rev().zip(new_data[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+            let middle = &new_data[prefix..new_data.len() - suffix];
+            if middle.len() < new_data.len() / 2 {
+                patch.write_u8(PATCH_OP_DELTA)?;
+                Self::write_patch_bytes(&mut patch, change.path.as_bytes())?;
+                patch.write_u32::<LittleEndian>(self.compute_crc(&old_data))?;
+                patch.write_u64::<LittleEndian>(prefix as u64)?;
+                patch.write_u64::<LittleEndian>(suffix as u64)?;
+                Self::write_patch_bytes(&mut patch, middle)?;
+            } else {
+                patch.write_u8(PATCH_OP_PUT)?;
+                Self::write_patch_bytes(&mut patch, change.path.as_bytes())?;
+                Self::write_patch_bytes(&mut patch, &new_data)?;
+            }
+        }
+        Ok(patch)
+    }
+
+    /// Where data splits into chunks, as (offset, length): after a byte whose gear hash has
+    /// the boundary bits clear, within CHUNK_MIN_SIZE..=CHUNK_MAX_SIZE.
+    fn chunk_boundaries(data: &[u8]) -> Vec<(usize, usize)> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < data.len() {
+            let end = (start + CHUNK_MAX_SIZE).min(data.len());
+            let mut cut = end;
+            let mut hash = 0u64;
+            for i in (start + CHUNK_MIN_SIZE).min(end)..end {
+                hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+                if hash & CHUNK_BOUNDARY_MASK == 0 {
+                    cut = i + 1;
+                    break;
+                }
+            }
+            chunks.push((start, cut - start));
+            start = cut;
+        }
+        chunks
+    }
+
+    /// Signatures of the chunks of the document at path, for a client holding an older version
+    /// to pass to missing_chunks and sync_document_from.
+    fn get_chunk_signatures(&self, path: &CxxString) -> io::Result<Vec<ffi::ChunkSignature>> {
+        let data = self.read_document(path.to_string_lossy().as_ref())?;
+        Ok(Self::chunk_boundaries(&data).into_iter()
+            .map(|(offset, length)| ffi::ChunkSignature {
+                offset: offset as u64,
+                length: length as u64,
+                hash: Sha256::digest(&data[offset..offset + length]).to_vec(),
+            })
+            .collect())
+    }
+
+    /// The current contents at path, empty if there is no document, and where each of its
+    /// chunks lies, by hash.
+    fn local_chunks(&self, path: &str) -> io::Result<(Vec<u8>, HashMap<Vec<u8>, std::ops::Range<usize>>)> {
+        let data = match self.read_document(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut chunks = HashMap::new();
+        for (offset, length) in Self::chunk_boundaries(&data) {
+            chunks.entry(Sha256::digest(&data[offset..offset + length]).to_vec()).or_insert(offset..offset + length);
+        }
+        Ok((data, chunks))
+    }
+
+    /// Indexes into signatures of the chunks the document at path does not already have.
+    fn missing_chunks(&self, path: &CxxString, signatures: &Vec<ffi::ChunkSignature>) -> io::Result<Vec<u64>> {
+        let (_, chunks) = self.local_chunks(path.to_string_lossy().as_ref())?;
+        Ok(signatures.iter().enumerate()
+            .filter(|(_, signature)| !chunks.contains_key(&signature.hash))
+            .map(|(index, _)| index as u64)
+            .collect())
+    }
+
+    /// Rewrites path as the version signatures describe, from the chunks it already has and the
+    /// ones in chunks, which must cover everything missing_chunks listed. Returns the bytes taken from chunks.
+    fn sync_document_from(self: Pin<&mut Self>, path: &CxxString, signatures: &Vec<ffi::ChunkSignature>, chunks: &Vec<ffi::ChunkData>) -> io::Result<u64> {
+        let provided: HashMap<u64, &[u8]> = chunks.iter().map(|chunk| (chunk.index, chunk.data.as_slice())).collect();
+        self.sync_document_with(path.to_string_lossy().as_ref(), signatures, &mut |index, _| {
+            provided.get(&(index as u64)).map(|data| data.to_vec())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Chunk {} was not provided", index)))
+        })
+    }
+
+    /// sync_document_from with the missing chunks fetched on demand, by index into signatures.
+    /// Every fetched chunk is checked against its signature before anything is written.
+    fn sync_document_with(&self, path: &str, signatures: &[ffi::ChunkSignature], provider: &mut dyn FnMut(usize, &ffi::ChunkSignature) -> io::Result<Vec<u8>>) -> io::Result<u64> {
+        self.ensure_open()?;
+        let (local, chunks) = self.local_chunks(path)?;
+        let mut data = Vec::new();
+        let mut fetched = 0u64;
+        for (index, signature) in signatures.iter().enumerate() {
+            if let Some(range) = chunks.get(&signature.hash).filter(|range| range.len() as u64 == signature.length) {
+                data.extend_from_slice(&local[range.clone()]);
+                continue;
+            }
+            let chunk = provider(index, signature)?;
+            if chunk.len() as u64 != signature.length || Sha256::digest(&chunk).as_slice() != signature.hash.as_slice() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Chunk {} does not match its signature", index)));
+            }
+            fetched += chunk.len() as u64;
+            data.extend_from_slice(&chunk);
+        }
+        self.write_document_unordered(path, &data, true, true, false)?;
+        Ok(fetched)
+    }
+
+    fn write_patch_bytes(patch: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+        patch.write_u64::<LittleEndian>(bytes.len() as u64)?;
+        patch.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn read_patch_bytes(reader: &mut Cursor<&[u8]>) -> io::Result<Vec<u8>> {
+        let length = reader.read_u64::<LittleEndian>()?;
+        if length > reader.get_ref().len() as u64 - reader.position() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed patch"));
+        }
+        let mut bytes = vec![0u8; length as usize];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// The base and target content hashes of a patch, and its operations.
+    fn parse_patch(patch: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>, Vec<PatchOp>)> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed patch");
+        let mut reader = Cursor::new(patch);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(|_| malformed())?;
+        if magic != PATCH_MAGIC {
+            return Err(malformed());
+        }
+        if reader.read_u16::<LittleEndian>()? != PATCH_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported patch version"));
+        }
+        let mut base_hash = vec![0u8; 32];
+        reader.read_exact(&mut base_hash)?;
+        let mut target_hash = vec![0u8; 32];
+        reader.read_exact(&mut target_hash)?;
+        let count = reader.read_u32::<LittleEndian>()?;
+        let mut ops = Vec::new();
+        for _ in 0..count {
+            let kind = reader.read_u8()?;
+            let path = String::from_utf8(Self::read_patch_bytes(&mut reader)?).map_err(|_| malformed())?;
+            ops.push(match kind {
+                PATCH_OP_PUT => PatchOp::Put { path, data: Self::read_patch_bytes(&mut reader)? },
+                PATCH_OP_REMOVE => PatchOp::Remove { path },
+                PATCH_OP_DELTA => PatchOp::Delta {
+                    path,
+                    base_checksum: reader.read_u32::<LittleEndian>()?,
+                    prefix: reader.read_u64::<LittleEndian>()?,
+                    suffix: reader.read_u64::<LittleEndian>()?,
+                    middle: Self::read_patch_bytes(&mut reader)?,
+                },
+                _ => return Err(malformed()),
+            });
+        }
+        Ok((base_hash, target_hash, ops))
+    }
+
+    /// Applies a patch from create_patch as one transaction: all of it lands or none does. The
+    /// database must hold the contents the patch was made from, and the contents it would leave
+    /// are checked against the patch's target before anything is published. Returns the number
+    /// of documents written or removed.
+    fn apply_patch(self: Pin<&mut Self>, patch: &CxxVector<u8>) -> io::Result<u64> {
+        let _writes = self.begin_write()?;
+        let (base_hash, target_hash, ops) = Self::parse_patch(patch.as_slice())?;
+        let current = self.export_manifest(false)?.entries;
+        if Self::hash_contents(current.iter().map(|entry| (entry.path.as_str(), entry.checksum, entry.size))) != base_hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Patch was made for different contents"));
+        }
+        // What the database will hold once the patch lands, to check against the target
+        let mut expected: BTreeMap<String, (u32, u64)> = current.into_iter().map(|entry| (entry.path, (entry.checksum, entry.size))).collect();
+        let mut tx = Transaction::new(self.commit_history.lock().sequence);
+        let staged = self.stage_patch(&mut tx, ops, &mut expected).and_then(|()| {
+            if Self::hash_contents(expected.iter().map(|(path, &(checksum, size))| (path.as_str(), checksum, size))) != target_hash {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Patched contents would not match the patch target"));
+            }
+            Ok(())
+        });
+        if let Err(e) = staged {
+            for document in &tx.documents {
+                self.free_chain(document.first_page_id)?;
+            }
+            return Err(e);
+        }
+        let applied = (tx.documents.len() + tx.changes.len()) as u64;
+        let result = self.apply_transaction(tx);
+        self.prune_commit_history();
+        result.map(|()| applied)
+    }
+
+    fn stage_patch(&self, tx: &mut Transaction, ops: Vec<PatchOp>, expected: &mut BTreeMap<String, (u32, u64)>) -> io::Result<()> {
+        for op in ops {
+            let (path, data) = match op {
+                PatchOp::Remove { path } => {
+                    let path = self.validate_path(&path)?;
+                    expected.remove(&path);
+                    tx.changes.push(StagedChange::Delete { path, force: false, secure: false });
+                    continue;
+                }
+                PatchOp::Put { path, data } => (path, data),
+                PatchOp::Delta { path, base_checksum, prefix, suffix, middle } => {
+                    let old = self.read_document(&path)?;
+                    if self.compute_crc(&old) != base_checksum || prefix.saturating_add(suffix) > old.len() as u64 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Patch delta does not match the stored document"));
+                    }
+                    let (prefix, suffix) = (prefix as usize, suffix as usize);
+                    let mut data = Vec::with_capacity(prefix + middle.len() + suffix);
+                    data.extend_from_slice(&old[..prefix]);
+                    data.extend_from_slice(&middle);
+                    data.extend_from_slice(&old[old.len() - suffix..]);
+                    (path, data)
+                }
+            };
+            let path = self.validate_path(&path)?;
+            let staged = StagedDocument {
+                first_page_id: self.write_document_chain(&path, &data, self.config.compression_level)?,
+                path,
+                checksum: self.compute_crc(&data),
+                size: data.len() as u64,
+            };
+            expected.insert(staged.path.clone(), (staged.checksum, staged.size));
+            tx.documents.push(staged);
+        }
+        Ok(())
+    }
+
+    /// export_manifest as a JSON object, for tooling outside the engine.
+    #[cfg(feature = "manifest")]
+    pub fn export_manifest_json(&self, include_physical: bool) -> io::Result<String> {
+        let manifest = self.export_manifest(include_physical)?;
+        let entries: Vec<serde_json::Value> = manifest.entries.iter()
+            .map(|entry| serde_json::json!({
+                "path": entry.path,
+                "uuid": entry.uuid,
+                "size": entry.size,
+                "version": entry.version,
+                "mtime": entry.modified,
+                "checksum": format!("{:08x}", entry.checksum),
+                "flags": entry.flags,
+                "stored_bytes": entry.stored_bytes,
+            }))
+            .collect();
+        let mut json = serde_json::json!({ "format_version": FORMAT_VERSION, "entries": entries });
+        if include_physical {
+            json["page_count"] = manifest.page_count.into();
+            json["file_bytes"] = manifest.file_bytes.into();
+        }
+        serde_json::to_string_pretty(&json).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    /// Sets the addon flag of the binding path resolves through.
+    fn bind_addon_path(self: Pin<&mut Self>, path: &CxxString, addon: bool) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        // Rebinding an existing path only updates its flag; it never duplicates the entry
+        let newly_bound = match doc.paths.iter_mut().find(|binding| binding.path == rust_path) {
+            Some(binding) => {
+                binding.addon = addon;
+                false
+            }
+            None => {
+                doc.paths.push(PathBinding { path: rust_path.clone(), addon, priority: 0, lang: String::new() });
+                true
+            }
+        };
+        let new_paths = if newly_bound { vec![(rust_path.clone(), id)] } else { Vec::new() };
+        self.commit_index(&index, false, &new_paths)?;
+        self.emit_event(ffi::DocumentEventOp::Bind, &rust_path, id);
+        self.path_cache.lock().put(rust_path, id);
+        Ok(())
+    }
+
+    /// Removes path from its document. Unbinding the last remaining path deletes the
+    /// document when delete_if_last is set and is refused otherwise.
+    fn unbind_addon_path(self: Pin<&mut Self>, path: &CxxString, delete_if_last: bool) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        doc.check_writable(false)?;
+        if doc.paths.len() == 1 && !delete_if_last {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot unbind the last path of a document"));
+        }
+        doc.paths.retain(|binding| binding.path != rust_path);
+        let removed = if doc.paths.is_empty() { index.remove(&id) } else { None };
+        self.release_binding(&index, &rust_path, id)?;
+        self.write_index(&index)?;
+        self.emit_event(ffi::DocumentEventOp::Unbind, &rust_path, id);
+        if let Some(doc) = removed {
+            self.untag_documents([&doc])?;
+            self.release_chain(&index, doc.first_page_id)?;
+            for link in &doc.previous_versions {
+                self.release_chain(&index, link.page_id)?;
+            }
+            self.emit_event(ffi::DocumentEventOp::Delete, &rust_path, id);
+        }
+        Ok(())
+    }
+
+    /// Gives the document at source, a uuid or one of its paths, the additional name path. It
+    /// then reads, searches and groups under every name it has, until remove_path, rename_path
+    /// (which moves one name) or delete_by_path (which deletes the document with all of them).
+    /// A path resolving to another document is refused unless shadow is set; the new binding
+    /// then takes the winning claim's language and priority, so it wins and removing it
+    /// uncovers the old one. Adding a name the document already resolves through does nothing.
+    fn add_path(self: Pin<&mut Self>, source: &CxxString, path: &CxxString, shadow: bool) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.resolve_source(source.to_string_lossy().as_ref())?;
+        let index = self.read_index()?;
+        index.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?.check_writable(false)?;
+        let current = match self.get_document_id_by_path(&rust_path) {
+            Ok(current) => Some(current),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let binding = match current {
+            Some(current) if current == id => return Ok(()),
+            Some(_) if !shadow => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Path already exists")),
+            Some(current) => {
+                let claims = Self::path_claims(&index, &rust_path, &self.active_language.read());
+                let (priority, lang) = claims.iter()
+                    .find(|(claimant, _)| *claimant == current)
+                    .map_or((0, String::new()), |(_, claim)| (claim.priority, claim.lang.clone()));
+                PathBinding { path: rust_path.clone(), addon: false, priority, lang }
+            }
+            None => PathBinding { path: rust_path.clone(), addon: false, priority: 0, lang: String::new() },
+        };
+        self.bind_layer(&rust_path, id, binding)
+    }
+
+    /// Removes the name path from the document it resolves to, uncovering any binding it
+    /// shadowed. The same as unbind_addon_path: removing the last name deletes the document
+    /// when delete_if_last is set and is refused otherwise.
+    fn remove_path(self: Pin<&mut Self>, path: &CxxString, delete_if_last: bool) -> io::Result<()> {
+        self.unbind_addon_path(path, delete_if_last)
+    }
+
+    /// The document source names: a uuid, or a path it resolves through.
+    fn resolve_source(&self, source: &str) -> io::Result<Uuid> {
+        match Uuid::parse_str(source) {
+            Ok(id) => Ok(id),
+            Err(_) => self.get_document_id_by_path(&self.validate_path(source)?),
+        }
+    }
+
+    /// Binds the document currently at source to path as an additional layer. The new
+    /// binding takes over resolution of path unless a higher-priority layer already claims it.
+    fn bind_path_layer(self: Pin<&mut Self>, path: &CxxString, source: &CxxString, addon: bool, priority: i32) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let source_path = self.validate_path(source.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&source_path)?;
+        self.bind_layer(&rust_path, id, PathBinding { path: rust_path.clone(), addon, priority, lang: String::new() })
+    }
+
+    /// Binds a document to path for one language only. source is either a document uuid or a
+    /// path the document is reachable at. The binding wins over default ones while lang is active.
+    fn bind_localized_path(self: Pin<&mut Self>, path: &CxxString, lang: &CxxString, source: &CxxString) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let lang = lang.to_string_lossy().to_string();
+        if lang.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Language must not be empty"));
+        }
+        let id = self.resolve_source(source.to_string_lossy().as_ref())?;
+        self.bind_layer(&rust_path, id, PathBinding { path: rust_path.clone(), addon: false, priority: 0, lang })
+    }
+
+    fn bind_layer(&self, path: &str, id: Uuid, new_binding: PathBinding) -> io::Result<()> {
+        let mut index = self.read_index()?;
+        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        match doc.paths.iter_mut().find(|binding| binding.path == path) {
+            Some(binding) => *binding = new_binding.clone(),
+            None => doc.paths.push(new_binding.clone()),
+        }
+        self.write_index(&index)?;
+        self.refresh_resolution(&index, path, Some((id, &new_binding)))?;
+        self.emit_event(ffi::DocumentEventOp::Bind, path, id);
+        Ok(())
+    }
+
+    /// Points the trie at whichever claim on path wins under the active language.
+    /// Ties go to newest, the binding just made, as later packs override earlier ones.
+    fn refresh_resolution(&self, index: &BTreeMap<Uuid, Document>, path: &str, newest: Option<(Uuid, &PathBinding)>) -> io::Result<()> {
+        let lang = self.active_language.read().clone();
+        let claims = Self::path_claims(index, path, &lang);
+        let winner = match newest {
+            Some((id, binding)) if claims.iter().any(|(other, _)| *other == id) => claims.iter()
+                .find(|(other, claim)| *other == id || Self::claim_rank(claim, &lang) > Self::claim_rank(binding, &lang))
+                .map(|(winner, _)| *winner),
+            _ => claims.first().map(|(winner, _)| *winner),
+        };
+        self.path_cache.lock().pop(path);
+        let resolved = self.get_document_id_by_path(path).ok();
+        match winner {
+            Some(winner) if resolved != Some(winner) => self.trie_insert(path, winner)?,
+            None if resolved.is_some() => self.trie_delete(path)?,
+            _ => {}
+        }
+        if let Some(winner) = winner {
+            self.path_cache.lock().put(path.to_string(), winner);
+        }
+        Ok(())
+    }
+
+    /// Switches the language whose bindings take precedence and re-resolves every path
+    /// that has a localized binding. An empty lang leaves only default bindings in effect.
+    fn set_active_language(self: Pin<&mut Self>, lang: &CxxString) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        *self.active_language.write() = lang.to_string_lossy().to_string();
+        let index = self.read_index()?;
+        let localized: BTreeSet<String> = index.values()
+            .flat_map(|doc| doc.paths.iter())
+            .filter(|binding| !binding.lang.is_empty())
+            .map(|binding| binding.path.clone())
+            .collect();
+        for path in localized {
+            self.refresh_resolution(&index, &path, None)?;
+        }
+        Ok(())
+    }
+
+    fn get_active_language(&self) -> String {
+        self.active_language.read().clone()
+    }
+
+    /// Lists the bindings made for exactly lang under prefix, regardless of the active
+    /// language; an empty lang lists the default bindings. search_paths_detailed shows the merged view.
+    fn search_language_bindings(&self, prefix: &CxxString, lang: &CxxString) -> io::Result<Vec<ffi::DocumentInfo>> {
+        self.ensure_open()?;
+        let prefix = prefix.to_string_lossy();
+        let lang = lang.to_string_lossy();
+        let index = self.read_index()?;
+        let mut results = Vec::new();
+        for doc in index.values() {
+            for binding in doc.paths.iter().filter(|binding| binding.lang == lang && binding.path.starts_with(prefix.as_ref())) {
+                results.push(self.document_info(&binding.path, doc)?);
+            }
+        }
+        results.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(results)
+    }
+
+    /// Reports which binding path resolves to and every binding it shadows.
+    fn resolve_path(&self, path: &CxxString) -> io::Result<ffi::PathResolution> {
+        self.ensure_open()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let id = self.get_document_id_by_path(&rust_path)?;
+        let index = self.read_index()?;
+        let claims = Self::path_claims(&index, &rust_path, &self.active_language.read());
+        let winner = claims.iter()
+            .find(|(other, _)| *other == id)
+            .map(|(_, binding)| binding.clone())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+        Ok(ffi::PathResolution {
+            uuid: id.to_string(),
+            addon: winner.addon,
+            priority: winner.priority,
+            lang: winner.lang,
+            shadowed: claims.into_iter()
+                .filter(|(other, _)| *other != id)
+                .map(|(other, binding)| ffi::PathClaim { uuid: other.to_string(), addon: binding.addon, priority: binding.priority, lang: binding.lang })
+                .collect(),
+        })
+    }
+
+    fn begin_transaction(self: Pin<&mut Self>) -> io::Result<i64> {
+        let _writes = self.begin_write()?;
+        let tx_id = self.next_stream_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let begun_after = self.commit_history.lock().sequence;
+        self.transactions.lock().insert(tx_id, Transaction::new(begun_after));
+        self.transaction_totals.lock().begun += 1;
+        Ok(tx_id)
+    }
+
+    fn commit_transaction(self: Pin<&mut Self>, tx_id: i64) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let tx = self.transactions.lock().remove(&tx_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid transaction ID"))?;
+        let _span = trace_span!("commit_transaction", tx_id = tx_id, pages = tx.writes.len(), documents = tx.documents.len());
+        *self.commit_pages.lock() = Some(HashSet::new());
+        let result = self.apply_transaction(tx);
+        let pages = self.commit_pages.lock().take().map_or(0, |pages| pages.len() as u64);
+        self.prune_commit_history();
+        let mut totals = self.transaction_totals.lock();
+        if result.is_ok() {
+            totals.committed += 1;
+            totals.pages_committed += pages;
+            totals.last_commit_pages = pages;
+            totals.max_commit_pages = totals.max_commit_pages.max(pages);
+        } else {
+            totals.failed += 1;
+        }
+        result
+    }
+
+    fn apply_transaction(&self, tx: Transaction) -> io::Result<()> {
+        // A conflict or a READONLY target fails the whole commit before anything is applied
+        let checked = self.check_commit_conflicts(&tx)
+            .and_then(|()| self.check_staged_writable(&tx.documents))
+            .and_then(|()| self.check_staged_changes(&tx.changes));
+        if let Err(e) = checked {
+            for staged in &tx.documents {
+                self.free_chain(staged.first_page_id)?;
+            }
+            return Err(e);
+        }
+        for (page_id, data, version) in tx.writes {
+            self.write_raw_page(page_id, &data, version, PageLinks::single(FLAG_DATA_PAGE))?;
+        }
+        if !tx.documents.is_empty() || !tx.changes.is_empty() {
+            self.publish_staged(&tx.documents, &tx.changes)?;
+            let mut history = self.commit_history.lock();
+            history.sequence += 1;
+            let sequence = history.sequence;
+            history.commits.push_back((sequence, tx.paths().cloned().collect()));
+        }
+        for page_id in tx.frees {
+            self.free_page(page_id)?;
+        }
+        Ok(())
+    }
+
+    /// First committer wins: a transaction may not publish a path that another transaction
+    /// has committed since this one began.
+    fn check_commit_conflicts(&self, tx: &Transaction) -> io::Result<()> {
+        let history = self.commit_history.lock();
+        for (_, paths) in history.commits.iter().filter(|(sequence, _)| *sequence > tx.begun_after) {
+            if let Some(path) = tx.paths().find(|path| paths.contains(*path)) {
+                return Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("Conflict: {} was committed by another transaction", path)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets commits that every open transaction began after.
+    fn prune_commit_history(&self) {
+        let oldest = self.transactions.lock().values().map(|tx| tx.begun_after).min();
+        let mut history = self.commit_history.lock();
+        match oldest {
+            Some(oldest) => history.commits.retain(|(sequence, _)| *sequence > oldest),
+            None => history.commits.clear(),
+        }
+    }
+
+    fn check_staged_writable(&self, documents: &[StagedDocument]) -> io::Result<()> {
+        let index = self.read_index()?;
+        for staged in documents {
+            match self.get_document_id_by_path(&staged.path) {
+                Ok(id) => {
+                    if let Some(doc) = index.get(&id) {
+                        doc.check_writable(false)?;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_staged_changes(&self, changes: &[StagedChange]) -> io::Result<()> {
+        let index = self.read_index()?;
+        for change in changes {
+            let (path, force) = match change {
+                StagedChange::Delete { path, force, .. } => (path, *force),
+                StagedChange::Rename { from, .. } => (from, false),
+            };
+            let id = self.get_document_id_by_path(path)?;
+            index.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?.check_writable(force)?;
+            if let StagedChange::Rename { to, .. } = change {
+                match self.get_document_id_by_path(to) {
+                    Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Path already exists")),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes every staged document and path change visible with one index write; a crash
+    /// before that write leaves all of their paths as they were. Paths losing their binding
+    /// leave the trie only once the index is durable (recover rebuilds the trie if a crash
+    /// comes between), and the chains nothing references any more are freed after that.
+    fn publish_staged(&self, documents: &[StagedDocument], changes: &[StagedChange]) -> io::Result<()> {
+        let mut index = self.read_index()?;
+        let mut stale_chains = Vec::new();
+        let mut published = Vec::with_capacity(documents.len());
+        let mut new_paths = Vec::new();
+        for staged in documents {
+            let existing = match self.get_document_id_by_path(&staged.path) {
+                Ok(id) => Some(id),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            let (id, created) = self.apply_document(&mut index, existing, &staged.path, staged.first_page_id, staged.checksum, staged.size, &mut stale_chains)?;
+            if created {
+                new_paths.push((staged.path.clone(), id));
+            }
+            published.push((staged.path.clone(), id));
+        }
+        let mut unbound = Vec::new();
+        let mut renamed = Vec::new();
+        let mut deleted = Vec::new();
+        for change in changes {
+            match change {
+                StagedChange::Delete { path, secure, .. } => {
+                    let id = self.get_document_id_by_path(path)?;
+                    if let Some(doc) = index.remove(&id) {
+                        unbound.extend(doc.paths.iter().map(|binding| (binding.path.clone(), id)));
+                        if *secure {
+                            let mut wipe_chains = self.wipe_chains.lock();
+                            wipe_chains.extend(std::iter::once(doc.first_page_id).chain(doc.previous_versions.iter().map(|link| link.page_id)).filter(|&page_id| page_id != -1));
+                        }
+                        deleted.push(doc);
+                    }
+                }
+                StagedChange::Rename { from, to } => {
+                    let id = self.get_document_id_by_path(from)?;
+                    let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
+                    if let Some(binding) = doc.paths.iter_mut().find(|binding| binding.path == *from) {
+                        binding.path = to.clone();
+                    }
+                    unbound.push((from.clone(), id));
+                    new_paths.push((to.clone(), id));
+                    renamed.push((from.clone(), to.clone(), id));
+                }
+            }
+        }
+        self.commit_index(&index, false, &new_paths)?;
+        for (path, id) in &unbound {
+            self.release_binding(&index, path, *id)?;
+        }
+        if !unbound.is_empty() {
+            self.write_barrier()?;
+        }
+        for page_id in stale_chains {
+            self.release_chain(&index, page_id)?;
+        }
+        self.untag_documents(&deleted)?;
+        // Chains shared through dedup survive
+        for doc in &deleted {
+            self.release_chain(&index, doc.first_page_id)?;
+            for link in &doc.previous_versions {
+                self.release_chain(&index, link.page_id)?;
+            }
+        }
+        if unbound.iter().map(|(path, _)| path).chain(renamed.iter().map(|(_, to, _)| to)).any(|path| Self::dictionary_id(path).is_some()) {
+            self.load_dictionaries()?;
+        }
+        let mut path_cache = self.path_cache.lock();
+        for (path, id) in published {
+            self.emit_event(ffi::DocumentEventOp::Write, &path, id);
+            path_cache.put(path, id);
+        }
+        for doc in &deleted {
+            for binding in &doc.paths {
+                self.emit_event(ffi::DocumentEventOp::Delete, &binding.path, doc.id);
+            }
+        }
+        for (from, to, id) in renamed {
+            self.emit_event(ffi::DocumentEventOp::Unbind, &from, id);
+            self.emit_event(ffi::DocumentEventOp::Bind, &to, id);
+            path_cache.put(to, id);
+        }
+        Ok(())
+    }
+
+    fn rollback_transaction(self: Pin<&mut Self>, tx_id: i64) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let tx = self.transactions.lock().remove(&tx_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid transaction ID"))?;
+        self.transaction_totals.lock().rolled_back += 1;
+        self.prune_commit_history();
+        // Staged chains were never reachable, so they can go straight back to the free list
+        for staged in tx.documents {
+            self.free_chain(staged.first_page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Transaction totals since open, and every open transaction with its age and what it
+    /// has staged, oldest first.
+    fn get_transaction_stats(&self) -> ffi::TransactionStats {
+        let mut open: Vec<_> = self.transactions.lock().iter()
+            .map(|(&tx_id, tx)| {
+                let age_ms = tx.started.elapsed().as_millis() as u64;
+                ffi::OpenTransaction {
+                    tx_id,
+                    started_unix_ms: tx.started_unix_ms,
+                    age_ms,
+                    staged_writes: (tx.writes.len() + tx.documents.len() + tx.changes.len()) as u64,
+                    staged_frees: tx.frees.len() as u64,
+                    long_open: age_ms >= self.config.long_transaction_ms,
+                }
+            })
+            .collect();
+        // Ids only grow, so they order transactions by when they began
+        open.sort_by_key(|tx| tx.tx_id);
+        let totals = self.transaction_totals.lock();
+        ffi::TransactionStats {
+            begun: totals.begun,
+            committed: totals.committed,
+            failed: totals.failed,
+            rolled_back: totals.rolled_back,
+            pages_committed: totals.pages_committed,
+            last_commit_pages: totals.last_commit_pages,
+            max_commit_pages: totals.max_commit_pages,
+            open,
+        }
+    }
+
+    /// Starts a group of document writes (e.g. a savegame slot) that become visible together.
+    /// Its commit fails with a "Conflict:" error, for the caller to retry, if another session
+    /// committed one of the same paths after this one began.
+    fn begin_save_session(self: Pin<&mut Self>) -> io::Result<i64> {
+        self.begin_transaction()
+    }
+
+    /// Writes the document's pages now but leaves path at its current version until commit.
+    /// Writing the same path twice in a session keeps the last data.
+    fn save_session_write(self: Pin<&mut Self>, session_id: i64, path: &CxxString, data: &CxxVector<u8>) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let rust_path = self.validate_path(path.to_string_lossy().as_ref())?;
+        let data = data.as_slice();
+        if !self.transactions.lock().contains_key(&session_id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid transaction ID"));
+        }
+        let staged = StagedDocument {
+            first_page_id: self.write_document_chain(&rust_path, data, self.config.compression_level)?,
+            path: rust_path,
+            checksum: self.compute_crc(data),
+            size: data.len() as u64,
+        };
+        let replaced = {
+            let mut txs = self.transactions.lock();
+            let tx = txs.get_mut(&session_id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid transaction ID"))?;
+            let replaced = tx.documents.iter().position(|other| other.path == staged.path).map(|i| tx.documents.remove(i));
+            tx.documents.push(staged);
+            replaced
+        };
+        if let Some(replaced) = replaced {
+            self.free_chain(replaced.first_page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes path when the session commits; force also deletes a READONLY document.
+    fn save_session_delete(self: Pin<&mut Self>, session_id: i64, path: &CxxString, force: bool) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let path = self.validate_path(path.to_string_lossy().as_ref())?;
+        self.stage_change(session_id, StagedChange::Delete { path, force, secure: false })
+    }
+
+    /// Renames from to to when the session commits.
+    fn save_session_rename(self: Pin<&mut Self>, session_id: i64, from: &CxxString, to: &CxxString) -> io::Result<()> {
+        let _writes = self.begin_write()?;
+        let from = self.validate_path(from.to_string_lossy().as_ref())?;
+        let to = self.validate_path(to.to_string_lossy().as_ref())?;
+        self.stage_change(session_id, StagedChange::Rename { from, to })
+    }
+
+    fn stage_change(&self, session_id: i64, change: StagedChange) -> io::Result<()> {
+        let mut txs = self.transactions.lock();
+        let tx = txs.get_mut(&session_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid transaction ID"))?;
+        tx.changes.push(change);
+        Ok(())
+    }
+
+    fn commit_save_session(self: Pin<&mut Self>, session_id: i64) -> io::Result<()> {
+        self.commit_transaction(session_id)
+    }
+
+    fn abort_save_session(self: Pin<&mut Self>, session_id: i64) -> io::Result<()> {
+        self.rollback_transaction(session_id)
+    }
+
+    /// Writes a consistent copy of the current documents to dest_path while the database stays
+    /// open. Returns the number of documents copied. The copy is built beside dest_path and
+    /// renamed into place, so dest_path never holds a partial snapshot.
+    fn snapshot_to(&self, dest_path: &CxxString) -> io::Result<u64> {
+        self.ensure_open()?;
+        let (index, pinned) = self.capture_snapshot()?;
+        let result = self.write_snapshot(&index, Path::new(dest_path.to_string_lossy().as_ref()));
+        for first_page_id in pinned {
+            self.unpin_chain(first_page_id);
+        }
+        result
+    }
+
+    /// Reads the index and pins every chain it names, so writers that supersede or delete a
+    /// document during the snapshot defer freeing its pages. Retries if a chain was freed
+    /// between reading the index and pinning it.
+    fn capture_snapshot(&self) -> io::Result<(BTreeMap<Uuid, Document>, Vec<i64>)> {
+        for _ in 0..8 {
+            let index = self.read_index()?;
+            let pinned: Vec<i64> = index.values().map(|doc| doc.first_page_id).filter(|&page_id| page_id != -1).collect();
+            for &first_page_id in &pinned {
+                self.pin_chain(first_page_id);
+            }
+            // A chain still referenced by its document, as current or retained version, was not freed
+            let check = self.read_index()?;
+            let stable = index.values().filter(|doc| doc.first_page_id != -1).all(|doc| {
+                check.get(&doc.id).map_or(false, |now| {
+                    now.first_page_id == doc.first_page_id
+                        || now.previous_versions.iter().any(|link| link.page_id == doc.first_page_id)
+                })
+            });
+            if stable {
+                return Ok((index, pinned));
+            }
+            for first_page_id in pinned {
+                self.unpin_chain(first_page_id);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::Other, "Snapshot could not capture a stable index"))
+    }
+
+    fn write_snapshot(&self, index: &BTreeMap<Uuid, Document>, dest_path: &Path) -> io::Result<u64> {
+        let mut partial_name = dest_path.as_os_str().to_owned();
+        partial_name.push(".partial");
+        let partial_path = Path::new(&partial_name).to_path_buf();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&partial_path)?;
+        let config = Config {
+            use_compression: self.config.use_compression,
+            codec: self.config.codec,
+            compression_level: self.config.compression_level,
+            compression_rules: self.get_compression_rules(),
+            path_policy: self.config.path_policy.clone(),
+            durable_writes: false, // one sync at the end is enough for a file nobody else has open
+            ..Default::default()
+        };
+        let dest = Self::open_file_with_config(file, &partial_path, config, false)?;
+        let mut dest_index = BTreeMap::new();
+        for doc in index.values() {
+            let data = self.read_chain_as(doc.first_page_id, PageCaching::Peek)?;
+            let first_page_id = dest.write_chain(&data, doc.paths.first().map_or(dest.default_codec(), |binding| dest.codec_for_path(&binding.path)))?;
+            dest_index.insert(doc.id, Document {
+                id: doc.id,
+                first_page_id,
+                current_version: doc.current_version,
+                checksum: doc.checksum,
+                paths: doc.paths.clone(),
+                previous_versions: Vec::new(), // a snapshot carries current contents only
+                expires_at: doc.expires_at,
+                tags: doc.tags.clone(),
+                size: doc.size,
+                modified: doc.modified,
+                flags: doc.flags,
+                page_count: dest.chain_page_count(first_page_id)?,
+            });
+        }
+        dest.write_index(&dest_index)?;
+        for doc in dest_index.values() {
+            for tag in &doc.tags {
+                dest.tag_table.write().entry(tag.clone()).or_default().insert(doc.id);
+            }
+        }
+        dest.write_tag_table()?;
+        let lang = self.active_language.read().clone();
+        let paths: BTreeSet<&str> = index.values().flat_map(|doc| doc.paths.iter().map(|binding| binding.path.as_str())).collect();
+        for path in paths {
+            if let Some((winner, _)) = Self::path_claims(index, path, &lang).first() {
+                dest.trie_insert(path, *winner)?;
+            }
+        }
+        if let Some(mmap) = dest.mmap.read().as_ref() {
+            mmap.flush()?;
+        }
+        dest.file.lock().sync_all()?;
+        drop(dest);
+        std::fs::rename(&partial_path, dest_path)?;
+        Ok(dest_index.len() as u64)
+    }
+
+    /// Makes every write so far durable: rewrites the roots, flushes the mapping and fsyncs.
+    /// A no-op when nothing was written since the last checkpoint. Writes are applied in place
+    /// (there is no log yet), so log_bytes_reclaimed is always 0.
+    fn checkpoint(&self) -> io::Result<ffi::CheckpointStats> {
+        self.ensure_open()?;
+        if self.dirty_pages.lock().is_empty() {
+            return Ok(ffi::CheckpointStats { pages_flushed: 0, log_bytes_reclaimed: 0 });
+        }
+        let _timer = self.latency.time(TimedOp::Flush, OpDetail::None);
+        self.write_roots()?;
+        // Taken before flushing: pages dirtied while the flush runs stay queued for the next checkpoint
+        let flushed = std::mem::take(&mut *self.dirty_pages.lock());
+        if let Some(mmap) = self.mmap.read().as_ref() {
+            mmap.flush()?;
+        }
+        self.sync_storage()?;
+        Ok(ffi::CheckpointStats { pages_flushed: flushed.len() as u64, log_bytes_reclaimed: 0 })
+    }
+
+    fn set_quick_mode(self: Pin<&mut Self>, enabled: bool) {
+        self.quick_mode.store(enabled, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Switches between mapped and file IO on the open database; returns whether it is mapped
+    /// afterwards. Dropping the mapping waits for every reader and mapped view holding it, and
+    /// for any write in progress, so no read sees a page half way through the switch.
+    fn set_io_mode(self: Pin<&mut Self>, mode: ffi::IoMode) -> io::Result<bool> {
+        if ![ffi::IoMode::Auto, ffi::IoMode::MmapPreferred, ffi::IoMode::FileOnly].contains(&mode) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unknown IO mode"));
+        }
+        let this = self.get_mut();
+        this.ensure_open()?;
+        let _layout = this.maintenance.layout.write();
+        let _writes = this.maintenance.gate.lock();
+        let mapping = {
+            let file = this.file.lock();
+            Self::map_file(&file, &this.config, mode, this.storage.as_ref(), this.file_len.load(std::sync::atomic::Ordering::SeqCst))
+        };
+        let mut mmap = this.mmap.write();
+        if let Some(old) = mmap.as_ref() {
+            // Pages written through the old mapping reach the file before reads bypass it
+            old.flush()?;
+        }
+        let huge_pages = this.config.huge_pages && mapping.as_ref().is_some_and(Self::advise_huge_pages);
+        this.huge_pages.store(huge_pages, std::sync::atomic::Ordering::SeqCst);
+        *mmap = mapping;
+        this.config.io_mode = mode;
+        let mapped = mmap.is_some();
+        drop(mmap);
+        if mapped {
+            // A fresh mapping starts cold, like the one made at open
+            this.prefault_with_progress(this.config.prefault, &mut |_, _| {}).unwrap_or(0);
+        }
+        Ok(mapped)
+    }
+
+    /// Faults pages in ahead of use; returns the bytes touched. See prefault_with_progress.
+    fn prefault(&self, prefault: ffi::Prefault) -> io::Result<u64> {
+        self.prefault_with_progress(prefault, &mut |_, _| {})
+    }
+
+    /// Faults in the pages the mode names and returns the bytes touched, calling progress with
+    /// (done, total) as it goes: bytes for Full, metadata structures for Metadata. Mapped
+    /// databases have their pages touched through the mapping; others are read through the
+    /// storage, which warms the OS cache instead. A remap as the file grows keeps the pages in
+    /// the OS cache, so the new mapping's first touches are minor faults that read nothing from
+    /// disk; prefault is not repeated for it.
+    pub fn prefault_with_progress(&self, prefault: ffi::Prefault, progress: &mut dyn FnMut(u64, u64)) -> io::Result<u64> {
+        self.ensure_open()?;
+        match prefault {
+            ffi::Prefault::Metadata => {
+                // Reading a structure faults its pages in; nodes are counted as a page each
+                self.prefault_range(0, self.config.page_size)?;
+                let mut pages = 1;
+                let mut pending = match self.document_index_root.read().page_id {
+                    -1 => Vec::new(),
+                    root => vec![(root, 0)],
+                };
+                while let Some((page_id, depth)) = pending.pop() {
+                    if depth > INDEX_MAX_DEPTH {
+                        return Err(Self::corrupt("document index"));
+                    }
+                    if let IndexNode::Branch { children, .. } = self.read_index_node(page_id)? {
+                        pending.extend(children.into_iter().map(|child| (child, depth + 1)));
+                    }
+                    pages += 1;
+                }
+                progress(1, 3);
+                let trie_root = self.trie_root.read().page_id;
+                let mut trie_pages = Vec::new();
+                if trie_root != -1 {
+                    self.trie_collect_pages(trie_root, &mut trie_pages)?;
+                }
+                pages += trie_pages.len() as u64;
+                progress(2, 3);
+                let mut page_id = self.free_list_root.read().page_id;
+                while page_id != -1 {
+                    page_id = self.read_free_list_header(page_id)?.0;
+                    pages += 1;
+                }
+                progress(3, 3);
+                Ok(pages * self.config.page_size)
+            }
+            ffi::Prefault::Full => {
+                let total = self.file_len.load(std::sync::atomic::Ordering::SeqCst);
+                #[cfg(target_os = "linux")]
+                if let Some(mmap) = self.mmap.read().as_ref() {
+                    let len = (total as usize).min(mmap.len());
+                    // Readahead for the whole file up front; the touches below then mostly find pages in
+                    unsafe { libc::madvise(mmap.as_ptr() as *mut libc::c_void, len, libc::MADV_WILLNEED) };
+                }
+                let mut done = 0;
+                while done < total {
+                    let len = PREFAULT_CHUNK_BYTES.min(total - done);
+                    done += self.prefault_range(done, len)?;
+                    progress(done, total);
+                }
+                Ok(done)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Touches one byte per OS page of the range through the mapping, or reads the range
+    /// through the storage when there is none. Holds the mapping lock only for the range.
+    fn prefault_range(&self, offset: u64, len: u64) -> io::Result<u64> {
+        // Only what the file holds; the mapping's tail has nothing behind it to fault in
+        let len = len.min(self.file_len.load(std::sync::atomic::Ordering::SeqCst).saturating_sub(offset));
+        if let Some(mmap) = self.mmap.read().as_ref() {
+            let range = Self::mmap_range(mmap, offset, len as usize).ok_or_else(Self::too_large)?;
+            let mut sum = 0u8;
+            for position in range.clone().step_by(4096) {
+                sum = sum.wrapping_add(unsafe { std::ptr::read_volatile(mmap.as_ptr().add(position)) });
+            }
+            std::hint::black_box(sum);
+            return Ok(range.len() as u64);
+        }
+        let mut buffer = vec![0u8; len.min(1024 * 1024) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(buffer.len() as u64) as usize;
+            self.storage.read_at(offset + done, &mut buffer[..chunk])?;
+            done += chunk as u64;
+        }
+        Ok(len)
+    }
+
+    /// Turns latency histograms on or off. Off costs one branch per timed operation.
+    fn set_latency_tracking(&self, enabled: bool) {
+        self.latency.enabled.store(enabled, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn get_latency_report(&self) -> ffi::LatencyReport {
+        ffi::LatencyReport {
+            page_reads: self.latency.page_reads.summary(),
+            document_gets: self.latency.document_gets.summary(),
+            writes: self.latency.writes.summary(),
+            flushes: self.latency.flushes.summary(),
+        }
+    }
+
+    /// Routes slow-operation warnings to the engine console; null stops them. The engine must
+    /// clear the sink before common is destroyed.
+    unsafe fn set_log_sink(&self, common: *const ffi::idCommon) {
+        self.latency.log_sink.store(common as *mut ffi::idCommon, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn reset_latency_stats(&self) {
+        for histogram in [&self.latency.page_reads, &self.latency.document_gets, &self.latency.writes, &self.latency.flushes] {
+            histogram.reset();
+        }
+    }
+
+    /// Turns lock contention tracking on or off. Off costs one branch per tracked acquisition;
+    /// on, an uncontended acquisition costs a try-lock and a counter.
+    fn set_lock_tracking(&self, enabled: bool) {
+        self.lock_stats.enabled.store(enabled, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn get_lock_stats(&self) -> Vec<ffi::LockStats> {
+        TrackedLock::ALL.iter().map(|&lock| {
+            let counters = &self.lock_stats.counters[lock as usize];
+            ffi::LockStats {
+                name: lock.name().to_string(),
+                acquisitions: counters.acquisitions.load(std::sync::atomic::Ordering::Relaxed),
+                contended: counters.contended.load(std::sync::atomic::Ordering::Relaxed),
+                total_wait_us: counters.wait_us.load(std::sync::atomic::Ordering::Relaxed),
+                max_wait_us: counters.max_wait_us.load(std::sync::atomic::Ordering::Relaxed),
+            }
+        }).collect()
+    }
+
+    fn reset_lock_stats(&self) {
+        for counters in &self.lock_stats.counters {
+            for counter in [&counters.acquisitions, &counters.contended, &counters.wait_us, &counters.max_wait_us] {
+                counter.store(0, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn list_tunables(&self) -> Vec<ffi::Tunable> {
+        let defaults = Config::default();
+        let int = |name: &str, current: u64, default_value: u64, runtime: bool| ffi::Tunable {
+            name: name.to_string(),
+            kind: "int".to_string(),
+            current: current.to_string(),
+            default_value: default_value.to_string(),
+            runtime,
+        };
+        let flag = |name: &str, current: bool, default_value: bool, runtime: bool| ffi::Tunable {
+            name: name.to_string(),
+            kind: "bool".to_string(),
+            current: (current as u8).to_string(),
+            default_value: (default_value as u8).to_string(),
+            runtime,
+        };
+        let mut tunables = vec![
+            int("page_cache_size", self.page_cache_capacity() as u64, defaults.page_cache_size as u64, true),
+            int("path_cache_size", self.path_cache.lock().cap() as u64, defaults.path_cache_size as u64, true),
+            int("trie_cache_size", self.trie_cache.lock().cap() as u64, defaults.trie_cache_size as u64, true),
+            int("path_snapshot_budget", self.config.path_snapshot_budget, defaults.path_snapshot_budget, true),
+            int("versions_to_keep", self.config.versions_to_keep as u64, defaults.versions_to_keep as u64, true),
+            flag("quick_mode", self.quick_mode.load(std::sync::atomic::Ordering::SeqCst), false, true),
+            flag("durable_writes", self.config.durable_writes, defaults.durable_writes, true),
+            int("dictionary_threshold", self.config.dictionary_threshold, defaults.dictionary_threshold, true),
+            int("index_log_threshold", self.config.index_log_threshold as u64, defaults.index_log_threshold as u64, true),
+            int("long_transaction_ms", self.config.long_transaction_ms, defaults.long_transaction_ms, true),
+            int("cursor_timeout_ms", self.config.cursor_timeout_ms, defaults.cursor_timeout_ms, true),
+            int("decompress_threads", self.config.decompress_threads as u64, defaults.decompress_threads as u64, true),
+            int("maintenance_fragmentation_percent", self.config.maintenance_fragmentation_percent as u64, defaults.maintenance_fragmentation_percent as u64, true),
+            int("maintenance_free_percent", self.config.maintenance_free_percent as u64, defaults.maintenance_free_percent as u64, true),
+            int("page_size", self.config.page_size, defaults.page_size, false),
+            flag("compression", self.config.use_compression, defaults.use_compression, false),
+            ffi::Tunable {
+                name: "compression_level".to_string(),
+                kind: "int".to_string(),
+                current: self.config.compression_level.to_string(),
+                default_value: defaults.compression_level.to_string(),
+                runtime: true,
+            },
+            int("segment_size", self.storage.segment_layout().0, defaults.segment_size, false),
+            flag("mmap", self.mmap.read().is_some(), defaults.io_mode != ffi::IoMode::FileOnly, false),
+            flag("hide_expired", self.config.hide_expired, defaults.hide_expired, true),
+        ];
+        for op in TimedOp::ALL {
+            let current = self.latency.slow_threshold_ms[op as usize].load(std::sync::atomic::Ordering::Relaxed);
+            tunables.push(int(op.tunable(), current, SLOW_OP_DEFAULT_MS[op as usize], true));
+        }
+        tunables
+    }
+
+    /// Applies a tunable to the open database. Settings that would change the file layout
+    /// (page size, codec, segmenting) or the mapping are refused; reopen with new options instead.
+    fn set_tunable(self: Pin<&mut Self>, name: &CxxString, value: &CxxString) -> io::Result<()> {
+        // Borrowing only the maintenance locks, so config can change while they are held
+        let this = self.get_mut();
+        this.ensure_open()?;
+        let _layout = this.maintenance.layout.write();
+        let _writes = this.maintenance.gate.lock();
+        let name = name.to_string_lossy();
+        let value = value.to_string_lossy();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid value for {}: {}", name, value));
+        let parse_int = || value.trim().parse::<usize>().map_err(|_| invalid());
+        let parse_bool = || match value.trim() {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => Err(invalid()),
+        };
+        match name.as_ref() {
+            "page_cache_size" => match parse_int()? {
+                0 => return Err(invalid()),
+                size => {
+                    for shard in &this.page_cache {
+                        shard.lock().resize(size.div_ceil(PAGE_CACHE_SHARDS));
+                    }
+                }
+            },
+            "path_cache_size" => match parse_int()? {
+                0 => return Err(invalid()),
+                size => this.path_cache.lock().resize(size),
+            },
+            "trie_cache_size" => match parse_int()? {
+                0 => return Err(invalid()),
+                size => this.trie_cache.lock().resize(size),
+            },
+            "versions_to_keep" => {
+                let versions = i32::try_from(parse_int()?).map_err(|_| invalid())?;
+                this.config.versions_to_keep = versions;
+            }
+            "quick_mode" => this.quick_mode.store(parse_bool()?, std::sync::atomic::Ordering::SeqCst),
+            tunable if TimedOp::ALL.iter().any(|op| op.tunable() == tunable) => {
+                let op = TimedOp::ALL.into_iter().find(|op| op.tunable() == tunable).unwrap();
+                let threshold_ms = parse_int()? as u64;
+                this.latency.slow_threshold_ms[op as usize].store(threshold_ms, std::sync::atomic::Ordering::Relaxed);
+            }
+            "durable_writes" => this.config.durable_writes = parse_bool()?,
+            "path_snapshot_budget" => this.config.path_snapshot_budget = parse_int()? as u64,
+            "hide_expired" => this.config.hide_expired = parse_bool()?,
+            "dictionary_threshold" => this.config.dictionary_threshold = parse_int()? as u64,
+            "index_log_threshold" => this.config.index_log_threshold = parse_int()?,
+            "long_transaction_ms" => this.config.long_transaction_ms = parse_int()? as u64,
+            "cursor_timeout_ms" => this.config.cursor_timeout_ms = parse_int()? as u64,
+            "decompress_threads" => this.config.decompress_threads = parse_int()?,
+            "maintenance_fragmentation_percent" => match parse_int()? {
+                percent if percent <= 100 => this.config.maintenance_fragmentation_percent = percent as u32,
+                _ => return Err(invalid()),
+            },
+            "maintenance_free_percent" => match parse_int()? {
+                percent if percent <= 100 => this.config.maintenance_free_percent = percent as u32,
+                _ => return Err(invalid()),
+            },
+            "compression_level" => {
+                let level = value.trim().parse::<i32>().map_err(|_| invalid())?;
+                Self::validate_compression_level(this.config.codec, level)?;
+                this.config.compression_level = level;
+            }
+            "page_size" | "compression" | "segment_size" | "mmap" => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} cannot change while the database is open", name)));
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown tunable {}", name))),
+        }
+        Ok(())
+    }
+
+    fn get_cache_stats(&self) -> CacheStats {
+        self.cache_stats.lock().clone()
+    }
+
+    /// Flushes and releases everything. Safe to call more than once; afterwards every call
+    /// that can fail returns a "Database is closed" error.
+    fn close_db(self: Pin<&mut Self>) {
+        self.shutdown();
+    }
+
+    fn shutdown(&self) {
+        if self.closed.load(std::sync::atomic::Ordering::SeqCst) {
+            return;
+        }
+        self.stop_maintenance();
+        self.stop_mirror();
+        // Document writes already laying down pages finish and commit first
+        let _layout = self.maintenance.layout.write();
+        // Without the lock the file may hold another process's changes, which nothing here may overwrite
+        if self.lock_released.load(std::sync::atomic::Ordering::SeqCst) {
+            self.closed.store(true, std::sync::atomic::Ordering::SeqCst);
+            *self.mmap.write() = None;
+            if let Some(mirror) = &self.mirror {
+                mirror.db.shutdown();
+            }
+            return;
+        }
+        // Outstanding stream handles become invalid; their deferred frees are applied now
+        let streams: Vec<_> = self.streams.write().drain().map(|(_, stream)| stream).collect();
+        for stream in streams {
+            self.release_stream(&stream.lock());
+        }
+        let cursors: Vec<_> = self.cursors.lock().drain().map(|(_, cursor)| cursor).collect();
+        for cursor in cursors {
+            self.release_cursor(cursor);
+        }
+        // Uncommitted transactions are rolled back; their staged chains were never reachable
+        let transactions: Vec<_> = self.transactions.lock().drain().map(|(_, tx)| tx).collect();
+        self.transaction_totals.lock().rolled_back += transactions.len() as u64;
+        for tx in transactions {
+            for staged in tx.documents {
+                self.free_chain(staged.first_page_id).unwrap_or(());
+            }
+        }
+        let appends: Vec<_> = self.appends.lock().drain().map(|(_, handle)| handle).collect();
+        for mut handle in appends {
+            self.sync_append_handle(&mut handle).unwrap_or(());
+        }
+        if self.config.truncate_on_close && !self.config.read_only {
+            self.truncate_free_tail().unwrap_or(0);
+        }
+        if let Some(mmap) = self.mmap.write().as_mut() {
+            mmap.flush().unwrap_or(());
+        }
+        self.sync_storage().unwrap_or(());
+        self.closed.store(true, std::sync::atomic::Ordering::SeqCst);
+        *self.mmap.write() = None;
+        self.file.lock().unlock().unwrap_or(());
+        if let Some(mirror) = &self.mirror {
+            mirror.db.shutdown();
+        }
+    }
+
+    fn ensure_open(&self) -> io::Result<()> {
+        if self.closed.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Database is closed"));
+        }
+        if self.lock_released.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "Database lock released; reload_if_changed takes it back"));
+        }
+        Ok(())
+    }
+
+    /// ensure_open for anything that changes the file, which a read-only open refuses.
+    fn ensure_writable(&self) -> io::Result<()> {
+        self.ensure_open()?;
+        if self.config.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Database is open read-only"));
+        }
+        Ok(())
+    }
+
+    /// ensure_writable for writers: also holds maintenance off until the returned guard drops.
+    fn begin_write(&self) -> io::Result<Ordered<ReentrantMutexGuard<'_, ()>>> {
+        self.ensure_writable()?;
+        Ok(self.lock_gate())
+    }
+
+    /// ensure_writable for writers that lay down pages before taking the gate: holds off work
+    /// that moves or truncates pages, but not other writers.
+    fn begin_layout_write(&self) -> io::Result<Ordered<parking_lot::RwLockReadGuard<'_, ()>>> {
+        self.ensure_writable()?;
+        Ok(self.lock_stats.acquire(TrackedLock::Layout, || self.maintenance.layout.try_read(), || self.maintenance.layout.read()))
+    }
+
+    /// begin_write for work that moves or truncates pages, which also waits for writers still
+    /// laying down pages outside the gate.
+    fn begin_exclusive_write(&self) -> io::Result<(Ordered<parking_lot::RwLockWriteGuard<'_, ()>>, Ordered<ReentrantMutexGuard<'_, ()>>)> {
+        self.ensure_writable()?;
+        Ok(self.lock_exclusive())
+    }
+
+    /// The locks begin_exclusive_write takes, for work that only needs writers held off.
+    fn lock_exclusive(&self) -> (Ordered<parking_lot::RwLockWriteGuard<'_, ()>>, Ordered<ReentrantMutexGuard<'_, ()>>) {
+        let layout = self.lock_stats.acquire(TrackedLock::Layout, || self.maintenance.layout.try_write(), || self.maintenance.layout.write());
+        (layout, self.lock_gate())
+    }
+
+    fn lock_gate(&self) -> Ordered<ReentrantMutexGuard<'_, ()>> {
+        self.lock_stats.acquire(TrackedLock::Gate, || self.maintenance.gate.try_lock(), || self.maintenance.gate.lock())
+    }
+}
+
+/// Read/Seek view of one document, modelled on idFile. Keeps the payload of the page
+/// holding the current position so small sequential reads stay out of the page cache.
+pub struct StreamDbFile<'a> {
+    db: &'a StreamDb,
+    first_page_id: i64, // identifies the pinned chain
+    pages: Vec<(i64, u64)>, // page_id and the document offset its payload starts at
+    length: u64,
+    position: u64,
+    buffer_index: Option<usize>, // index into pages of the payload held in buffer
+    buffer: Vec<u8>,
+}
+
+impl<'a> StreamDbFile<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.db.ensure_open()?;
+        let mut written = 0;
+        while written < buf.len() && self.position < self.length {
+            // Last page starting at or before the position; empty pages share their start with the next page
+            let index = self.pages.partition_point(|&(_, start)| start <= self.position) - 1;
+            if self.buffer_index != Some(index) {
+                self.buffer = self.db.view_page(self.pages[index].0)?.0.to_vec();
+                self.buffer_index = Some(index);
+            }
+            let offset = (self.position - self.pages[index].1) as usize;
+            let count = (self.buffer.len() - offset).min(buf.len() - written);
+            if count == 0 {
+                break;
+            }
+            buf[written..written + count].copy_from_slice(&self.buffer[offset..offset + count]);
+            written += count;
+            self.position += count as u64;
+        }
+        Ok(written)
+    }
+
+    fn seek(&mut self, offset: i64, origin: ffi::SeekOrigin) -> io::Result<u64> {
+        let base = match origin {
+            ffi::SeekOrigin::Cur => self.position as i64,
+            ffi::SeekOrigin::End => self.length as i64,
+            ffi::SeekOrigin::Set => 0,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek origin")),
+        };
+        self.set_position(base.checked_add(offset))
+    }
+
+    fn set_position(&mut self, position: Option<i64>) -> io::Result<u64> {
+        // Seeking past the end is allowed, as with idFile; reads there return 0
+        match position {
+            Some(position) if position >= 0 => {
+                self.position = position as u64;
+                Ok(self.position)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position")),
+        }
+    }
+
+    fn tell(&self) -> u64 {
+        self.position
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Documents carry no timestamps of their own; like files inside a pk4, they report the container's.
+    fn timestamp(&self) -> u64 {
+        self.db.file.lock().metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| elapsed.as_secs())
+    }
+}
+
+impl<'a> Read for StreamDbFile<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        StreamDbFile::read(self, buf)
+    }
+}
+
+impl<'a> Seek for StreamDbFile<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => i64::try_from(offset).ok(),
+            SeekFrom::End(offset) => (self.length as i64).checked_add(offset),
+            SeekFrom::Current(offset) => (self.position as i64).checked_add(offset),
+        };
+        self.set_position(position)
+    }
+}
+
+impl<'a> Drop for StreamDbFile<'a> {
+    fn drop(&mut self) {
+        self.db.unpin_chain(self.first_page_id);
+    }
+}
+
+// Dropping the UniquePtr without close_db still flushes and releases everything
+impl Drop for StreamDb {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(feature = "async")]
+pub use async_db::{AsyncDocumentStream, AsyncStreamDb};
+
+// Async access for Rust tools running on an async runtime. The blocking core runs on a few IO
+// threads owned by this module, so callers need no spawn_blocking of their own; the FFI stays
+// synchronous.
+#[cfg(feature = "async")]
+mod async_db {
+    use super::*;
+    use std::future::Future;
+
+    const IO_THREADS: usize = 4;
+
+    type IoJob = Box<dyn FnOnce() + Send>;
+
+    // Each thread runs its jobs in the order they were queued; a handle always queues on the same one
+    struct IoPool {
+        queues: Vec<std::sync::mpsc::Sender<IoJob>>,
+        next: std::sync::atomic::AtomicUsize,
+    }
+
+    impl IoPool {
+        fn get() -> &'static IoPool {
+            static POOL: std::sync::OnceLock<IoPool> = std::sync::OnceLock::new();
+            POOL.get_or_init(|| {
+                let queues = (0..IO_THREADS).map(|i| {
+                    let (sender, receiver) = std::sync::mpsc::channel::<IoJob>();
+                    std::thread::Builder::new()
+                        .name(format!("streamdb-io-{}", i))
+                        .spawn(move || {
+                            for job in receiver {
+                                // A panicking call fails only its own future
+                                std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)).unwrap_or(());
+                            }
+                        })
+                        .expect("failed to spawn a StreamDB IO thread");
+                    sender
+                }).collect();
+                IoPool { queues, next: std::sync::atomic::AtomicUsize::new(0) }
+            })
+        }
+
+        fn assign(&self) -> usize {
+            self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % self.queues.len()
+        }
+    }
+
+    fn io_stopped() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "StreamDB IO thread stopped")
+    }
+
+    /// A database for async Rust callers. Calls on one handle run in the order they were made,
+    /// even if their futures are awaited in another order or not at all; a clone is a separate
+    /// handle whose calls may run alongside this one's.
+    pub struct AsyncStreamDb {
+        db: Arc<StreamDb>,
+        thread: usize, // index of the IO thread this handle queues on
+    }
+
+    impl AsyncStreamDb {
+        /// Takes over an open database, starting its maintenance thread if its options ask for one.
+        pub fn new(db: StreamDb) -> io::Result<AsyncStreamDb> {
+            let db = Arc::new(db);
+            db.start_maintenance()?;
+            Ok(AsyncStreamDb { db, thread: IoPool::get().assign() })
+        }
+
+        /// Queues call on this handle's IO thread. Returns false if the thread has stopped.
+        fn queue(&self, call: impl FnOnce(&StreamDb) + Send + 'static) -> bool {
+            let db = self.db.clone();
+            IoPool::get().queues[self.thread].send(Box::new(move || call(&db))).is_ok()
+        }
+
+        /// Queues call now, so ordering does not depend on when the returned future is first polled.
+        fn submit<T: Send + 'static>(&self, call: impl FnOnce(&StreamDb) -> io::Result<T> + Send + 'static) -> impl Future<Output = io::Result<T>> {
+            let (sender, receiver) = tokio::sync::oneshot::channel();
+            let queued = self.queue(move |db| {
+                sender.send(call(db)).ok();
+            });
+            async move {
+                if !queued {
+                    return Err(io_stopped());
+                }
+                receiver.await.unwrap_or_else(|_| Err(io_stopped()))
+            }
+        }
+
+        pub fn get(&self, path: &str) -> impl Future<Output = io::Result<Vec<u8>>> {
+            let path = path.to_string();
+            self.submit(move |db| db.read_document(&path))
+        }
+
+        /// Like write_document: overwrites, and shares the chain of identical stored contents.
+        pub fn write_document(&self, path: &str, data: Vec<u8>) -> impl Future<Output = io::Result<Uuid>> {
+            let path = path.to_string();
+            self.submit(move |db| db.write_document_unordered(&path, &data, true, true, false))
+        }
+
+        pub fn search_paths(&self, prefix: &str) -> impl Future<Output = io::Result<Vec<String>>> {
+            let prefix = prefix.to_string();
+            self.submit(move |db| db.search_path_list(&prefix))
+        }
+
+        /// Opens a stream over the document's current chain, as start_stream_with_chunk_size does.
+        pub fn stream(&self, path: &str, chunk_size: usize) -> impl Future<Output = io::Result<AsyncDocumentStream>> {
+            let path = path.to_string();
+            let handle = AsyncStreamDb { db: self.db.clone(), thread: self.thread };
+            let opened = self.submit(move |db| db.open_stream(&path, chunk_size));
+            async move { Ok(AsyncDocumentStream { handle, stream_id: opened.await? }) }
+        }
+    }
+
+    impl Clone for AsyncStreamDb {
+        fn clone(&self) -> Self {
+            AsyncStreamDb { db: self.db.clone(), thread: IoPool::get().assign() }
+        }
+    }
+
+    /// A stream from AsyncStreamDb::stream, read in order with the other calls on the handle
+    /// that opened it. Dropping it ends the stream.
+    pub struct AsyncDocumentStream {
+        handle: AsyncStreamDb,
+        stream_id: i64,
+    }
+
+    impl AsyncDocumentStream {
+        /// The next chunk, or None once the whole document has been read.
+        pub fn next_chunk(&mut self) -> impl Future<Output = io::Result<Option<Vec<u8>>>> {
+            let stream_id = self.stream_id;
+            self.handle.submit(move |db| match db.stream_chunk(stream_id) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                chunk => chunk.map(Some),
+            })
+        }
+    }
+
+    impl Drop for AsyncDocumentStream {
+        fn drop(&mut self) {
+            let stream_id = self.stream_id;
+            self.handle.queue(move |db| db.close_stream(stream_id));
+        }
+    }
+}
+
+pub fn main() {} // Required for cxx::bridge
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own under the temp directory, removed with everything in it when dropped
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            let dir = std::env::temp_dir().join(format!("streamdb-test-{}", Uuid::new_v4().simple()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn db(&self) -> std::path::PathBuf {
+            self.0.join("test.db")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).unwrap_or(());
+        }
+    }
+
+    fn open(dir: &TempDir, options: ffi::StreamDbOptions) -> StreamDb {
+        StreamDb::open_path_with_options(&dir.db(), &options).unwrap()
+    }
+
+    fn open_error(dir: &TempDir, options: ffi::StreamDbOptions) -> io::Error {
+        match StreamDb::open_path_with_options(&dir.db(), &options) {
+            Ok(_) => panic!("open succeeded"),
+            Err(e) => e,
+        }
+    }
+
+    fn mapped_len(db: &StreamDb) -> Option<usize> {
+        db.mmap.read().as_ref().map(|mmap| mmap.len())
+    }
+
+    #[test]
+    fn mapping_covers_the_file_as_it_grows() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().use_compression(false).io_mode(ffi::IoMode::MmapPreferred));
+        assert!(mapped_len(&db).is_some_and(|len| len as u64 >= db.file_len.load(std::sync::atomic::Ordering::SeqCst)));
+        // Past the least a mapping reserves on unix, so it has to be remapped on the way
+        let documents: Vec<Vec<u8>> = (0..3u8).map(|i| (0..24 * 1024 * 1024).map(|j| (j % 251) as u8 ^ i).collect()).collect();
+        for (i, data) in documents.iter().enumerate() {
+            db.write_document_unordered(&format!("grow/{i}"), data, true, false, false).unwrap();
+        }
+        let file_len = db.file_len.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(file_len > 64 * 1024 * 1024);
+        assert!(mapped_len(&db).is_some_and(|len| len as u64 >= file_len));
+        for (i, data) in documents.iter().enumerate() {
+            assert_eq!(&db.read_document(&format!("grow/{i}")).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn page_offsets_past_the_address_space_are_refused() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(db.page_offset(i64::MAX).unwrap_err().kind(), io::ErrorKind::FileTooLarge);
+        assert!(db.page_offset(-1).is_err());
+        // Past 4GB offsets stay exact; only usize decides whether the mapping can serve them
+        let page_id = ((5u64 << 30) / db.config.page_size) as i64;
+        assert_eq!(db.page_offset(page_id).unwrap(), page_id as u64 * db.config.page_size);
+    }
+
+    #[test]
+    fn mapping_ranges_it_cannot_address_fall_back_to_the_storage() {
+        let mapping = vec![0u8; 4096];
+        assert_eq!(StreamDb::mmap_range(&mapping, 0, 4096), Some(0..4096));
+        assert_eq!(StreamDb::mmap_range(&mapping, 1, 4096), None);
+        assert_eq!(StreamDb::mmap_range(&mapping, u64::MAX, 1), None);
+        // A 32-bit usize cannot hold an offset past 4GB at all
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(StreamDb::mmap_range(&mapping, 1 << 32, 0), None);
+    }
+
+    // Sparse files keep the 5GB file cheap; Windows would allocate all of it
+    #[cfg(unix)]
+    #[test]
+    fn pages_above_4gb_read_back() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().io_mode(ffi::IoMode::MmapPreferred));
+        let offset = 5u64 << 30;
+        db.set_storage_len(offset + 4096).unwrap();
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        db.write_bytes_at(offset, &data).unwrap();
+        let mut read = vec![0u8; data.len()];
+        db.read_bytes_at(offset, &mut read).unwrap();
+        assert_eq!(read, data);
+        // 64-bit builds serve it from the mapping, which set_storage_len grew to cover it;
+        // 32-bit ones cannot map that far and read it through the storage
+        #[cfg(target_pointer_width = "64")]
+        assert!(mapped_len(&db).is_some_and(|len| len as u64 >= offset + 4096));
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(mapped_len(&db), None);
+    }
+
+    #[test]
+    fn uncompressed_pages_are_viewed_through_the_mapping() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().use_compression(false).io_mode(ffi::IoMode::MmapPreferred));
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let id = db.write_document_unordered("mapped", &data, true, false, false).unwrap();
+        let doc = db.lookup_document(&id).unwrap().unwrap();
+        let (view, _) = db.view_page(doc.first_page_id).unwrap();
+        assert!(matches!(view, PageView::Mapped(..)));
+        assert_eq!(&view[..], &data[..view.len()]);
+        drop(view);
+        assert_eq!(db.read_document("mapped").unwrap(), data);
+    }
+
+    #[test]
+    fn switching_io_mode_changes_how_pages_are_read() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().use_compression(false).io_mode(ffi::IoMode::MmapPreferred));
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let id = db.write_document_unordered("switched", &data, true, false, false).unwrap();
+        let page_id = db.lookup_document(&id).unwrap().unwrap().first_page_id;
+        assert!(!Pin::new(&mut db).set_io_mode(ffi::IoMode::FileOnly).unwrap());
+        assert_eq!(mapped_len(&db), None);
+        assert!(matches!(db.view_page(page_id).unwrap().0, PageView::Owned(_)));
+        // Growing the file while unmapped leaves it unmapped
+        db.write_document_unordered("unmapped", &data, true, false, false).unwrap();
+        assert_eq!(mapped_len(&db), None);
+        assert!(Pin::new(&mut db).set_io_mode(ffi::IoMode::MmapPreferred).unwrap());
+        assert!(matches!(db.view_page(page_id).unwrap().0, PageView::Mapped(..)));
+        assert_eq!(db.read_document("switched").unwrap(), data);
+        assert_eq!(db.read_document("unmapped").unwrap(), data);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn huge_page_mappings_are_aligned_and_advised() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().io_mode(ffi::IoMode::MmapPreferred).huge_pages(true));
+        let aligned = |db: &StreamDb| db.mmap.read().as_ref().is_some_and(|mmap| mmap.as_ptr() as usize % HUGE_PAGE_SIZE == 0);
+        assert!(aligned(&db));
+        // The kernel takes the advice wherever it has transparent huge pages at all
+        let transparent = Path::new("/sys/kernel/mm/transparent_hugepage/enabled").exists();
+        assert_eq!(db.get_db_info().unwrap().huge_pages, transparent);
+        // A remap as the file grows past the mapping is aligned and advised the same
+        db.set_storage_len(2 * MMAP_RESERVE_BYTES + 4096).unwrap();
+        assert!(mapped_len(&db).is_some_and(|len| len as u64 > 2 * MMAP_RESERVE_BYTES));
+        assert!(aligned(&db));
+        assert_eq!(db.get_db_info().unwrap().huge_pages, transparent);
+    }
+
+    #[test]
+    fn huge_pages_leave_reads_and_writes_alone() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().io_mode(ffi::IoMode::MmapPreferred).huge_pages(true));
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        db.write_document_unordered("huge", &data, true, false, false).unwrap();
+        assert_eq!(db.read_document("huge").unwrap(), data);
+        #[cfg(not(target_os = "linux"))]
+        assert!(!db.get_db_info().unwrap().huge_pages);
+    }
+
+    #[test]
+    fn prefault_touches_the_whole_file_through_either_path() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().io_mode(ffi::IoMode::MmapPreferred).prefault(ffi::Prefault::Full));
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        db.write_document_unordered("warm", &data, true, false, false).unwrap();
+        assert!(mapped_len(&db).is_some());
+        let file_len = db.file_len.load(std::sync::atomic::Ordering::SeqCst);
+        let mut reported = (0, 0);
+        assert_eq!(db.prefault_with_progress(ffi::Prefault::Full, &mut |done, total| reported = (done, total)).unwrap(), file_len);
+        assert_eq!(reported, (file_len, file_len));
+        drop(db);
+        let file_only = open(&dir, StreamDb::create_options().io_mode(ffi::IoMode::FileOnly));
+        assert_eq!(mapped_len(&file_only), None);
+        assert_eq!(file_only.prefault(ffi::Prefault::Full).unwrap(), file_only.file_len.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn readers_share_the_lock_and_writers_hold_it_alone() {
+        let dir = TempDir::new();
+        let data = b"shared".to_vec();
+        let writer = open(&dir, StreamDb::create_options());
+        writer.write_document_unordered("shared", &data, true, false, false).unwrap();
+        let error = open_error(&dir, StreamDb::create_options().read_only(true));
+        assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(error.to_string(), "Database already locked by another process");
+        drop(writer);
+        let first = open(&dir, StreamDb::create_options().read_only(true));
+        let second = open(&dir, StreamDb::create_options().read_only(true));
+        assert_eq!(first.read_document("shared").unwrap(), data);
+        assert_eq!(second.read_document("shared").unwrap(), data);
+        assert_eq!(first.write_document_unordered("other", &data, true, false, false).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(open_error(&dir, StreamDb::create_options()).kind(), io::ErrorKind::ResourceBusy);
+        drop(second);
+        // A writer given a timeout waits out the last reader
+        let waiting = std::thread::spawn({
+            let path = dir.db();
+            move || StreamDb::open_path_with_options(&path, &StreamDb::create_options().lock_timeout_ms(10_000)).map(|_| ())
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(first);
+        waiting.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn read_only_opens_need_an_existing_database() {
+        let dir = TempDir::new();
+        assert_eq!(open_error(&dir, StreamDb::create_options().read_only(true)).kind(), io::ErrorKind::NotFound);
+        assert!(!dir.db().exists());
+    }
+
+    #[test]
+    fn documents_roll_over_into_new_segments() {
+        let dir = TempDir::new();
+        let segment_size = 16 * PAGE_SIZE;
+        let documents: Vec<Vec<u8>> = (0..8u8).map(|i| (0..20_000).map(|j| (j % 251) as u8 ^ i).collect()).collect();
+        {
+            let db = open(&dir, StreamDb::create_options().use_compression(false).segmented(segment_size));
+            for (i, data) in documents.iter().enumerate() {
+                db.write_document_unordered(&format!("segments/{i}"), data, true, false, false).unwrap();
+            }
+            assert!(db.storage.segment_layout().1 > 2);
+        }
+        assert!(SegmentedStorage::segment_path(&dir.db(), 2).exists());
+        assert_eq!(std::fs::metadata(SegmentedStorage::segment_path(&dir.db(), 1)).unwrap().len(), segment_size);
+        let db = open(&dir, StreamDb::create_options());
+        for (i, data) in documents.iter().enumerate() {
+            assert_eq!(&db.read_document(&format!("segments/{i}")).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn concurrent_writes_past_the_last_segment_keep_each_other() {
+        let dir = TempDir::new();
+        let segment_size = 4 * PAGE_SIZE;
+        let file = StreamDb::db_open_options().create(true).open(dir.db()).unwrap();
+        let storage = SegmentedStorage::open(file, &dir.db(), segment_size, 0).unwrap();
+        std::thread::scope(|scope| {
+            for i in 1..=16u64 {
+                let storage = &storage;
+                scope.spawn(move || storage.write_at(i * segment_size + 8, &i.to_le_bytes()).unwrap());
+            }
+        });
+        assert_eq!(storage.segment_layout().1, 17);
+        for i in 1..=16u64 {
+            let mut read = [0u8; 8];
+            storage.read_at(i * segment_size + 8, &mut read).unwrap();
+            assert_eq!(u64::from_le_bytes(read), i);
+        }
+    }
+
+    fn write_paths(db: &StreamDb, paths: &[&str]) {
+        for path in paths {
+            db.write_document_unordered(path, path.as_bytes(), true, false, false).unwrap();
+        }
+    }
+
+    #[test]
+    fn path_snapshot_is_rebuilt_exactly_when_the_paths_change() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        write_paths(&db, &["maps/a", "maps/b", "sounds/c"]);
+        let first = db.all_paths_sorted().unwrap();
+        let generation = db.path_generation.load(std::sync::atomic::Ordering::SeqCst);
+        db.read_document("maps/a").unwrap();
+        assert!(Arc::ptr_eq(&first, &db.all_paths_sorted().unwrap()));
+        assert_eq!(db.get_all_paths_sorted().unwrap(), *first);
+        write_paths(&db, &["maps/new"]);
+        assert_ne!(db.path_generation.load(std::sync::atomic::Ordering::SeqCst), generation);
+        let second = db.all_paths_sorted().unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(*second, ["maps/a", "maps/b", "maps/new", "sounds/c"]);
+        assert!(Arc::ptr_eq(&second, &db.all_paths_sorted().unwrap()));
+        cxx::let_cxx_string!(path = "sounds/c");
+        Pin::new(&mut db).delete_by_path(&path).unwrap();
+        assert_eq!(*db.all_paths_sorted().unwrap(), ["maps/a", "maps/b", "maps/new"]);
+    }
+
+    #[test]
+    fn completion_normalizes_what_was_typed() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().path_policy(ffi::PathPolicy { normalize: true, ..Default::default() }));
+        write_paths(&db, &["maps/de1", "maps/de2", "maps/e"]);
+        cxx::let_cxx_string!(typed = "\\maps\\de");
+        assert_eq!(db.complete_path(&typed, 10).unwrap(), ["maps/de1", "maps/de2"]);
+        cxx::let_cxx_string!(directory = "./maps/");
+        assert_eq!(db.complete_path(&directory, 10).unwrap(), ["maps/de1", "maps/de2", "maps/e"]);
+        assert_eq!(db.complete_path(&directory, 1).unwrap(), ["maps/de1"]);
+    }
+
+    /// (path, addon, priority, lang) of each binding, in path order; default bindings must resolve.
+    fn bound_paths(db: &StreamDb, id: Uuid) -> Vec<(String, bool, i32, String)> {
+        cxx::let_cxx_string!(uuid = id.to_string());
+        let infos = db.get_paths_for_uuid(&uuid).unwrap();
+        assert!(infos.iter().filter(|info| info.lang.is_empty()).all(|info| info.resolved));
+        let mut paths: Vec<_> = infos.into_iter().map(|info| (info.path, info.addon, info.priority, info.lang)).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn paths_for_uuid_follow_renames_and_unbinds() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let id = db.write_document_unordered("textures/wall.tga", b"wall", true, false, false).unwrap();
+        cxx::let_cxx_string!(source = "textures/wall.tga");
+        cxx::let_cxx_string!(layer = "addons/wall.tga");
+        Pin::new(&mut db).bind_path_layer(&layer, &source, true, 5).unwrap();
+        cxx::let_cxx_string!(localized = "textures/mur.tga");
+        cxx::let_cxx_string!(lang = "fr");
+        Pin::new(&mut db).bind_localized_path(&localized, &lang, &source).unwrap();
+        assert_eq!(bound_paths(&db, id), [
+            ("addons/wall.tga".to_string(), true, 5, String::new()),
+            ("textures/mur.tga".to_string(), false, 0, "fr".to_string()),
+            ("textures/wall.tga".to_string(), false, 0, String::new()),
+        ]);
+        cxx::let_cxx_string!(renamed = "addons/wall2.tga");
+        Pin::new(&mut db).rename_path(&layer, &renamed).unwrap();
+        assert_eq!(bound_paths(&db, id).into_iter().map(|binding| binding.0).collect::<Vec<_>>(), ["addons/wall2.tga", "textures/mur.tga", "textures/wall.tga"]);
+        Pin::new(&mut db).unbind_addon_path(&renamed, false).unwrap();
+        assert_eq!(bound_paths(&db, id).into_iter().map(|binding| binding.0).collect::<Vec<_>>(), ["textures/mur.tga", "textures/wall.tga"]);
+        cxx::let_cxx_string!(unknown = Uuid::new_v4().to_string());
+        assert_eq!(db.get_paths_for_uuid(&unknown).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    fn resolves(db: &StreamDb, path: &str) -> Option<Uuid> {
+        db.get_document_id_by_path(path).ok()
+    }
+
+    #[test]
+    fn added_paths_rename_delete_and_group_with_their_document() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let id = db.write_document_unordered("sounds/door.wav", b"door", true, false, false).unwrap();
+        let other = db.write_document_unordered("sounds/bell.wav", b"bell", true, false, false).unwrap();
+        cxx::let_cxx_string!(source = id.to_string());
+        cxx::let_cxx_string!(alias = "sounds/gate.wav");
+        Pin::new(&mut db).add_path(&source, &alias, false).unwrap();
+        assert_eq!(resolves(&db, "sounds/gate.wav"), Some(id));
+        // Adding a name the document already has changes nothing; another document's name needs shadow
+        Pin::new(&mut db).add_path(&source, &alias, false).unwrap();
+        cxx::let_cxx_string!(taken = "sounds/bell.wav");
+        assert_eq!(Pin::new(&mut db).add_path(&source, &taken, false).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        Pin::new(&mut db).add_path(&source, &taken, true).unwrap();
+        assert_eq!(resolves(&db, "sounds/bell.wav"), Some(id));
+        Pin::new(&mut db).remove_path(&taken, false).unwrap();
+        assert_eq!(resolves(&db, "sounds/bell.wav"), Some(other));
+
+        // Search results group every name of the document under it
+        cxx::let_cxx_string!(prefix = "sounds/");
+        let grouped = db.search_documents(&prefix, true).unwrap();
+        let door = grouped.iter().find(|group| group.uuid == id.to_string()).unwrap();
+        assert_eq!(door.paths, ["sounds/door.wav", "sounds/gate.wav"]);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(db.search_documents(&prefix, false).unwrap().len(), 3);
+
+        // Renaming moves one name and keeps the rest
+        cxx::let_cxx_string!(renamed = "sounds/portal.wav");
+        Pin::new(&mut db).rename_path(&alias, &renamed).unwrap();
+        assert_eq!(resolves(&db, "sounds/gate.wav"), None);
+        assert_eq!(resolves(&db, "sounds/portal.wav"), Some(id));
+        assert_eq!(resolves(&db, "sounds/door.wav"), Some(id));
+
+        // Deleting through any name removes the document with all of them
+        Pin::new(&mut db).delete_by_path(&renamed).unwrap();
+        assert_eq!(resolves(&db, "sounds/door.wav"), None);
+        assert_eq!(resolves(&db, "sounds/portal.wav"), None);
+        assert!(db.lookup_document(&id).unwrap().is_none());
+        assert_eq!(resolves(&db, "sounds/bell.wav"), Some(other));
+    }
+
+    #[test]
+    fn removing_the_last_path_deletes_only_when_asked() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let id = db.write_document_unordered("maps/last.map", b"last", true, false, false).unwrap();
+        cxx::let_cxx_string!(path = "maps/last.map");
+        assert_eq!(Pin::new(&mut db).remove_path(&path, false).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(resolves(&db, "maps/last.map"), Some(id));
+        Pin::new(&mut db).remove_path(&path, true).unwrap();
+        assert_eq!(resolves(&db, "maps/last.map"), None);
+        assert!(db.lookup_document(&id).unwrap().is_none());
+    }
+
+    #[test]
+    fn rebuilding_a_corrupt_trie_restores_every_path() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let paths: Vec<String> = (0..200).map(|i| format!("maps/level{}/area{}.map", i % 7, i)).collect();
+        let ids: Vec<Uuid> = paths.iter().map(|path| db.write_document_unordered(path, path.as_bytes(), true, false, false).unwrap()).collect();
+        let root = db.trie_root.read().page_id;
+        let root_page = StreamDb::slab_record(root).map_or(root, |(slab_page_id, _)| slab_page_id);
+        db.write_bytes_at(db.payload_offset(root_page).unwrap(), &[0xAB; 64]).unwrap();
+        db.clear_page_cache();
+        db.trie_cache.lock().clear();
+        db.clear_path_cache();
+        assert!(!db.check_trie().unwrap().violations.is_empty());
+        assert_eq!(Pin::new(&mut db).rebuild_trie().unwrap(), paths.len() as u64);
+        for (path, id) in paths.iter().zip(&ids) {
+            assert_eq!(db.get_document_id_by_path(path).unwrap(), *id);
+        }
+        assert!(db.check_trie().unwrap().violations.is_empty());
+        // The old trie's pages, found by their type, went back to the free list rather than leaking
+        let mut live = Vec::new();
+        db.trie_collect_pages(db.trie_root.read().page_id, &mut live).unwrap();
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        assert!(free.contains(&root_page));
+        for page_id in FIRST_PAGE_ID..db.page_count() {
+            if db.read_page_header(page_id).is_ok_and(|header| header.flags & FLAG_TRIE_PAGE != 0) {
+                assert!(live.contains(&page_id) || free.contains(&page_id), "trie page {page_id} leaked");
+            }
+        }
+    }
+
+    #[test]
+    fn trie_damage_is_reported_and_repaired() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        // Two families ending in different characters, so the reversed trie's root has a subtree for each
+        let paths: Vec<String> = (0..40).map(|i| if i % 2 == 0 { format!("maps/level{i}.map") } else { format!("scripts/weapon{i}.script") }).collect();
+        let ids: Vec<Uuid> = paths.iter().map(|path| db.write_document_unordered(path, path.as_bytes(), true, false, false).unwrap()).collect();
+        assert!(db.check_trie().unwrap().violations.is_empty());
+        let mut root = db.read_trie_node(db.trie_root.read().page_id).unwrap();
+        assert!(root.children.len() >= 2);
+        // An orphan under the first subtree: a terminal naming a document the index lacks
+        let mut node = db.read_trie_node(*root.children.values().next().unwrap()).unwrap();
+        while node.document_id.is_none() {
+            node = db.read_trie_node(*node.children.values().next().unwrap()).unwrap();
+        }
+        node.document_id = Some(Uuid::new_v4());
+        db.write_trie_node(&node).unwrap();
+        // A dangling child in place of the last subtree: a data page where a trie node should be
+        let data_page = db.lookup_document(&ids[1]).unwrap().unwrap().first_page_id;
+        let key = *root.children.keys().next_back().unwrap();
+        root.children.insert(key, data_page);
+        db.write_trie_node(&root).unwrap();
+        db.clear_path_cache();
+        let kinds: Vec<ffi::TrieViolationKind> = db.check_trie().unwrap().violations.iter().map(|violation| violation.kind).collect();
+        assert!(kinds.contains(&ffi::TrieViolationKind::DanglingChild));
+        assert!(kinds.contains(&ffi::TrieViolationKind::OrphanDocument));
+        assert!(kinds.contains(&ffi::TrieViolationKind::UnreachablePath));
+        let report = Pin::new(&mut db).repair_db().unwrap();
+        assert!(!report.trie.violations.is_empty());
+        assert_eq!(report.paths_restored, paths.len() as u64);
+        assert!(db.check_trie().unwrap().violations.is_empty());
+        for (path, id) in paths.iter().zip(&ids) {
+            assert_eq!(db.get_document_id_by_path(path).unwrap(), *id);
+        }
+    }
+
+    #[test]
+    fn path_policy_limits_and_characters() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let reason = |path: &str| db.validate_path(path).unwrap_err().to_string();
+        let long = "a/".repeat(5_000);
+        assert_eq!(long.len(), 10_000);
+        assert_eq!(reason(&long), "Invalid path: path too long");
+        assert_eq!(reason(&format!("maps/{}", "c".repeat(MAX_PATH_COMPONENT_LENGTH + 1))), "Invalid path: path component too long");
+        assert_eq!(reason("maps/a\0b.map"), "Invalid path: control character 0x00");
+        assert_eq!(reason("maps/a\nb.map"), "Invalid path: control character 0x0a");
+        assert_eq!(reason("maps/é.map"), "Invalid path: non-ASCII character");
+        assert_eq!(reason("/maps/a.map"), "Invalid path: absolute path");
+        assert_eq!(reason("maps\\a.map"), "Invalid path: backslash separator");
+        assert_eq!(reason("maps/../a.map"), "Invalid path: parent directory reference");
+        assert_eq!(db.validate_path("maps/with space.map").unwrap(), "maps/with space.map");
+        assert_eq!(db.write_document_unordered("maps/a\0b.map", b"", true, false, false).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        drop(db);
+
+        let policy = ffi::PathPolicy {
+            max_path_length: 20_000,
+            max_component_length: 20_000,
+            allow_spaces: false,
+            allow_non_ascii: true,
+            normalize: true,
+        };
+        let db = open(&dir, StreamDb::create_options().path_policy(policy));
+        assert_eq!(db.validate_path(&long).unwrap().len(), long.len() - 1);
+        assert_eq!(db.validate_path(&"d".repeat(10_000)).unwrap().len(), 10_000);
+        assert_eq!(db.validate_path("/maps\\.\\é.map").unwrap(), "maps/é.map");
+        assert_eq!(db.validate_path("maps/with space.map").unwrap_err().to_string(), "Invalid path: space character");
+        // Control characters stay out whatever the policy
+        assert!(db.validate_path("maps/a\0b.map").is_err());
+        assert!(db.validate_path("maps/a\nb.map").is_err());
+    }
+
+    #[test]
+    fn rewriting_a_path_keeps_one_document_and_recycles_old_chains() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().use_compression(false).versions_to_keep(3));
+        let contents = |version: usize| format!("version {version:03};").repeat(1_000).into_bytes();
+        let id = db.write_document_unordered("config.cfg", &contents(0), true, false, false).unwrap();
+        let mut steady_pages = 0;
+        for version in 1..=100 {
+            assert_eq!(db.write_document_unordered("config.cfg", &contents(version), true, false, false).unwrap(), id);
+            if version == 10 {
+                steady_pages = db.page_count();
+            }
+        }
+        let index = db.read_index().unwrap();
+        assert_eq!(index.len(), 1);
+        let doc = &index[&id];
+        assert_eq!(doc.current_version, 100);
+        assert_eq!(doc.previous_versions.iter().map(|link| link.version).collect::<Vec<_>>(), [98, 99]);
+        for link in &doc.previous_versions {
+            assert_eq!(db.read_chain(link.page_id).unwrap(), contents(link.version as usize));
+        }
+        assert_eq!(db.read_document("config.cfg").unwrap(), contents(100));
+        // Chains past versions_to_keep are freed and reused, so the file stops growing
+        assert!(db.page_count() <= steady_pages + doc.page_count as i64);
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        assert!(!free.contains(&doc.first_page_id));
+        assert!(doc.previous_versions.iter().all(|link| !free.contains(&link.page_id)));
+    }
+
+    #[test]
+    fn appends_span_pages_survive_reopen_and_close() {
+        let dir = TempDir::new();
+        let options = || StreamDb::create_options().use_compression(false);
+        let mut db = open(&dir, options());
+        cxx::let_cxx_string!(path = "logs/game.log");
+        let capacity = db.append_page_capacity(CODEC_NONE);
+        let data: Vec<u8> = (0..capacity * 5 / 2).map(|i| (i % 253) as u8).collect();
+        let handle = Pin::new(&mut db).open_append(&path).unwrap();
+        // Odd-sized pieces so page boundaries fall in the middle of an append
+        for piece in data.chunks(997) {
+            Pin::new(&mut db).append(handle, piece).unwrap();
+        }
+        assert!(db.read_document("logs/game.log").unwrap().is_empty());
+        Pin::new(&mut db).sync_append(handle).unwrap();
+        assert_eq!(db.read_document("logs/game.log").unwrap(), data);
+        let id = db.get_document_id_by_path("logs/game.log").unwrap();
+        assert_eq!(db.read_index().unwrap()[&id].page_count, 3);
+        Pin::new(&mut db).close_append(handle).unwrap();
+        assert_eq!(Pin::new(&mut db).append(handle, b"late").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        drop(db);
+
+        // Reopened, the short last page becomes the tail and the next append continues it
+        let mut db = open(&dir, options());
+        assert_eq!(db.read_document("logs/game.log").unwrap(), data);
+        let handle = Pin::new(&mut db).open_append(&path).unwrap();
+        let more: Vec<u8> = (0..capacity).map(|i| (i % 241) as u8).collect();
+        Pin::new(&mut db).append(handle, &more).unwrap();
+        Pin::new(&mut db).close_append(handle).unwrap();
+        let expected = [data, more].concat();
+        assert_eq!(db.read_document("logs/game.log").unwrap(), expected);
+        let doc = db.read_index().unwrap()[&id].clone();
+        assert_eq!((doc.size, doc.page_count), (expected.len() as u64, 4));
+        drop(db);
+
+        let db = open(&dir, options());
+        assert_eq!(db.read_document("logs/game.log").unwrap(), expected);
+        let report = db.verify_db(true).unwrap();
+        assert!(report.index_ok && report.corrupt_pages.is_empty());
+    }
+
+    // A copy of the database file as it stands, as if the process died right now
+    fn crash_image(dir: &TempDir) -> TempDir {
+        let image = TempDir::new();
+        std::fs::copy(dir.db(), image.db()).unwrap();
+        image
+    }
+
+    const SLOT: [&str; 3] = ["savegames/slot0/gamestate.save", "savegames/slot0/gamestate.tga", "savegames/slot0/gamestate.txt"];
+
+    fn slot_contents(save: &str) -> Vec<Vec<u8>> {
+        SLOT.iter().map(|path| format!("{save}:{path}").repeat(500).into_bytes()).collect()
+    }
+
+    fn save_slot(db: &mut StreamDb, session_id: i64, save: &str, files: usize) {
+        for (path, data) in SLOT.iter().zip(slot_contents(save)).take(files) {
+            cxx::let_cxx_string!(path = *path);
+            Pin::new(&mut *db).save_session_write(session_id, &path, &cxx::CxxVector::from(data)).unwrap();
+        }
+    }
+
+    fn read_slot(db: &StreamDb) -> Vec<Vec<u8>> {
+        SLOT.iter().map(|path| db.read_document(path).unwrap()).collect()
+    }
+
+    #[test]
+    fn save_sessions_publish_a_slot_whole_or_not_at_all() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let session = Pin::new(&mut db).begin_save_session().unwrap();
+        save_slot(&mut db, session, "first", 3);
+        assert!(db.read_document(SLOT[0]).is_err());
+        Pin::new(&mut db).commit_save_session(session).unwrap();
+        assert_eq!(read_slot(&db), slot_contents("first"));
+
+        // Aborted part-way, nothing of the new save shows and its pages go back to the free list
+        let session = Pin::new(&mut db).begin_save_session().unwrap();
+        save_slot(&mut db, session, "aborted", 2);
+        Pin::new(&mut db).abort_save_session(session).unwrap();
+        let pages = db.page_count();
+        assert_eq!(read_slot(&db), slot_contents("first"));
+        assert_eq!(db.get_transaction_stats().rolled_back, 1);
+        let session = Pin::new(&mut db).begin_save_session().unwrap();
+        save_slot(&mut db, session, "second", 2);
+        assert_eq!(db.page_count(), pages);
+
+        // A crash between the writes of a session, or before its commit, leaves the old slot intact
+        let mid_session = crash_image(&dir);
+        save_slot(&mut db, session, "second", 3);
+        let before_commit = crash_image(&dir);
+        for image in [&mid_session, &before_commit] {
+            let recovered = open(image, StreamDb::create_options());
+            assert_eq!(read_slot(&recovered), slot_contents("first"));
+            assert!(recovered.verify_db(true).unwrap().index_ok);
+        }
+        Pin::new(&mut db).commit_save_session(session).unwrap();
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(read_slot(&db), slot_contents("second"));
+    }
+
+    fn stage(db: &mut StreamDb, tx_id: i64, path: &str, data: &str) {
+        cxx::let_cxx_string!(path = path);
+        Pin::new(db).save_session_write(tx_id, &path, &cxx::CxxVector::from(data.as_bytes().to_vec())).unwrap();
+    }
+
+    #[test]
+    fn transactions_commit_roll_back_and_fail_as_a_whole() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        write_paths(&db, &["cfg/a.cfg", "cfg/b.cfg"]);
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        stage(&mut db, tx, "cfg/a.cfg", "a1");
+        stage(&mut db, tx, "cfg/new.cfg", "new");
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        assert_eq!(db.read_document("cfg/a.cfg").unwrap(), b"a1");
+        assert_eq!(db.read_document("cfg/new.cfg").unwrap(), b"new");
+
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        stage(&mut db, tx, "cfg/a.cfg", "a2");
+        Pin::new(&mut db).rollback_transaction(tx).unwrap();
+        assert_eq!(db.read_document("cfg/a.cfg").unwrap(), b"a1");
+        assert_eq!(Pin::new(&mut db).commit_transaction(tx).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // The rename onto a taken path fails the commit after two writes were staged; neither lands
+        let index = db.read_index().unwrap();
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        stage(&mut db, tx, "cfg/a.cfg", "a3");
+        stage(&mut db, tx, "cfg/other.cfg", "other");
+        cxx::let_cxx_string!(from = "cfg/new.cfg");
+        cxx::let_cxx_string!(to = "cfg/b.cfg");
+        Pin::new(&mut db).save_session_rename(tx, &from, &to).unwrap();
+        assert_eq!(Pin::new(&mut db).commit_transaction(tx).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(db.read_index().unwrap() == index);
+        assert_eq!(db.read_document("cfg/a.cfg").unwrap(), b"a1");
+        assert!(resolves(&db, "cfg/other.cfg").is_none());
+        // Its staged chains went back to the free list
+        let pages = db.page_count();
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        stage(&mut db, tx, "cfg/a.cfg", "a3");
+        stage(&mut db, tx, "cfg/other.cfg", "other");
+        assert_eq!(db.page_count(), pages);
+        Pin::new(&mut db).rollback_transaction(tx).unwrap();
+        let stats = db.get_transaction_stats();
+        assert_eq!((stats.committed, stats.rolled_back, stats.failed), (1, 2, 1));
+    }
+
+    #[test]
+    fn overlapping_transactions_fail_only_on_the_path_they_share() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let first = Pin::new(&mut db).begin_transaction().unwrap();
+        let second = Pin::new(&mut db).begin_transaction().unwrap();
+        let third = Pin::new(&mut db).begin_transaction().unwrap();
+        stage(&mut db, first, "shared.cfg", "first");
+        stage(&mut db, first, "first.cfg", "first");
+        stage(&mut db, second, "shared.cfg", "second");
+        stage(&mut db, second, "second.cfg", "second");
+        stage(&mut db, third, "third.cfg", "third");
+        Pin::new(&mut db).commit_transaction(first).unwrap();
+        let e = Pin::new(&mut db).commit_transaction(second).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ResourceBusy);
+        assert!(e.to_string().starts_with("Conflict: shared.cfg"));
+        // A failed commit ends its transaction as well
+        Pin::new(&mut db).commit_transaction(third).unwrap();
+        assert_eq!(db.read_document("shared.cfg").unwrap(), b"first");
+        assert!(resolves(&db, "second.cfg").is_none());
+        assert_eq!(db.read_document("third.cfg").unwrap(), b"third");
+        assert!(db.get_transaction_stats().open.is_empty());
+
+        // Begun after the winning commit, a retry of the loser goes through
+        let retry = Pin::new(&mut db).begin_transaction().unwrap();
+        // Ids are never handed out twice, so a finished one stays invalid
+        assert!(![first, second, third].contains(&retry));
+        assert_eq!(Pin::new(&mut db).rollback_transaction(first).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        stage(&mut db, retry, "shared.cfg", "second");
+        Pin::new(&mut db).commit_transaction(retry).unwrap();
+        assert_eq!(db.read_document("shared.cfg").unwrap(), b"second");
+    }
+
+    #[test]
+    fn resync_brings_the_mirror_level_with_the_primary() {
+        let dir = TempDir::new();
+        let mirror_path = dir.0.join("mirror.db");
+        let options = || StreamDb::create_options().mirror(mirror_path.to_str().unwrap(), false);
+        let mut db = open(&dir, options());
+        write_paths(&db, &["maps/e1m1.map", "maps/e1m2.map", "sound/door.wav"]);
+        // Without open_db_with_options no mirror thread runs, so only a resync copies anything
+        assert!(!db.get_mirror_status().mirrored);
+        assert_eq!(db.resync_mirror().unwrap(), 3);
+        assert_eq!(db.resync_mirror().unwrap(), 0);
+        db.write_document_unordered("maps/e1m1.map", b"edited", true, false, false).unwrap();
+        cxx::let_cxx_string!(removed = "sound/door.wav");
+        Pin::new(&mut db).delete_by_path(&removed).unwrap();
+        assert_eq!(db.resync_mirror().unwrap(), 2);
+        let mirror = &db.mirror.as_ref().unwrap().db;
+        assert_eq!(mirror.content_hash().unwrap(), db.content_hash().unwrap());
+        assert_eq!(mirror.read_document("maps/e1m1.map").unwrap(), b"edited");
+        assert!(resolves(mirror, "sound/door.wav").is_none());
+        assert!(!db.get_mirror_status().diverged);
+    }
+
+    #[test]
+    fn a_corrupt_primary_falls_back_to_the_mirror() {
+        let dir = TempDir::new();
+        let mirror_path = dir.0.join("mirror.db");
+        let options = |fallback| StreamDb::create_options().mirror(mirror_path.to_str().unwrap(), fallback);
+        let db = open(&dir, options(true));
+        assert!(!db.get_mirror_status().serving_mirror);
+        write_paths(&db, &["maps/e1m1.map", "maps/e1m2.map"]);
+        db.resync_mirror().unwrap();
+        drop(db);
+
+        // Overwrite the primary's magic
+        let mut file = OpenOptions::new().write(true).open(dir.db()).unwrap();
+        file.write_all(&[0u8; 8]).unwrap();
+        drop(file);
+        assert_eq!(open_error(&dir, options(false)).kind(), io::ErrorKind::InvalidData);
+        let db = open(&dir, options(true));
+        assert!(db.get_mirror_status().serving_mirror);
+        assert!(db.mirror.is_none());
+        assert_eq!(db.read_document("maps/e1m1.map").unwrap(), b"maps/e1m1.map");
+        assert_eq!(db.read_document("maps/e1m2.map").unwrap(), b"maps/e1m2.map");
+    }
+
+    #[test]
+    fn a_corrupt_chain_is_read_from_the_mirror_without_a_full_scan_on_open() {
+        let dir = TempDir::new();
+        let mirror_path = dir.0.join("mirror.db");
+        let options = |fallback| StreamDb::create_options().mirror(mirror_path.to_str().unwrap(), fallback);
+        let db = open(&dir, options(true));
+        let id = db.write_document_unordered("maps/e1m1.bin", b"e1m1", true, false, false).unwrap();
+        write_paths(&db, &["maps/e1m2.map"]);
+        db.resync_mirror().unwrap();
+        let offset = db.page_offset(db.lookup_document(&id).unwrap().unwrap().first_page_id).unwrap();
+        drop(db);
+
+        // Damage the payload but not the index, so the open check passes
+        let mut file = OpenOptions::new().write(true).open(dir.db()).unwrap();
+        file.seek(SeekFrom::Start(offset + PAGE_HEADER_SIZE)).unwrap();
+        file.write_all(b"XXXX").unwrap();
+        drop(file);
+        let db = open(&dir, options(false));
+        assert_eq!(db.read_document("maps/e1m1.bin").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(db);
+        let db = open(&dir, options(true));
+        assert!(!db.get_mirror_status().serving_mirror);
+        assert_eq!(db.read_document("maps/e1m1.bin").unwrap(), b"e1m1");
+        assert_eq!(db.read_document("maps/e1m2.map").unwrap(), b"maps/e1m2.map");
+    }
+
+    // Small deterministic generator, so a failing case is the same on every run
+    struct Xorshift(u64);
+
+    impl Xorshift {
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            choices[(self.0 % choices.len() as u64) as usize]
+        }
+    }
+
+    #[test]
+    fn pruned_glob_search_agrees_with_matching_every_path() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let mut rng = Xorshift(0x9E37_79B9_7F4A_7C15);
+        let names = ["models", "maps", "mapsx", "lod1.lwo", "lod2.lwo", "lod10.lwo", "a", "ab", "b.c", "x*y", "[x]"];
+        let mut paths = BTreeSet::new();
+        while paths.len() < 300 {
+            let depth = rng.pick(&["1", "2", "3", "4", "5"]).parse().unwrap();
+            paths.insert((0..depth).map(|_| rng.pick(&names)).collect::<Vec<_>>().join("/"));
+        }
+        for path in &paths {
+            db.write_document_unordered(path, path.as_bytes(), true, false, false).unwrap();
+        }
+        let all_paths = db.all_paths_sorted().unwrap();
+        assert_eq!(all_paths.len(), paths.len());
+
+        let components = ["*", "**", "?", "lod?.lwo", "lod*", "*.lwo", "a*", "[ab]*", "[!m]*", "m*s", "models", "maps", "b.c", "x\\*y", "\\[x]"];
+        let mut patterns = vec!["models/**/lod?.lwo".to_string(), "**".to_string(), "maps/".to_string(), "/a/**/b.c".to_string()];
+        for _ in 0..300 {
+            let depth = rng.pick(&["1", "2", "3", "4"]).parse().unwrap();
+            let body = (0..depth).map(|_| rng.pick(&components)).collect::<Vec<_>>().join("/");
+            patterns.push(format!("{}{}{}", rng.pick(&["", "", "/"]), body, rng.pick(&["", "", "", "/"])));
+        }
+        for pattern in &patterns {
+            cxx::let_cxx_string!(cxx_pattern = pattern);
+            let expected: Vec<String> = all_paths.iter()
+                .filter(|path| {
+                    cxx::let_cxx_string!(cxx_path = path.as_str());
+                    StreamDb::match_path(&cxx_pattern, &cxx_path).unwrap()
+                })
+                .cloned()
+                .collect();
+            assert_eq!(db.search_glob(&cxx_pattern).unwrap(), expected, "pattern {pattern}");
+        }
+    }
+
+    // Rewrites the index as the single page format_version used and stamps that version, leaving
+    // the file as a build of that version would have for the migrations to convert
+    fn downgrade_index(db: &StreamDb, format_version: u16) {
+        let index = db.read_index().unwrap();
+        db.index_format.store(format_version, std::sync::atomic::Ordering::SeqCst);
+        db.write_flat_index(&index).unwrap();
+        db.write_roots_as(format_version).unwrap();
+    }
+
+    fn stamped_format_version(dir: &TempDir) -> u16 {
+        StreamDb::header_format_version(&std::fs::read(dir.db()).unwrap()[..DB_HEADER_SIZE])
+    }
+
+    #[test]
+    fn older_formats_migrate_to_the_same_contents() {
+        let dir = TempDir::new();
+        let options = || StreamDb::create_options().use_compression(false);
+        let db = open(&dir, options());
+        let documents: Vec<(String, Vec<u8>)> = (0..20usize)
+            .map(|i| (format!("maps/e{}m{}.map", i / 5, i % 5), vec![i as u8; 100 + i * 700]))
+            .collect();
+        for (path, data) in &documents {
+            db.write_document_unordered(path, data, true, false, false).unwrap();
+        }
+        let before = db.read_index().unwrap();
+        downgrade_index(&db, 4);
+        let v4 = crash_image(&dir);
+        // Stopped two steps in, as a crash between steps leaves it
+        db.migrate_v4_to_v5().unwrap();
+        db.migrate_v5_to_v6().unwrap();
+        let v6 = crash_image(&dir);
+        assert_eq!(stamped_format_version(&v4), 4);
+        assert_eq!(stamped_format_version(&v6), 6);
+        for image in [&v4, &v6] {
+            let migrated = open(image, options());
+            assert_eq!(migrated.get_db_info().unwrap().format_version, FORMAT_VERSION);
+            let after = migrated.read_index().unwrap();
+            assert_eq!(after.keys().collect::<Vec<_>>(), before.keys().collect::<Vec<_>>());
+            for (id, doc) in &before {
+                let migrated_doc = &after[id];
+                assert!(migrated_doc.paths == doc.paths);
+                assert_eq!((migrated_doc.first_page_id, migrated_doc.checksum), (doc.first_page_id, doc.checksum));
+                assert_eq!((migrated_doc.size, migrated_doc.page_count), (doc.size, doc.page_count));
+            }
+            for (path, data) in &documents {
+                assert_eq!(&migrated.read_document(path).unwrap(), data);
+            }
+            drop(migrated);
+            assert_eq!(stamped_format_version(image), FORMAT_VERSION);
+        }
+    }
+
+    // Page ids of a chain in link order, checking each page's type and back link on the way
+    fn chain_pages(db: &StreamDb, first_page_id: i64) -> Vec<i64> {
+        let mut pages = Vec::new();
+        let (mut page_id, mut prev_page_id) = (first_page_id, -1);
+        while page_id != -1 {
+            let header = db.read_page_header(page_id).unwrap();
+            assert!(header.flags & FLAG_DATA_PAGE != 0);
+            assert_eq!(header.prev_page_id, prev_page_id);
+            pages.push(page_id);
+            prev_page_id = page_id;
+            page_id = header.next_page_id;
+        }
+        pages
+    }
+
+    #[test]
+    fn a_ten_page_document_links_exactly_its_own_pages() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().use_compression(false));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        write_paths(&db, &["before.bin"]);
+        let data: Vec<u8> = (0..capacity * 10).map(|i| (i % 251) as u8).collect();
+        let id = db.write_document_unordered("maps/big.bin", &data, true, false, false).unwrap();
+        write_paths(&db, &["after.bin"]);
+        let index = db.read_index().unwrap();
+        let doc = &index[&id];
+        let pages = chain_pages(&db, doc.first_page_id);
+        let distinct: BTreeSet<i64> = pages.iter().copied().collect();
+        assert_eq!((pages.len(), distinct.len(), doc.page_count), (10, 10, 10));
+        assert_eq!(db.chain_page_count(doc.first_page_id).unwrap(), 10);
+        // Neither another document nor the free list claims any of them
+        for other in index.values().filter(|other| other.id != id) {
+            assert!(chain_pages(&db, other.first_page_id).iter().all(|page_id| !distinct.contains(page_id)));
+        }
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        for (i, &page_id) in pages.iter().enumerate() {
+            assert!(!free.contains(&page_id));
+            let (_, payload) = db.read_page_payload(page_id).unwrap();
+            assert_eq!(payload, data[i * capacity..(i + 1) * capacity]);
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn a_failed_chain_write_gives_its_pages_back() {
+        let dir = TempDir::new();
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let db = StreamDb::open_with_faults(&dir.db(), false, schedule.clone()).unwrap();
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let data = vec![7u8; capacity * 10];
+        let first_page_id = db.write_chain_at(&data, CODEC_NONE, 0, 0).unwrap();
+        let pages = chain_pages(&db, first_page_id);
+        db.free_chain(first_page_id).unwrap();
+        let page_count = db.page_count();
+        // The next chain takes the same pages back off the free list, and the sixth cannot be written
+        schedule.lock().fail_page = Some(pages[5]);
+        assert!(db.write_chain_at(&data, CODEC_NONE, 0, 0).is_err());
+        schedule.lock().fail_page = None;
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        assert!(pages.iter().all(|page_id| free.contains(page_id)));
+        assert_eq!(db.page_count(), page_count);
+    }
+
+    #[test]
+    fn concurrent_allocation_never_issues_a_live_page_twice() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let free_before: BTreeSet<i64> = db.free_list_pages(&db.lock_allocation()).unwrap().into_iter().collect();
+        let in_use_before: BTreeSet<i64> = (FIRST_PAGE_ID..db.page_count()).filter(|page_id| !free_before.contains(page_id)).collect();
+        let live = Mutex::new(HashSet::new());
+        let held: Vec<Vec<i64>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8).map(|_| scope.spawn(|| {
+                let mut held = std::collections::VecDeque::new();
+                for i in 0..600 {
+                    // Two allocations to each free, so the file keeps growing while pages are reused
+                    if i % 3 == 2 {
+                        let page_id = held.pop_front().unwrap();
+                        // Out of the live set before it is free, or its next owner would look like a duplicate
+                        assert!(live.lock().unwrap().remove(&page_id));
+                        db.free_page(page_id).unwrap();
+                    } else {
+                        let page_id = db.allocate_page().unwrap();
+                        assert!(!in_use_before.contains(&page_id));
+                        assert!(live.lock().unwrap().insert(page_id), "page {page_id} issued while live");
+                        held.push_back(page_id);
+                    }
+                }
+                held.into_iter().collect()
+            })).collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+        let held: Vec<i64> = held.into_iter().flatten().collect();
+        assert_eq!(held.len(), 8 * 400);
+        let free: Vec<i64> = db.free_list_pages(&db.lock_allocation()).unwrap();
+        // Free, held and what was in use before cover every page of the grown file, each once
+        let mut covered: Vec<i64> = free.iter().chain(&held).chain(&in_use_before).copied().collect();
+        covered.sort_unstable();
+        assert_eq!(covered, (FIRST_PAGE_ID..db.page_count()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_released_lock_lets_another_open_change_the_file_until_reload() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        write_paths(&db, &["maps/e1m1.map"]);
+        assert_eq!(open_error(&dir, StreamDb::create_options()).kind(), io::ErrorKind::ResourceBusy);
+        Pin::new(&mut db).release_lock().unwrap();
+        assert_eq!(db.read_document("maps/e1m1.map").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let mut tool = open(&dir, StreamDb::create_options());
+        assert_eq!(tool.read_document("maps/e1m1.map").unwrap(), b"maps/e1m1.map");
+        write_paths(&tool, &["maps/e1m2.map"]);
+        let big: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        tool.write_document_unordered("maps/big.bin", &big, true, false, false).unwrap();
+        cxx::let_cxx_string!(gone = "maps/e1m1.map");
+        Pin::new(&mut tool).delete_by_path(&gone).unwrap();
+        // The lock only comes back once the tool lets go of it
+        assert_eq!(Pin::new(&mut db).reload_if_changed().unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+        drop(tool);
+        assert!(Pin::new(&mut db).reload_if_changed().unwrap());
+        assert_eq!(db.read_document("maps/e1m2.map").unwrap(), b"maps/e1m2.map");
+        assert_eq!(db.read_document("maps/big.bin").unwrap(), big);
+        assert_eq!(resolves(&db, "maps/e1m1.map"), None);
+        assert!(!Pin::new(&mut db).reload_if_changed().unwrap());
+        assert_eq!(open_error(&dir, StreamDb::create_options()).kind(), io::ErrorKind::ResourceBusy);
+        write_paths(&db, &["maps/e1m3.map"]);
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(db.read_document("maps/e1m2.map").unwrap(), b"maps/e1m2.map");
+        assert_eq!(db.read_document("maps/e1m3.map").unwrap(), b"maps/e1m3.map");
+        assert_eq!(db.read_document("maps/big.bin").unwrap(), big);
+        assert_eq!(resolves(&db, "maps/e1m1.map"), None);
+    }
+
+    #[test]
+    fn a_lock_is_not_released_under_an_open_transaction() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        assert_eq!(Pin::new(&mut db).release_lock().unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+        Pin::new(&mut db).rollback_transaction(tx).unwrap();
+        Pin::new(&mut db).release_lock().unwrap();
+        // Closing a released handle writes nothing, so the file stays as the last writer left it
+        drop(db);
+        open(&dir, StreamDb::create_options());
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn injected_faults_keep_direct_io_off_the_mapping() {
+        let dir = TempDir::new();
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let config = Config { direct_io: true, io_mode: ffi::IoMode::MmapPreferred, faults: Some(schedule), ..Default::default() };
+        let file = StreamDb::db_open_options().create(true).open(dir.db()).unwrap();
+        let db = StreamDb::open_file_with_config(file, &dir.db(), config, false).unwrap();
+        // Where the filesystem refuses direct IO the open falls back to buffered IO
+        let direct = DirectStorage::open(&dir.db()).is_ok();
+        assert_eq!(db.storage.direct_io(), direct);
+        if direct {
+            assert_eq!(mapped_len(&db), None);
+        }
+        db.write_document_unordered("maps/e1m1.bin", b"e1m1", true, false, false).unwrap();
+        assert_eq!(db.read_document("maps/e1m1.bin").unwrap(), b"e1m1");
+    }
+
+    #[test]
+    fn a_rewritten_or_reused_page_never_serves_its_old_bytes() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().use_compression(false));
+        let page_id = db.allocate_page().unwrap();
+        db.write_raw_page(page_id, b"old life", 1, PageLinks::single(FLAG_DATA_PAGE)).unwrap();
+        assert_eq!(db.read_raw_page(page_id).unwrap(), b"old life");
+        // A reader that took the generation before the rewrite caches what it read under it afterwards
+        let stale = db.page_generations.lock().get(&page_id).copied().unwrap_or(0);
+        db.write_raw_page(page_id, b"new life", 2, PageLinks::single(FLAG_DATA_PAGE)).unwrap();
+        db.lock_page_cache(page_id).put((page_id, stale), b"old life".to_vec());
+        assert_eq!(db.read_raw_page(page_id).unwrap(), b"new life");
+        assert_eq!(db.read_raw_page_as(page_id, PageCaching::Peek).unwrap(), b"new life");
+        db.free_page(page_id).unwrap();
+
+        // A document's page freed and handed out again
+        let old = db.write_document_unordered("maps/old.bin", b"old document", true, false, false).unwrap();
+        let old_page = db.lookup_document(&old).unwrap().unwrap().first_page_id;
+        assert_eq!(db.read_document("maps/old.bin").unwrap(), b"old document");
+        db.free_chain(old_page).unwrap();
+        // The free list hands back the page freed last
+        assert_eq!(db.allocate_page().unwrap(), old_page);
+        db.write_raw_page(old_page, b"new document", 1, PageLinks::single(FLAG_DATA_PAGE)).unwrap();
+        assert_eq!(db.read_raw_page(old_page).unwrap(), b"new document");
+        assert_ne!(db.read_document("maps/old.bin").ok(), Some(b"old document".to_vec()));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn a_crash_at_any_write_leaves_every_path_readable() {
+        let base = TempDir::new();
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let db = StreamDb::open_with_faults(&base.db(), false, schedule.clone()).unwrap();
+        write_paths(&db, &["maps/e1m1.map", "sounds/door.wav"]);
+        drop(db);
+        let new_e1m1 = vec![0x5au8; 3 * PAGE_SIZE as usize];
+        let write = |db: &StreamDb| -> io::Result<()> {
+            db.write_document_unordered("maps/e1m1.map", &new_e1m1, true, false, false)?;
+            db.write_document_unordered("maps/e1m2.map", b"e1m2", true, false, false)?;
+            Ok(())
+        };
+
+        // Size the sweep with a run that does not crash
+        let image = crash_image(&base);
+        let db = StreamDb::open_with_faults(&image.db(), false, schedule.clone()).unwrap();
+        let before = schedule.lock().writes;
+        write(&db).unwrap();
+        let writes = schedule.lock().writes - before;
+        drop(db);
+        assert!(writes > 3);
+
+        for n in 1..=writes {
+            let image = crash_image(&base);
+            let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+            let db = StreamDb::open_with_faults(&image.db(), false, schedule.clone()).unwrap();
+            {
+                let mut schedule = schedule.lock();
+                schedule.fail_write = Some(schedule.writes + n);
+                schedule.halt = true;
+            }
+            assert!(write(&db).is_err(), "write {} did not fail", n);
+            drop(db);
+
+            let db = open(&image, StreamDb::create_options());
+            assert!(db.check_trie().unwrap().violations.is_empty(), "crash at write {}", n);
+            for path in db.get_all_paths_sorted().unwrap() {
+                assert!(db.read_document(&path).is_ok(), "crash at write {} left {} unreadable", n, path);
+            }
+            // Each document is the old version or the new one, never a mix
+            let e1m1 = db.read_document("maps/e1m1.map").unwrap();
+            assert!(e1m1 == b"maps/e1m1.map" || e1m1 == new_e1m1, "crash at write {}", n);
+            assert!(resolves(&db, "maps/e1m2.map").is_none() || db.read_document("maps/e1m2.map").unwrap() == b"e1m2");
+            assert_eq!(db.read_document("sounds/door.wav").unwrap(), b"sounds/door.wav");
+        }
+    }
+
+    // Bytes as a little-endian build lays them out, spelled out so a host of either byte order
+    // must decode them to the same values and write them back the same
+    #[test]
+    fn golden_bytes_decode_and_encode_the_same_on_every_host() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().use_compression(false));
+        assert_eq!(db.compute_crc(b"123456789"), 0xcbf4_3926);
+
+        let page_id = db.allocate_page().unwrap();
+        let header = [
+            0x27, 0x86, 0xff, 0x33, // crc of "golden"
+            0x03, 0x00, 0x00, 0x00, // version
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // prev: none
+            0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // next
+            FLAG_DATA_PAGE,
+            0x06, 0x00, 0x00, 0x00, // data length
+            0x00, 0x00, 0x00,
+        ];
+        db.write_bytes_at(db.page_offset(page_id).unwrap(), &header).unwrap();
+        db.write_bytes_at(db.payload_offset(page_id).unwrap(), b"golden").unwrap();
+        db.invalidate_page(page_id);
+        let parsed = db.read_page_header(page_id).unwrap();
+        assert_eq!((parsed.crc, parsed.version, parsed.prev_page_id, parsed.next_page_id), (0x33ff_8627, 3, -1, 12));
+        assert_eq!((parsed.flags, parsed.data_length, parsed.padding), (FLAG_DATA_PAGE, 6, [0; 3]));
+        assert_eq!(db.read_raw_page(page_id).unwrap(), b"golden");
+
+        let index: &[u8] = &[
+            0x01, 0x00, 0x00, 0x00, // one document
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, // id
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // first page
+            0x02, 0x00, 0x00, 0x00, // current version
+            0x39, 0x26, 0xf4, 0xcb, // checksum
+            0x01, 0x00, 0x00, 0x00, // one path
+            0x04, 0x00, 0x00, 0x00, b'a', b'.', b'd', b'b',
+            0x01, // addon
+            0xfe, 0xff, 0xff, 0xff, // priority -2
+            0x02, 0x00, 0x00, 0x00, b'e', b'n',
+            0x01, 0x00, 0x00, 0x00, // one previous version
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, // expires at
+            0x01, 0x00, 0x00, 0x00, // one tag
+            0x03, 0x00, 0x00, 0x00, b'm', b'a', b'p',
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // size 1 << 32
+            0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // modified
+            0x01, 0x00, 0x00, 0x00, // flags
+            0x01, 0x00, 0x00, 0x00, // page count
+        ];
+        let docs = db.deserialize_index(index, FORMAT_VERSION).unwrap();
+        let doc = &docs[&Uuid::from_bytes([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])];
+        assert_eq!((doc.first_page_id, doc.current_version, doc.checksum), (5, 2, 0xcbf4_3926));
+        assert!(doc.paths == [PathBinding { path: "a.db".to_string(), addon: true, priority: -2, lang: "en".to_string() }]);
+        assert_eq!((doc.previous_versions[0].page_id, doc.previous_versions[0].version), (3, 1));
+        assert_eq!((doc.expires_at, doc.size, doc.modified, doc.flags, doc.page_count), (0x0102_0304_0506_0708, 1 << 32, 16, 1, 1));
+        assert_eq!(doc.tags.iter().collect::<Vec<_>>(), ["map"]);
+        assert_eq!(db.serialize_index(&docs).unwrap(), index);
+
+        let node: &[u8] = &[
+            0x02, 0x00, 0x00, 0x00, b'p', b'm', // edge
+            0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // parent
+            0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // self
+            0x01, 0x00, 0x00, 0x00, // has a document
+            0x0f, 0x0e, 0x0d, 0x0c, 0x0b, 0x0a, 0x09, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x00,
+            0x01, 0x00, 0x00, 0x00, // one child
+            b'a', 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let parsed = db.deserialize_trie_node(node).unwrap();
+        assert_eq!((parsed.edge.as_str(), parsed.parent_page_id, parsed.self_page_id), ("pm", 4, 9));
+        assert_eq!(parsed.document_id, Some(Uuid::from_bytes([15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0])));
+        assert_eq!(parsed.children.into_iter().collect::<Vec<_>>(), [('a', 12)]);
+        assert_eq!(db.serialize_trie_node(&db.deserialize_trie_node(node).unwrap()).unwrap(), node);
+    }
+
+    // Regression inputs for the deserializers of untrusted page data: each must fail with a
+    // Corrupt error, never panic or size an allocation from the bad value
+    #[test]
+    fn corrupt_page_data_is_refused_without_panicking() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let compressed = |codec| PageHeader {
+            crc: 0, version: 1, prev_page_id: -1, next_page_id: -1,
+            flags: FLAG_DATA_PAGE | FLAG_COMPRESSED, data_length: 0, padding: [codec, 0, 0],
+        };
+        let valid = snappy::compress(b"maps/e1m1.map");
+        assert_eq!(db.decompress_page(&valid, &compressed(CODEC_SNAPPY)).unwrap(), b"maps/e1m1.map");
+        // A snappy preamble declaring a 4GB page
+        let oversized = [0xff, 0xff, 0xff, 0xff, 0x0f, 0x00, 0x00];
+        assert_eq!(db.decompress_page(&oversized, &compressed(CODEC_SNAPPY)).unwrap_err().to_string(), "Corrupt compressed page");
+        assert_eq!(db.decompress_page(&valid, &compressed(0x7f)).unwrap_err().to_string(), "Corrupt page codec");
+        assert_eq!(db.decompress_page(&[0x28, 0xb5, 0x2f, 0xfd, 0xff], &compressed(CODEC_ZSTD)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let page_id = db.allocate_page().unwrap();
+        let max_pages = db.config.max_pages;
+        let capacity = (db.config.page_size - db.config.page_header_size) as i32;
+        for (prev_page_id, next_page_id, data_length) in [(-2, -1, 0), (-1, max_pages, 0), (i64::MIN, i64::MAX, 0), (-1, -1, -1), (-1, -1, capacity + 1)] {
+            let header = PageHeader { crc: 0, version: 1, prev_page_id, next_page_id, flags: FLAG_DATA_PAGE, data_length, padding: [0; 3] };
+            db.write_page_header(page_id, &header).unwrap();
+            assert_eq!(db.read_page_header(page_id).unwrap_err().to_string(), "Corrupt page header");
+            assert_eq!(db.read_raw_page(page_id).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        let huge_count = i32::MAX.to_le_bytes();
+        assert_eq!(db.deserialize_index(&huge_count, FORMAT_VERSION).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(db.deserialize_trie_node(&huge_count).unwrap_err().to_string(), "Corrupt trie edge");
+        assert_eq!(db.deserialize_trie_node(&(-1i32).to_le_bytes()).unwrap_err().to_string(), "Corrupt trie edge");
+    }
+
+    #[test]
+    fn a_cold_exact_lookup_reads_one_page() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let paths: Vec<String> = (0..200).map(|i| format!("sound/music/level{}/ambient/track{}.ogg", i % 7, i)).collect();
+        write_paths(&db, &paths.iter().map(String::as_str).collect::<Vec<_>>());
+        let ids: Vec<Uuid> = paths.iter().map(|path| resolves(&db, path).unwrap()).collect();
+        drop(db);
+
+        let db = open(&dir, StreamDb::create_options());
+        db.read_index().unwrap();
+        for (path, id) in paths.iter().zip(&ids).step_by(17) {
+            db.clear_page_cache();
+            db.clear_path_cache();
+            db.trie_cache.lock().clear();
+            let misses = db.cache_stats.lock().misses;
+            assert_eq!(db.get_document_id_by_path(path).unwrap(), *id);
+            // The path's hash bucket, where a trie walk would read a page per edge
+            assert_eq!(db.cache_stats.lock().misses - misses, 1, "{}", path);
+        }
+        db.clear_page_cache();
+        db.clear_path_cache();
+        assert_eq!(db.get_document_id_by_path("sound/music/level0/missing.ogg").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn index_writes_touch_a_logarithmic_number_of_pages() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        let paths: Vec<String> = (0..3000).map(|i| format!("textures/set{}/wall{}.tga", i % 31, i)).collect();
+        write_paths(&db, &paths.iter().map(String::as_str).collect::<Vec<_>>());
+        let root = db.document_index_root.read().page_id;
+        let (mut depth, mut node) = (1, db.read_index_node(root).unwrap());
+        while let IndexNode::Branch { children, .. } = node {
+            depth += 1;
+            node = db.read_index_node(children[0]).unwrap();
+        }
+        let leaves = db.index_leaves(root).count();
+        assert!(depth <= 4 && leaves > 8 * depth, "depth {} over {} leaves", depth, leaves);
+        // Index pages carry the version of the write that last stored them
+        let written = |db: &StreamDb| {
+            let version = db.document_index_root.read().version;
+            let mut pages = 0;
+            db.scan_page_headers(db.page_count(), |_, header| {
+                if header.is_some_and(|header| header.flags & FLAG_INDEX_PAGE != 0 && header.version == version) {
+                    pages += 1;
+                }
+                Ok(())
+            }).unwrap();
+            pages
+        };
+        for i in 0..20 {
+            write_paths(&db, &[format!("textures/extra/wall{}.tga", i).as_str()]);
+            assert!(written(&db) <= 2 * depth + 1);
+            cxx::let_cxx_string!(path = paths[i * 100].as_str());
+            Pin::new(&mut db).delete_by_path(&path).unwrap();
+            assert!(written(&db) <= 2 * depth + 1);
+        }
+        drop(db);
+
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(db.read_index().unwrap().len(), 3000);
+        for (i, path) in paths.iter().enumerate() {
+            let expected = if i % 100 == 0 && i < 2000 { None } else { Some(path.as_bytes().to_vec()) };
+            assert_eq!(db.read_document(path).ok(), expected);
+        }
+        for i in 0..20 {
+            let path = format!("textures/extra/wall{}.tga", i);
+            assert_eq!(db.read_document(&path).unwrap(), path.as_bytes());
+        }
+    }
+
+    #[test]
+    fn a_stream_reads_its_document_to_the_end_after_a_delete() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let track: Vec<u8> = (0..capacity * 5).map(|i| (i % 253) as u8).collect();
+        let id = db.write_document_unordered("sound/music/track3.bin", &track, true, false, false).unwrap();
+        let pages = chain_pages(&db, db.lookup_document(&id).unwrap().unwrap().first_page_id);
+        cxx::let_cxx_string!(path = "sound/music/track3.bin");
+        let stream = db.start_stream_with_chunk_size(&path, capacity).unwrap();
+        let mut streamed = db.stream_chunk(stream).unwrap();
+        assert_eq!(db.get_db_stats().open_streams, 1);
+
+        Pin::new(&mut db).delete_by_path(&path).unwrap();
+        // The pages stay out of the free list, so these writes cannot land on them
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        assert!(pages.iter().all(|page_id| !free.contains(page_id)));
+        for i in 0..5 {
+            db.write_document_unordered(&format!("sound/music/other{}.bin", i), &vec![0xee; capacity], true, false, false).unwrap();
+        }
+        while streamed.len() < track.len() {
+            streamed.extend(db.stream_chunk(stream).unwrap());
+        }
+        assert_eq!(streamed, track);
+        assert_eq!(db.get_db_stats().open_streams, 1);
+        Pin::new(&mut db).end_stream(stream);
+        assert_eq!(db.get_db_stats().open_streams, 0);
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        assert!(pages.iter().all(|page_id| free.contains(page_id)));
+    }
+
+    #[test]
+    fn thirty_two_streams_read_every_byte_of_their_documents() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let documents: Vec<(String, Vec<u8>)> = (0..4)
+            .map(|d| (format!("sound/stream{}.bin", d), (0..(d + 1) * 9000).map(|i| (i * (d + 3) % 251) as u8).collect()))
+            .collect();
+        for (path, data) in &documents {
+            db.write_document_unordered(path, data, true, false, false).unwrap();
+        }
+        std::thread::scope(|scope| {
+            for t in 0..32 {
+                let (db, (path, data)) = (&db, &documents[t % documents.len()]);
+                scope.spawn(move || {
+                    cxx::let_cxx_string!(path = path.as_str());
+                    let chunk_size = 1 + t * 397;
+                    let stream = db.start_stream_with_chunk_size(&path, chunk_size).unwrap();
+                    let mut streamed = Vec::new();
+                    loop {
+                        match db.stream_chunk(stream) {
+                            Ok(chunk) => {
+                                assert!(chunk.len() <= chunk_size);
+                                streamed.extend(chunk);
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                            Err(e) => panic!("stream {}: {}", t, e),
+                        }
+                    }
+                    assert!(streamed == *data, "stream {} of {}", t, path);
+                    let stats = db.get_stream_stats(stream).unwrap();
+                    assert!(stats.finished);
+                    assert_eq!(stats.bytes_read, data.len() as u64);
+                    db.close_stream(stream);
+                    db.close_stream(stream);
+                });
+            }
+        });
+        assert_eq!(db.get_db_stats().open_streams, 0);
+
+        // Closing the database invalidates what is still open
+        cxx::let_cxx_string!(path = documents[0].0.as_str());
+        let stream = db.start_stream(&path).unwrap();
+        db.stream_chunk(stream).unwrap();
+        db.shutdown();
+        assert!(db.stream_chunk(stream).is_err());
+        assert!(db.get_stream_stats(stream).is_err());
+        db.close_stream(stream);
+    }
+
+    #[test]
+    fn path_counts_agree_with_enumerating_the_paths() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let mut rng = Xorshift(0xD1B5_4A32_D192_ED03);
+        let names = ["sound", "vo", "vox", "v", "e1m1", "maps", "a.ogg", "b.ogg", "ab.ogg"];
+        let mut paths = BTreeSet::new();
+        while paths.len() < 200 {
+            let depth = rng.pick(&["1", "2", "3", "4"]).parse().unwrap();
+            paths.insert((0..depth).map(|_| rng.pick(&names)).collect::<Vec<_>>().join("/"));
+        }
+        for path in &paths {
+            db.write_document_unordered(path, path.as_bytes(), true, false, false).unwrap();
+        }
+        let check = |db: &StreamDb, paths: &BTreeSet<String>| {
+            assert_eq!(db.document_count().unwrap(), paths.len() as u64);
+            assert_eq!(db.document_count().unwrap(), db.read_index().unwrap().len() as u64);
+            for prefix in ["", "sound", "sound/", "sound/vo", "sound/vo/", "v", "vo/e1m1/", "maps/a.ogg", "nothing/"] {
+                cxx::let_cxx_string!(cxx_prefix = prefix);
+                let expected = paths.iter().filter(|path| path.starts_with(prefix)).count() as u64;
+                assert_eq!(db.count_paths(&cxx_prefix).unwrap(), expected, "prefix {prefix}");
+                assert_eq!(db.count_paths(&cxx_prefix).unwrap(), db.search_paths(&cxx_prefix).unwrap().len() as u64);
+            }
+        };
+        check(&db, &paths);
+
+        // Counts follow deletes and renames
+        for _ in 0..40 {
+            let path = paths.iter().nth(rng.pick(&["0", "3", "7", "11", "19"]).parse::<usize>().unwrap() % paths.len()).unwrap().clone();
+            cxx::let_cxx_string!(from = path.as_str());
+            if rng.pick(&["delete", "rename"]) == "delete" {
+                Pin::new(&mut db).delete_by_path(&from).unwrap();
+            } else {
+                let renamed = format!("renamed/{}", path);
+                cxx::let_cxx_string!(to = renamed.as_str());
+                Pin::new(&mut db).rename_path(&from, &to).unwrap();
+                paths.insert(renamed);
+            }
+            paths.remove(&path);
+        }
+        check(&db, &paths);
+        drop(db);
+        check(&open(&dir, StreamDb::create_options()), &paths);
+    }
+
+    #[test]
+    fn detailed_search_agrees_with_stat_for_every_path() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        for i in 0..30 {
+            db.write_document_unordered(&format!("maps/e{}m{}.bin", i / 10, i), &vec![i as u8; i * 700], true, false, false).unwrap();
+        }
+        // A second version and a second path for some of them
+        for i in (0..30).step_by(4) {
+            db.write_document_unordered(&format!("maps/e{}m{}.bin", i / 10, i), &vec![0xaa; i * 300 + 5], true, false, false).unwrap();
+        }
+        cxx::let_cxx_string!(source = "maps/e0m3.bin");
+        cxx::let_cxx_string!(alias = "maps/alias.bin");
+        Pin::new(&mut db).add_path(&source, &alias, false).unwrap();
+        write_paths(&db, &["sound/other.wav"]);
+
+        cxx::let_cxx_string!(prefix = "maps/");
+        let detailed = db.search_paths_detailed(&prefix).unwrap();
+        let paths: Vec<String> = db.search_paths(&prefix).unwrap().iter().map(|path| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(detailed.iter().map(|info| info.path.clone()).collect::<Vec<_>>(), paths);
+        assert_eq!(detailed.len(), 31);
+        for info in &detailed {
+            cxx::let_cxx_string!(path = info.path.as_str());
+            let stat = db.stat(&path).unwrap();
+            assert_eq!((&info.uuid, info.size, info.version, info.page_count), (&stat.uuid, stat.size, stat.version, stat.page_count), "{}", info.path);
+            assert_eq!(info.size, db.read_document(&info.path).unwrap().len() as u64);
+        }
+        let alias_info = detailed.iter().find(|info| info.path == "maps/alias.bin").unwrap();
+        let source_info = detailed.iter().find(|info| info.path == "maps/e0m3.bin").unwrap();
+        assert_eq!(alias_info.uuid, source_info.uuid);
+    }
+
+    #[test]
+    fn binding_twice_keeps_one_entry_and_unbinding_mirrors_the_trie() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let id = db.write_document_unordered("guis/mainmenu.gui", b"menu", true, false, false).unwrap();
+        cxx::let_cxx_string!(base = "guis/mainmenu.gui");
+        cxx::let_cxx_string!(addon = "guis/addon_menu.gui");
+        Pin::new(&mut db).add_path(&base, &addon, false).unwrap();
+        for _ in 0..2 {
+            Pin::new(&mut db).bind_addon_path(&addon, true).unwrap();
+        }
+        let doc = db.lookup_document(&id).unwrap().unwrap();
+        let bindings: Vec<(&str, bool)> = doc.paths.iter().map(|binding| (binding.path.as_str(), binding.addon)).collect();
+        assert_eq!(bindings, [("guis/mainmenu.gui", false), ("guis/addon_menu.gui", true)]);
+        Pin::new(&mut db).bind_addon_path(&addon, false).unwrap();
+        assert!(!db.lookup_document(&id).unwrap().unwrap().paths[1].addon);
+        assert!(db.check_trie().unwrap().violations.is_empty());
+
+        // The lookup is cached, so unbinding must drop it from the path cache as well as the trie
+        assert_eq!(resolves(&db, "guis/addon_menu.gui"), Some(id));
+        Pin::new(&mut db).unbind_addon_path(&addon, false).unwrap();
+        assert_eq!(resolves(&db, "guis/addon_menu.gui"), None);
+        assert_eq!(db.lookup_document(&id).unwrap().unwrap().paths.len(), 1);
+        assert!(db.check_trie().unwrap().violations.is_empty());
+
+        let e = Pin::new(&mut db).unbind_addon_path(&base, false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(db.read_document("guis/mainmenu.gui").unwrap(), b"menu");
+        let page_id = db.lookup_document(&id).unwrap().unwrap().first_page_id;
+        Pin::new(&mut db).unbind_addon_path(&base, true).unwrap();
+        assert_eq!(resolves(&db, "guis/mainmenu.gui"), None);
+        assert!(db.lookup_document(&id).unwrap().is_none());
+        assert!(db.free_list_pages(&db.lock_allocation()).unwrap().contains(&page_id));
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(db.document_count().unwrap(), 0);
+        assert_eq!(resolves(&db, "guis/addon_menu.gui"), None);
+    }
+
+    #[test]
+    fn resolution_reports_the_winning_layer_and_what_it_shadows() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let base = db.write_document_unordered("guis/mainmenu.gui", b"base", true, false, false).unwrap();
+        let first = db.write_document_unordered("addons/first/mainmenu.gui", b"first", true, false, false).unwrap();
+        let second = db.write_document_unordered("addons/second/mainmenu.gui", b"second", true, false, false).unwrap();
+        cxx::let_cxx_string!(path = "guis/mainmenu.gui");
+        for (source, priority) in [("addons/second/mainmenu.gui", 5), ("addons/first/mainmenu.gui", 10)] {
+            cxx::let_cxx_string!(source = source);
+            Pin::new(&mut db).bind_path_layer(&path, &source, true, priority).unwrap();
+        }
+        let claims = |resolution: &ffi::PathResolution| -> Vec<(String, bool, i32)> {
+            resolution.shadowed.iter().map(|claim| (claim.uuid.clone(), claim.addon, claim.priority)).collect()
+        };
+        let resolution = db.resolve_path(&path).unwrap();
+        assert_eq!((resolution.uuid.clone(), resolution.addon, resolution.priority), (first.to_string(), true, 10));
+        assert_eq!(claims(&resolution), [(second.to_string(), true, 5), (base.to_string(), false, 0)]);
+        assert_eq!(db.read_document("guis/mainmenu.gui").unwrap(), b"first");
+
+        // Removing the winning layer uncovers the next one down
+        Pin::new(&mut db).unbind_addon_path(&path, false).unwrap();
+        let resolution = db.resolve_path(&path).unwrap();
+        assert_eq!((resolution.uuid.clone(), resolution.addon, resolution.priority), (second.to_string(), true, 5));
+        assert_eq!(claims(&resolution), [(base.to_string(), false, 0)]);
+        assert_eq!(db.read_document("guis/mainmenu.gui").unwrap(), b"second");
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        let resolution = db.resolve_path(&path).unwrap();
+        assert_eq!(resolution.uuid, second.to_string());
+        assert_eq!(claims(&resolution), [(base.to_string(), false, 0)]);
+    }
+
+    #[test]
+    fn finishing_a_stream_catches_a_corrupt_page_in_quick_mode() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().quick_mode(true));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let video: Vec<u8> = (0..capacity * 4).map(|i| (i % 241) as u8).collect();
+        let id = db.write_document_unordered("video/intro.bin", &video, true, false, false).unwrap();
+        cxx::let_cxx_string!(path = "video/intro.bin");
+        let stream_all = |db: &StreamDb| {
+            let stream = db.start_stream_with_chunk_size(&path, 5000).unwrap();
+            let mut streamed = Vec::new();
+            while let Ok(chunk) = db.stream_chunk(stream) {
+                streamed.extend(chunk);
+            }
+            (stream, streamed)
+        };
+        let (stream, streamed) = stream_all(&db);
+        assert_eq!(streamed, video);
+        assert_eq!(Pin::new(&mut db).finish_stream(stream).unwrap(), ffi::StreamVerification::Verified);
+        // Ended before the last chunk, nothing can be said
+        let stream = db.start_stream(&path).unwrap();
+        db.stream_chunk(stream).unwrap();
+        assert_eq!(Pin::new(&mut db).finish_stream(stream).unwrap(), ffi::StreamVerification::Unverified);
+
+        // Quick mode skips the page CRCs, so only the whole-document check sees the damage
+        let pages = chain_pages(&db, db.lookup_document(&id).unwrap().unwrap().first_page_id);
+        db.write_bytes_at(db.payload_offset(pages[2]).unwrap() + 100, b"glitch").unwrap();
+        db.invalidate_page(pages[2]);
+        let (stream, streamed) = stream_all(&db);
+        assert_eq!(streamed.len(), video.len());
+        assert_ne!(streamed, video);
+        assert_eq!(Pin::new(&mut db).finish_stream(stream).unwrap(), ffi::StreamVerification::Corrupt);
+        assert_eq!(Pin::new(&mut db).finish_stream(stream).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn streams_coalesce_pages_into_the_requested_chunk_size() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        // Freeing every other filler leaves holes the music's chain is threaded through
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        for i in 0..200 {
+            db.write_document_unordered(&format!("filler/{}.bin", i), &vec![1; capacity], true, false, false).unwrap();
+        }
+        for i in (0..200).step_by(2) {
+            cxx::let_cxx_string!(path = format!("filler/{}.bin", i));
+            Pin::new(&mut db).delete_by_path(&path).unwrap();
+        }
+        let music: Vec<u8> = (0..1 << 20).map(|i| (i * 7 % 256) as u8).collect();
+        let id = db.write_document_unordered("sound/music/track1.bin", &music, true, false, false).unwrap();
+        let pages = chain_pages(&db, db.lookup_document(&id).unwrap().unwrap().first_page_id);
+        assert!(pages.windows(2).any(|pair| pair[1] != pair[0] + 1));
+
+        cxx::let_cxx_string!(path = "sound/music/track1.bin");
+        for chunk_size in [64 << 10, 100] {
+            let stream = db.start_stream_with_chunk_size(&path, chunk_size).unwrap();
+            let mut chunks = Vec::new();
+            loop {
+                match db.stream_chunk(stream) {
+                    Ok(chunk) => chunks.push(chunk),
+                    Err(e) => {
+                        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+                        break;
+                    }
+                }
+            }
+            assert!(db.get_stream_stats(stream).unwrap().finished);
+            let (last, full) = chunks.split_last().unwrap();
+            assert!(full.iter().all(|chunk| chunk.len() == chunk_size));
+            assert!(!last.is_empty() && last.len() <= chunk_size);
+            assert_eq!(chunks.len(), music.len().div_ceil(chunk_size));
+            assert!(chunks.concat() == music);
+            db.close_stream(stream);
+        }
+    }
+
+    #[test]
+    fn reads_into_caller_buffers_match_get() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        // One stored as is and read from the mapping, one compressed by the default rules
+        for path in ["textures/wall.bin", "scripts/doom.cfg"] {
+            let data: Vec<u8> = (0..capacity * 3 + 123).map(|i| (i / 7 % 256) as u8).collect();
+            db.write_document_unordered(path, &data, true, false, false).unwrap();
+            cxx::let_cxx_string!(cxx_path = path);
+            let got = db.get(&cxx_path).unwrap().as_slice().to_vec();
+            assert_eq!(got, data);
+            for (offset, len) in [(0, data.len()), (0, 10), (capacity as u64 - 5, 10), (1000, capacity * 2), (data.len() as u64 - 3, 100), (data.len() as u64 + 10, 8)] {
+                let mut buffer = vec![0u8; len];
+                let written = unsafe { db.read_into(&cxx_path, offset, buffer.as_mut_ptr(), buffer.len()) }.unwrap();
+                let start = (offset as usize).min(got.len());
+                let expected = &got[start..(start + len).min(got.len())];
+                assert_eq!(&buffer[..written], expected, "{} at {}", path, offset);
+            }
+            let stream = db.start_stream(&cxx_path).unwrap();
+            let mut streamed = Vec::new();
+            let mut buffer = vec![0u8; 1500];
+            loop {
+                let written = unsafe { db.stream_read_into(stream, buffer.as_mut_ptr(), buffer.len()) }.unwrap();
+                if written == 0 {
+                    break;
+                }
+                streamed.extend_from_slice(&buffer[..written]);
+            }
+            assert_eq!(streamed, got);
+            db.close_stream(stream);
+            assert_eq!(unsafe { db.read_into(&cxx_path, 0, std::ptr::null_mut(), 0) }.unwrap(), 0);
+            assert_eq!(unsafe { db.read_into(&cxx_path, 0, std::ptr::null_mut(), 16) }.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn file_handles_follow_random_seeks_and_reads_like_a_reference_copy() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let mut rng = Xorshift(0x2545_F491_4F6C_DD1D);
+        let amounts = ["0", "1", "17", "4000", "4064", "9000", "30000"];
+        for path in ["maps/e1m1.bin", "scripts/autoexec.cfg"] {
+            let data: Vec<u8> = (0..capacity * 6 + 999).map(|i| (i * 31 % 256) as u8).collect();
+            db.write_document_unordered(path, &data, true, false, false).unwrap();
+            cxx::let_cxx_string!(cxx_path = path);
+            let mut file = db.open_file(&cxx_path).unwrap();
+            let mut reference = Cursor::new(data.clone());
+            assert_eq!(file.length(), data.len() as u64);
+            for _ in 0..500 {
+                let amount: i64 = rng.pick(&amounts).parse().unwrap();
+                match rng.pick(&["read", "set", "cur", "end", "std"]) {
+                    "read" => {
+                        let mut got = vec![0u8; amount as usize];
+                        let mut expected = vec![0u8; amount as usize];
+                        let n = file.read(&mut got).unwrap();
+                        assert_eq!(n, Read::read(&mut reference, &mut expected).unwrap());
+                        assert_eq!(got[..n], expected[..n]);
+                    }
+                    "set" => assert_eq!(file.seek(amount, ffi::SeekOrigin::Set).unwrap(), reference.seek(SeekFrom::Start(amount as u64)).unwrap()),
+                    "cur" => {
+                        let back = if rng.pick(&["+", "-"]) == "-" { -amount.min(reference.position() as i64) } else { amount };
+                        assert_eq!(file.seek(back, ffi::SeekOrigin::Cur).unwrap(), reference.seek(SeekFrom::Current(back)).unwrap());
+                    }
+                    "end" => {
+                        let back = amount.min(data.len() as i64);
+                        assert_eq!(file.seek(-back, ffi::SeekOrigin::End).unwrap(), reference.seek(SeekFrom::End(-back)).unwrap());
+                    }
+                    _ => assert_eq!(Seek::seek(&mut *file, SeekFrom::Start(amount as u64 * 3)).unwrap(), reference.seek(SeekFrom::Start(amount as u64 * 3)).unwrap()),
+                }
+                assert_eq!(file.tell(), reference.position());
+            }
+            assert_eq!(file.seek(-1, ffi::SeekOrigin::Set).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            // Through std::io, the whole document from the start
+            Seek::seek(&mut *file, SeekFrom::Start(0)).unwrap();
+            let mut all = Vec::new();
+            Read::read_to_end(&mut *file, &mut all).unwrap();
+            assert_eq!(all, data);
+            assert!(file.timestamp() > 0);
+        }
+    }
+
+    #[test]
+    fn bytes_at_the_extents_are_the_document() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().slab_threshold(256));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let sound: Vec<u8> = (0..capacity * 3 + 50).map(|i| (i % 199) as u8).collect();
+        db.write_document_unordered("sound/door.bin", &sound, true, false, false).unwrap();
+        db.write_document_unordered("sound/click.bin", b"tiny enough for a slab", true, false, false).unwrap();
+        db.write_document_unordered("scripts/doom.cfg", &vec![b'x'; capacity * 2], true, false, false).unwrap();
+        let mut file = File::open(dir.db()).unwrap();
+        for path in ["sound/door.bin", "sound/click.bin"] {
+            cxx::let_cxx_string!(cxx_path = path);
+            let extents = db.get_extents(&cxx_path).unwrap();
+            assert!(extents.eligible);
+            let mut direct = Vec::new();
+            for extent in &extents.extents {
+                let mut bytes = vec![0u8; extent.length as usize];
+                file.seek(SeekFrom::Start(extent.file_offset)).unwrap();
+                file.read_exact(&mut bytes).unwrap();
+                direct.extend(bytes);
+            }
+            assert_eq!(direct, db.get(&cxx_path).unwrap().as_slice(), "{}", path);
+            assert!(db.validate_extents(&cxx_path, extents.version_stamp).unwrap());
+        }
+        cxx::let_cxx_string!(compressed = "scripts/doom.cfg");
+        let extents = db.get_extents(&compressed).unwrap();
+        assert!(!extents.eligible && extents.extents.is_empty());
+
+        // A rewrite moves the document, which the stamp shows
+        cxx::let_cxx_string!(door = "sound/door.bin");
+        let stamp = db.get_extents(&door).unwrap().version_stamp;
+        db.write_document_unordered("sound/door.bin", &sound, true, false, false).unwrap();
+        assert!(!db.validate_extents(&door, stamp).unwrap());
+        assert!(db.validate_extents(&door, db.get_extents(&door).unwrap().version_stamp).unwrap());
+    }
+
+    #[test]
+    fn two_languages_override_overlapping_and_disjoint_paths() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        for name in ["menu", "hud", "credits"] {
+            let path = format!("strings/{name}.lang");
+            db.write_document_unordered(&path, name.as_bytes(), true, false, false).unwrap();
+        }
+        // Both languages override menu; each also overrides one default and binds one path of its own
+        let packs = [
+            ("french", ["strings/menu.lang", "strings/hud.lang", "strings/french.lang"]),
+            ("german", ["strings/menu.lang", "strings/credits.lang", "strings/german.lang"]),
+        ];
+        let mut localized = HashMap::new();
+        for (lang, paths) in packs {
+            for path in paths {
+                let source = format!("lang/{lang}/{}", path.trim_start_matches("strings/"));
+                let id = db.write_document_unordered(&source, format!("{lang} {path}").as_bytes(), true, false, false).unwrap();
+                cxx::let_cxx_string!(path_cxx = path);
+                cxx::let_cxx_string!(lang_cxx = lang);
+                cxx::let_cxx_string!(source_cxx = source.as_str());
+                Pin::new(&mut db).bind_localized_path(&path_cxx, &lang_cxx, &source_cxx).unwrap();
+                localized.insert((lang, path), id);
+            }
+        }
+        let paths = ["strings/menu.lang", "strings/hud.lang", "strings/credits.lang", "strings/french.lang", "strings/german.lang"];
+        let defaults: HashMap<&str, Option<Uuid>> = paths.iter().map(|path| (*path, resolves(&db, path))).collect();
+        assert_eq!(defaults["strings/french.lang"], None);
+        assert_eq!(defaults["strings/german.lang"], None);
+
+        for lang in ["french", "german", ""] {
+            cxx::let_cxx_string!(lang_cxx = lang);
+            Pin::new(&mut db).set_active_language(&lang_cxx).unwrap();
+            assert_eq!(db.get_active_language(), lang);
+            for path in paths {
+                let expected = localized.get(&(lang, path)).copied().or(defaults[path]);
+                assert_eq!(resolves(&db, path), expected, "{path} under {lang:?}");
+                let contents = match localized.contains_key(&(lang, path)) {
+                    true => format!("{lang} {path}"),
+                    false => path.trim_start_matches("strings/").trim_end_matches(".lang").to_string(),
+                };
+                match expected {
+                    Some(_) => assert_eq!(db.read_document(path).unwrap(), contents.as_bytes()),
+                    None => assert!(db.read_document(path).is_err()),
+                }
+            }
+
+            // The merged view follows the active language
+            cxx::let_cxx_string!(prefix = "strings/");
+            let mut merged: Vec<(String, String)> = db.search_paths_detailed(&prefix).unwrap().into_iter().map(|info| (info.path, info.uuid)).collect();
+            merged.sort();
+            let mut expected: Vec<(String, String)> = paths.iter()
+                .filter_map(|path| resolves(&db, path).map(|id| (path.to_string(), id.to_string())))
+                .collect();
+            expected.sort();
+            assert_eq!(merged, expected);
+
+            // Per-language listings ignore the active language
+            for (pack, pack_paths) in packs {
+                cxx::let_cxx_string!(pack_cxx = pack);
+                let bindings: Vec<(String, String)> = db.search_language_bindings(&prefix, &pack_cxx).unwrap().into_iter().map(|info| (info.path, info.uuid)).collect();
+                let mut expected: Vec<(String, String)> = pack_paths.iter().map(|path| (path.to_string(), localized[&(pack, *path)].to_string())).collect();
+                expected.sort();
+                assert_eq!(bindings, expected);
+            }
+            cxx::let_cxx_string!(default_lang = "");
+            let bindings: Vec<String> = db.search_language_bindings(&prefix, &default_lang).unwrap().into_iter().map(|info| info.path).collect();
+            assert_eq!(bindings, ["strings/credits.lang", "strings/hud.lang", "strings/menu.lang"]);
+        }
+    }
+
+    #[test]
+    fn a_temp_database_leaves_no_file_and_keeps_durability_to_itself() {
+        let dir = TempDir::new();
+        let entries = || std::fs::read_dir(&dir.0).unwrap().count();
+        cxx::let_cxx_string!(hint = dir.0.to_string_lossy().as_ref());
+        let temp = StreamDb::open_temp(&hint).unwrap();
+        assert!(!temp.config.durable_writes);
+        temp.write_document_unordered("maps/game/intermediate.bin", &[7u8; 10000], true, false, false).unwrap();
+        assert_eq!(temp.read_document("maps/game/intermediate.bin").unwrap(), [7u8; 10000]);
+        #[cfg(unix)]
+        assert_eq!(entries(), 0);
+        drop(temp);
+        assert_eq!(entries(), 0);
+
+        // A panic while the database is open still leaves nothing behind
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let temp = StreamDb::open_temp(&hint).unwrap();
+            temp.write_document_unordered("maps/game/intermediate.bin", b"scratch", true, false, false).unwrap();
+            panic!("map compile failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(entries(), 0);
+
+        // Databases opened afterwards still flush, and outlive their handles
+        let _temp = StreamDb::open_temp(&hint).unwrap();
+        let db = open(&dir, StreamDb::create_options());
+        assert!(db.config.durable_writes);
+        write_paths(&db, &["maps/game/kept.bin"]);
+        drop(db);
+        assert!(dir.db().exists());
+        #[cfg(feature = "fault-injection")]
+        {
+            let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+            let db = StreamDb::open_with_faults(&dir.db(), false, schedule.clone()).unwrap();
+            write_paths(&db, &["maps/game/synced.bin"]);
+            let schedule = schedule.lock();
+            assert!(schedule.full_syncs + schedule.data_syncs > 0);
+        }
+    }
+
+    #[test]
+    fn snapshots_under_write_load_hold_one_point_in_time() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let paths: Vec<String> = (0..6).map(|i| format!("save/profile/slot{}.bin", i)).collect();
+        let contents = |path: &str, generation: u64| -> Vec<u8> {
+            let mut data = format!("{}:{};", generation, path).into_bytes();
+            data.extend((0..6000).map(|i| ((i as u64 * 7 + generation) % 251) as u8));
+            data
+        };
+        for path in &paths {
+            db.write_document_unordered(path, &contents(path, 0), true, false, false).unwrap();
+        }
+        let stop = std::sync::atomic::AtomicBool::new(false);
+        let generations = std::thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let mut generation = 0;
+                while !stop.load(std::sync::atomic::Ordering::SeqCst) {
+                    generation += 1;
+                    for path in &paths {
+                        db.write_document_unordered(path, &contents(path, generation), true, false, false).unwrap();
+                    }
+                }
+                generation
+            });
+            for n in 0..5 {
+                let dest = dir.0.join(format!("snapshot{}.db", n));
+                cxx::let_cxx_string!(dest_cxx = dest.to_string_lossy().as_ref());
+                assert_eq!(db.snapshot_to(&dest_cxx).unwrap(), paths.len() as u64);
+            }
+            stop.store(true, std::sync::atomic::Ordering::SeqCst);
+            writer.join().unwrap()
+        });
+
+        // Each clone opens cleanly and holds a prefix of one generation and the rest of the one before
+        for n in 0..5 {
+            let dest = dir.0.join(format!("snapshot{}.db", n));
+            assert!(!dir.0.join(format!("snapshot{}.db.partial", n)).exists());
+            let clone = StreamDb::open_path_with_options(&dest, &StreamDb::create_options()).unwrap();
+            assert!(clone.verify_db(true).unwrap().index_ok);
+            let captured: Vec<u64> = paths.iter().map(|path| {
+                let data = clone.read_document(path).unwrap();
+                let header = data.iter().position(|&b| b == b':').unwrap();
+                let generation: u64 = std::str::from_utf8(&data[..header]).unwrap().parse().unwrap();
+                assert!(data == contents(path, generation), "snapshot {} of {}", n, path);
+                generation
+            }).collect();
+            assert!(captured.windows(2).all(|pair| pair[0] >= pair[1]), "snapshot {}: {:?}", n, captured);
+            assert!(captured[0] - captured[captured.len() - 1] <= 1, "snapshot {}: {:?}", n, captured);
+            assert_eq!(clone.get_db_stats().document_count, paths.len() as u64);
+        }
+        assert_eq!(db.get_db_stats().pinned_chains, 0);
+        for path in &paths {
+            assert!(db.read_document(path).unwrap() == contents(path, generations));
+        }
+    }
+
+    #[test]
+    fn a_crash_right_after_a_checkpoint_leaves_nothing_to_recover() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().durable_writes(false));
+        let paths: Vec<String> = (0..12).map(|i| format!("maps/game/area{}.bin", i)).collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        write_paths(&db, &paths);
+        let stats = std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        for path in &paths {
+                            assert_eq!(db.read_document(path).unwrap(), path.as_bytes());
+                        }
+                    }
+                });
+            }
+            db.checkpoint().unwrap()
+        });
+        assert!(stats.pages_flushed > 0);
+        assert_eq!(stats.log_bytes_reclaimed, 0);
+        let again = db.checkpoint().unwrap();
+        assert_eq!((again.pages_flushed, again.log_bytes_reclaimed), (0, 0));
+
+        // Killed as checkpoint returns: a reader, which skips recovery, finds every write in place
+        let image = crash_image(&dir);
+        let reader = open(&image, StreamDb::create_options().read_only(true));
+        assert_eq!(reader.index_log_root.read().page_id, -1);
+        for path in &paths {
+            assert_eq!(reader.read_document(path).unwrap(), path.as_bytes());
+        }
+        let report = reader.verify_db(true).unwrap();
+        assert!(report.index_ok && report.document_count_ok);
+        assert!(report.corrupt_pages.is_empty() && report.trie.violations.is_empty());
+        assert_eq!(report.paths_restored, 0);
+    }
+
+    #[test]
+    fn dropping_closing_twice_and_using_a_closed_database_are_safe() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        write_paths(&db, &["maps/game/kept.bin"]);
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        stage(&mut db, tx, "maps/game/staged.bin", "staged");
+        cxx::let_cxx_string!(kept = "maps/game/kept.bin");
+        let stream = db.start_stream(&kept).unwrap();
+
+        // Dropped without close_db: writes reach the file, the lock is let go and the transaction aborted
+        drop(db);
+        let mut db = open(&dir, StreamDb::create_options());
+        assert_eq!(db.read_document("maps/game/kept.bin").unwrap(), b"maps/game/kept.bin");
+        assert_eq!(resolves(&db, "maps/game/staged.bin"), None);
+        assert!(db.get_stream_stats(stream).is_err());
+
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        let stream = db.start_stream(&kept).unwrap();
+        Pin::new(&mut db).close_db();
+        Pin::new(&mut db).close_db();
+        assert_eq!(db.get_db_stats().open_streams, 0);
+        let closed = |result: io::Result<()>| result.unwrap_err().to_string() == "Database is closed";
+        assert!(closed(db.read_document("maps/game/kept.bin").map(drop)));
+        assert!(closed(db.get(&kept).map(drop)));
+        assert!(closed(db.write_document_unordered("maps/game/late.bin", b"late", true, false, false).map(drop)));
+        assert!(closed(db.stream_chunk(stream).map(drop)));
+        assert!(closed(Pin::new(&mut db).commit_transaction(tx)));
+        assert!(closed(db.checkpoint().map(drop)));
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(resolves(&db, "maps/game/late.bin"), None);
+        assert_eq!(db.read_document("maps/game/kept.bin").unwrap(), b"maps/game/kept.bin");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn a_database_past_max_path_opens_and_takes_simultaneous_readers() {
+        let dir = TempDir::new();
+        let mut deep = dir.0.clone();
+        while deep.to_string_lossy().len() < 280 {
+            deep.push("a_rather_deep_mod_directory");
+        }
+        std::fs::create_dir_all(StreamDb::db_path(deep.to_string_lossy().as_ref())).unwrap();
+        let path = deep.join("game.streamdb");
+        assert!(path.to_string_lossy().len() >= 300);
+        let db = StreamDb::open_path_with_options(&path, &StreamDb::create_options()).unwrap();
+        write_paths(&db, &["maps/game/deep.bin"]);
+        drop(db);
+
+        let readers: Vec<StreamDb> = (0..2)
+            .map(|_| StreamDb::open_path_with_options(&path, &StreamDb::create_options().read_only(true)).unwrap())
+            .collect();
+        for reader in &readers {
+            assert_eq!(reader.read_document("maps/game/deep.bin").unwrap(), b"maps/game/deep.bin");
+        }
+        // Other handles may still read the file while it is open
+        assert!(std::fs::read(StreamDb::db_path(path.to_string_lossy().as_ref())).is_ok());
+    }
+
+    #[test]
+    fn decompression_bombs_read_through_pages_are_refused() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let page_id = db.allocate_page().unwrap();
+        // Stored as is, then relabelled compressed; the CRC still matches what is stored
+        let plant = |payload: &[u8], codec: u8| {
+            assert!(payload.len() <= capacity);
+            let mut header = db.write_raw_page_as(page_id, payload, 1, PageLinks::single(FLAG_DATA_PAGE), CODEC_NONE, 0, 0).unwrap();
+            header.flags |= FLAG_COMPRESSED;
+            header.padding = [codec, 0, 0];
+            db.write_page_header(page_id, &header).unwrap();
+            db.invalidate_page(page_id);
+        };
+        let zeros = vec![0u8; capacity * 8];
+        let mut declared_huge = snappy::compress(b"maps/e1m1.map");
+        declared_huge.splice(..1, [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        let bombs = [
+            (snappy::compress(&zeros), CODEC_SNAPPY),
+            (declared_huge, CODEC_SNAPPY),
+            (zstd::bulk::compress(&vec![0u8; 16 << 20], 3).unwrap(), CODEC_ZSTD),
+        ];
+        for (bomb, codec) in bombs {
+            plant(&bomb, codec);
+            let error = db.read_raw_page(page_id).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().starts_with("Corrupt compressed page"), "{}", error);
+        }
+
+        // The same page holding what fits decompresses as before
+        plant(&snappy::compress(&zeros[..capacity]), CODEC_SNAPPY);
+        assert_eq!(db.read_raw_page(page_id).unwrap(), zeros[..capacity]);
+    }
+
+    #[test]
+    fn creation_metadata_and_feature_flags_round_trip() {
+        let dir = TempDir::new();
+        let policy = ffi::PathPolicy { normalize: true, ..ffi::PathPolicy::default() };
+        let options = || StreamDb::create_options()
+            .creator("dhewm3 1.5.3 packer")
+            .compression(ffi::PageCodec::Zstd, 3)
+            .segmented(0)
+            .path_policy(policy.clone());
+        let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let db = open(&dir, options());
+        let after = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let info = db.get_db_info().unwrap();
+        assert_eq!(info.format_version, FORMAT_VERSION);
+        assert!((before..=after).contains(&info.created_unix_secs));
+        assert_eq!(info.creator, "dhewm3 1.5.3 packer");
+        assert_eq!(info.page_size, PAGE_SIZE as u32);
+        assert_eq!((info.codec.as_str(), info.checksum_algorithm.as_str()), ("zstd", "crc32"));
+        assert_eq!((info.critical_features, info.optional_features), (FEATURE_SEGMENTED, FEATURE_NORMALIZED_PATHS));
+        drop(db);
+        // Reopening under other options keeps what creation recorded
+        let db = open(&dir, StreamDb::create_options().creator("someone else").path_policy(policy.clone()));
+        let reopened = db.get_db_info().unwrap();
+        assert_eq!(reopened.created_unix_secs, info.created_unix_secs);
+        assert_eq!(reopened.creator, info.creator);
+        assert_eq!(reopened.page_size, info.page_size);
+        assert_eq!((reopened.codec, reopened.checksum_algorithm), (info.codec, info.checksum_algorithm));
+        assert_eq!((reopened.critical_features, reopened.optional_features), (info.critical_features, info.optional_features));
+        drop(db);
+
+        let plain = TempDir::new();
+        drop(open(&plain, StreamDb::create_options()));
+        let set_features = |offset: u64, features: u32| {
+            let mut file = OpenOptions::new().write(true).open(plain.db()).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&features.to_le_bytes()).unwrap();
+        };
+        const CRITICAL_OFFSET: u64 = EXTENDED_HEADER_OFFSET as u64 + 46;
+        set_features(CRITICAL_OFFSET + 4, 0x8000_0000);
+        assert_eq!(open(&plain, StreamDb::create_options()).get_db_info().unwrap().optional_features, 0x8000_0000);
+        set_features(CRITICAL_OFFSET, 0x8);
+        let error = open_error(&plain, StreamDb::create_options());
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(error.to_string(), "Database requires unsupported features 0x8");
+    }
+
+    #[test]
+    fn each_open_option_takes_effect() {
+        let config: Vec<u8> = b"seta r_mode 5\n".repeat(200);
+        let first_page = |db: &StreamDb, path: &str| {
+            let doc = db.lookup_document(&resolves(db, path).unwrap()).unwrap().unwrap();
+            db.read_page_header(doc.first_page_id).unwrap()
+        };
+
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        db.write_document_unordered("default.cfg", &config, true, false, false).unwrap();
+        let header = first_page(&db, "default.cfg");
+        assert!(header.flags & FLAG_COMPRESSED != 0 && header.padding[0] == CODEC_SNAPPY);
+        assert!(mapped_len(&db).is_some());
+        drop(db);
+
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().use_compression(false).io_mode(ffi::IoMode::FileOnly).quick_mode(true));
+        db.write_document_unordered("default.cfg", &config, true, false, false).unwrap();
+        assert_eq!(first_page(&db, "default.cfg").flags & FLAG_COMPRESSED, 0);
+        assert!(mapped_len(&db).is_none());
+        assert!(db.quick_mode.load(std::sync::atomic::Ordering::SeqCst));
+        drop(db);
+
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().compression(ffi::PageCodec::Zstd, 9).cache_sizes(2, 8).durable_writes(false));
+        db.write_document_unordered("default.cfg", &config, true, false, false).unwrap();
+        assert_eq!(first_page(&db, "default.cfg").padding[0], CODEC_ZSTD);
+        assert_eq!((db.config.compression_level, db.config.page_cache_size, db.config.path_cache_size), (9, 2, 8));
+        assert!(!db.config.durable_writes);
+        drop(db);
+        let db = open(&dir, StreamDb::create_options().read_only(true));
+        assert_eq!(db.read_document("default.cfg").unwrap(), config);
+        let error = db.write_document_unordered("autoexec.cfg", &config, true, false, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        drop(db);
+
+        // Invalid combinations are refused before the file is touched
+        let dir = TempDir::new();
+        assert_eq!(open_error(&dir, StreamDb::create_options().cache_sizes(0, 8)).kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(open_error(&dir, StreamDb::create_options().compression(ffi::PageCodec::Snappy, 3)).kind(), io::ErrorKind::InvalidInput);
+        assert!(!dir.db().exists());
+
+        // open_db is a thin wrapper over the same options
+        cxx::let_cxx_string!(path = dir.db().to_string_lossy().as_ref());
+        let db = StreamDb::open_db(&path, false, true).unwrap();
+        assert!(!db.config.use_compression);
+        assert!(db.quick_mode.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn every_runtime_tunable_can_be_set_and_takes_effect() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let tunables = db.list_tunables();
+        let names: HashSet<&str> = tunables.iter().map(|tunable| tunable.name.as_str()).collect();
+        assert_eq!(names.len(), tunables.len());
+        for name in ["page_cache_size", "quick_mode", "durable_writes", "page_size", "compression", "slow_read_ms"] {
+            assert!(names.contains(name), "{}", name);
+        }
+        for tunable in &tunables {
+            assert_eq!(tunable.current, tunable.default_value, "{}", tunable.name);
+            let value = match (tunable.kind.as_str(), tunable.name.as_str()) {
+                ("bool", _) => if tunable.current == "1" { "0".to_string() } else { "1".to_string() },
+                (_, "page_cache_size") => (tunable.current.parse::<usize>().unwrap() + PAGE_CACHE_SHARDS).to_string(),
+                (_, "compression_level") => "0".to_string(),
+                (_, name) if name.ends_with("_percent") => "50".to_string(),
+                _ => (tunable.current.parse::<u64>().unwrap() + 1).to_string(),
+            };
+            cxx::let_cxx_string!(name = tunable.name.as_str());
+            cxx::let_cxx_string!(value_cxx = value.as_str());
+            let result = Pin::new(&mut db).set_tunable(&name, &value_cxx);
+            if !tunable.runtime {
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported, "{}", tunable.name);
+                continue;
+            }
+            result.unwrap();
+            let now = db.list_tunables().into_iter().find(|now| now.name == tunable.name).unwrap();
+            assert_eq!(now.current, value, "{}", tunable.name);
+        }
+        cxx::let_cxx_string!(unknown = "page_prefetch_depth");
+        cxx::let_cxx_string!(one = "1");
+        assert_eq!(Pin::new(&mut db).set_tunable(&unknown, &one).unwrap_err().kind(), io::ErrorKind::NotFound);
+        cxx::let_cxx_string!(quick_mode = "quick_mode");
+        cxx::let_cxx_string!(maybe = "maybe");
+        assert_eq!(Pin::new(&mut db).set_tunable(&quick_mode, &maybe).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // Cache capacity changes without a reopen: a cache of one page per shard cannot hold a second pass
+        let paths: Vec<String> = (0..40).map(|i| format!("maps/game/area{}.bin", i)).collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        write_paths(&db, &paths);
+        let second_pass_misses = |db: &StreamDb| {
+            db.clear_page_cache();
+            for pass in 0..2 {
+                let misses = db.cache_stats.lock().misses;
+                for path in &paths {
+                    db.read_document(path).unwrap();
+                }
+                if pass == 1 {
+                    return db.cache_stats.lock().misses - misses;
+                }
+            }
+            unreachable!()
+        };
+        for (size, evicting) in [(PAGE_CACHE_SHARDS, true), (4096, false)] {
+            cxx::let_cxx_string!(name = "page_cache_size");
+            cxx::let_cxx_string!(value = size.to_string());
+            Pin::new(&mut db).set_tunable(&name, &value).unwrap();
+            assert_eq!(db.page_cache_capacity(), size);
+            let misses = second_pass_misses(&db);
+            assert_eq!(misses >= 20, evicting, "{} misses at {} pages", misses, size);
+            if !evicting {
+                assert_eq!(misses, 0);
+            }
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn a_slowed_page_read_lands_in_the_latency_tail() {
+        let dir = TempDir::new();
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let db = StreamDb::open_with_faults(&dir.db(), false, schedule.clone()).unwrap();
+        let paths: Vec<String> = (0..400).map(|i| format!("maps/game/area{}.bin", i)).collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        write_paths(&db, &paths);
+        db.clear_page_cache();
+        for path in &paths {
+            db.read_document(path).unwrap();
+        }
+        // Off: nothing was recorded
+        let report = db.get_latency_report();
+        assert_eq!((report.page_reads.count, report.document_gets.count, report.writes.count), (0, 0, 0));
+
+        db.set_latency_tracking(true);
+        db.clear_page_cache();
+        for path in &paths {
+            db.read_document(path).unwrap();
+        }
+        schedule.lock().read_delay_ms = 40;
+        db.clear_page_cache();
+        db.read_document(paths[0]).unwrap();
+        schedule.lock().read_delay_ms = 0;
+        let report = db.get_latency_report();
+        for (name, summary) in [("page reads", &report.page_reads), ("document gets", &report.document_gets)] {
+            assert!(summary.count > paths.len() as u64, "{}: {:?}", name, summary);
+            assert!(summary.max_us >= 40_000, "{}: {:?}", name, summary);
+            assert!(summary.p50_us < 40_000 && summary.p99_us < 40_000, "{}: {:?}", name, summary);
+        }
+
+        db.reset_latency_stats();
+        let report = db.get_latency_report();
+        assert_eq!((report.page_reads.count, report.page_reads.max_us, report.document_gets.max_us), (0, 0, 0));
+    }
+
+    #[test]
+    fn drained_events_follow_a_mixed_operation_sequence() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        db.write_document_unordered("maps/game/unrecorded.bin", b"before", true, false, false).unwrap();
+        db.set_event_recording(true);
+        let e1m1 = db.write_document_unordered("maps/e1m1.bin", b"one", true, false, false).unwrap();
+        let e1m2 = db.write_document_unordered("maps/e1m2.bin", b"two", true, false, false).unwrap();
+        let rewritten = db.write_document_unordered("maps/e1m1.bin", b"one again", true, false, false).unwrap();
+        cxx::let_cxx_string!(e1m1_path = "maps/e1m1.bin");
+        cxx::let_cxx_string!(e1m2_path = "maps/e1m2.bin");
+        cxx::let_cxx_string!(alias = "maps/start.bin");
+        cxx::let_cxx_string!(renamed = "maps/e1m3.bin");
+        Pin::new(&mut db).add_path(&e1m1_path, &alias, false).unwrap();
+        Pin::new(&mut db).rename_path(&e1m2_path, &renamed).unwrap();
+        Pin::new(&mut db).remove_path(&alias, false).unwrap();
+        Pin::new(&mut db).delete_by_path(&e1m1_path).unwrap();
+
+        type Op = ffi::DocumentEventOp;
+        let expected = [
+            (Op::Write, "maps/e1m1.bin", e1m1),
+            (Op::Write, "maps/e1m2.bin", e1m2),
+            (Op::Write, "maps/e1m1.bin", rewritten),
+            (Op::Bind, "maps/start.bin", rewritten),
+            (Op::Unbind, "maps/e1m2.bin", e1m2),
+            (Op::Bind, "maps/e1m3.bin", e1m2),
+            (Op::Unbind, "maps/start.bin", rewritten),
+            (Op::Delete, "maps/e1m1.bin", rewritten),
+        ];
+        let batch = db.drain_events();
+        assert_eq!(batch.dropped, 0);
+        let drained: Vec<(Op, &str, String)> = batch.events.iter()
+            .map(|event| (event.op, event.path.as_str(), event.uuid.clone()))
+            .collect();
+        let expected: Vec<(Op, &str, String)> = expected.iter()
+            .map(|(op, path, id)| (*op, *path, id.to_string()))
+            .collect();
+        assert_eq!(drained, expected);
+        assert!(db.drain_events().events.is_empty());
+
+        // The queue keeps the newest events and counts what it dropped
+        for i in 0..EVENT_QUEUE_CAPACITY + 5 {
+            db.emit_event(Op::Write, &format!("maps/flood{}.bin", i), e1m2);
+        }
+        let batch = db.drain_events();
+        assert_eq!((batch.events.len(), batch.dropped), (EVENT_QUEUE_CAPACITY, 5));
+        assert_eq!(batch.events[0].path, "maps/flood5.bin");
+
+        db.set_event_recording(false);
+        db.write_document_unordered("maps/e1m4.bin", b"four", true, false, false).unwrap();
+        let batch = db.drain_events();
+        assert!(batch.events.is_empty() && batch.dropped == 0);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn slow_reads_warn_once_per_interval() {
+        let dir = TempDir::new();
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let mut db = StreamDb::open_with_faults(&dir.db(), false, schedule.clone()).unwrap();
+        let paths = ["maps/e1m1.bin", "maps/e1m2.bin", "maps/e1m3.bin", "maps/e1m4.bin", "maps/e1m5.bin"];
+        write_paths(&db, &paths);
+        *db.latency.captured_warnings.lock() = Some(Vec::new());
+        // Only page reads warn, past 10ms
+        for (tunable, threshold_ms) in [("slow_read_ms", "10"), ("slow_get_ms", "0"), ("slow_write_ms", "0"), ("slow_flush_ms", "0")] {
+            cxx::let_cxx_string!(name = tunable);
+            cxx::let_cxx_string!(value = threshold_ms);
+            Pin::new(&mut db).set_tunable(&name, &value).unwrap();
+        }
+        db.clear_page_cache();
+        for path in paths {
+            db.read_document(path).unwrap();
+        }
+        assert_eq!(db.latency.captured_warnings.lock().as_ref().unwrap().len(), 0);
+
+        schedule.lock().read_delay_ms = 25;
+        db.clear_page_cache();
+        for path in paths {
+            db.read_document(path).unwrap();
+        }
+        schedule.lock().read_delay_ms = 0;
+        let warnings = db.latency.captured_warnings.lock().take().unwrap();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].starts_with("StreamDB: slow page read of page "), "{}", warnings[0]);
+        assert!(warnings[0].ends_with("(threshold 10ms)\n"), "{}", warnings[0]);
+        let suppressed = db.latency.slow_suppressed[TimedOp::PageRead as usize].load(std::sync::atomic::Ordering::Relaxed);
+        assert!(suppressed >= paths.len() as u64 - 1, "{} suppressed", suppressed);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_spans_nest_under_the_operation_that_opened_them() {
+        // A minimal subscriber: every span with its parent and fields, in creation order
+        #[derive(Clone, Default)]
+        struct Recorder {
+            spans: Arc<Mutex<Vec<(String, Option<usize>, String)>>>,
+            entered: Arc<Mutex<Vec<usize>>>,
+        }
+
+        struct Fields<'a>(&'a mut String);
+
+        impl tracing::field::Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0.push_str(&format!("{}={:?} ", field.name(), value));
+            }
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, attributes: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+                let parent = match attributes.parent() {
+                    Some(parent) => Some(parent.into_u64() as usize - 1),
+                    None if attributes.is_contextual() => self.entered.lock().unwrap().last().copied(),
+                    None => None,
+                };
+                let mut fields = String::new();
+                attributes.record(&mut Fields(&mut fields));
+                let mut spans = self.spans.lock().unwrap();
+                spans.push((attributes.metadata().name().to_string(), parent, fields));
+                tracing::span::Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+            fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+            fn event(&self, _: &tracing::Event<'_>) {}
+            fn enter(&self, span: &tracing::span::Id) {
+                self.entered.lock().unwrap().push(span.into_u64() as usize - 1);
+            }
+            fn exit(&self, _: &tracing::span::Id) {
+                self.entered.lock().unwrap().pop();
+            }
+        }
+
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let map = b"{ \"classname\" \"worldspawn\" }\n".repeat(capacity / 10);
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            db.write_document_unordered("maps/e1m1.map", &map, true, false, false).unwrap();
+            db.clear_page_cache();
+            db.clear_path_cache();
+            assert_eq!(db.read_document("maps/e1m1.map").unwrap(), map);
+            assert!(db.read_document("maps/missing.map").is_err());
+            let tx = Pin::new(&mut db).begin_transaction().unwrap();
+            stage(&mut db, tx, "maps/e1m2.map", "{ }");
+            Pin::new(&mut db).commit_transaction(tx).unwrap();
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        let named = |name: &str| -> Vec<usize> { (0..spans.len()).filter(|&i| spans[i].0 == name).collect() };
+        let children = |parent: usize, name: &str| -> Vec<usize> {
+            named(name).into_iter().filter(|&i| spans[i].1 == Some(parent)).collect()
+        };
+        let write = named("write_document").into_iter().find(|&i| spans[i].2.contains("path=\"maps/e1m1.map\"")).unwrap();
+        assert_eq!(spans[write].1, None);
+        assert!(spans[write].2.contains(&format!("bytes={}", map.len())));
+        let pages = children(write, "write_page");
+        assert!(pages.len() >= 2, "{:?}", *spans);
+        assert!(pages.iter().all(|&page| spans[page].2.contains("page_id=")));
+        assert!(pages.iter().filter(|&&page| children(page, "compress").len() == 1).count() >= 2);
+
+        let get = named("get_document").into_iter().find(|&i| spans[i].2.contains("path=\"maps/e1m1.map\"")).unwrap();
+        let reads = children(get, "read_page");
+        assert!(reads.iter().filter(|&&read| spans[read].2.contains("cache_hit=false")).count() >= 2);
+        assert!(reads.iter().any(|&read| children(read, "decompress").len() == 1));
+        let missing = named("get_document").into_iter().find(|&i| spans[i].2.contains("path=\"maps/missing.map\"")).unwrap();
+        assert_eq!(children(missing, "trie_lookup").len(), 1);
+
+        let commit = named("commit_transaction");
+        assert_eq!(commit.len(), 1);
+        assert!(spans[commit[0]].2.contains("documents=1"));
+        assert!(!children(commit[0], "write_page").is_empty());
+    }
+
+    #[test]
+    fn fifty_copies_of_a_payload_are_stored_once_and_deleted_independently() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let payload: Vec<u8> = (0..1 << 20).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        let paths: Vec<String> = (0..50).map(|i| format!("textures/map{}/wall.bin", i)).collect();
+        let pages_before = db.page_count();
+        let ids: Vec<Uuid> = paths.iter().map(|path| {
+            cxx::let_cxx_string!(path = path.as_str());
+            Pin::new(&mut db).write_document_with_dedup(&path, &cxx::CxxVector::from(payload.clone()), true, true).unwrap()
+        }).collect();
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), paths.len());
+        let docs: Vec<Document> = ids.iter().map(|id| db.lookup_document(id).unwrap().unwrap()).collect();
+        let shared = docs[0].first_page_id;
+        assert!(docs.iter().all(|doc| doc.first_page_id == shared));
+        let stored = db.chain_stored_bytes(shared).unwrap();
+        // Near 1x: the one chain plus index, trie and hash pages
+        let grown = ((db.page_count() - pages_before) as u64) * db.config.page_size;
+        assert!(grown < stored + stored / 10, "{} bytes grown for {} stored", grown, stored);
+        assert_eq!(db.get_db_stats().dedup_bytes_saved, stored * (paths.len() as u64 - 1));
+
+        // Opting out stores an independent copy
+        cxx::let_cxx_string!(copy = "textures/copy/wall.bin");
+        let copy = Pin::new(&mut db).write_document_with_dedup(&copy, &cxx::CxxVector::from(payload.clone()), true, false).unwrap();
+        assert_ne!(db.lookup_document(&copy).unwrap().unwrap().first_page_id, shared);
+
+        // The table survives a reopen
+        drop(db);
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        cxx::let_cxx_string!(late = "textures/late/wall.bin");
+        let late = Pin::new(&mut db).write_document_with_dedup(&late, &cxx::CxxVector::from(payload.clone()), true, true).unwrap();
+        assert_eq!(db.lookup_document(&late).unwrap().unwrap().first_page_id, shared);
+
+        // Deleting one path leaves every other copy readable; the chain goes with the last
+        for (i, path) in paths.iter().enumerate() {
+            cxx::let_cxx_string!(path_cxx = path.as_str());
+            Pin::new(&mut db).delete_by_path(&path_cxx).unwrap();
+            assert!(db.read_document(path).is_err());
+            for other in paths[i + 1..].iter().step_by(7) {
+                assert!(db.read_document(other).unwrap() == payload);
+            }
+            assert!(!db.free_list_pages(&db.lock_allocation()).unwrap().contains(&shared));
+        }
+        assert!(db.read_document("textures/late/wall.bin").unwrap() == payload);
+        Pin::new(&mut db).delete_by_path(&late).unwrap();
+        assert!(db.free_list_pages(&db.lock_allocation()).unwrap().contains(&shared));
+        assert!(db.read_document("textures/copy/wall.bin").unwrap() == payload);
+    }
+
+    #[test]
+    fn expiries_hide_and_purge_documents_and_survive_a_reopen() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let paths = ["cache/master/servers.bin", "cache/pak/fragment0.bin", "cache/pak/fragment1.bin", "cache/pak/keep.bin"];
+        write_paths(&db, &paths);
+        let now = StreamDb::unix_now();
+        for (path, expires_at) in [(paths[0], now - 60), (paths[1], now + 3600), (paths[2], now - 60)] {
+            cxx::let_cxx_string!(path = path);
+            Pin::new(&mut db).set_expiry(&path, expires_at).unwrap();
+        }
+        // A rewrite clears the expiry
+        write_paths(&db, &paths[2..3]);
+        let expiry = |db: &StreamDb, path: &str| db.lookup_document(&resolves(db, path).unwrap()).unwrap().unwrap().expires_at;
+        assert_eq!(expiry(&db, paths[2]), 0);
+        // Without hide_expired every document is still visible
+        for path in paths {
+            assert_eq!(db.read_document(path).unwrap(), path.as_bytes());
+        }
+        let expired_bytes = db.chain_stored_bytes(db.lookup_document(&resolves(&db, paths[0]).unwrap()).unwrap().unwrap().first_page_id).unwrap();
+        drop(db);
+
+        let mut db = open(&dir, StreamDb::create_options().hide_expired(true));
+        assert_eq!((expiry(&db, paths[1]), expiry(&db, paths[2])), (now + 3600, 0));
+        assert_eq!(db.read_document(paths[0]).unwrap_err().kind(), io::ErrorKind::NotFound);
+        for path in &paths[1..] {
+            assert_eq!(db.read_document(path).unwrap(), path.as_bytes());
+        }
+        let report = Pin::new(&mut db).purge_expired(now).unwrap();
+        assert_eq!((report.documents, report.bytes_reclaimed), (1, expired_bytes));
+        let report = Pin::new(&mut db).purge_expired(now).unwrap();
+        assert_eq!((report.documents, report.bytes_reclaimed), (0, 0));
+        drop(db);
+
+        let mut db = open(&dir, StreamDb::create_options());
+        assert_eq!(resolves(&db, paths[0]), None);
+        assert_eq!(Pin::new(&mut db).purge_expired(now + 7200).unwrap().documents, 1);
+        assert_eq!(resolves(&db, paths[1]), None);
+        for path in &paths[2..] {
+            assert_eq!(db.read_document(path).unwrap(), path.as_bytes());
+        }
+    }
+
+    #[test]
+    fn tags_answer_queries_across_reopen_rename_rewrite_and_delete() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        let paths: Vec<String> = (0..300).map(|i| format!("textures/site{}/wall{}.bin", i % 3, i)).collect();
+        write_paths(&db, &paths.iter().map(String::as_str).collect::<Vec<_>>());
+        let tags_of = |i: usize| {
+            let mut tags = vec![format!("map:site{}", i % 3), format!("quality:{}", if i % 2 == 0 { "low" } else { "high" })];
+            if i % 5 == 0 {
+                tags.push("generated".to_string());
+            }
+            tags
+        };
+        for (i, path) in paths.iter().enumerate() {
+            cxx::let_cxx_string!(path = path.as_str());
+            for tag in tags_of(i) {
+                cxx::let_cxx_string!(tag = tag.as_str());
+                Pin::new(&mut db).add_tag(&path, &tag).unwrap();
+                Pin::new(&mut db).add_tag(&path, &tag).unwrap();
+            }
+        }
+        let tagged = |db: &StreamDb, tag: &str| -> BTreeSet<String> {
+            cxx::let_cxx_string!(tag = tag);
+            db.find_by_tag(&tag).unwrap().into_iter().map(|info| info.path).collect()
+        };
+        let expected = |paths: &[String], tag: &str| -> BTreeSet<String> {
+            paths.iter().enumerate().filter(|(i, _)| tags_of(*i).iter().any(|t| t == tag)).map(|(_, path)| path.clone()).collect()
+        };
+        let queries = ["map:site0", "map:site1", "map:site2", "quality:low", "quality:high", "generated"];
+        for tag in queries {
+            assert_eq!(tagged(&db, tag), expected(&paths, tag), "{}", tag);
+        }
+        assert!(tagged(&db, "quality:medium").is_empty());
+        cxx::let_cxx_string!(first = paths[0].as_str());
+        let mut tags = db.get_tags(&first).unwrap();
+        tags.sort();
+        assert_eq!(tags, ["generated", "map:site0", "quality:low"]);
+
+        // Tags are capped in number and length
+        for n in 3..MAX_TAGS_PER_DOCUMENT {
+            cxx::let_cxx_string!(tag = format!("extra:{}", n));
+            Pin::new(&mut db).add_tag(&first, &tag).unwrap();
+        }
+        cxx::let_cxx_string!(one_more = "extra:last");
+        assert_eq!(Pin::new(&mut db).add_tag(&first, &one_more).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        cxx::let_cxx_string!(too_long = "x".repeat(MAX_TAG_LENGTH + 1));
+        cxx::let_cxx_string!(second = paths[1].as_str());
+        assert_eq!(Pin::new(&mut db).add_tag(&second, &too_long).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        for n in 3..MAX_TAGS_PER_DOCUMENT {
+            cxx::let_cxx_string!(tag = format!("extra:{}", n));
+            assert!(Pin::new(&mut db).remove_tag(&first, &tag).unwrap());
+            assert!(!Pin::new(&mut db).remove_tag(&first, &tag).unwrap());
+        }
+        drop(db);
+
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        for tag in queries {
+            assert_eq!(tagged(&db, tag), expected(&paths, tag), "{}", tag);
+        }
+        // A rename carries the tags along, as does a new version
+        let mut paths = paths;
+        cxx::let_cxx_string!(renamed = "textures/site0/renamed0.bin");
+        Pin::new(&mut db).rename_path(&first, &renamed).unwrap();
+        paths[0] = "textures/site0/renamed0.bin".to_string();
+        db.write_document_unordered(&paths[5], b"second version", true, false, false).unwrap();
+        for tag in queries {
+            assert_eq!(tagged(&db, tag), expected(&paths, tag), "{}", tag);
+        }
+
+        // Deleting documents removes them from the table
+        for path in paths.iter().step_by(10) {
+            cxx::let_cxx_string!(path = path.as_str());
+            Pin::new(&mut db).delete_by_path(&path).unwrap();
+        }
+        let remaining: Vec<String> = paths.iter().enumerate().map(|(i, path)| if i % 10 == 0 { String::new() } else { path.clone() }).collect();
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        for tag in queries {
+            let mut want = expected(&remaining, tag);
+            want.remove("");
+            assert_eq!(tagged(&db, tag), want, "{}", tag);
+        }
+        let tag_table = db.tag_table.read();
+        assert!(tag_table.values().all(|ids| ids.len() < 300 && !ids.is_empty()));
+        assert_eq!(tag_table.values().map(|ids| ids.len()).sum::<usize>(), (0..300).filter(|i| i % 10 != 0).map(|i| tags_of(i).len()).sum::<usize>());
+    }
+
+    #[test]
+    fn secondary_index_queries_agree_with_scanning_randomized_documents() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().durable_writes(false));
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut next = move |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        let write = |db: &StreamDb, i: usize, size: u64| {
+            db.write_document_unordered(&format!("maps/random/doc{}.bin", i), &vec![i as u8; size as usize], true, false, false).unwrap();
+        };
+        // Written before the size index exists, so creating it backfills them
+        for i in 0..200 {
+            write(&db, i, next(20_000));
+        }
+        Pin::new(&mut db).create_secondary_index(ffi::SecondaryIndexKind::Size).unwrap();
+        for i in 200..300 {
+            write(&db, i, next(20_000));
+        }
+        for i in (0..300).step_by(10) {
+            cxx::let_cxx_string!(path = format!("maps/random/doc{}.bin", i));
+            Pin::new(&mut db).delete_by_path(&path).unwrap();
+        }
+        for i in (5..300).step_by(10) {
+            write(&db, i, next(20_000));
+        }
+        let mut index = db.read_index().unwrap();
+        for doc in index.values_mut() {
+            doc.modified = 1_600_000_000 + next(1_000_000);
+        }
+        db.write_index(&index).unwrap();
+        Pin::new(&mut db).create_secondary_index(ffi::SecondaryIndexKind::Modified).unwrap();
+
+        let found = |infos: Vec<ffi::DocumentInfo>| -> Vec<String> { infos.into_iter().map(|info| info.uuid).collect() };
+        let scanned = |db: &StreamDb, key: &dyn Fn(&Document) -> u64, min: u64, max: u64| -> Vec<String> {
+            let mut matches: Vec<(u64, Uuid)> = db.read_index().unwrap().values()
+                .filter(|doc| (min..=max).contains(&key(doc)))
+                .map(|doc| (key(doc), doc.id))
+                .collect();
+            matches.sort_unstable();
+            matches.into_iter().map(|(_, id)| id.to_string()).collect()
+        };
+        let ranges: Vec<(u64, u64, u64)> = (0..50).map(|_| {
+            let min = next(20_000);
+            (min, min + next(8_000), 1_600_000_000 + next(1_000_000))
+        }).collect();
+        let check = |db: &StreamDb| {
+            for &(min, max, since) in &ranges {
+                assert_eq!(found(db.find_by_size_range(min, max).unwrap()), scanned(db, &|doc| doc.size, min, max), "{}..={}", min, max);
+                assert_eq!(found(db.find_modified_since(since).unwrap()), scanned(db, &|doc| doc.modified, since, u64::MAX), "since {}", since);
+            }
+            assert!(db.find_by_size_range(10, 5).unwrap().is_empty());
+        };
+        check(&db);
+        drop(db);
+
+        let mut db = open(&dir, StreamDb::create_options());
+        assert!(db.secondary.read().get(ffi::SecondaryIndexKind::Size).is_some());
+        assert!(db.secondary.read().get(ffi::SecondaryIndexKind::Modified).is_some());
+        check(&db);
+        // Without the indexes the same queries scan
+        Pin::new(&mut db).drop_secondary_index(ffi::SecondaryIndexKind::Size).unwrap();
+        Pin::new(&mut db).drop_secondary_index(ffi::SecondaryIndexKind::Modified).unwrap();
+        assert!(db.secondary.read().is_empty());
+        check(&db);
+    }
+
+    #[test]
+    fn read_only_documents_refuse_changes_unless_forced_and_precache_ones_are_listed() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let paths = ["maps/game/base.bin", "maps/game/site3.bin", "textures/base/wall.bin", "savegames/quick.bin"];
+        write_paths(&db, &paths);
+        for (path, flags) in [(paths[0], DOCUMENT_READONLY | DOCUMENT_PRECACHE), (paths[1], DOCUMENT_PRECACHE), (paths[2], DOCUMENT_READONLY)] {
+            cxx::let_cxx_string!(path = path);
+            Pin::new(&mut db).set_flags(&path, flags).unwrap();
+        }
+        cxx::let_cxx_string!(save = "savegames/quick.bin");
+        assert_eq!(Pin::new(&mut db).set_flags(&save, 0x80).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        drop(db);
+
+        let mut db = open(&dir, StreamDb::create_options());
+        assert_eq!(db.get_precache_list().unwrap(), ["maps/game/base.bin", "maps/game/site3.bin"]);
+        cxx::let_cxx_string!(base = "maps/game/base.bin");
+        cxx::let_cxx_string!(moved = "maps/game/moved.bin");
+        let read_only = |error: io::Error| error.kind() == io::ErrorKind::PermissionDenied && error.to_string() == "Document is read-only";
+        assert!(read_only(db.write_document_unordered(paths[0], b"patched", true, false, false).unwrap_err()));
+        assert!(read_only(Pin::new(&mut db).delete_by_path(&base).unwrap_err()));
+        assert!(read_only(Pin::new(&mut db).rename_path(&base, &moved).unwrap_err()));
+        assert_eq!(db.read_document(paths[0]).unwrap(), paths[0].as_bytes());
+        assert_eq!(resolves(&db, "maps/game/moved.bin"), None);
+        // Documents without the flag are unaffected
+        write_paths(&db, &paths[1..2]);
+        Pin::new(&mut db).delete_by_path(&save).unwrap();
+
+        // Forcing goes through, and a forced rewrite keeps the flags
+        Pin::new(&mut db).write_document_forced(&base, &cxx::CxxVector::from(b"patched".to_vec())).unwrap();
+        assert_eq!(db.read_document(paths[0]).unwrap(), b"patched");
+        let flags = db.lookup_document(&resolves(&db, paths[0]).unwrap()).unwrap().unwrap().flags;
+        assert_eq!(flags, DOCUMENT_READONLY | DOCUMENT_PRECACHE);
+        cxx::let_cxx_string!(wall = "textures/base/wall.bin");
+        Pin::new(&mut db).delete_by_path_ex(&wall, true).unwrap();
+        assert_eq!(resolves(&db, "textures/base/wall.bin"), None);
+
+        // Clearing the flag makes the document writable again
+        Pin::new(&mut db).set_flags(&base, DOCUMENT_PRECACHE).unwrap();
+        Pin::new(&mut db).rename_path(&base, &moved).unwrap();
+        assert_eq!(db.get_precache_list().unwrap(), ["maps/game/moved.bin", "maps/game/site3.bin"]);
+    }
+
+    #[test]
+    fn extension_rules_decide_which_pages_are_compressed() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let mut text = b"textures/base_wall/lfwall13f3 { qer_editorimage textures/base_wall/lfwall13f3_d }\n".repeat(capacity * 3 / 80 + 1);
+        text.truncate(capacity * 3);
+        let stored = |db: &StreamDb, path: &str| -> Vec<(bool, usize)> {
+            let first_page_id = db.lookup_document(&resolves(db, path).unwrap()).unwrap().unwrap().first_page_id;
+            chain_pages(db, first_page_id).into_iter().map(|page_id| {
+                let header = db.read_page_header(page_id).unwrap();
+                (header.flags & FLAG_COMPRESSED != 0, header.data_length as usize)
+            }).collect()
+        };
+        let compressed = |pages: &[(bool, usize)]| pages.iter().all(|&(compressed, length)| compressed && length < capacity / 2);
+        let raw = |pages: &[(bool, usize)]| pages.iter().all(|&(compressed, _)| !compressed)
+            && pages.iter().map(|&(_, length)| length).sum::<usize>() == text.len();
+
+        // The defaults compress .map and store .ogg as is, whatever the contents
+        for path in ["maps/e1m1.map", "sound/music/e1m1.ogg"] {
+            db.write_document_unordered(path, &text, true, false, false).unwrap();
+        }
+        assert!(compressed(&stored(&db, "maps/e1m1.map")));
+        assert!(raw(&stored(&db, "sound/music/e1m1.ogg")));
+        drop(db);
+
+        // Rules given at open replace the stored ones
+        let db = open(&dir, StreamDb::create_options()
+            .compression_rule("ogg", ffi::PageCodec::Snappy)
+            .compression_rule("map", ffi::PageCodec::None));
+        for path in ["maps/e1m2.map", "sound/music/e1m2.ogg"] {
+            db.write_document_unordered(path, &text, true, false, false).unwrap();
+        }
+        assert!(raw(&stored(&db, "maps/e1m2.map")));
+        assert!(compressed(&stored(&db, "sound/music/e1m2.ogg")));
+        drop(db);
+
+        // and persist; pages written under the old rules still read by their own flags
+        let mut db = open(&dir, StreamDb::create_options());
+        let rules: Vec<(String, ffi::PageCodec)> = db.get_compression_rules().into_iter().map(|rule| (rule.extension, rule.codec)).collect();
+        assert_eq!(rules.len(), 2);
+        assert!(rules.contains(&("ogg".to_string(), ffi::PageCodec::Snappy)) && rules.contains(&("map".to_string(), ffi::PageCodec::None)));
+        for path in ["maps/e1m1.map", "sound/music/e1m1.ogg", "maps/e1m2.map", "sound/music/e1m2.ogg"] {
+            assert!(db.read_document(path).unwrap() == text, "{}", path);
+        }
+        Pin::new(&mut db).set_compression_rules(&StreamDb::default_compression_rules()).unwrap();
+        db.write_document_unordered("maps/e1m3.map", &text, true, false, false).unwrap();
+        assert!(compressed(&stored(&db, "maps/e1m3.map")));
+    }
+
+
+    #[test]
+    fn compression_levels_trade_size_and_recompress_keeps_contents() {
+        let words = ["worldspawn", "func_static", "light", "origin", "model", "target", "info_player_start", "\"", "{", "}", "\n", " ", "0", "128", "-64", "_color"];
+        let mut rng = Xorshift(0x9e37_79b9_7f4a_7c15);
+        let text: String = (0..80_000).map(|_| rng.pick(&words)).collect();
+        let levels = |db: &StreamDb, path: &str| -> Vec<u8> {
+            let first_page_id = db.lookup_document(&resolves(db, path).unwrap()).unwrap().unwrap().first_page_id;
+            chain_pages(db, first_page_id).into_iter().map(|page_id| {
+                let header = db.read_page_header(page_id).unwrap();
+                assert!(header.flags & FLAG_COMPRESSED != 0 && header.padding[0] == CODEC_ZSTD);
+                header.padding[1]
+            }).collect()
+        };
+        cxx::let_cxx_string!(path = "maps/e1m1.map");
+
+        // The same contents at levels 1 and 19: the higher level is recorded per page and stores less
+        let mut sizes = Vec::new();
+        let mut dirs = Vec::new();
+        for level in [1, 19] {
+            let dir = TempDir::new();
+            let db = open(&dir, StreamDb::create_options()
+                .compression(ffi::PageCodec::Zstd, level)
+                .compression_rule("map", ffi::PageCodec::Zstd));
+            db.write_document_unordered("maps/e1m1.map", text.as_bytes(), true, false, false).unwrap();
+            assert!(levels(&db, "maps/e1m1.map").iter().all(|&recorded| recorded as i32 == level));
+            sizes.push(db.get_physical_size(&path).unwrap());
+            drop(db);
+            dirs.push(dir);
+        }
+        assert!(sizes[1] < sizes[0], "level 19 stored {} bytes, level 1 stored {}", sizes[1], sizes[0]);
+
+        // Recompressing the level 1 copy at 19 shrinks it without touching its contents
+        let mut db = open(&dirs[0], StreamDb::create_options());
+        let version = db.lookup_document(&resolves(&db, "maps/e1m1.map").unwrap()).unwrap().unwrap().current_version;
+        let stored = Pin::new(&mut db).recompress(&path, 19).unwrap();
+        let physical = db.get_physical_size(&path).unwrap();
+        assert!(physical < sizes[0] && stored < physical);
+        assert!(levels(&db, "maps/e1m1.map").iter().all(|&recorded| recorded == 19));
+        assert!(db.read_document("maps/e1m1.map").unwrap() == text.as_bytes());
+        assert_eq!(db.lookup_document(&resolves(&db, "maps/e1m1.map").unwrap()).unwrap().unwrap().current_version, version);
+        drop(db);
+        let mut db = open(&dirs[0], StreamDb::create_options());
+        assert!(db.read_document("maps/e1m1.map").unwrap() == text.as_bytes());
+
+        // Levels are checked against the codec the path's rule selects
+        for level in [23, 1000] {
+            assert_eq!(Pin::new(&mut db).recompress(&path, level).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        db.write_document_unordered("sound/music/e1m1.ogg", text.as_bytes(), true, false, false).unwrap();
+        cxx::let_cxx_string!(raw = "sound/music/e1m1.ogg");
+        assert_eq!(Pin::new(&mut db).recompress(&raw, 19).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(Pin::new(&mut db).recompress(&raw, 0).unwrap(), text.len() as u64);
+    }
+
+
+    #[test]
+    fn a_trained_dictionary_shrinks_small_documents_and_is_required_to_read_them() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().compression_rule("def", ffi::PageCodec::Zstd));
+        let mut rng = Xorshift(0x2545_F491_4F6C_DD1D);
+        let monsters = ["zombie_fat", "zombie_maint", "imp", "hellknight", "maggot", "cacodemon", "lost_soul", "revenant"];
+        let digits = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        let mut entity_def = |n: usize| -> Vec<u8> {
+            let monster = rng.pick(&monsters);
+            let mut number = || [rng.pick(&digits), rng.pick(&digits), rng.pick(&digits)].concat();
+            format!("entityDef monster_{monster}_{n} {{\n\t\"inherit\"\t\t\t\"monster_{monster}_default\"\n\t\"model\"\t\t\t\"models/md5/monsters/{monster}/{monster}.md5mesh\"\n\t\"health\"\t\t\t\"{}\"\n\t\"melee_range\"\t\t\"{}\"\n\t\"size\"\t\t\t\"{} {} {}\"\n\t\"snd_sight\"\t\t\"{monster}_sight\"\n\t\"snd_pain\"\t\t\"{monster}_pain\"\n\t\"def_projectile\"\t\"projectile_{monster}\"\n\t\"ragdoll\"\t\t\"{monster}\"\n}}\n",
+                number(), number(), number(), number(), number()).into_bytes()
+        };
+        let corpus: Vec<Vec<u8>> = (0..400).map(&mut entity_def).collect();
+        let stored = |db: &StreamDb, paths: &[String]| -> u64 {
+            paths.iter().map(|path| {
+                cxx::let_cxx_string!(path = path.as_str());
+                db.get_physical_size(&path).unwrap()
+            }).sum()
+        };
+        let dictionaries = |db: &StreamDb, path: &str| -> Vec<u8> {
+            let first_page_id = db.lookup_document(&resolves(db, path).unwrap()).unwrap().unwrap().first_page_id;
+            chain_pages(db, first_page_id).into_iter().map(|page_id| db.read_page_header(page_id).unwrap().padding[2]).collect()
+        };
+
+        // With no dictionary yet each file is compressed on its own
+        let before: Vec<String> = (300..400).map(|n| format!("def/before/{}.def", n)).collect();
+        for (path, data) in before.iter().zip(&corpus[300..]) {
+            db.write_document_unordered(path, data, true, false, false).unwrap();
+            assert_eq!(dictionaries(&db, path), [0]);
+        }
+        for (n, data) in corpus[..300].iter().enumerate() {
+            db.write_document_unordered(&format!("def/train/{}.def", n), data, true, false, false).unwrap();
+        }
+        cxx::let_cxx_string!(prefix = "def/train/");
+        let id = Pin::new(&mut db).train_dictionary(&prefix, 16 * 1024).unwrap();
+        assert_eq!(id, 1);
+
+        // The same contents written after training use it and store measurably less
+        let after: Vec<String> = (300..400).map(|n| format!("def/after/{}.def", n)).collect();
+        for (path, data) in after.iter().zip(&corpus[300..]) {
+            db.write_document_unordered(path, data, true, false, false).unwrap();
+            assert_eq!(dictionaries(&db, path), [id]);
+        }
+        let (without, with) = (stored(&db, &before), stored(&db, &after));
+        assert!(with * 4 < without * 3, "{} bytes with the dictionary, {} without", with, without);
+
+        // Documents at or past the threshold do not
+        let large = corpus.concat();
+        db.write_document_unordered("def/all.def", &large, true, false, false).unwrap();
+        assert!(dictionaries(&db, "def/all.def").iter().all(|&dictionary| dictionary == 0));
+        drop(db);
+
+        // The dictionary is a stored document and is loaded again on open
+        let mut db = open(&dir, StreamDb::create_options());
+        for (path, data) in after.iter().chain(&before).zip(corpus[300..].iter().chain(&corpus[300..])) {
+            assert!(db.read_document(path).unwrap() == *data, "{}", path);
+        }
+        let report = db.verify_db(true).unwrap();
+        assert!(report.missing_dictionaries.is_empty() && report.corrupt_pages.is_empty());
+        drop(db);
+
+        // Without it the pages that reference it refuse to decode and verify_db names it
+        let mut db = open(&dir, StreamDb::create_options());
+        let dictionary_path = format!("{}{}", DICTIONARY_PATH_PREFIX, id);
+        cxx::let_cxx_string!(dictionary_cxx = dictionary_path.as_str());
+        Pin::new(&mut db).delete_by_path_ex(&dictionary_cxx, false).unwrap_err();
+        Pin::new(&mut db).delete_by_path_ex(&dictionary_cxx, true).unwrap();
+        for path in &after {
+            db.read_document(path).unwrap_err();
+        }
+        for (path, data) in before.iter().zip(&corpus[300..]) {
+            assert!(db.read_document(path).unwrap() == *data, "{}", path);
+        }
+        assert!(db.read_document("def/all.def").unwrap() == large);
+        let report = db.verify_db(true).unwrap();
+        assert_eq!(report.missing_dictionaries, [id]);
+        assert!(report.corrupt_pages.is_empty());
+    }
+
+
+    #[test]
+    fn garbage_compressed_pages_read_as_corrupt_with_their_owner() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().compression_rule("def", ffi::PageCodec::Zstd));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let words = ["entityDef", "monster_imp", "\"inherit\"", "\"model\"", "{", "}", "\n\t", " ", "128", "-64", "\"health\""];
+        let mut rng = Xorshift(0xD1B5_4A32_D192_ED03);
+        let text: String = (0..capacity * 4).map(|_| rng.pick(&words)).collect();
+        let mut state = 0x8BB8_4B93_962E_ACC9u64;
+        let mut random = |length: usize| -> Vec<u8> {
+            (0..length).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).collect()
+        };
+
+        for (path, codec) in [("maps/e1m1.map", CODEC_SNAPPY), ("def/monsters.def", CODEC_ZSTD)] {
+            db.write_document_unordered(path, text.as_bytes(), true, false, false).unwrap();
+            let first_page_id = db.lookup_document(&resolves(&db, path).unwrap()).unwrap().unwrap().first_page_id;
+            let pages = chain_pages(&db, first_page_id);
+            assert!(pages.len() > 1, "{}", path);
+            let page_id = pages[1];
+            let original = db.read_page_header(page_id).unwrap();
+            assert!(original.flags & FLAG_COMPRESSED != 0 && original.padding[0] == codec);
+            let (_, stored) = db.read_page_payload(page_id).unwrap();
+            // Stored as is with its links kept, then relabelled compressed with the CRC matching
+            let plant = |payload: &[u8]| -> PageHeader {
+                let links = PageLinks { flags: FLAG_DATA_PAGE, prev_page_id: original.prev_page_id, next_page_id: original.next_page_id };
+                let mut header = db.write_raw_page_as(page_id, payload, original.version, links, CODEC_NONE, 0, 0).unwrap();
+                header.flags |= FLAG_COMPRESSED;
+                header.padding = original.padding;
+                db.write_page_header(page_id, &header).unwrap();
+                db.invalidate_page(page_id);
+                header
+            };
+            let garbage = [
+                stored[..stored.len() / 2].to_vec(),
+                stored[..1].to_vec(),
+                Vec::new(),
+                random(64),
+                random(capacity),
+                [&stored[..8], &random(stored.len() - 8)[..]].concat(),
+            ];
+            for payload in &garbage {
+                let header = plant(payload);
+                db.clear_page_cache();
+                let error = db.read_document(path).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}: {}", path, error);
+                assert!(error.to_string().ends_with(&format!("at page {} of {}", page_id, path)), "{}", error);
+
+                // Quick mode skips the CRC; a garbled one must still end in the same error
+                db.write_page_header(page_id, &PageHeader { crc: header.crc ^ 0xdead_beef, ..header }).unwrap();
+                db.invalidate_page(page_id);
+                assert_eq!(db.read_document(path).unwrap_err().to_string(), "CRC mismatch");
+                db.quick_mode.store(true, std::sync::atomic::Ordering::SeqCst);
+                let error = db.read_document(path).unwrap_err();
+                db.quick_mode.store(false, std::sync::atomic::Ordering::SeqCst);
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+                assert!(error.to_string().starts_with("Corrupt compressed page at page "), "{}", error);
+            }
+
+            // Restoring the page restores the document
+            assert_eq!(plant(&stored).crc, original.crc);
+            assert!(db.read_document(path).unwrap() == text.as_bytes());
+        }
+    }
+
+
+    #[test]
+    fn ten_thousand_tiny_documents_share_slab_pages() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().slab_threshold(256));
+        let contents = |n: usize| format!("table pain_{n:05} {{ snap {{ 0, 1, 0.5 }} }}\n").repeat(8).into_bytes()[..200].to_vec();
+        let paths: Vec<String> = (0..10_000).map(|n| format!("def/decls/pain_{:05}.def", n)).collect();
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        for (n, path) in paths.iter().enumerate() {
+            cxx::let_cxx_string!(path = path.as_str());
+            Pin::new(&mut db).save_session_write(tx, &path, &cxx::CxxVector::from(contents(n))).unwrap();
+        }
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        let slab_pages = |db: &StreamDb| StreamDb::document_slabs(&db.read_index().unwrap()).len();
+
+        // The records themselves take little more than their logical size, and the whole file
+        // a fraction of the page per document they would otherwise need
+        let logical = 200 * paths.len();
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let full = slab_pages(&db);
+        assert!(full * capacity < logical * 5 / 4, "{} slab pages for {} bytes", full, logical);
+        let file_size = std::fs::metadata(dir.db()).unwrap().len() as usize;
+        assert!(file_size < paths.len() * PAGE_SIZE as usize / 8, "{} bytes on disk", file_size);
+
+        // Reads, stat and streams see the records as ordinary documents
+        for (n, path) in paths.iter().enumerate().step_by(97) {
+            assert_eq!(db.read_document(path).unwrap(), contents(n));
+            cxx::let_cxx_string!(path = path.as_str());
+            let info = db.stat(&path).unwrap();
+            assert_eq!((info.size, info.page_count), (200, 0));
+            let stream = db.start_stream_with_chunk_size(&path, 64).unwrap();
+            let mut streamed = Vec::new();
+            loop {
+                match db.stream_chunk(stream) {
+                    Ok(chunk) => streamed.extend(chunk),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            Pin::new(&mut db).end_stream(stream);
+            assert_eq!(streamed, contents(n));
+        }
+
+        // Deleting every other record leaves half of each slab free until vacuum compacts them
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        for path in paths.iter().step_by(2) {
+            cxx::let_cxx_string!(path = path.as_str());
+            Pin::new(&mut db).save_session_delete(tx, &path, false).unwrap();
+        }
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        assert_eq!(slab_pages(&db), full);
+        let report = Pin::new(&mut db).vacuum().unwrap();
+        assert_eq!(report.records_moved, paths.len() as u64 / 2);
+        let compacted = slab_pages(&db);
+        assert!(compacted * 2 <= full + 1, "{} slab pages after vacuum, {} before", compacted, full);
+        assert!(report.pages_freed as usize >= full - compacted);
+        drop(db);
+
+        let db = open(&dir, StreamDb::create_options());
+        for (n, path) in paths.iter().enumerate() {
+            match n % 2 {
+                0 => assert_eq!(resolves(&db, path), None),
+                _ => assert_eq!(db.read_document(path).unwrap(), contents(n)),
+            }
+        }
+        assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+    }
+
+
+    #[test]
+    fn fifty_thousand_paths_pack_trie_nodes_into_slabs() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().trie_cache_size(128));
+        let mut rng = Xorshift(0x5851_F42D_4C95_7F2D);
+        let dirs = ["textures/base_wall", "textures/base_floor", "textures/hell", "models/monsters", "sound/weapons", "maps/game", "def", "materials"];
+        let exts = [".tga", ".jpg", ".md5mesh", ".ogg", ".map", ".def", ".mtr"];
+        let paths: Vec<String> = (0..50_000).map(|n| format!("{}/{}_{}{}", rng.pick(&dirs), rng.pick(&["lfwall", "sflr", "panel", "imp", "shotgun"]), n, rng.pick(&exts))).collect();
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        for path in &paths {
+            cxx::let_cxx_string!(path_cxx = path.as_str());
+            Pin::new(&mut db).save_session_write(tx, &path_cxx, &cxx::CxxVector::from(path.as_bytes().to_vec())).unwrap();
+        }
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        let ids: HashMap<&str, Uuid> = paths.iter().map(|path| (path.as_str(), resolves(&db, path).unwrap())).collect();
+        let trie_pages = |db: &StreamDb| {
+            let mut pages = Vec::new();
+            db.trie_collect_pages(db.trie_root.read().page_id, &mut pages).unwrap();
+            pages.sort_unstable();
+            pages.dedup();
+            pages.len() as u64
+        };
+
+        // Tens of nodes share each page where every node used to take one
+        let report = db.check_trie().unwrap();
+        assert!(report.violations.is_empty());
+        assert_eq!(report.paths_checked, paths.len() as u64);
+        let pages = trie_pages(&db);
+        assert!(pages * 20 < report.nodes_checked, "{} nodes on {} pages", report.nodes_checked, pages);
+        drop(db);
+
+        // Walked cold through a node cache far smaller than the trie, every path still leads to its document
+        let mut db = open(&dir, StreamDb::create_options().trie_cache_size(128));
+        let mut reached = HashMap::new();
+        db.trie_for_each_terminal(db.trie_root.read().page_id, &mut String::new(), &mut |reversed, id| {
+            reached.insert(reversed.chars().rev().collect::<String>(), id);
+        }).unwrap();
+        assert_eq!(reached.len(), paths.len());
+        assert!(reached.iter().all(|(path, id)| ids[path.as_str()] == *id));
+        assert!(db.trie_cache.lock().len() <= 128);
+
+        // Deletes free slots and splits rewrite nodes in place; the trie stays consistent
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        for path in paths.iter().step_by(3) {
+            cxx::let_cxx_string!(path_cxx = path.as_str());
+            Pin::new(&mut db).save_session_delete(tx, &path_cxx, false).unwrap();
+        }
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        for n in 0..200 {
+            let path = format!("{}/added_{}{}", rng.pick(&dirs), n, rng.pick(&exts));
+            db.write_document_unordered(&path, path.as_bytes(), true, false, false).unwrap();
+        }
+        let report = db.check_trie().unwrap();
+        assert!(report.violations.is_empty());
+        assert_eq!(report.paths_checked, db.document_count().unwrap());
+        for path in paths.iter().skip(1).step_by(3).take(500) {
+            assert_eq!(resolves(&db, path), Some(ids[path.as_str()]));
+        }
+    }
+
+
+    #[test]
+    fn thousands_of_metadata_changes_go_through_the_index_log() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().index_log_threshold(256));
+        let paths: Vec<String> = (0..100).map(|n| format!("materials/decals/decal{}.mtr", n)).collect();
+        write_paths(&db, &paths.iter().map(String::as_str).collect::<Vec<_>>());
+        let far = StreamDb::unix_now() + 1_000_000;
+        // Expected flags, expiry and tags of each document
+        let mut expected: Vec<(u32, u64, BTreeSet<String>)> = vec![(0, 0, BTreeSet::new()); paths.len()];
+        let check = |db: &StreamDb, expected: &[(u32, u64, BTreeSet<String>)]| {
+            for (path, (flags, expires_at, tags)) in paths.iter().zip(expected) {
+                let doc = db.lookup_document(&resolves(db, path).unwrap()).unwrap().unwrap();
+                assert_eq!((doc.flags, doc.expires_at, &doc.tags), (*flags, *expires_at, tags), "{}", path);
+                assert!(db.read_document(path).unwrap() == path.as_bytes());
+            }
+        };
+        let mut folds = 0;
+        let mut index_version = db.document_index_root.read().version;
+        let mut state = 0x94D0_49BB_1331_11EBu64;
+        for step in 0..3_000u64 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let n = (state % paths.len() as u64) as usize;
+            cxx::let_cxx_string!(path = paths[n].as_str());
+            let logged = db.index_log.lock().records;
+            match step % 3 {
+                0 => {
+                    expected[n].0 ^= DOCUMENT_PRECACHE;
+                    Pin::new(&mut db).set_flags(&path, expected[n].0).unwrap();
+                }
+                1 => {
+                    expected[n].1 = far + step;
+                    Pin::new(&mut db).set_expiry(&path, far + step).unwrap();
+                }
+                _ => {
+                    let tag = format!("group{}", state % 5);
+                    cxx::let_cxx_string!(tag_cxx = tag.as_str());
+                    if expected[n].2.remove(&tag) {
+                        assert!(Pin::new(&mut db).remove_tag(&path, &tag_cxx).unwrap());
+                    } else {
+                        Pin::new(&mut db).add_tag(&path, &tag_cxx).unwrap();
+                        expected[n].2.insert(tag);
+                    }
+                }
+            }
+            // Each change lands in the log until it is full; only then is the B-tree rewritten
+            let records = db.index_log.lock().records;
+            let version = db.document_index_root.read().version;
+            if records > logged {
+                assert_eq!(version, index_version);
+            } else {
+                assert_eq!(records, 0, "step {}", step);
+                assert!(version > index_version);
+                assert_eq!(db.index_log_root.read().page_id, -1);
+                folds += 1;
+            }
+            index_version = version;
+            assert!(records <= 256);
+            if step % 250 == 0 {
+                check(&db, &expected);
+            }
+        }
+        assert!(folds >= 3_000 / 257, "{} folds", folds);
+        assert!(db.index_log.lock().records > 0);
+        check(&db, &expected);
+
+        // Recovery replays the log left by a crash over the base index
+        let image = crash_image(&dir);
+        let recovered = open(&image, StreamDb::create_options());
+        assert_eq!(recovered.index_log.lock().records, db.index_log.lock().records);
+        check(&recovered, &expected);
+        drop(recovered);
+
+        // Folding empties the log and frees its chain without changing what is read
+        let log_pages = chain_pages(&db, db.index_log_root.read().page_id);
+        let logged = db.index_log.lock().records as u64;
+        assert_eq!(Pin::new(&mut db).fold_index_log().unwrap(), logged);
+        assert_eq!(db.index_log.lock().records, 0);
+        assert_eq!(db.index_log_root.read().page_id, -1);
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        assert!(log_pages.iter().all(|page_id| free.contains(page_id)));
+        assert_eq!(Pin::new(&mut db).fold_index_log().unwrap(), 0);
+        check(&db, &expected);
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(db.index_log_root.read().page_id, -1);
+        check(&db, &expected);
+    }
+
+
+    #[test]
+    fn the_document_count_follows_every_mutation_and_survives_recovery_and_migration() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let counted = |db: &StreamDb, live: &BTreeSet<String>| {
+            cxx::let_cxx_string!(all = "");
+            let enumerated = db.search_documents(&all, true).unwrap().len() as u64;
+            assert_eq!(enumerated, live.len() as u64);
+            assert_eq!(db.read_index().unwrap().len() as u64, enumerated);
+            assert_eq!(db.document_count().unwrap(), enumerated);
+            assert_eq!(db.get_db_stats().document_count, enumerated);
+        };
+        let mut live = BTreeSet::new();
+        let mut state = 0xBF58_476D_1CE4_E5B9u64;
+        let mut next = |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        for _ in 0..400 {
+            let path = format!("savegames/slot{}/save{}.sav", next(4), next(16));
+            cxx::let_cxx_string!(path_cxx = path.as_str());
+            match next(6) {
+                0 | 1 => {
+                    db.write_document_unordered(&path, path.as_bytes(), true, false, false).unwrap();
+                    live.insert(path);
+                }
+                2 if live.contains(&path) => {
+                    Pin::new(&mut db).delete_by_path_ex(&path_cxx, false).unwrap();
+                    live.remove(&path);
+                }
+                3 if live.contains(&path) => {
+                    let to = format!("savegames/renamed/{}", next(1_000_000));
+                    cxx::let_cxx_string!(to_cxx = to.as_str());
+                    if !live.contains(&to) {
+                        Pin::new(&mut db).rename_path(&path_cxx, &to_cxx).unwrap();
+                        live.remove(&path);
+                        live.insert(to);
+                    }
+                }
+                4 => {
+                    // Three writes and a delete, committed or rolled back as one
+                    let tx = Pin::new(&mut db).begin_transaction().unwrap();
+                    let written: Vec<String> = (0..3).map(|_| format!("savegames/tx/save{}.sav", next(12))).collect();
+                    for path in &written {
+                        stage(&mut db, tx, path, path);
+                    }
+                    let deleted = live.iter().filter(|path| !written.contains(*path)).nth(next(live.len() as u64 + 1) as usize).cloned();
+                    if let Some(deleted) = &deleted {
+                        cxx::let_cxx_string!(deleted_cxx = deleted.as_str());
+                        Pin::new(&mut db).save_session_delete(tx, &deleted_cxx, false).unwrap();
+                    }
+                    if next(2) == 0 {
+                        Pin::new(&mut db).commit_transaction(tx).unwrap();
+                        live.extend(written);
+                        if let Some(deleted) = &deleted {
+                            live.remove(deleted);
+                        }
+                    } else {
+                        Pin::new(&mut db).rollback_transaction(tx).unwrap();
+                    }
+                }
+                5 if live.contains(&path) => {
+                    Pin::new(&mut db).set_expiry(&path_cxx, 1).unwrap();
+                    assert_eq!(Pin::new(&mut db).purge_expired(StreamDb::unix_now()).unwrap().documents, 1);
+                    live.remove(&path);
+                }
+                _ => {}
+            }
+            counted(&db, &live);
+        }
+        assert!(live.len() > 10);
+
+        // The count in the header is the one a crash leaves, and recovery agrees with it
+        let image = crash_image(&dir);
+        let stored = Cursor::new(&std::fs::read(image.db()).unwrap()[DOCUMENT_COUNT_OFFSET..]).read_u64::<LittleEndian>().unwrap();
+        assert_eq!(stored, live.len() as u64);
+        let recovered = open(&image, StreamDb::create_options());
+        counted(&recovered, &live);
+        assert!(recovered.verify_db(true).unwrap().document_count_ok);
+        drop(recovered);
+
+        // A wrong count is reported by verify_db and put right by repair_db
+        let mut bytes = std::fs::read(image.db()).unwrap();
+        bytes[DOCUMENT_COUNT_OFFSET..DOCUMENT_COUNT_OFFSET + 8].copy_from_slice(&999u64.to_le_bytes());
+        std::fs::write(image.db(), &bytes).unwrap();
+        let mut damaged = open(&image, StreamDb::create_options());
+        assert_eq!(damaged.document_count().unwrap(), 999);
+        assert!(!damaged.verify_db(true).unwrap().document_count_ok);
+        assert!(!Pin::new(&mut damaged).repair_db().unwrap().document_count_ok);
+        counted(&damaged, &live);
+        drop(damaged);
+        let repaired = open(&image, StreamDb::create_options());
+        counted(&repaired, &live);
+        drop(repaired);
+
+        // Files from before the count existed are counted once as they migrate
+        downgrade_index(&db, 15);
+        let v15 = crash_image(&dir);
+        let mut bytes = std::fs::read(v15.db()).unwrap();
+        bytes[DOCUMENT_COUNT_OFFSET..DOCUMENT_COUNT_OFFSET + 8].copy_from_slice(&0u64.to_le_bytes());
+        std::fs::write(v15.db(), &bytes).unwrap();
+        let migrated = open(&v15, StreamDb::create_options());
+        counted(&migrated, &live);
+    }
+
+
+    #[test]
+    fn freed_pages_at_the_end_are_cut_from_the_file() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let contents = |n: usize| vec![n as u8; capacity * 3];
+        let file_size = |dir: &TempDir| std::fs::metadata(dir.db()).unwrap().len();
+        for n in 0..20 {
+            db.write_document_unordered(&format!("maps/first/{}.bin", n), &contents(n), true, false, false).unwrap();
+        }
+        let first_half = file_size(&dir);
+        for n in 20..40 {
+            db.write_document_unordered(&format!("maps/second/{}.bin", n), &contents(n), true, false, false).unwrap();
+        }
+        let full = file_size(&dir);
+        assert!(full >= first_half + 60 * PAGE_SIZE);
+
+        // Deleting the last-written half frees the end of the file, which vacuum gives back
+        for n in (20..40).rev() {
+            cxx::let_cxx_string!(path = format!("maps/second/{}.bin", n));
+            Pin::new(&mut db).delete_by_path_ex(&path, false).unwrap();
+        }
+        assert_eq!(file_size(&dir), full);
+        let report = Pin::new(&mut db).vacuum().unwrap();
+        let truncated = file_size(&dir);
+        assert_eq!(full - truncated, report.pages_truncated * PAGE_SIZE);
+        assert!(truncated <= first_half + 8 * PAGE_SIZE, "{} bytes, {} before the second half", truncated, first_half);
+        assert_eq!(mapped_len(&db), Some(truncated as usize));
+        assert!(db.free_list_pages(&db.lock_allocation()).unwrap().iter().all(|&page_id| ((page_id + 1) as u64) * PAGE_SIZE <= truncated));
+        for n in 0..20 {
+            assert_eq!(db.read_document(&format!("maps/first/{}.bin", n)).unwrap(), contents(n));
+        }
+
+        // Pages an open stream still reads are not freed, so not cut either, until it ends
+        db.write_document_unordered("maps/last.bin", &contents(99), true, false, false).unwrap();
+        let with_last = file_size(&dir);
+        cxx::let_cxx_string!(last = "maps/last.bin");
+        let stream = db.start_stream_with_chunk_size(&last, capacity).unwrap();
+        let mut streamed = db.stream_chunk(stream).unwrap();
+        Pin::new(&mut db).delete_by_path_ex(&last, false).unwrap();
+        Pin::new(&mut db).vacuum().unwrap();
+        assert!(file_size(&dir) >= with_last - 8 * PAGE_SIZE);
+        while let Ok(chunk) = db.stream_chunk(stream) {
+            streamed.extend(chunk);
+        }
+        assert_eq!(streamed, contents(99));
+        Pin::new(&mut db).end_stream(stream);
+        assert!(Pin::new(&mut db).vacuum().unwrap().pages_truncated >= 3);
+        assert!(file_size(&dir) <= with_last - 3 * PAGE_SIZE);
+        drop(db);
+
+        // On close only when asked to
+        for truncate in [false, true] {
+            let mut db = open(&dir, StreamDb::create_options().truncate_on_close(truncate));
+            db.write_document_unordered("maps/tail.bin", &contents(7), true, false, false).unwrap();
+            let grown = file_size(&dir);
+            cxx::let_cxx_string!(tail = "maps/tail.bin");
+            Pin::new(&mut db).delete_by_path_ex(&tail, false).unwrap();
+            drop(db);
+            assert_eq!(file_size(&dir) < grown, truncate);
+        }
+        let db = open(&dir, StreamDb::create_options());
+        for n in 0..20 {
+            assert_eq!(db.read_document(&format!("maps/first/{}.bin", n)).unwrap(), contents(n));
+        }
+        assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+    }
+
+
+    #[test]
+    fn the_maintenance_thread_cleans_up_churn_unless_paused() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().slab_threshold(256).maintenance(10, 10, 5));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let file_size = |dir: &TempDir| std::fs::metadata(dir.db()).unwrap().len();
+        let slab_pages = |db: &StreamDb| StreamDb::document_slabs(&db.read_index().unwrap()).len();
+        let eventually = |what: &str, done: &dyn Fn() -> bool| {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+            while !done() {
+                assert!(std::time::Instant::now() < deadline, "maintenance never {}", what);
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+        // Churn: slabs left three quarters empty, a freed run of pages at the end and expired documents
+        let churn = |db: &mut StreamDb, round: usize| {
+            let tx = Pin::new(&mut *db).begin_transaction().unwrap();
+            for n in 0..400 {
+                stage(db, tx, &format!("def/round{}/{}.def", round, n), &format!("{:0>200}", n));
+            }
+            Pin::new(&mut *db).commit_transaction(tx).unwrap();
+            let tx = Pin::new(&mut *db).begin_transaction().unwrap();
+            for n in (0..400).filter(|n| n % 4 != 0) {
+                cxx::let_cxx_string!(path = format!("def/round{}/{}.def", round, n));
+                Pin::new(&mut *db).save_session_delete(tx, &path, false).unwrap();
+            }
+            Pin::new(&mut *db).commit_transaction(tx).unwrap();
+            for n in 0..4 {
+                let path = format!("maps/round{}/expired{}.bin", round, n);
+                db.write_document_unordered(&path, b"stale", true, false, false).unwrap();
+                cxx::let_cxx_string!(path = path);
+                Pin::new(&mut *db).set_expiry(&path, 1).unwrap();
+            }
+            for n in 0..16 {
+                db.write_document_unordered(&format!("maps/round{}/tail{}.bin", round, n), &vec![n as u8; capacity * 4], true, false, false).unwrap();
+            }
+            for n in (0..16).rev() {
+                cxx::let_cxx_string!(path = format!("maps/round{}/tail{}.bin", round, n));
+                Pin::new(&mut *db).delete_by_path_ex(&path, false).unwrap();
+            }
+        };
+
+        // Paused, as for a level load, nothing is touched however long it waits
+        db.pause_maintenance();
+        churn(&mut db, 0);
+        let (slabs, size) = (slab_pages(&db), file_size(&dir));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert_eq!((slab_pages(&db), file_size(&dir)), (slabs, size));
+        assert!(resolves(&db, "maps/round0/expired0.bin").is_some());
+
+        // Resumed, it purges, repacks and truncates with no foreground calls
+        db.resume_maintenance();
+        eventually("purged", &|| (0..4).all(|n| resolves(&db, &format!("maps/round0/expired{}.bin", n)).is_none()));
+        eventually("repacked", &|| slab_pages(&db) * 2 < slabs);
+        eventually("truncated", &|| file_size(&dir) + 32 * PAGE_SIZE <= size);
+
+        // and keeps doing so as churn goes on, leaving every live document readable
+        churn(&mut db, 1);
+        eventually("purged", &|| (0..4).all(|n| resolves(&db, &format!("maps/round1/expired{}.bin", n)).is_none()));
+        for round in 0..2 {
+            for n in (0..400).step_by(4) {
+                assert_eq!(db.read_document(&format!("def/round{}/{}.def", round, n)).unwrap(), format!("{:0>200}", n).into_bytes());
+            }
+        }
+        db.pause_maintenance();
+        assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+    }
+
+
+    #[test]
+    fn one_millisecond_maintenance_slices_finish_a_repack_across_frames() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().slab_threshold(256).maintenance(0, 10, 5));
+        let paths: Vec<String> = (0..20_000).map(|n| format!("def/decls/{}.def", n)).collect();
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        for (n, path) in paths.iter().enumerate() {
+            stage(&mut db, tx, path, &format!("{:0>200}", n));
+        }
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        for path in paths.iter().enumerate().filter(|(n, _)| n % 4 != 0).map(|(_, path)| path) {
+            cxx::let_cxx_string!(path = path.as_str());
+            Pin::new(&mut db).save_session_delete(tx, &path, false).unwrap();
+        }
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        let slabs = StreamDb::document_slabs(&db.read_index().unwrap()).len() as u64;
+
+        // Paused, a slice does nothing
+        db.pause_maintenance();
+        let report = Pin::new(&mut db).run_maintenance_slice(1).unwrap();
+        assert_eq!((report.slab_pages_scanned, report.records_moved, report.pass_completed), (0, 0, false));
+        db.resume_maintenance();
+
+        // Frame after frame the pass advances where the last slice stopped, each within its budget
+        let mut slices = 0;
+        let mut scanned = 0;
+        let mut moved = 0;
+        loop {
+            let started = std::time::Instant::now();
+            let report = Pin::new(&mut db).run_maintenance_slice(1).unwrap();
+            let elapsed = started.elapsed();
+            assert!(elapsed < std::time::Duration::from_millis(1 + 25), "slice {} took {:?}", slices, elapsed);
+            slices += 1;
+            scanned += report.slab_pages_scanned;
+            moved += report.records_moved;
+            if report.pass_completed {
+                break;
+            }
+            assert!(slices < 100_000);
+        }
+        assert!(slices > 1, "the pass fit in one slice");
+        assert_eq!(scanned, slabs);
+        assert_eq!(moved, paths.len() as u64 / 4);
+        assert!((StreamDb::document_slabs(&db.read_index().unwrap()).len() as u64) * 2 < slabs);
+        for (n, path) in paths.iter().enumerate() {
+            match n % 4 {
+                0 => assert_eq!(db.read_document(path).unwrap(), format!("{:0>200}", n).into_bytes()),
+                _ => assert_eq!(resolves(&db, path), None),
+            }
+        }
+
+        // With nothing left to do the next pass measures and completes in a slice or two
+        let report = Pin::new(&mut db).run_maintenance_slice(1000).unwrap();
+        assert!(report.pass_completed);
+        assert_eq!(report.records_moved, 0);
+    }
+
+
+    #[test]
+    fn transaction_stats_report_totals_and_each_open_transaction() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        write_paths(&db, &["savegames/old.save"]);
+        cxx::let_cxx_string!(name = "long_transaction_ms");
+        cxx::let_cxx_string!(value = "50");
+        Pin::new(&mut db).set_tunable(&name, &value).unwrap();
+        let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let txs: Vec<i64> = (0..4).map(|_| Pin::new(&mut db).begin_transaction().unwrap()).collect();
+        let after = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        stage(&mut db, txs[0], "savegames/a/game.save", "a");
+        stage(&mut db, txs[0], "savegames/a/game.txt", "a");
+        stage(&mut db, txs[1], "savegames/b/game.save", "b");
+        cxx::let_cxx_string!(old = "savegames/old.save");
+        Pin::new(&mut db).save_session_delete(txs[1], &old, false).unwrap();
+        let large = "x".repeat(capacity * 3);
+        stage(&mut db, txs[2], "savegames/c/game.bin", &large);
+        stage(&mut db, txs[2], "savegames/c/game.save", "c");
+        stage(&mut db, txs[2], "savegames/c/game.txt", "c");
+
+        let stats = db.get_transaction_stats();
+        assert_eq!((stats.begun, stats.committed, stats.rolled_back, stats.failed), (4, 0, 0, 0));
+        assert_eq!(stats.open.iter().map(|tx| tx.tx_id).collect::<Vec<_>>(), txs);
+        assert_eq!(stats.open.iter().map(|tx| tx.staged_writes).collect::<Vec<_>>(), [2, 2, 3, 0]);
+        assert_eq!(stats.open[3].staged_frees, 0);
+        for tx in &stats.open {
+            assert!((before..=after).contains(&tx.started_unix_ms));
+            assert!(!tx.long_open && tx.age_ms < 50);
+        }
+
+        // Past the threshold every one still open is flagged
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        assert!(db.get_transaction_stats().open.iter().all(|tx| tx.long_open && tx.age_ms >= 50));
+
+        // Commits add up the pages they wrote; the largest one is remembered
+        Pin::new(&mut db).commit_transaction(txs[2]).unwrap();
+        let first = db.get_transaction_stats();
+        assert!(first.last_commit_pages > 0);
+        assert_eq!((first.pages_committed, first.max_commit_pages), (first.last_commit_pages, first.last_commit_pages));
+        Pin::new(&mut db).commit_transaction(txs[0]).unwrap();
+        Pin::new(&mut db).rollback_transaction(txs[1]).unwrap();
+        let stats = db.get_transaction_stats();
+        assert!(stats.last_commit_pages > 0);
+        assert_eq!(stats.pages_committed, first.pages_committed + stats.last_commit_pages);
+        assert_eq!(stats.max_commit_pages, first.last_commit_pages.max(stats.last_commit_pages));
+        assert_eq!((stats.begun, stats.committed, stats.rolled_back, stats.failed), (4, 2, 1, 0));
+        assert_eq!(stats.open.len(), 1);
+        assert_eq!(stats.open[0].tx_id, txs[3]);
+        assert!(resolves(&db, "savegames/old.save").is_some());
+
+        // Closing with one open counts it as rolled back
+        Pin::new(&mut db).close_db();
+        let stats = db.get_transaction_stats();
+        assert_eq!(stats.rolled_back, 2);
+        assert!(stats.open.is_empty());
+    }
+
+
+    #[test]
+    fn a_cursor_sees_its_snapshot_while_deletes_and_compaction_run() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().slab_threshold(256));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let contents = |n: usize| match n % 3 {
+            0 => vec![n as u8; capacity * 2 + n],
+            _ => format!("{:0>100}", n).into_bytes(),
+        };
+        let path = |n: usize| match n % 3 {
+            0 => format!("export/maps/{:04}.bin", n),
+            _ => format!("export/def/{:04}.def", n),
+        };
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        for n in 0..600 {
+            cxx::let_cxx_string!(path_cxx = path(n));
+            Pin::new(&mut db).save_session_write(tx, &path_cxx, &cxx::CxxVector::from(contents(n))).unwrap();
+        }
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        let snapshot: BTreeMap<String, Vec<u8>> = (0..600).map(|n| (path(n), contents(n))).collect();
+        cxx::let_cxx_string!(prefix = "export/");
+        let cursor = db.open_cursor(&prefix).unwrap();
+
+        let seen = std::thread::scope(|scope| {
+            let db = &db;
+            // Deletes, rewrites and compaction that move or free everything the cursor holds
+            scope.spawn(move || {
+                for round in 0..4 {
+                    for n in (round..600).step_by(4) {
+                        db.commit_changes(vec![StagedChange::Delete { path: path(n), force: false, secure: false }]).unwrap();
+                        if n % 8 == round {
+                            db.write_document_unordered(&path(n + 1_000), &contents(n + 1), true, false, false).unwrap();
+                        }
+                    }
+                    let _writes = db.begin_exclusive_write().unwrap();
+                    db.vacuum_slabs(0, &mut WorkBudget::unlimited()).unwrap();
+                    db.truncate_free_tail().unwrap();
+                }
+            });
+            let mut seen = BTreeMap::new();
+            loop {
+                let batch = db.cursor_next(cursor, 25).unwrap();
+                if batch.is_empty() {
+                    break;
+                }
+                for info in batch {
+                    cxx::let_cxx_string!(path_cxx = info.path.as_str());
+                    let data = db.cursor_read(cursor, &path_cxx).unwrap().as_slice().to_vec();
+                    assert_eq!(info.size, data.len() as u64);
+                    assert!(seen.insert(info.path, data).is_none());
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            seen
+        });
+        assert!(seen == snapshot);
+        assert!(resolves(&db, &path(0)).is_none() && resolves(&db, &path(1_000)).is_some());
+        cxx::let_cxx_string!(gone = path(0));
+        assert_eq!(db.cursor_read(cursor, &gone).unwrap().as_slice(), contents(0));
+
+        // Closing releases its pins and with them the pages of everything since deleted
+        assert!(db.get_db_stats().pinned_chains > 0);
+        db.close_cursor(cursor);
+        let stats = db.get_db_stats();
+        assert_eq!((stats.pinned_chains, stats.pending_free_chains), (0, 0));
+        assert_eq!(db.cursor_next(cursor, 1).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+
+        // A forgotten cursor is closed once it has been idle for cursor_timeout_ms
+        cxx::let_cxx_string!(name = "cursor_timeout_ms");
+        cxx::let_cxx_string!(value = "50");
+        Pin::new(&mut db).set_tunable(&name, &value).unwrap();
+        let forgotten = db.open_cursor(&prefix).unwrap();
+        assert_eq!(db.cursor_next(forgotten, 1).unwrap().len(), 1);
+        std::thread::sleep(std::time::Duration::from_millis(80));
+        assert_eq!(db.cursor_next(forgotten, 1).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(db.get_db_stats().pinned_chains, 0);
+    }
+
+
+    #[test]
+    fn writers_on_disjoint_prefixes_run_in_parallel() {
+        const WRITERS: usize = 8;
+        const PER_WRITER: usize = 12;
+        let words = ["seta", "bind", "g_fov", "r_mode", "\"", " ", "\n", "1", "0", "90", "com_showFPS", "exec"];
+        let mut rng = Xorshift(0x2F7A_3C1B_99D0_4E85);
+        // Level 19 zstd makes the part of each write done outside the locks the bulk of it
+        let config: Vec<u8> = (0..64 * 1024).map(|_| rng.pick(&words)).collect::<String>().into_bytes();
+        let contents = |writer: usize, n: usize| {
+            let mut data = config.clone();
+            data[..16].copy_from_slice(format!("{:08}{:08}", writer, n).as_bytes());
+            data
+        };
+        let options = || StreamDb::create_options()
+            .compression(ffi::PageCodec::Zstd, 19)
+            .compression_rule("cfg", ffi::PageCodec::Zstd)
+            .durable_writes(false);
+        let run = |threads: usize| {
+            let dir = TempDir::new();
+            let db = open(&dir, options());
+            let started = std::time::Instant::now();
+            std::thread::scope(|scope| {
+                for chunk in (0..WRITERS).collect::<Vec<_>>().chunks(WRITERS / threads) {
+                    let (db, chunk) = (&db, chunk.to_vec());
+                    scope.spawn(move || {
+                        for writer in chunk {
+                            for n in 0..PER_WRITER {
+                                db.write_document_unordered(&format!("writer{}/configs/{}.cfg", writer, n), &contents(writer, n), true, false, false).unwrap();
+                            }
+                        }
+                    });
+                }
+            });
+            let elapsed = started.elapsed();
+            for writer in 0..WRITERS {
+                for n in 0..PER_WRITER {
+                    assert!(db.read_document(&format!("writer{}/configs/{}.cfg", writer, n)).unwrap() == contents(writer, n));
+                }
+            }
+            assert_eq!(db.get_db_stats().document_count, (WRITERS * PER_WRITER) as u64);
+            assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+            elapsed
+        };
+        let serialized = run(1);
+        let parallel = run(WRITERS);
+        // Only meaningful with cores to spread over
+        if std::thread::available_parallelism().map_or(1, |cores| cores.get()) >= 4 {
+            assert!(parallel * 3 < serialized * 2, "8 writers took {:?}, one took {:?}", parallel, serialized);
+        }
+    }
+
+
+    #[test]
+    fn waiting_on_a_slow_lock_holder_is_recorded() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let gate = |db: &StreamDb| db.get_lock_stats().into_iter().find(|stats| stats.name == "write gate").unwrap();
+        assert_eq!(db.get_lock_stats().len(), TrackedLock::ALL.len());
+
+        // Off by default: nothing is counted
+        write_paths(&db, &["maps/e1m1.map"]);
+        assert!(db.get_lock_stats().iter().all(|stats| stats.acquisitions == 0 && stats.total_wait_us == 0));
+
+        db.set_lock_tracking(true);
+        write_paths(&db, &["maps/e1m2.map"]);
+        let uncontended = gate(&db);
+        assert!(uncontended.acquisitions > 0);
+        assert_eq!((uncontended.contended, uncontended.total_wait_us), (0, 0));
+        db.reset_lock_stats();
+
+        // A writer arriving while another holds the gate for 100ms waits out most of it
+        let (held, holding) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let db = &db;
+            scope.spawn(move || {
+                let _writes = db.begin_write().unwrap();
+                held.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            });
+            holding.recv().unwrap();
+            db.write_document_unordered("maps/e1m3.map", b"waited", true, false, false).unwrap();
+        });
+        let waited = gate(&db);
+        assert!(waited.acquisitions >= 2);
+        assert!(waited.contended >= 1);
+        assert!(waited.max_wait_us >= 50_000, "waited {}us", waited.max_wait_us);
+        assert!(waited.total_wait_us >= waited.max_wait_us);
+
+        // Reset clears the counters; turned off, they stay cleared
+        db.set_lock_tracking(false);
+        db.reset_lock_stats();
+        write_paths(&db, &["maps/e1m4.map"]);
+        assert!(db.get_lock_stats().iter().all(|stats| (stats.acquisitions, stats.contended, stats.max_wait_us) == (0, 0, 0)));
     }
 
-    fn initialize(&mut self) -> io::Result<()> {
-        let mut file = self.file.lock();
-        file.seek(SeekFrom::Start(0))?;
-        let mut header = vec![0u8; 32]; // MAGIC + roots
-        if file.read(&mut header)? == 0 {
-            // New DB: Write header
-            let mut writer = BufWriter::new(Vec::new());
-            writer.write_all(&MAGIC)?;
-            writer.write_i64::<LittleEndian>(-1)?; // index_root
-            writer.write_i32::<LittleEndian>(0)?;
-            writer.write_i64::<LittleEndian>(-1)?; // trie_root
-            writer.write_i32::<LittleEndian>(0)?;
-            writer.write_i64::<LittleEndian>(-1)?; // free_list_root
-            writer.write_i32::<LittleEndian>(0)?;
-            file.write_all(&writer.into_inner()?)?;
-            file.flush()?;
-        } else {
-            let mut reader = Cursor::new(header);
-            let magic = reader.read_u64::<LittleEndian>()?;
-            if magic != u64::from_le_bytes(MAGIC) {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid DB magic"));
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn async_reads_and_writes_agree_with_the_sync_api() {
+        let dir = TempDir::new();
+        let db = AsyncStreamDb::new(open(&dir, StreamDb::create_options())).unwrap();
+        let contents = |n: usize| format!("seta r_mode {n}\n").repeat(n + 1).into_bytes();
+        let path = |writer: usize, n: usize| format!("configs/{}/{}.cfg", writer, n);
+
+        // Writers and then readers on handles of their own, all at once
+        let writers: Vec<_> = (0..8).map(|writer| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for n in 0..16 {
+                    db.write_document(&path(writer, n), contents(writer * 16 + n)).await.unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        let readers: Vec<_> = (0..8).map(|reader| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for writer in 0..8 {
+                    let n = (reader + writer) % 16;
+                    assert_eq!(db.get(&path(writer, n)).await.unwrap(), contents(writer * 16 + n));
+                }
+            })
+        }).collect();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+
+        // Calls on one handle run in the order they were made, however their futures are awaited
+        let first = db.write_document("configs/order.cfg", b"first".to_vec());
+        let second = db.write_document("configs/order.cfg", b"second".to_vec());
+        let read = db.get("configs/order.cfg");
+        assert_eq!(read.await.unwrap(), b"second");
+        second.await.unwrap();
+        first.await.unwrap();
+        drop(db.write_document("configs/dropped.cfg", b"queued".to_vec()));
+        assert_eq!(db.get("configs/dropped.cfg").await.unwrap(), b"queued");
+        assert_eq!(db.get("configs/missing.cfg").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let found = db.search_paths("configs/3/").await.unwrap();
+        assert_eq!(found.len(), 16);
+        let mut stream = db.stream(&path(7, 15), 100).await.unwrap();
+        let mut streamed = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await.unwrap() {
+            assert!(!chunk.is_empty() && chunk.len() <= 100);
+            streamed.extend(chunk);
+        }
+        assert_eq!(streamed, contents(7 * 16 + 15));
+        drop(stream);
+        drop(db);
+
+        // The same database through the sync API, once the IO threads have let go of it
+        let db = open(&dir, StreamDb::create_options().lock_timeout_ms(10_000));
+        assert_eq!(db.search_path_list("configs/3/").unwrap(), found);
+        for writer in 0..8 {
+            for n in 0..16 {
+                assert_eq!(db.read_document(&path(writer, n)).unwrap(), contents(writer * 16 + n));
             }
-            *self.document_index_root.write() = VersionedLink {
-                page_id: reader.read_i64::<LittleEndian>()?,
-                version: reader.read_i32::<LittleEndian>()?,
-            };
-            *self.trie_root.write() = VersionedLink {
-                page_id: reader.read_i64::<LittleEndian>()?,
-                version: reader.read_i32::<LittleEndian>()?,
-            };
-            *self.free_list_root.write() = VersionedLink {
-                page_id: reader.read_i64::<LittleEndian>()?,
-                version: reader.read_i32::<LittleEndian>()?,
-            };
         }
-        self.recover()?;
-        Ok(())
+        assert_eq!(db.read_document("configs/order.cfg").unwrap(), b"second");
+        assert_eq!(db.read_document("configs/dropped.cfg").unwrap(), b"queued");
     }
 
-    fn recover(&mut self) -> io::Result<()> {
-        let mut used_pages = vec![];
-        let mut index = BTreeMap::new();
-        let mut trie = BTreeMap::new();
-        let mut current_size = self.file.lock().metadata()?.len();
-        let max_page_id = (current_size / self.config.page_size) as i64;
 
-        for page_id in 0..max_page_id {
-            let header = match self.read_page_header(page_id) {
-                Ok(h) => h,
-                Err(_) => continue,
-            };
-            if header.flags & FLAG_DATA_PAGE != 0 || header.flags & FLAG_TRIE_PAGE != 0 || header.flags & FLAG_INDEX_PAGE != 0 {
-                used_pages.push(page_id);
-                if header.flags & FLAG_INDEX_PAGE != 0 {
-                    let data = self.read_raw_page(page_id)?;
-                    let docs = self.deserialize_index(&data)?;
-                    for (id, doc) in docs {
-                        index.insert(id, doc);
-                    }
-                } else if header.flags & FLAG_TRIE_PAGE != 0 {
-                    let node = self.deserialize_trie_node(&data)?;
-                    trie.insert(page_id, node);
-                }
+    #[test]
+    fn batch_reads_decompress_on_the_pool_faster_and_to_the_same_bytes() {
+        let dir = TempDir::new();
+        let options = |threads| StreamDb::create_options()
+            .compression_rule("def", ffi::PageCodec::Zstd)
+            .decompress_threads(threads)
+            .cache_sizes(8192, 1024);
+        let db = open(&dir, options(0));
+        let words = ["entityDef", "monster_imp", "\"inherit\"", "\"model\"", "{", "}", "\n\t", " ", "128", "-64", "\"health\"", "\"skin\""];
+        let mut rng = Xorshift(0x6A09_E667_F3BC_C909);
+        let paths: Vec<String> = (0..48).map(|n| format!("def/monsters/{}.def", n)).collect();
+        for path in &paths {
+            let text: String = (0..40_000).map(|_| rng.pick(&words)).collect();
+            db.write_document_unordered(path, text.as_bytes(), true, false, false).unwrap();
+        }
+        drop(db);
+        // Best of several cold batch reads, the page cache emptied before each
+        let timed = |threads: usize| -> (std::time::Duration, Vec<ffi::DocumentData>) {
+            let db = open(&dir, options(threads));
+            let mut best = std::time::Duration::MAX;
+            let mut documents = Vec::new();
+            for _ in 0..5 {
+                db.clear_page_cache();
+                let started = std::time::Instant::now();
+                documents = db.get_many(&paths).unwrap();
+                best = best.min(started.elapsed());
             }
+            // What the pool decompressed landed in the cache: reading again misses nothing
+            let misses = db.cache_stats.lock().misses;
+            db.get_many(&paths).unwrap();
+            assert_eq!(db.cache_stats.lock().misses, misses);
+            (best, documents)
+        };
+        let (inline, inline_documents) = timed(0);
+        let (pooled, pooled_documents) = timed(4);
+        assert_eq!(inline_documents.len(), paths.len());
+        for ((path, inline), pooled) in paths.iter().zip(&inline_documents).zip(&pooled_documents) {
+            assert_eq!((&inline.path, &pooled.path), (path, path));
+            assert!(inline.data == pooled.data, "{}", path);
         }
+        if std::thread::available_parallelism().map_or(1, |cores| cores.get()) >= 4 {
+            assert!(pooled < inline, "4 threads took {:?}, inline took {:?}", pooled, inline);
+        }
+    }
 
-        // Rebuild free list
-        let mut free_pages = (0..max_page_id).filter(|&id| !used_pages.contains(&id)).collect::<Vec<_>>();
-        free_pages.sort();
-        let mut free_root = self.free_list_root.write();
-        free_root.page_id = if !free_pages.is_empty() {
-            let first_free = free_pages[0];
-            self.write_free_list_page(first_free, &free_pages[1..], free_pages.len() as i32)?;
-            first_free
-        } else {
-            -1
+
+    #[test]
+    fn the_page_iterator_accounts_for_every_page_of_a_known_database() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let paths: Vec<String> = (1..=12).map(|n| format!("maps/game/chunk{}.bin", n)).collect();
+        for (n, path) in paths.iter().enumerate() {
+            db.write_document_unordered(path, &vec![n as u8; capacity * n + 10], true, false, false).unwrap();
+        }
+        let pages = |db: &StreamDb| {
+            let iterator = db.open_page_iterator().unwrap();
+            let mut pages = Vec::new();
+            loop {
+                let batch = db.page_iterator_next(iterator, 7).unwrap();
+                if batch.is_empty() {
+                    break;
+                }
+                pages.extend(batch);
+            }
+            db.close_page_iterator(iterator);
+            assert_eq!(db.page_iterator_next(iterator, 1).unwrap_err().kind(), io::ErrorKind::NotFound);
+            pages
         };
+        let listed = pages(&db);
+        assert_eq!(listed.iter().map(|page| page.page_id).collect::<Vec<_>>(), (FIRST_PAGE_ID..db.page_count()).collect::<Vec<_>>());
+        assert!(listed.iter().all(|page| page.crc_ok));
 
-        // Update index/trie roots
-        if !index.is_empty() {
-            let index_page = self.allocate_page()?;
-            self.write_raw_page(index_page, &self.serialize_index(&index)?, 0)?;
-            *self.document_index_root.write() = VersionedLink { page_id: index_page, version: 0 };
+        // Live data pages are the documents' chains, whose lengths stat reports, plus the internal chains
+        let free: HashSet<i64> = db.free_list_pages(&db.lock_allocation()).unwrap().into_iter().collect();
+        let live = |flag: u8| listed.iter().filter(|page| page.flags & flag != 0 && !free.contains(&page.page_id)).count();
+        let document_pages: u64 = paths.iter().map(|path| {
+            cxx::let_cxx_string!(path = path.as_str());
+            db.stat(&path).unwrap().page_count as u64
+        }).sum();
+        assert_eq!(document_pages, (1..=12u64).sum::<u64>());
+        let internal_pages: usize = [&db.dedup_root, &db.tag_root, &db.secondary_root, &db.rules_root, &db.index_log_root].iter()
+            .map(|root| root.read().page_id)
+            .filter(|&page_id| page_id != -1)
+            .map(|page_id| chain_pages(&db, page_id).len())
+            .sum();
+        assert_eq!(live(FLAG_DATA_PAGE) as u64, document_pages + internal_pages as u64);
+        assert_eq!(db.get_db_stats().document_count, paths.len() as u64);
+        assert!(live(FLAG_INDEX_PAGE) > 0 && live(FLAG_HASH_PAGE) > 0 && live(FLAG_TRIE_PAGE) > 0);
+
+        // The iterator lists the pages there were when it opened, however the file grows after
+        let iterator = db.open_page_iterator().unwrap();
+        let first = db.page_iterator_next(iterator, 3).unwrap();
+        db.write_document_unordered("maps/game/later.bin", &vec![7u8; capacity * 20], true, false, false).unwrap();
+        let mut rest = db.page_iterator_next(iterator, usize::MAX).unwrap();
+        rest.splice(..0, first);
+        assert_eq!(rest.len(), listed.len());
+        db.close_page_iterator(iterator);
+
+        // A damaged payload shows up as a failed CRC on that page alone
+        let document = db.lookup_document(&resolves(&db, &paths[4]).unwrap()).unwrap().unwrap();
+        let damaged = chain_pages(&db, document.first_page_id)[2];
+        db.write_bytes_at(db.payload_offset(damaged).unwrap() + 5, &[0xAA]).unwrap();
+        let failed: Vec<i64> = pages(&db).into_iter().filter(|page| !page.crc_ok).map(|page| page.page_id).collect();
+        assert_eq!(failed, [damaged]);
+
+        // Raw pages only for admin opens
+        assert_eq!(db.read_raw_page_admin(damaged).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        drop(db);
+        let db = open(&dir, StreamDb::create_options().admin(true));
+        let raw = db.read_raw_page_admin(damaged).unwrap();
+        assert_eq!(raw.len(), PAGE_SIZE as usize);
+        let header_size = db.config.page_header_size as usize;
+        assert_eq!(raw[header_size + 5], 0xAA);
+        assert!(raw[header_size..header_size + 5].iter().all(|&byte| byte == 4));
+        assert_eq!(db.read_raw_page_admin(db.page_count()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(db.read_raw_page_admin(-1).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+
+    #[test]
+    fn manifest_entries_agree_with_stat_and_checksums() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let before = StreamDb::unix_now();
+        db.write_document_unordered("maps/e1m1.bin", &vec![3u8; capacity * 2 + 17], true, false, false).unwrap();
+        db.write_document_unordered("maps/e1m1.bin", &vec![4u8; capacity * 3], true, false, false).unwrap();
+        db.write_document_unordered("scripts/doom.cfg", &b"bind w _forward\n".repeat(600), true, false, false).unwrap();
+        db.write_document_unordered("sound/empty.ogg", b"", true, false, false).unwrap();
+        db.write_document_unordered("def/weapons.def", b"entityDef weapon_pistol {}", true, false, false).unwrap();
+        cxx::let_cxx_string!(source = "def/weapons.def");
+        cxx::let_cxx_string!(alias = "def/weapon_pistol.def");
+        Pin::new(&mut db).add_path(&source, &alias, false).unwrap();
+        Pin::new(&mut db).set_flags(&source, DOCUMENT_PRECACHE).unwrap();
+        let after = StreamDb::unix_now();
+        let paths = ["def/weapon_pistol.def", "def/weapons.def", "maps/e1m1.bin", "scripts/doom.cfg", "sound/empty.ogg"];
+
+        // Straight from the index: no page is read for it
+        db.read_index().unwrap();
+        let reads = |db: &StreamDb| {
+            let stats = db.cache_stats.lock();
+            (stats.hits, stats.misses, stats.bypassed)
+        };
+        let reads_before = reads(&db);
+        let manifest = db.export_manifest(false).unwrap();
+        assert_eq!(reads(&db), reads_before);
+        assert_eq!(manifest.entries.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(), paths);
+        assert_eq!((manifest.page_count, manifest.file_bytes), (0, 0));
+        for entry in &manifest.entries {
+            cxx::let_cxx_string!(path = entry.path.as_str());
+            let info = db.stat(&path).unwrap();
+            assert_eq!((&entry.uuid, entry.size, entry.version, entry.flags), (&info.uuid, info.size, info.version, info.flags), "{}", entry.path);
+            assert_eq!(entry.checksum, db.compute_crc(&db.read_document(&entry.path).unwrap()), "{}", entry.path);
+            assert!((before..=after).contains(&entry.modified));
+            assert_eq!(entry.stored_bytes, 0);
         }
-        if !trie.is_empty() {
-            let trie_page = self.allocate_page()?;
-            let root_node = trie.remove(&trie_root.page_id).unwrap_or_default();
-            self.write_raw_page(trie_page, &self.serialize_trie_node(&root_node)?, 0)?;
-            *self.trie_root.write() = VersionedLink { page_id: trie_page, version: 0 };
+        let by_path: HashMap<&str, &ffi::ManifestEntry> = manifest.entries.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+        assert_eq!(by_path["maps/e1m1.bin"].version, 2);
+        assert_eq!(by_path["def/weapons.def"].uuid, by_path["def/weapon_pistol.def"].uuid);
+        assert_eq!(by_path["def/weapons.def"].flags, DOCUMENT_PRECACHE);
+        assert_eq!(by_path["sound/empty.ogg"].size, 0);
+
+        // With physical stats, each chain's stored bytes and the file's size
+        let physical = db.export_manifest(true).unwrap();
+        assert_eq!(physical.page_count, db.page_count() as u64);
+        assert_eq!(physical.file_bytes, std::fs::metadata(dir.db()).unwrap().len());
+        for (entry, logical) in physical.entries.iter().zip(&manifest.entries) {
+            let doc = db.lookup_document(&resolves(&db, &entry.path).unwrap()).unwrap().unwrap();
+            let stored = if doc.first_page_id == -1 { 0 } else { db.chain_stored_bytes(doc.first_page_id).unwrap() };
+            assert_eq!(entry.stored_bytes, stored, "{}", entry.path);
+            assert_eq!((&entry.path, entry.checksum, entry.size), (&logical.path, logical.checksum, logical.size));
         }
+        assert_eq!(by_path["maps/e1m1.bin"].size, physical.entries[2].stored_bytes);
+        assert!(physical.entries[3].stored_bytes < physical.entries[3].size);
 
-        *self.current_size.lock() = current_size;
-        Ok(())
+        #[cfg(feature = "manifest")]
+        {
+            let json: serde_json::Value = serde_json::from_str(&db.export_manifest_json(true).unwrap()).unwrap();
+            assert_eq!(json["format_version"], FORMAT_VERSION);
+            assert_eq!(json["page_count"], physical.page_count);
+            let entries = json["entries"].as_array().unwrap();
+            assert_eq!(entries.len(), paths.len());
+            for (json, entry) in entries.iter().zip(&physical.entries) {
+                assert_eq!(json["path"], entry.path.as_str());
+                assert_eq!(json["uuid"], entry.uuid.as_str());
+                assert_eq!((json["size"].as_u64(), json["mtime"].as_u64()), (Some(entry.size), Some(entry.modified)));
+                assert_eq!(json["checksum"], format!("{:08x}", entry.checksum));
+                assert_eq!(json["stored_bytes"], entry.stored_bytes);
+            }
+        }
     }
 
-    fn validate_path(&self, path: &str) -> io::Result<()> {
-        if path.is_empty() || path.contains("..") || path.contains("::") || path.starts_with('/') || path.starts_with('\\') {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"));
+
+    #[test]
+    fn diffing_two_releases_lists_exactly_what_changed() {
+        let (old_dir, new_dir) = (TempDir::new(), TempDir::new());
+        let old = open(&old_dir, StreamDb::create_options());
+        // The patched release is laid out differently: zstd everywhere and small files in slabs
+        let new = open(&new_dir, StreamDb::create_options()
+            .compression(ffi::PageCodec::Zstd, 19)
+            .compression_rule("cfg", ffi::PageCodec::Zstd)
+            .compression_rule("map", ffi::PageCodec::Zstd)
+            .slab_threshold(256));
+        let map = b"{ \"classname\" \"worldspawn\" }\n".repeat(400);
+        let mut patched_map = map.clone();
+        patched_map[100] = b'W';
+        for (path, data) in [
+            ("maps/e1m1.map", &map[..]),
+            ("maps/e1m2.map", &map[..]),
+            ("scripts/doom.cfg", b"seta r_mode 3"),
+            ("scripts/old_only.cfg", b"removed in 1.2"),
+            ("sound/moved.ogg", b"renamed in 1.2"),
+            ("def/grown.def", b"short"),
+        ] {
+            old.write_document_unordered(path, data, true, false, false).unwrap();
         }
-        Ok(())
+        for (path, data) in [
+            ("maps/e1m1.map", &map[..]),
+            ("maps/e1m2.map", &patched_map[..]),
+            ("scripts/doom.cfg", b"seta r_mode 3"),
+            ("sound/renamed.ogg", b"renamed in 1.2"),
+            ("def/grown.def", b"somewhat longer"),
+            ("scripts/new_only.cfg", b"added in 1.2"),
+        ] {
+            new.write_document_unordered(path, data, true, false, false).unwrap();
+        }
+        // Same contents written twice is not a change, whatever the version says
+        new.write_document_unordered("scripts/doom.cfg", b"seta r_mode 3", true, false, false).unwrap();
+        drop(new);
+
+        let changes = |list: &[ffi::DocumentChange]| list.iter().map(|change| (change.path.as_str(), change.old_size, change.new_size)).collect::<Vec<_>>();
+        // Only the indexes are compared, so once those are cached nothing is read
+        old.export_manifest(false).unwrap();
+        let reads = old.cache_stats.lock().misses;
+        cxx::let_cxx_string!(new_path = new_dir.db().to_string_lossy().as_ref());
+        let diff = old.diff_db(&new_path).unwrap();
+        assert_eq!(old.cache_stats.lock().misses, reads);
+        assert_eq!(changes(&diff.added), [("scripts/new_only.cfg", 0, 12), ("sound/renamed.ogg", 0, 14)]);
+        assert_eq!(changes(&diff.removed), [("scripts/old_only.cfg", 14, 0), ("sound/moved.ogg", 14, 0)]);
+        assert_eq!(changes(&diff.modified), [("def/grown.def", 5, 15), ("maps/e1m2.map", map.len() as u64, map.len() as u64)]);
+
+        // The other way round, added and removed trade places
+        drop(old);
+        let new = open(&new_dir, StreamDb::create_options());
+        cxx::let_cxx_string!(old_path = old_dir.db().to_string_lossy().as_ref());
+        let reverse = new.diff_db(&old_path).unwrap();
+        assert_eq!(changes(&reverse.added), changes(&diff.removed).into_iter().map(|(path, old, new)| (path, new, old)).collect::<Vec<_>>());
+        assert_eq!(reverse.modified.len(), 2);
+
+        // A database against itself, and against a file that does not exist
+        let same = TempDir::new();
+        std::fs::copy(new_dir.db(), same.db()).unwrap();
+        cxx::let_cxx_string!(same_path = same.db().to_string_lossy().as_ref());
+        let none = new.diff_db(&same_path).unwrap();
+        assert!(none.added.is_empty() && none.removed.is_empty() && none.modified.is_empty());
+        cxx::let_cxx_string!(missing = old_dir.0.join("missing.db").to_string_lossy().as_ref());
+        assert_eq!(new.diff_db(&missing).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(!old_dir.0.join("missing.db").exists());
     }
 
-    fn read_raw_page(&self, page_id: i64) -> io::Result<Vec<u8>> {
-        if page_id < 0 || page_id >= self.config.max_pages {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
-        }
-        if let Some(cached) = self.page_cache.lock().get(&page_id) {
-            self.cache_stats.lock().hits += 1;
-            return Ok(cached.clone());
+
+    fn patch_releases() -> (TempDir, TempDir) {
+        let (old_dir, new_dir) = (TempDir::new(), TempDir::new());
+        let map = b"{ \"classname\" \"worldspawn\" }\n".repeat(2000);
+        let mut patched_map = map.clone();
+        patched_map[30_000..30_010].copy_from_slice(b"info_start");
+        let old = open(&old_dir, StreamDb::create_options());
+        for (path, data) in [
+            ("maps/e1m1.map", map.clone()),
+            ("maps/e1m2.map", b"version 1.1 of e1m2".to_vec()),
+            ("scripts/doom.cfg", b"seta r_mode 3".to_vec()),
+            ("scripts/old_only.cfg", b"removed in 1.2".to_vec()),
+        ] {
+            old.write_document_unordered(path, &data, true, false, false).unwrap();
         }
-        self.cache_stats.lock().misses += 1;
-        let offset = page_id as u64 * self.config.page_size + self.config.page_header_size;
-        let header = self.read_page_header(page_id)?;
-        let mut buffer = vec![0u8; header.data_length as usize];
-        if let Some(mmap) = self.mmap.read().as_ref() {
-            let start = offset as usize;
-            buffer.copy_from_slice(&mmap[start..start + header.data_length as usize]);
-        } else {
-            let mut file = self.file.lock();
-            file.seek(SeekFrom::Start(offset))?;
-            file.read_exact(&mut buffer)?;
+        let new = open(&new_dir, StreamDb::create_options().compression(ffi::PageCodec::Zstd, 3));
+        for (path, data) in [
+            ("maps/e1m1.map", patched_map),
+            ("maps/e1m2.map", b"e1m2, rebuilt for 1.2".to_vec()),
+            ("scripts/doom.cfg", b"seta r_mode 3".to_vec()),
+            ("scripts/new_only.cfg", b"added in 1.2".to_vec()),
+        ] {
+            new.write_document_unordered(path, &data, true, false, false).unwrap();
         }
-        if !self.quick_mode.load(std::sync::atomic::Ordering::SeqCst) {
-            let computed_crc = self.compute_crc(&buffer);
-            if computed_crc != header.crc {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch"));
+        (old_dir, new_dir)
+    }
+
+    fn make_patch(old_dir: &TempDir, new_dir: &TempDir) -> Vec<u8> {
+        let old = open(old_dir, StreamDb::create_options());
+        cxx::let_cxx_string!(new_path = new_dir.db().to_string_lossy().as_ref());
+        old.create_patch(&new_path).unwrap()
+    }
+
+    #[test]
+    fn a_patch_takes_the_old_release_to_exactly_the_new_one() {
+        let (old_dir, new_dir) = patch_releases();
+        let patch = make_patch(&old_dir, &new_dir);
+        let old_hash = open(&old_dir, StreamDb::create_options()).content_hash().unwrap();
+        let new_db = open(&new_dir, StreamDb::create_options());
+        let (new_hash, new_map) = (new_db.content_hash().unwrap(), new_db.read_document("maps/e1m1.map").unwrap());
+        drop(new_db);
+        // The one-line edit to the big map travels as a delta, not the whole file
+        assert!(patch.len() < new_map.len() / 4, "{} byte patch", patch.len());
+
+        let target = crash_image(&old_dir);
+        let mut db = open(&target, StreamDb::create_options());
+        let patch = cxx::CxxVector::from(patch);
+        assert_eq!(Pin::new(&mut db).apply_patch(&patch).unwrap(), 4);
+        assert_eq!(db.content_hash().unwrap(), new_hash);
+        assert_eq!(db.read_document("maps/e1m1.map").unwrap(), new_map);
+        assert_eq!(db.get_all_paths_sorted().unwrap(), ["maps/e1m1.map", "maps/e1m2.map", "scripts/doom.cfg", "scripts/new_only.cfg"]);
+        drop(db);
+        let mut db = open(&target, StreamDb::create_options());
+        assert_eq!(db.content_hash().unwrap(), new_hash);
+
+        // The patch is for 1.1; applied to 1.2 it is refused and changes nothing
+        let err = Pin::new(&mut db).apply_patch(&patch).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(db.content_hash().unwrap(), new_hash);
+
+        // A damaged patch is caught by the target hash before anything is published
+        let mut damaged = patch.as_slice().to_vec();
+        *damaged.last_mut().unwrap() ^= 0xff;
+        let target = crash_image(&old_dir);
+        let mut db = open(&target, StreamDb::create_options());
+        let page_count = db.page_count();
+        let err = Pin::new(&mut db).apply_patch(&cxx::CxxVector::from(damaged)).unwrap_err();
+        assert_eq!((err.kind(), err.to_string().as_str()), (io::ErrorKind::InvalidData, "Patched contents would not match the patch target"));
+        assert_eq!(db.content_hash().unwrap(), old_hash);
+        // The staged chains went back on the free list
+        assert!(db.free_list_pages(&db.lock_allocation()).unwrap().len() as i64 >= db.page_count() - page_count);
+        assert_eq!(Pin::new(&mut db).apply_patch(&cxx::CxxVector::from(b"not a patch".to_vec())).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Between equal contents the patch is empty
+        let patch = make_patch(&old_dir, &crash_image(&old_dir));
+        assert_eq!(Pin::new(&mut db).apply_patch(&cxx::CxxVector::from(patch)).unwrap(), 0);
+        assert_eq!(db.content_hash().unwrap(), old_hash);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn a_patch_interrupted_at_any_write_leaves_the_old_release_or_the_new_one() {
+        let (old_dir, new_dir) = patch_releases();
+        let patch = cxx::CxxVector::from(make_patch(&old_dir, &new_dir));
+        let old_hash = open(&old_dir, StreamDb::create_options()).content_hash().unwrap();
+        let new_hash = open(&new_dir, StreamDb::create_options()).content_hash().unwrap();
+
+        let image = crash_image(&old_dir);
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let mut db = StreamDb::open_with_faults(&image.db(), true, schedule.clone()).unwrap();
+        let before = schedule.lock().writes;
+        Pin::new(&mut db).apply_patch(&patch).unwrap();
+        let writes = schedule.lock().writes - before;
+        drop(db);
+        assert!(writes > 3);
+
+        for n in 1..=writes {
+            let image = crash_image(&old_dir);
+            let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+            let mut db = StreamDb::open_with_faults(&image.db(), true, schedule.clone()).unwrap();
+            {
+                let mut schedule = schedule.lock();
+                schedule.fail_write = Some(schedule.writes + n);
+                schedule.halt = true;
+            }
+            assert!(Pin::new(&mut db).apply_patch(&patch).is_err(), "write {} did not fail", n);
+            drop(db);
+
+            let db = open(&image, StreamDb::create_options());
+            let hash = db.content_hash().unwrap();
+            assert!(hash == old_hash || hash == new_hash, "crash at write {} left a partial patch", n);
+            if n == 1 {
+                assert_eq!(hash, old_hash);
             }
         }
-        let data = if self.config.use_compression {
-            snappy::decompress(&buffer)?
-        } else {
-            buffer
+    }
+
+
+    #[test]
+    fn syncing_a_one_percent_edit_fetches_about_one_percent() {
+        let (server_dir, client_dir) = (TempDir::new(), TempDir::new());
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
         };
-        self.page_cache.lock().put(page_id, data.clone());
-        Ok(data)
+        let old: Vec<u8> = (0..10 << 20).map(|_| next() as u8).collect();
+        // Ten 10KB edits spread through the file, one of them an insertion that shifts the rest
+        let mut new = old.clone();
+        for edit in 0..10 {
+            let at = edit * (1 << 20) + 4096;
+            if edit == 5 {
+                let inserted: Vec<u8> = (0..10_240).map(|_| next() as u8).collect();
+                new.splice(at..at, inserted);
+            } else {
+                new[at..at + 10_240].iter_mut().for_each(|byte| *byte = next() as u8);
+            }
+        }
+        let server = open(&server_dir, StreamDb::create_options());
+        server.write_document_unordered("maps/e1m1.bin", &new, true, false, false).unwrap();
+        let mut client = open(&client_dir, StreamDb::create_options());
+        client.write_document_unordered("maps/e1m1.bin", &old, true, false, false).unwrap();
+
+        cxx::let_cxx_string!(path = "maps/e1m1.bin");
+        let signatures = server.get_chunk_signatures(&path).unwrap();
+        assert_eq!(signatures.iter().map(|signature| signature.length).sum::<u64>(), new.len() as u64);
+        assert!(signatures.iter().all(|signature| signature.length as usize <= CHUNK_MAX_SIZE));
+        assert!(signatures.len() > 10 << 20 >> 14, "{} chunks", signatures.len());
+
+        let missing = client.missing_chunks(&path, &signatures).unwrap();
+        let wanted: u64 = missing.iter().map(|&index| signatures[index as usize].length).sum();
+        assert!(wanted >= 100 * 1024 && wanted < new.len() as u64 / 20, "{} bytes missing", wanted);
+
+        // A chunk that does not match its signature is refused before anything is written
+        let mut chunks: Vec<ffi::ChunkData> = missing.iter()
+            .map(|&index| {
+                let signature = &signatures[index as usize];
+                ffi::ChunkData { index, data: new[signature.offset as usize..(signature.offset + signature.length) as usize].to_vec() }
+            })
+            .collect();
+        chunks[0].data[0] ^= 1;
+        let err = Pin::new(&mut client).sync_document_from(&path, &signatures, &chunks).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(client.read_document("maps/e1m1.bin").unwrap() == old);
+        chunks[0].data[0] ^= 1;
+
+        // Through the provider, every byte it hands over is counted
+        let mut provided = 0u64;
+        let fetched = client.sync_document_with("maps/e1m1.bin", &signatures, &mut |index, _| {
+            let data = chunks.iter().find(|chunk| chunk.index == index as u64).unwrap().data.clone();
+            provided += data.len() as u64;
+            Ok(data)
+        }).unwrap();
+        assert_eq!((fetched, provided), (wanted, wanted));
+        assert!(client.read_document("maps/e1m1.bin").unwrap() == new);
+        assert_eq!(client.lookup_document(&resolves(&client, "maps/e1m1.bin").unwrap()).unwrap().unwrap().checksum, client.compute_crc(&new));
+
+        // In sync, nothing is missing and nothing is fetched
+        assert!(client.missing_chunks(&path, &signatures).unwrap().is_empty());
+        assert_eq!(Pin::new(&mut client).sync_document_from(&path, &signatures, &Vec::new()).unwrap(), 0);
+        // A client with no copy at all fetches everything
+        cxx::let_cxx_string!(fresh = "maps/e1m1_copy.bin");
+        assert_eq!(client.missing_chunks(&fresh, &signatures).unwrap().len(), signatures.len());
     }
 
-    fn write_raw_page(&self, page_id: i64, data: &[u8], version: i32) -> io::Result<()> {
-        if page_id < 0 || page_id >= self.config.max_pages {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
+
+    #[test]
+    fn bypassing_scans_leave_the_hot_pages_cached() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().cache_sizes(256, 1024));
+        let hot: Vec<String> = (0..8).map(|i| format!("scripts/hot{}.cfg", i)).collect();
+        let cold: Vec<String> = (0..400).map(|i| format!("scripts/cold{}.cfg", i)).collect();
+        for path in hot.iter().chain(&cold) {
+            db.write_document_unordered(path, format!("seta {} 1\n", path).repeat(40).as_bytes(), true, false, false).unwrap();
         }
-        let mut compressed = if self.config.use_compression {
-            snappy::compress(data)
-        } else {
-            data.to_vec()
+        let hot_pages: Vec<i64> = hot.iter().map(|path| db.lookup_document(&resolves(&db, path).unwrap()).unwrap().unwrap().first_page_id).collect();
+        // Compressed, so their reads go through the page cache rather than the mapping
+        assert!(hot_pages.iter().all(|&page_id| db.read_page_header(page_id).unwrap().flags & FLAG_COMPRESSED != 0));
+        let cached = |db: &StreamDb| hot_pages.iter().filter(|&&page_id| db.lock_page_cache(page_id).iter().any(|(&(id, _), _)| id == page_id)).count();
+        let stats = |db: &StreamDb| {
+            let stats = db.cache_stats.lock();
+            (stats.hits, stats.misses, stats.bypassed)
         };
-        if compressed.len() as u64 > self.config.page_size - self.config.page_header_size {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Data too large for page"));
+
+        db.clear_page_cache();
+        for path in &hot {
+            db.read_document(path).unwrap();
         }
-        let crc = self.compute_crc(&compressed);
-        let header = PageHeader {
-            crc,
-            version,
-            prev_page_id: -1,
-            next_page_id: -1,
-            flags: FLAG_DATA_PAGE,
-            data_length: compressed.len() as i32,
-            padding: [0; 3],
+        assert_eq!(cached(&db), hot.len());
+
+        // Verification and physical manifests read every page once without promoting any
+        let bypassed = stats(&db).2;
+        assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+        db.export_manifest(true).unwrap();
+        assert_eq!(cached(&db), hot.len());
+        assert!(stats(&db).2 >= bypassed + cold.len());
+
+        // So does a bypassing bulk get, which still returns every document
+        let options = ffi::ReadOptions { bypass_cache: true };
+        let (hits, misses, bypassed) = stats(&db);
+        let documents = db.get_many_with_options(&cold, &options).unwrap();
+        assert_eq!(documents.len(), cold.len());
+        for (document, path) in documents.iter().zip(&cold) {
+            assert_eq!(document.data, format!("seta {} 1\n", path).repeat(40).as_bytes());
+        }
+        assert_eq!(stats(&db), (hits, misses, bypassed + cold.len()));
+        assert_eq!(cached(&db), hot.len());
+        // Hits are still served from the cache
+        cxx::let_cxx_string!(path = hot[0].as_str());
+        db.get_with_options(&path, &options).unwrap();
+        assert_eq!(stats(&db), (hits + 1, misses, bypassed + cold.len()));
+
+        // An ordinary pass over the same documents flushes them out
+        for path in &cold {
+            db.read_document(path).unwrap();
+        }
+        assert_eq!(stats(&db).1, misses + cold.len());
+        assert!(cached(&db) < hot.len());
+    }
+
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct_io_round_trips_unaligned_writes_and_odd_sized_tails() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().direct_io(true));
+        // Where the filesystem (tmpfs, for one) refuses O_DIRECT the open falls back to buffered IO
+        let direct = DirectStorage::open(&dir.db()).is_ok();
+        assert_eq!(db.get_db_stats().direct_io, direct);
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let sizes = [0, 1, 511, 4095, 4097, capacity - 1, capacity, capacity + 1, 3 * capacity + 17];
+        let contents = |size: usize| (0..size).map(|i| (i * 7 + size) as u8).collect::<Vec<u8>>();
+        for size in sizes {
+            db.write_document_unordered(&format!("maps/tail{}.bin", size), &contents(size), true, false, false).unwrap();
+        }
+        for size in sizes {
+            assert!(db.read_document(&format!("maps/tail{}.bin", size)).unwrap() == contents(size), "{} bytes", size);
+        }
+        drop(db);
+        let db = open(&dir, StreamDb::create_options().direct_io(true));
+        for size in sizes {
+            assert!(db.read_document(&format!("maps/tail{}.bin", size)).unwrap() == contents(size), "{} bytes after reopen", size);
+        }
+        assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+        drop(db);
+        if !direct {
+            return;
+        }
+
+        // Below the page layer: offsets and lengths that cover parts of blocks
+        let raw = TempDir::new();
+        std::fs::write(raw.db(), b"").unwrap();
+        let storage = DirectStorage::open(&raw.db()).unwrap();
+        storage.write_at(5, b"abc").unwrap();
+        assert_eq!(storage.len().unwrap(), 8);
+        let straddling = vec![0xa5u8; 10];
+        storage.write_at(DIRECT_IO_ALIGNMENT as u64 - 3, &straddling).unwrap();
+        assert_eq!(storage.len().unwrap(), DIRECT_IO_ALIGNMENT as u64 + 7);
+        let mut buffer = [0u8; 3];
+        storage.read_at(5, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"abc");
+        let mut buffer = vec![0u8; 10];
+        storage.read_at(DIRECT_IO_ALIGNMENT as u64 - 3, &mut buffer).unwrap();
+        assert_eq!(buffer, straddling);
+        // The bytes around a partial write are kept, not zeroed by the whole-block write
+        storage.write_at(6, b"X").unwrap();
+        let mut buffer = [0u8; 3];
+        storage.read_at(5, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"aXc");
+        let err = storage.read_at(DIRECT_IO_ALIGNMENT as u64, &mut [0u8; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        drop(storage);
+        let file = std::fs::read(raw.db()).unwrap();
+        assert_eq!(&file[5..8], b"aXc");
+        assert_eq!(&file[DIRECT_IO_ALIGNMENT - 3..], &straddling[..]);
+    }
+
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn in_place_writes_fdatasync_and_growth_fsyncs() {
+        let dir = TempDir::new();
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let mut db = StreamDb::open_with_faults(&dir.db(), false, schedule.clone()).unwrap();
+        assert!(db.config.durable_writes);
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let counts = || {
+            let mut schedule = schedule.lock();
+            let counts = (schedule.full_syncs, schedule.data_syncs, schedule.write_backs);
+            (schedule.full_syncs, schedule.data_syncs, schedule.write_backs) = (0, 0, 0);
+            counts
         };
-        self.write_page_header(page_id, &header)?;
-        let offset = page_id as u64 * self.config.page_size + self.config.page_header_size;
-        if let Some(mmap) = self.mmap.write().as_mut() {
-            let start = offset as usize;
-            mmap[start..start + compressed.len()].copy_from_slice(&compressed);
-            mmap.flush()?;
-        } else {
-            let mut file = self.file.lock();
-            file.seek(SeekFrom::Start(offset))?;
-            file.write_all(&compressed)?;
-            file.flush()?;
+
+        // Growing the file needs its new length made durable too
+        counts();
+        let file_len = std::fs::metadata(dir.db()).unwrap().len();
+        for i in 0..20 {
+            db.write_document_unordered(&format!("maps/area{}.bin", i), &vec![i as u8; capacity * 4], true, false, false).unwrap();
         }
-        self.page_cache.lock().pop(&page_id);
-        Ok(())
+        assert!(std::fs::metadata(dir.db()).unwrap().len() > file_len);
+        let (full_syncs, _, _) = counts();
+        assert!(full_syncs > 0);
+
+        // Rewriting pages inside the file only needs their data
+        for i in 0..10 {
+            cxx::let_cxx_string!(path = format!("maps/area{}.bin", i));
+            Pin::new(&mut db).delete_by_path(&path).unwrap();
+        }
+        counts();
+        let file_len = std::fs::metadata(dir.db()).unwrap().len();
+        db.write_document_unordered("maps/area0.bin", &vec![0xaa; capacity * 4], true, false, false).unwrap();
+        assert_eq!(std::fs::metadata(dir.db()).unwrap().len(), file_len);
+        let (full_syncs, data_syncs, write_backs) = counts();
+        assert_eq!(full_syncs, 0);
+        assert!(data_syncs > 0);
+        // Far less than a write-back batch was written, so none was started
+        assert_eq!(write_backs, 0);
+
+        // A write of several batches starts their write-back before the sync
+        db.write_document_unordered("maps/big.bin", &vec![0x55; 3 * WRITE_BACK_BATCH_BYTES as usize], true, false, false).unwrap();
+        let (full_syncs, _, write_backs) = counts();
+        assert!(write_backs >= 2, "{} write-backs", write_backs);
+        assert!(full_syncs > 0);
+        drop(db);
+
+        // SyncPolicy::Full fsyncs even in place
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let config = Config { io_mode: ffi::IoMode::FileOnly, sync_policy: ffi::SyncPolicy::Full, faults: Some(schedule.clone()), ..Default::default() };
+        let file = StreamDb::db_open_options().open(dir.db()).unwrap();
+        let mut db = StreamDb::open_file_with_config(file, &dir.db(), config, false).unwrap();
+        for i in 10..20 {
+            cxx::let_cxx_string!(path = format!("maps/area{}.bin", i));
+            Pin::new(&mut db).delete_by_path(&path).unwrap();
+        }
+        let file_len = std::fs::metadata(dir.db()).unwrap().len();
+        let before = (schedule.lock().full_syncs, schedule.lock().data_syncs);
+        db.write_document_unordered("maps/area10.bin", b"in place", true, false, false).unwrap();
+        assert_eq!(std::fs::metadata(dir.db()).unwrap().len(), file_len);
+        let schedule = schedule.lock();
+        assert!(schedule.full_syncs > before.0);
+        assert_eq!(schedule.data_syncs, before.1);
     }
 
-    fn write_page_header(&self, page_id: i64, header: &PageHeader) -> io::Result<()> {
-        let offset = page_id as u64 * self.config.page_size;
-        let mut buffer = Vec::new();
-        let mut writer = BufWriter::new(&mut buffer);
-        writer.write_u32::<LittleEndian>(header.crc)?;
-        writer.write_i32::<LittleEndian>(header.version)?;
-        writer.write_i64::<LittleEndian>(header.prev_page_id)?;
-        writer.write_i64::<LittleEndian>(header.next_page_id)?;
-        writer.write_u8(header.flags)?;
-        writer.write_i32::<LittleEndian>(header.data_length)?;
-        writer.write_all(&header.padding)?;
-        let data = buffer;
-        if let Some(mmap) = self.mmap.write().as_mut() {
-            let start = offset as usize;
-            mmap[start..start + self.config.page_header_size as usize].copy_from_slice(&data);
-            mmap.flush()?;
-        } else {
-            let mut file = self.file.lock();
-            file.seek(SeekFrom::Start(offset))?;
-            file.write_all(&data)?;
-            file.flush()?;
+
+    // Counts the allocations of at least LARGE_ALLOCATION bytes made on each thread, so a test
+    // can tell a buffer reserved once from one regrown page by page
+    struct CountingAllocator;
+
+    const LARGE_ALLOCATION: usize = 1 << 20;
+
+    thread_local! {
+        static LARGE_ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn count_large_allocation(size: usize) {
+        if size >= LARGE_ALLOCATION {
+            let _ = LARGE_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
         }
-        Ok(())
     }
 
-    fn read_page_header(&self, page_id: i64) -> io::Result<PageHeader> {
-        if page_id < 0 || page_id >= self.config.max_pages {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID"));
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            count_large_allocation(layout.size());
+            std::alloc::System.alloc(layout)
         }
-        let offset = page_id as u64 * self.config.page_size;
-        let mut buffer = vec![0u8; self.config.page_header_size as usize];
-        if let Some(mmap) = self.mmap.read().as_ref() {
-            let start = offset as usize;
-            buffer.copy_from_slice(&mmap[start..start + self.config.page_header_size as usize]);
-        } else {
-            let mut file = self.file.lock();
-            file.seek(SeekFrom::Start(offset))?;
-            file.read_exact(&mut buffer)?;
+
+        unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+            count_large_allocation(layout.size());
+            std::alloc::System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+            count_large_allocation(new_size);
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
         }
-        let mut reader = Cursor::new(buffer);
-        let crc = reader.read_u32::<LittleEndian>()?;
-        let version = reader.read_i32::<LittleEndian>()?;
-        let prev_page_id = reader.read_i64::<LittleEndian>()?;
-        let next_page_id = reader.read_i64::<LittleEndian>()?;
-        let flags = reader.read_u8()?;
-        let data_length = reader.read_i32::<LittleEndian>()?;
-        let mut padding = [0u8; 3];
-        reader.read_exact(&mut padding)?;
-        Ok(PageHeader {
-            crc,
-            version,
-            prev_page_id,
-            next_page_id,
-            flags,
-            data_length,
-            padding,
-        })
     }
 
-    fn allocate_page(&self) -> io::Result<i64> {
-        if let Ok(page_id) = self.pop_free_page() {
-            *self.empty_free_list_count.lock() = 0;
-            return Ok(page_id);
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn large_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = LARGE_ALLOCATIONS.with(|count| count.get());
+        let result = f();
+        (result, LARGE_ALLOCATIONS.with(|count| count.get()) - before)
+    }
+
+    #[test]
+    fn a_large_get_allocates_its_buffer_once_at_the_stored_size() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let video: Vec<u8> = (0..32u32 << 20).map(|i| (i % 251) as u8).collect();
+        db.write_document_unordered("video/intro.bin", &video, true, false, false).unwrap();
+
+        let (data, allocations) = large_allocations(|| db.read_document("video/intro.bin").unwrap());
+        assert!(data == video);
+        assert_eq!(allocations, 1);
+        assert_eq!(data.capacity(), video.len());
+        drop(data);
+
+        // get_many reserves each document once too
+        for i in 0..3 {
+            db.write_document_unordered(&format!("video/cut{}.bin", i), &video[..(4 << 20) + i * 1000], true, false, false).unwrap();
         }
-        let mut empty_count = self.empty_free_list_count.lock();
-        *empty_count += 1;
-        if *empty_count >= MAX_CONSECUTIVE_EMPTY_FREE_LIST {
-            let new_pages = self.grow_file(BATCH_GROW_PAGES)?;
-            *empty_count = 0;
-            return Ok(new_pages);
+        let paths: Vec<String> = (0..3).map(|i| format!("video/cut{}.bin", i)).collect();
+        let (documents, allocations) = large_allocations(|| db.get_many(&paths).unwrap());
+        assert_eq!(allocations, 3);
+        for (i, document) in documents.iter().enumerate() {
+            assert!(document.data[..] == video[..(4 << 20) + i * 1000]);
         }
-        let page_id = {
-            let mut current_size = self.current_size.lock();
-            let page_id = (*current_size / self.config.page_size) as i64;
-            if page_id >= self.config.max_pages {
-                return Err(io::Error::new(io::ErrorKind::Other, "Max pages exceeded"));
-            }
-            *current_size += self.config.page_size;
-            page_id
-        };
-        let mut file = self.file.lock();
-        file.set_len(page_id as u64 * self.config.page_size + self.config.page_size)?;
-        Ok(page_id)
+        drop(documents);
+
+        // Appends keep the stored size the reads reserve by
+        cxx::let_cxx_string!(path = "video/cut0.bin");
+        let handle = Pin::new(&mut db).open_append(&path).unwrap();
+        Pin::new(&mut db).append(handle, &video[..LARGE_ALLOCATION]).unwrap();
+        Pin::new(&mut db).close_append(handle).unwrap();
+        let expected = [&video[..4 << 20], &video[..LARGE_ALLOCATION]].concat();
+        assert_eq!(db.stat(&path).unwrap().size, expected.len() as u64);
+        let (data, allocations) = large_allocations(|| db.read_document("video/cut0.bin").unwrap());
+        assert!(data == expected);
+        assert_eq!(allocations, 1);
+        drop(data);
+
+        // A chain that no longer adds up to the stored size is corrupt, and says whose it is
+        let mut index = db.read_index().unwrap();
+        index.get_mut(&resolves(&db, "video/intro.bin").unwrap()).unwrap().size -= 1;
+        db.write_index(&index).unwrap();
+        let err = db.read_document("video/intro.bin").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Corrupt document size") && err.to_string().contains("video/intro.bin"), "{}", err);
     }
 
-    fn pop_free_page(&self) -> io::Result<i64> {
-        let mut free_root = self.free_list_root.lock();
-        if free_root.page_id == -1 {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "No free pages"));
+
+    #[test]
+    fn stored_sizes_and_page_counts_survive_reopen_and_are_repaired_when_wrong() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().use_compression(false));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let sizes = [("maps/empty.bin", 0), ("maps/small.bin", 100), ("maps/exact.bin", capacity * 2), ("maps/large.bin", capacity * 5 + 1)];
+        for (path, size) in sizes {
+            db.write_document_unordered(path, &vec![7u8; size], true, false, false).unwrap();
         }
-        let offset = free_root.page_id as u64 * self.config.page_size + self.config.page_header_size;
-        let mut buffer = vec![0u8; FREE_LIST_HEADER_SIZE as usize + 8];
-        if let Some(mmap) = self.mmap.read().as_ref() {
-            let start = offset as usize;
-            buffer.copy_from_slice(&mmap[start..start + FREE_LIST_HEADER_SIZE as usize + 8]);
-        } else {
-            let mut file = self.file.lock();
-            file.seek(SeekFrom::Start(offset))?;
-            file.read_exact(&mut buffer)?;
+        let expect = |db: &StreamDb, path: &str, size: usize| {
+            cxx::let_cxx_string!(cxx_path = path);
+            let info = db.stat(&cxx_path).unwrap();
+            let doc = db.lookup_document(&resolves(db, path).unwrap()).unwrap().unwrap();
+            let pages = if size == 0 { 0 } else { chain_pages(db, doc.first_page_id).len() as u32 };
+            assert_eq!((info.size, info.page_count), (size as u64, pages), "{}", path);
+            assert_eq!((doc.size, doc.page_count), (size as u64, pages), "{}", path);
+        };
+        for (path, size) in sizes {
+            expect(&db, path, size);
         }
-        let mut reader = Cursor::new(buffer);
-        let next_free_list_page = reader.read_i64::<LittleEndian>()?;
-        let used_entries = reader.read_i32::<LittleEndian>()?;
-        if used_entries <= 0 {
-            free_root.page_id = next_free_list_page;
-            return Err(io::Error::new(io::ErrorKind::NotFound, "No free pages in list"));
+        assert_eq!(db.lookup_document(&resolves(&db, "maps/large.bin").unwrap()).unwrap().unwrap().page_count, 6);
+
+        // Appends and new versions keep them current
+        cxx::let_cxx_string!(small = "maps/small.bin");
+        let handle = Pin::new(&mut db).open_append(&small).unwrap();
+        Pin::new(&mut db).append(handle, &vec![8u8; capacity]).unwrap();
+        Pin::new(&mut db).close_append(handle).unwrap();
+        db.write_document_unordered("maps/large.bin", &vec![9u8; capacity + 1], true, false, false).unwrap();
+        let sizes = [("maps/empty.bin", 0), ("maps/small.bin", 100 + capacity), ("maps/exact.bin", capacity * 2), ("maps/large.bin", capacity + 1)];
+        for (path, size) in sizes {
+            expect(&db, path, size);
         }
-        let page_id = reader.read_i64::<LittleEndian>()?;
-        self.update_free_list_used(free_root.page_id, used_entries - 1)?;
-        if used_entries == 1 {
-            free_root.page_id = next_free_list_page;
+        drop(db);
+        let mut db = open(&dir, StreamDb::create_options().use_compression(false));
+        for (path, size) in sizes {
+            expect(&db, path, size);
         }
-        Ok(page_id)
-    }
+        assert!(db.verify_db(true).unwrap().size_mismatches.is_empty());
 
-    fn update_free_list_used(&self, page_id: i64, used_entries: i32) -> io::Result<()> {
-        let offset = page_id as u64 * self.config.page_size + self.config.page_header_size;
-        let mut buffer = Vec::new();
-        let mut writer = BufWriter::new(&mut buffer);
-        writer.write_i64::<LittleEndian>(-1)?;
-        writer.write_i32::<LittleEndian>(used_entries)?;
-        let data = buffer;
-        if let Some(mmap) = self.mmap.write().as_mut() {
-            let start = offset as usize;
-            mmap[start..start + FREE_LIST_HEADER_SIZE as usize].copy_from_slice(&data);
-            mmap.flush()?;
-        } else {
-            let mut file = self.file.lock();
-            file.seek(SeekFrom::Start(offset))?;
-            file.write_all(&data)?;
-            file.flush()?;
+        // Falsified entries are reported by a deep verify and remeasured by repair
+        let mut index = db.read_index().unwrap();
+        index.get_mut(&resolves(&db, "maps/exact.bin").unwrap()).unwrap().size += 10;
+        index.get_mut(&resolves(&db, "maps/large.bin").unwrap()).unwrap().page_count = 40;
+        db.write_index(&index).unwrap();
+        assert!(db.verify_db(false).unwrap().size_mismatches.is_empty());
+        let mut reported = db.verify_db(true).unwrap().size_mismatches;
+        reported.sort();
+        assert_eq!(reported, ["maps/exact.bin", "maps/large.bin"]);
+        assert_eq!(Pin::new(&mut db).repair_db().unwrap().size_mismatches.len(), 2);
+        drop(db);
+        let db = open(&dir, StreamDb::create_options().use_compression(false));
+        assert!(db.verify_db(true).unwrap().size_mismatches.is_empty());
+        for (path, size) in sizes {
+            expect(&db, path, size);
         }
-        Ok(())
+
+        // Entries written before page counts were stored are counted as they migrate
+        downgrade_index(&db, 16);
+        drop(db);
+        assert_eq!(stamped_format_version(&dir), 16);
+        let db = open(&dir, StreamDb::create_options().use_compression(false));
+        assert_eq!(stamped_format_version(&dir), FORMAT_VERSION);
+        for (path, size) in sizes {
+            expect(&db, path, size);
+        }
+    }
+
+
+    #[test]
+    fn physical_sizes_match_the_bytes_a_documents_pages_hold() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().slab_threshold(256));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let header_size = db.config.page_header_size;
+        let raw: Vec<u8> = (0..capacity * 3 + 500).map(|i| (i * 31 % 256) as u8).collect();
+        db.write_document_unordered("maps/raw.bin", &raw, true, false, false).unwrap();
+        let map = b"{ \"classname\" \"light\" }\n".repeat(2000);
+        db.write_document_unordered("maps/e1m1.map", &map, true, false, false).unwrap();
+        db.write_document_unordered("scripts/tiny.cfg", b"seta r_mode 3", true, false, false).unwrap();
+        db.write_document_unordered("sound/empty.ogg", b"", true, false, false).unwrap();
+        // What each chain's pages hold, read payload and all
+        let occupied = |path: &str| -> u64 {
+            let first_page_id = db.lookup_document(&resolves(&db, path).unwrap()).unwrap().unwrap().first_page_id;
+            if StreamDb::slab_record(first_page_id).is_some() {
+                return db.chain_stored_bytes(first_page_id).unwrap();
+            }
+            chain_pages(&db, first_page_id).into_iter().map(|page_id| db.read_page_payload(page_id).unwrap().1.len() as u64 + header_size).sum()
+        };
+
+        db.read_index().unwrap();
+        let reads = |db: &StreamDb| {
+            let stats = db.cache_stats.lock();
+            (stats.hits, stats.misses, stats.bypassed)
+        };
+        let reads_before = reads(&db);
+        let physical = |path: &str| {
+            cxx::let_cxx_string!(cxx_path = path);
+            db.get_physical_size(&cxx_path).unwrap()
+        };
+        let sizes: Vec<u64> = ["maps/raw.bin", "maps/e1m1.map", "scripts/tiny.cfg", "sound/empty.ogg"].map(physical).to_vec();
+        let top = db.top_documents_by_physical_size(3).unwrap();
+        assert_eq!(reads(&db), reads_before);
+
+        // Uncompressed: the contents plus a header for each of its four pages
+        assert_eq!(sizes[0], raw.len() as u64 + 4 * header_size);
+        assert_eq!(sizes[0], occupied("maps/raw.bin"));
+        // Compressed: what snappy left, well under the logical size
+        assert_eq!(sizes[1], occupied("maps/e1m1.map"));
+        assert!(sizes[1] * 4 < map.len() as u64);
+        // In a slab: its record, the slab's header being shared
+        assert_eq!(sizes[2], occupied("scripts/tiny.cfg"));
+        assert!(sizes[2] < header_size + 13 + 16);
+        assert_eq!(sizes[3], occupied("sound/empty.ogg"));
+
+        let listed: Vec<(&str, u64, u64)> = top.iter().map(|entry| (entry.path.as_str(), entry.logical_size, entry.physical_size)).collect();
+        assert_eq!(listed, [("maps/raw.bin", raw.len() as u64, sizes[0]), ("maps/e1m1.map", map.len() as u64, sizes[1]), ("scripts/tiny.cfg", 13, sizes[2])]);
+        assert!(top[0].compression_ratio < 1.0);
+        assert!((top[1].compression_ratio - map.len() as f64 / sizes[1] as f64).abs() < 1e-9 && top[1].compression_ratio > 4.0);
+        assert_eq!(db.top_documents_by_physical_size(10).unwrap().len(), 4);
+        assert_eq!(db.top_documents_by_physical_size(10).unwrap()[3].compression_ratio, 0.0);
+        cxx::let_cxx_string!(missing = "maps/missing.bin");
+        assert_eq!(db.get_physical_size(&missing).unwrap_err().kind(), io::ErrorKind::NotFound);
     }
 
-    fn grow_file(&self, num_pages: u64) -> io::Result<i64> {
-        let mut current_size = self.current_size.lock();
-        let new_size = *current_size + num_pages * self.config.page_size;
-        if new_size / self.config.page_size as u64 > self.config.max_pages as u64 {
-            return Err(io::Error::new(io::ErrorKind::Other, "Max pages exceeded"));
+
+    #[test]
+    fn every_page_type_is_stamped_on_disk_after_a_mixed_workload() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options().slab_threshold(256));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let paths: Vec<String> = (0..60).map(|i| format!("maps/mixed/area{}.{}", i, ["bin", "cfg", "ogg"][i % 3])).collect();
+        for (i, path) in paths.iter().enumerate() {
+            db.write_document_unordered(path, &vec![i as u8; (i % 7) * capacity / 2 + 40], true, false, false).unwrap();
         }
-        let mut file = self.file.lock();
-        file.set_len(new_size)?;
-        *current_size = new_size;
-        Ok((new_size / self.config.page_size) as i64 - num_pages as i64)
-    }
+        cxx::let_cxx_string!(log = "logs/console.log");
+        let handle = Pin::new(&mut db).open_append(&log).unwrap();
+        for _ in 0..3 {
+            Pin::new(&mut db).append(handle, &vec![b'x'; capacity]).unwrap();
+        }
+        Pin::new(&mut db).close_append(handle).unwrap();
+        for path in paths.iter().step_by(4) {
+            cxx::let_cxx_string!(path = path.as_str());
+            Pin::new(&mut db).delete_by_path(&path).unwrap();
+        }
+        let kept: Vec<&str> = paths.iter().enumerate().filter(|(i, _)| i % 4 != 0).map(|(_, path)| path.as_str()).chain(["logs/console.log"]).collect();
+        let page_type = |page_id: i64| StreamDb::page_type(db.read_page_header(page_id).unwrap().flags);
 
-    fn serialize_index(&self, index: &BTreeMap<Uuid, Document>) -> io::Result<Vec<u8>> {
-        let mut buffer = Vec::new();
-        let mut writer = BufWriter::new(&mut buffer);
-        writer.write_i32::<LittleEndian>(index.len() as i32)?;
-        for (id, doc) in index {
-            writer.write_all(id.as_bytes())?;
-            writer.write_i64::<LittleEndian>(doc.first_page_id)?;
-            writer.write_i32::<LittleEndian>(doc.current_version)?;
-            writer.write_i32::<LittleEndian>(doc.paths.len() as i32)?;
-            for path in &doc.paths {
-                let bytes = path.as_bytes();
-                writer.write_i32::<LittleEndian>(bytes.len() as i32)?;
-                writer.write_all(bytes)?;
+        // Documents: linked data pages, or a slab for the small ones
+        let mut appended = false;
+        for path in &kept {
+            let first_page_id = db.lookup_document(&resolves(&db, path).unwrap()).unwrap().unwrap().first_page_id;
+            if let Some((slab_page_id, _)) = StreamDb::slab_record(first_page_id) {
+                assert_eq!(page_type(slab_page_id), FLAG_SLAB_PAGE, "{}", path);
+                continue;
+            }
+            for page_id in chain_pages(&db, first_page_id) {
+                assert_eq!(page_type(page_id), FLAG_DATA_PAGE, "{} page {}", path, page_id);
+                appended |= db.read_page_header(page_id).unwrap().flags & FLAG_APPEND_PAGE != 0;
             }
         }
-        Ok(buffer)
-    }
+        assert!(appended);
 
-    fn deserialize_index(&self, data: &[u8]) -> io::Result<BTreeMap<Uuid, Document>> {
-        let mut index = BTreeMap::new();
-        let mut reader = Cursor::new(data);
-        let count = reader.read_i32::<LittleEndian>()?;
-        for _ in 0..count {
-            let mut id_bytes = [0u8; 16];
-            reader.read_exact(&mut id_bytes)?;
-            let id = Uuid::from_bytes(id_bytes);
-            let first_page_id = reader.read_i64::<LittleEndian>()?;
-            let current_version = reader.read_i32::<LittleEndian>()?;
-            let path_count = reader.read_i32::<LittleEndian>()?;
-            let mut paths = Vec::with_capacity(path_count as usize);
-            for _ in 0..path_count {
-                let len = reader.read_i32::<LittleEndian>()?;
-                let mut path_bytes = vec![0u8; len as usize];
-                reader.read_exact(&mut path_bytes)?;
-                paths.push(String::from_utf8(path_bytes)?);
-            }
-            index.insert(id, Document { id, first_page_id, current_version, paths });
+        // Index, trie, path hash and free list pages each carry their own type
+        let mut index_pages = Vec::new();
+        db.index_node_pages(db.document_index_root.read().page_id, &mut index_pages).unwrap();
+        assert!(!index_pages.is_empty());
+        assert!(index_pages.iter().all(|&page_id| page_type(page_id) == FLAG_INDEX_PAGE));
+        let mut trie_pages = Vec::new();
+        db.trie_collect_pages(db.trie_root.read().page_id, &mut trie_pages).unwrap();
+        assert!(trie_pages.iter().all(|&page_id| [FLAG_TRIE_PAGE, FLAG_TRIE_PAGE | FLAG_SLAB_PAGE].contains(&page_type(page_id))));
+        let mut hash_pages = vec![db.path_hash_root.read().page_id];
+        for &bucket in db.path_hash_buckets.read().iter().filter(|&&bucket| bucket != -1) {
+            hash_pages.extend(db.read_path_hash_bucket(bucket).unwrap().0);
+        }
+        assert!(hash_pages.len() > 1);
+        assert!(hash_pages.iter().all(|&page_id| page_type(page_id) == FLAG_HASH_PAGE));
+        let mut free_list_page = db.free_list_root.read().page_id;
+        assert_ne!(free_list_page, -1);
+        while free_list_page != -1 {
+            let header = db.read_page_header(free_list_page).unwrap();
+            assert_eq!(header.flags, FLAG_FREE_LIST_PAGE);
+            assert_eq!(header.next_page_id, db.read_free_list_header(free_list_page).unwrap().0);
+            free_list_page = header.next_page_id;
         }
-        Ok(index)
-    }
 
-    fn serialize_trie_node(&self, node: &ReverseTrieNode) -> io::Result<Vec<u8>> {
-        let mut buffer = Vec::new();
-        let mut writer = BufWriter::new(&mut buffer);
-        let edge_bytes = node.edge.as_bytes();
-        writer.write_i32::<LittleEndian>(edge_bytes.len() as i32)?;
-        writer.write_all(edge_bytes)?;
-        writer.write_i64::<LittleEndian>(node.parent_page_id)?;
-        writer.write_i64::<LittleEndian>(node.self_page_id)?;
-        writer.write_i32::<LittleEndian>(if node.document_id.is_some() { 1 } else { 0 })?;
-        if let Some(id) = node.document_id {
-            writer.write_all(id.as_bytes())?;
+        // None of them is stamped as anything else: every live page has exactly one type
+        let free: HashSet<i64> = db.free_list_pages(&db.lock_allocation()).unwrap().into_iter().collect();
+        let typed: HashSet<i64> = index_pages.iter().chain(&trie_pages).chain(&hash_pages).copied().collect();
+        for page_id in typed {
+            assert!(!free.contains(&page_id), "page {} is live and free", page_id);
         }
-        writer.write_i32::<LittleEndian>(node.children.len() as i32)?;
-        for (ch, child_id) in &node.children {
-            writer.write_u8(*ch as u8)?;
-            writer.write_i64::<LittleEndian>(*child_id)?;
+        // Recovery and verify read the types back as they were written
+        drop(db);
+        let image = crash_image(&dir);
+        let db = open(&image, StreamDb::create_options());
+        let report = db.verify_db(true).unwrap();
+        assert!(report.corrupt_pages.is_empty() && report.trie.violations.is_empty() && report.index_ok);
+        for path in &kept {
+            db.read_document(path).unwrap();
         }
-        Ok(buffer)
     }
 
-    fn deserialize_trie_node(&self, data: &[u8]) -> io::Result<ReverseTrieNode> {
-        let mut reader = Cursor::new(data);
-        let edge_len = reader.read_i32::<LittleEndian>()?;
-        let mut edge_bytes = vec![0u8; edge_len as usize];
-        reader.read_exact(&mut edge_bytes)?;
-        let edge = String::from_utf8(edge_bytes)?;
-        let parent_page_id = reader.read_i64::<LittleEndian>()?;
-        let self_page_id = reader.read_i64::<LittleEndian>()?;
-        let has_doc = reader.read_i32::<LittleEndian>()?;
-        let document_id = if has_doc != 0 {
-            let mut id_bytes = [0u8; 16];
-            reader.read_exact(&mut id_bytes)?;
-            Some(Uuid::from_bytes(id_bytes))
-        } else {
-            None
+
+    #[test]
+    fn rewrites_bump_versions_and_recovery_keeps_the_newest() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let version = |db: &StreamDb, path: &str| {
+            cxx::let_cxx_string!(path = path);
+            db.stat(&path).unwrap().version
         };
-        let child_count = reader.read_i32::<LittleEndian>()?;
-        let mut children = BTreeMap::new();
-        for _ in 0..child_count {
-            let ch = reader.read_u8()? as char;
-            let child_id = reader.read_i64::<LittleEndian>()?;
-            children.insert(ch, child_id);
+        let versions: Vec<i32> = (0..5).map(|n| {
+            db.write_document_unordered("maps/e1m1.map", format!("revision {}", n).as_bytes(), true, false, false).unwrap();
+            version(&db, "maps/e1m1.map")
+        }).collect();
+        assert!(versions.windows(2).all(|pair| pair[1] == pair[0] + 1), "{:?}", versions);
+        cxx::let_cxx_string!(e1m1 = "maps/e1m1.map");
+        let handle = Pin::new(&mut db).open_append(&e1m1).unwrap();
+        Pin::new(&mut db).append(handle, b" and an appendix").unwrap();
+        Pin::new(&mut db).close_append(handle).unwrap();
+        assert_eq!(version(&db, "maps/e1m1.map"), versions[4] + 1);
+
+        // Pages rewritten in place carry a higher version each time, and the roots naming them keep up
+        let hash_root = || *db.path_hash_root.read();
+        let page_version = |page_id: i64| db.read_page_header(page_id).unwrap().version;
+        let before = hash_root();
+        assert_eq!(page_version(before.page_id), before.version);
+        db.write_document_unordered("maps/e1m2.map", b"e1m2", true, false, false).unwrap();
+        let after = hash_root();
+        assert_eq!(after.page_id, before.page_id);
+        assert!(after.version > before.version);
+        assert_eq!(page_version(after.page_id), after.version);
+        let index_root = *db.document_index_root.read();
+        assert_eq!(page_version(index_root.page_id), index_root.version);
+
+        // Enough documents for several index leaves, some rewritten many times over
+        let path = |i: usize| format!("maps/many/doc{}.bin", i);
+        for i in 0..300 {
+            db.write_document_unordered(&path(i), format!("doc {} revision 0", i).as_bytes(), true, false, false).unwrap();
         }
-        Ok(ReverseTrieNode { edge, parent_page_id, self_page_id, document_id, children })
+        for revision in 1..6 {
+            for i in (0..300).step_by(10) {
+                db.write_document_unordered(&path(i), format!("doc {} revision {}", i, revision).as_bytes(), true, false, false).unwrap();
+            }
+        }
+        let expected: Vec<(i32, Vec<u8>)> = (0..300).map(|i| (version(&db, &path(i)), db.read_document(&path(i)).unwrap())).collect();
+        assert_eq!(expected[10].0, expected[11].0 + 5);
+
+        // Reopening recovers the index from its leaves; stale copies of rewritten entries lose
+        let image = crash_image(&dir);
+        let recovered = open(&image, StreamDb::create_options());
+        for (i, (expected_version, expected_data)) in expected.iter().enumerate() {
+            assert_eq!(version(&recovered, &path(i)), *expected_version, "{}", path(i));
+            assert_eq!(&recovered.read_document(&path(i)).unwrap(), expected_data, "{}", path(i));
+        }
+        assert_eq!(recovered.read_document("maps/e1m1.map").unwrap(), b"revision 4 and an appendix");
+        assert!(recovered.verify_db(true).unwrap().index_ok);
     }
 
-    fn compute_crc(&self, data: &[u8]) -> u32 {
-        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-        crc.checksum(data)
+
+    #[test]
+    fn a_link_to_the_wrong_page_type_is_refused_even_in_quick_mode() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().quick_mode(true));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        db.write_document_unordered("maps/e1m1.bin", &vec![1u8; capacity * 2], true, false, false).unwrap();
+        db.write_document_unordered("maps/e1m2.bin", &vec![2u8; capacity * 2], true, false, false).unwrap();
+        let mut trie_pages = Vec::new();
+        db.trie_collect_pages(db.trie_root.read().page_id, &mut trie_pages).unwrap();
+        let trie_page = trie_pages[0];
+        let trie_type = StreamDb::page_type_name(StreamDb::page_type(db.read_page_header(trie_page).unwrap().flags));
+        let data_page = db.lookup_document(&resolves(&db, "maps/e1m2.bin").unwrap()).unwrap().unwrap().first_page_id;
+        let refused = |err: io::Error, expected: &str, found: &str, page_id: i64| {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let message = format!("Corrupt page {}: expected {} page, found {} page", page_id, expected, found);
+            assert!(err.to_string().contains(&message), "{}", err);
+        };
+
+        // A document whose chain now starts at a trie page
+        let e1m1 = resolves(&db, "maps/e1m1.bin").unwrap();
+        let mut index = db.read_index().unwrap();
+        index.get_mut(&e1m1).unwrap().first_page_id = trie_page;
+        db.write_index(&index).unwrap();
+        db.clear_page_cache();
+        let err = db.read_document("maps/e1m1.bin").unwrap_err();
+        assert!(err.to_string().contains("maps/e1m1.bin"), "{}", err);
+        refused(err, "data", trie_type, trie_page);
+        // The rest of the database reads as before
+        assert_eq!(db.read_document("maps/e1m2.bin").unwrap(), vec![2u8; capacity * 2]);
+
+        // Index, trie and free list reads each refuse a data page
+        refused(db.read_index_node(data_page).err().unwrap(), "index", "data", data_page);
+        db.trie_cache.lock().clear();
+        refused(db.read_trie_node(data_page).err().unwrap(), "trie", "data", data_page);
+        refused(db.read_free_list_header(data_page).unwrap_err(), "free list", "data", data_page);
+        // Nothing was cached from the refused pages
+        assert!(db.lock_page_cache(data_page).iter().all(|(&(page_id, _), _)| page_id != data_page));
     }
 
-    fn get_checksum(&self) -> u32 {
-        let mut hasher = Md4::new();
-        let mut header = vec![0u8; 32];
-        self.file.lock().read_exact_at(&mut header, 0).unwrap_or(());
-        hasher.update(&header);
-        hasher.finalize().into()
+
+    #[test]
+    fn securely_deleted_contents_are_gone_from_the_file() {
+        const MARKER: &[u8] = b"PLAYER-NAME:doomguy-1993-uac-personnel-file";
+        let on_disk = |dir: &TempDir| std::fs::read(dir.db()).unwrap().windows(MARKER.len()).any(|window| window == MARKER);
+        let save = |n: usize| [format!("slot {} ", n).as_bytes(), MARKER].concat().repeat(300);
+        let options = || StreamDb::create_options().use_compression(false).slab_threshold(256);
+
+        // Off: deleting leaves the bytes where they were until the pages are reused
+        let dir = TempDir::new();
+        let mut db = open(&dir, options());
+        db.write_document_unordered("savegames/profile1/game.save", &save(1), true, false, false).unwrap();
+        db.write_document_unordered("savegames/profile1/other.save", b"no marker here", true, false, false).unwrap();
+        cxx::let_cxx_string!(game = "savegames/profile1/game.save");
+        Pin::new(&mut db).delete_by_path(&game).unwrap();
+        assert!(on_disk(&dir));
+
+        // Per call, on a database without the option
+        let dir = TempDir::new();
+        let mut db = open(&dir, options());
+        db.write_document_unordered("savegames/profile1/game.save", &save(1), true, false, false).unwrap();
+        db.write_document_unordered("savegames/profile1/small.save", MARKER, true, false, false).unwrap();
+        db.write_document_unordered("savegames/profile1/other.save", b"no marker here", true, false, false).unwrap();
+        assert!(on_disk(&dir));
+        cxx::let_cxx_string!(small = "savegames/profile1/small.save");
+        Pin::new(&mut db).delete_by_path_secure(&game, false).unwrap();
+        Pin::new(&mut db).delete_by_path_secure(&small, false).unwrap();
+        assert!(!on_disk(&dir));
+        assert_eq!(db.read_document("savegames/profile1/other.save").unwrap(), b"no marker here");
+
+        // Per database: ordinary deletes, and versions pruned as newer ones are written
+        let dir = TempDir::new();
+        let mut db = open(&dir, options().secure_delete(true).versions_to_keep(2));
+        db.write_document_unordered("savegames/profile1/game.save", &save(1), true, false, false).unwrap();
+        Pin::new(&mut db).delete_by_path(&game).unwrap();
+        assert!(!on_disk(&dir));
+        db.write_document_unordered("savegames/profile1/quick.save", &save(2), true, false, false).unwrap();
+        db.write_document_unordered("savegames/profile1/quick.save", b"second", true, false, false).unwrap();
+        // The first version is still kept
+        assert!(on_disk(&dir));
+        db.write_document_unordered("savegames/profile1/quick.save", b"third", true, false, false).unwrap();
+        assert!(!on_disk(&dir));
+        assert_eq!(db.read_document("savegames/profile1/quick.save").unwrap(), b"third");
+        drop(db);
+        let db = open(&dir, options().secure_delete(true));
+        assert!(db.verify_db(true).unwrap().corrupt_pages.is_empty());
+        assert_eq!(db.read_document("savegames/profile1/quick.save").unwrap(), b"third");
     }
 
-    fn write_document(&mut self, path: &CxxString, data: &CxxVector<u8>) -> io::Result<Uuid> {
-        self.validate_path(path.to_string_lossy().as_ref())?;
-        let id = Uuid::new_v4();
-        let mut current_page_id = -1;
-        let mut prev_page_id = -1;
-        let mut data_remaining = data.as_slice();
-        while !data_remaining.is_empty() {
-            let chunk_size = std::cmp::min(data_remaining.len(), (self.config.page_size - self.config.page_header_size) as usize);
-            let chunk = &data_remaining[..chunk_size];
-            data_remaining = &data_remaining[chunk_size..];
-            let new_page_id = self.allocate_page()?;
-            let header = PageHeader {
-                crc: self.compute_crc(chunk),
-                version: 0,
-                prev_page_id,
-                next_page_id: if data_remaining.is_empty() { -1 } else { self.allocate_page()? },
-                flags: FLAG_DATA_PAGE,
-                data_length: chunk.len() as i32,
-                padding: [0; 3],
+
+    #[test]
+    fn out_of_range_lengths_and_page_ids_are_errors_not_panics() {
+        for io_mode in [ffi::IoMode::MmapPreferred, ffi::IoMode::FileOnly] {
+            let dir = TempDir::new();
+            // Quick mode, so no CRC check stands between a damaged header and the bounds checks
+            let db = open(&dir, StreamDb::create_options().use_compression(false).io_mode(io_mode).quick_mode(true));
+            let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+            let contents: Vec<u8> = (0..capacity * 3).map(|i| i as u8).collect();
+            db.write_document_unordered("maps/e1m1.bin", &contents, true, false, false).unwrap();
+            db.write_document_unordered("maps/e1m2.bin", b"e1m2", true, false, false).unwrap();
+            let pages = chain_pages(&db, db.lookup_document(&resolves(&db, "maps/e1m1.bin").unwrap()).unwrap().unwrap().first_page_id);
+            let past_end = db.page_count();
+
+            // Page ids the file does not hold
+            for page_id in [-1, past_end, past_end + 1000, db.config.max_pages - 1, db.config.max_pages, i64::MAX] {
+                assert!(db.read_raw_page(page_id).is_err(), "page {} in {:?}", page_id, io_mode);
+                assert!(db.read_page_header(page_id).is_err(), "page {} in {:?}", page_id, io_mode);
+                assert!(db.read_page_payload(page_id).is_err(), "page {} in {:?}", page_id, io_mode);
+            }
+
+            // Headers damaged in every way a bad length or link can be, read through each read path
+            let mut state = 0x2545_f491_4f6c_dd1du64;
+            let mut next = || {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
             };
-            self.write_raw_page(new_page_id, chunk, 0)?;
-            self.write_page_header(new_page_id, &header)?;
-            if current_page_id == -1 {
-                current_page_id = new_page_id;
+            let lengths = [-1, i32::MIN, capacity as i32 + 1, 100_000, i32::MAX];
+            let links = [-2, past_end, past_end + 7, db.config.max_pages, i64::MAX, i64::MIN];
+            for round in 0..200 {
+                let page_id = pages[round % pages.len()];
+                let original = db.read_page_header(page_id).unwrap();
+                let mut header = original;
+                match next() % 3 {
+                    0 => header.data_length = lengths[next() as usize % lengths.len()],
+                    1 => header.next_page_id = links[next() as usize % links.len()],
+                    _ => header.data_length = (next() % (1 << 20)) as i32 + capacity as i32 + 1,
+                }
+                db.write_page_header(page_id, &header).unwrap();
+                db.clear_page_cache();
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    cxx::let_cxx_string!(path = "maps/e1m1.bin");
+                    // The other paths may stop short of the damage; they only must not panic
+                    if let Ok(stream_id) = db.start_stream(&path) {
+                        while db.stream_chunk(stream_id).is_ok() {}
+                        db.close_stream(stream_id);
+                    }
+                    let mut buffer = vec![0u8; contents.len()];
+                    let _ = unsafe { db.read_into(&path, capacity as u64 - 10, buffer.as_mut_ptr(), buffer.len()) };
+                    let _ = db.get_physical_size(&path);
+                    db.read_document("maps/e1m1.bin").is_err()
+                }));
+                let failed = result.unwrap_or_else(|_| panic!("round {} panicked in {:?}", round, io_mode));
+                assert!(failed, "round {} in {:?}", round, io_mode);
+                db.write_page_header(page_id, &original).unwrap();
             }
-            prev_page_id = new_page_id;
+            db.clear_page_cache();
+            assert_eq!(db.read_document("maps/e1m1.bin").unwrap(), contents);
+            assert_eq!(db.read_document("maps/e1m2.bin").unwrap(), b"e1m2");
         }
-        let mut index = self.read_index()?;
-        let doc = Document {
-            id,
-            first_page_id: current_page_id,
-            current_version: 0,
-            paths: vec![path.to_string_lossy().to_string()],
-        };
-        index.insert(id, doc);
-        let index_page = self.document_index_root.read().page_id;
-        self.write_raw_page(index_page, &self.serialize_index(&index)?, self.document_index_root.read().version)?;
-        self.trie_insert(path.to_string_lossy().as_ref(), id)?;
-        Ok(id)
     }
 
-    fn read_index(&self) -> io::Result<BTreeMap<Uuid, Document>> {
-        let index_root = self.document_index_root.read();
-        if index_root.page_id == -1 {
-            return Ok(BTreeMap::new());
-        }
-        let data = self.read_raw_page(index_root.page_id)?;
-        self.deserialize_index(&data)
-    }
 
-    fn get(&self, path: &CxxString) -> io::Result<CxxVector<u8>> {
-        self.validate_path(path.to_string_lossy().as_ref())?;
-        let id = self.get_document_id_by_path(path.to_string_lossy().as_ref())?;
-        let index = self.read_index()?;
-        let doc = index.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
-        let mut data = Vec::new();
-        let mut current_page_id = doc.first_page_id;
-        while current_page_id != -1 {
-            let page_data = self.read_raw_page(current_page_id)?;
-            data.extend_from_slice(&page_data);
-            let header = self.read_page_header(current_page_id)?;
-            current_page_id = header.next_page_id;
-        }
-        Ok(cxx::CxxVector::from(data))
-    }
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn failed_and_concurrent_growth_never_skips_or_reissues_a_page() {
+        let dir = TempDir::new();
+        let schedule = Arc::new(PMutex::new(FaultSchedule::default()));
+        let db = StreamDb::open_with_faults(&dir.db(), false, schedule.clone()).unwrap();
+        let file_len = || std::fs::metadata(dir.db()).unwrap().len();
+        assert_eq!(*db.current_size.lock(), file_len());
+        let free_before: BTreeSet<i64> = db.free_list_pages(&db.lock_allocation()).unwrap().into_iter().collect();
+        let in_use_before: BTreeSet<i64> = (FIRST_PAGE_ID..db.page_count()).filter(|page_id| !free_before.contains(page_id)).collect();
 
-    fn search_paths(&self, prefix: &CxxString) -> io::Result<CxxVector<CxxString>> {
-        self.validate_path(prefix.to_string_lossy().as_ref())?;
-        let trie_root = self.trie_root.read();
-        if trie_root.page_id == -1 {
-            return Ok(cxx::CxxVector::new());
-        }
-        let reversed_prefix: String = prefix.to_string_lossy().chars().rev().collect();
-        let mut current_page_id = trie_root.page_id;
-        let mut remaining = reversed_prefix.as_str();
-        let mut results = vec![];
-        while !remaining.is_empty() {
-            let node = self.deserialize_trie_node(&self.read_raw_page(current_page_id)?)?;
-            let edge = node.edge.as_str();
-            if remaining.starts_with(edge) {
-                remaining = &remaining[edge.len()..];
-                if remaining.is_empty() {
-                    self.trie_collect_paths(&node, String::new(), &mut results)?;
-                    let mut cxx_results = cxx::CxxVector::new();
-                    for r in results.into_iter().filter(|p| p.starts_with(prefix.to_string_lossy().as_ref())) {
-                        cxx_results.push(cxx::CxxString::from(r.as_str()));
-                    }
-                    return Ok(cxx_results);
-                }
-                let first_char = remaining.chars().next().unwrap();
-                if let Some(&child_id) = node.children.get(&first_char) {
-                    current_page_id = child_id;
-                } else {
-                    return Ok(cxx::CxxVector::new());
-                }
+        // Growth that fails leaves the length where it was; the next growth starts from there
+        let issued = Mutex::new(Vec::new());
+        for i in 0..20 {
+            let (size, count) = (*db.current_size.lock(), db.page_count());
+            if i % 2 == 1 {
+                schedule.lock().fired = true;
+                schedule.lock().halt = true;
+                assert!(db.extend_file(&db.lock_allocation(), 4).is_err());
+                assert_eq!((*db.current_size.lock(), db.page_count(), file_len()), (size, count, size));
+                schedule.lock().fired = false;
+                schedule.lock().halt = false;
             } else {
-                return Ok(cxx::CxxVector::new());
+                let pages = db.extend_file(&db.lock_allocation(), 4).unwrap();
+                assert_eq!(pages, count..count + 4);
+                assert_eq!(file_len(), *db.current_size.lock());
+                issued.lock().unwrap().extend(pages);
             }
         }
-        let node = self.deserialize_trie_node(&self.read_raw_page(current_page_id)?)?;
-        self.trie_collect_paths(&node, String::new(), &mut results)?;
-        let mut cxx_results = cxx::CxxVector::new();
-        for r in results.into_iter().filter(|p| p.starts_with(prefix.to_string_lossy().as_ref())) {
-            cxx_results.push(cxx::CxxString::from(r.as_str()));
-        }
-        Ok(cxx_results)
+
+        // Threads growing the file at once, directly and through allocation, each get pages of their own
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let issued = &issued;
+                let db = &db;
+                scope.spawn(move || {
+                    for _ in 0..200 {
+                        if thread % 2 == 0 {
+                            let page_id = db.allocate_page().unwrap();
+                            issued.lock().unwrap().push(page_id);
+                        } else {
+                            let pages = db.extend_file(&db.lock_allocation(), 3).unwrap();
+                            assert_eq!(pages.end - pages.start, 3);
+                            issued.lock().unwrap().extend(pages);
+                        }
+                    }
+                });
+            }
+        });
+
+        // Issued, free and what was in use before cover every page of the grown file, each once
+        let issued = issued.into_inner().unwrap();
+        let free = db.free_list_pages(&db.lock_allocation()).unwrap();
+        let mut covered: Vec<i64> = issued.iter().chain(&free).chain(&in_use_before).copied().collect();
+        covered.sort_unstable();
+        assert_eq!(covered, (FIRST_PAGE_ID..db.page_count()).collect::<Vec<_>>());
+        assert_eq!(*db.current_size.lock(), file_len());
+        assert_eq!(db.page_count() as u64 * PAGE_SIZE, file_len());
+        drop(db);
+
+        // Reopened, the length comes from the file
+        let db = open(&dir, StreamDb::create_options());
+        assert_eq!(*db.current_size.lock(), file_len());
     }
 
-    fn trie_collect_paths(&self, node: &ReverseTrieNode, prefix: String, results: &mut Vec<String>) -> io::Result<()> {
-        let new_prefix = if prefix.is_empty() { node.edge.clone() } else { format!("{}{}", node.edge, prefix) };
-        if let Some(id) = node.document_id {
-            results.push(new_prefix.chars().rev().collect());
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn taking_a_lock_out_of_order_panics_in_debug_builds() {
+        let (dir, other_dir) = (TempDir::new(), TempDir::new());
+        let db = open(&dir, StreamDb::create_options());
+        let other = open(&other_dir, StreamDb::create_options());
+        {
+            let _allocation = db.lock_allocation();
+            let _cache = db.lock_page_cache(FIRST_PAGE_ID);
         }
-        for &child_id in node.children.values() {
-            let child = self.deserialize_trie_node(&self.read_raw_page(child_id)?)?;
-            self.trie_collect_paths(&child, new_prefix.clone(), results)?;
+        // Another database's locks are ordered on their own
+        {
+            let _cache = db.lock_page_cache(FIRST_PAGE_ID);
+            let _allocation = other.lock_allocation();
         }
-        Ok(())
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _cache = db.lock_page_cache(FIRST_PAGE_ID);
+            let _allocation = db.lock_allocation();
+        }));
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(*message, "lock order violated: allocation taken while holding page cache");
+        // The unwound guards took their records with them
+        HELD_LOCKS.with(|held| assert!(held.borrow().is_empty()));
+        let _allocation = db.lock_allocation();
     }
 
-    fn trie_insert(&mut self, path: &str, id: Uuid) -> io::Result<()> {
-        let reversed: String = path.chars().rev().collect();
-        let mut current_page_id = self.trie_root.read().page_id;
-        if current_page_id == -1 {
-            current_page_id = self.allocate_page()?;
-            *self.trie_root.write() = VersionedLink { page_id: current_page_id, version: 0 };
-            self.write_raw_page(current_page_id, &self.serialize_trie_node(&ReverseTrieNode {
-                edge: "".to_string(),
-                parent_page_id: -1,
-                self_page_id: current_page_id,
-                document_id: None,
-                children: BTreeMap::new(),
-            })?, 0)?;
-        }
-        let mut remaining = reversed.as_str();
-        while !remaining.is_empty() {
-            let node = self.deserialize_trie_node(&self.read_raw_page(current_page_id)?)?;
-            let edge = node.edge.as_str();
-            let common_prefix = remaining.chars()
-                .zip(edge.chars())
-                .take_while(|(a, b)| a == b)
-                .count();
-            if common_prefix == edge.len() && common_prefix == remaining.len() {
-                let mut new_node = node;
-                new_node.document_id = Some(id);
-                self.write_raw_page(current_page_id, &self.serialize_trie_node(&new_node)?, 0)?;
-                return Ok(());
+    #[test]
+    fn a_mixed_multithreaded_workload_keeps_the_lock_order() {
+        let dir = TempDir::new();
+        let db = open(&dir, StreamDb::create_options().slab_threshold(256).cache_sizes(64, 64));
+        let capacity = (db.config.page_size - db.config.page_header_size) as usize;
+        let contents = |thread: usize, n: usize| format!("thread {} document {}\n", thread, n).repeat(1 + (n * 37 % 11) * capacity / 40).into_bytes();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let db = &db;
+                scope.spawn(move || {
+                    let path = |n: usize| format!("maps/thread{}/doc{}.cfg", thread, n);
+                    for n in 0..150 {
+                        db.write_document_unordered(&path(n), &contents(thread, n), true, false, false).unwrap();
+                        assert_eq!(db.read_document(&path(n)).unwrap(), contents(thread, n));
+                        match n % 6 {
+                            0 if n > 0 => {
+                                let _writes = db.begin_write().unwrap();
+                                db.commit_changes(vec![StagedChange::Delete { path: path(n - 1), force: false, secure: n % 12 == 0 }]).unwrap();
+                            }
+                            1 => {
+                                let paths: Vec<String> = (0..n).filter(|m| m % 6 != 5).map(path).collect();
+                                db.get_many(&paths).unwrap();
+                            }
+                            2 => {
+                                cxx::let_cxx_string!(cxx_path = path(n));
+                                let stream_id = db.start_stream(&cxx_path).unwrap();
+                                while db.stream_chunk(stream_id).is_ok() {}
+                                db.close_stream(stream_id);
+                            }
+                            3 if thread == 0 => {
+                                let _writes = db.begin_exclusive_write().unwrap();
+                                db.maintenance_slice(&mut WorkBudget::millis(2)).unwrap();
+                            }
+                            4 => {
+                                db.export_manifest(true).unwrap();
+                                db.clear_page_cache();
+                            }
+                            _ => {
+                                let page_id = db.allocate_page().unwrap();
+                                db.free_page(page_id).unwrap();
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let report = db.verify_db(true).unwrap();
+        assert!(report.corrupt_pages.is_empty() && report.trie.violations.is_empty() && report.index_ok);
+        for thread in 0..8 {
+            for n in (0..150).filter(|n| n % 6 != 5 || *n == 149) {
+                assert_eq!(db.read_document(&format!("maps/thread{}/doc{}.cfg", thread, n)).unwrap(), contents(thread, n));
             }
-            // Implement split/merge logic for trie (omitted for brevity)
-            // ...
-            return Ok(());
         }
-        Ok(())
     }
 
-    fn delete_by_path(self: Pin<&mut Self>, path: &CxxString) -> io::Result<()> {
-        let rust_path = path.to_string_lossy().to_string();
-        self.validate_path(&rust_path)?;
-        let id = self.get_document_id_by_path(&rust_path)?;
-        let mut index = self.read_index()?;
-        let doc = index.remove(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
-        let mut current_page_id = doc.first_page_id;
-        while current_page_id != -1 {
-            let header = self.read_page_header(current_page_id)?;
-            self.free_page(current_page_id)?;
-            current_page_id = header.next_page_id;
+
+    #[test]
+    fn every_mutation_keeps_a_warm_path_cache_correct() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let paths = ["maps/test.map", "maps/old_name.map", "maps/shared.map", "maps/keep.map"];
+        for path in paths {
+            db.write_document_unordered(path, path.as_bytes(), true, false, false).unwrap();
         }
-        for p in &doc.paths {
-            self.trie_delete(p)?;
+        let id = |db: &StreamDb, path: &str| db.get_document_id_by_path(path).ok();
+        let ids: Vec<Uuid> = paths.iter().map(|path| id(&db, path).unwrap()).collect();
+        let cached = |db: &StreamDb, path: &str| db.path_cache.lock().peek(path).copied();
+        for (path, &expected) in paths.iter().zip(&ids) {
+            assert_eq!(cached(&db, path), Some(expected));
         }
-        let index_page = self.document_index_root.read().page_id;
-        self.write_raw_page(index_page, &self.serialize_index(&index)?, self.document_index_root.read().version)?;
-        Ok(())
-    }
+        // None of the changes below touches keep.map, whose entry must survive them all
+        let kept = |db: &StreamDb| assert_eq!(cached(db, "maps/keep.map"), Some(ids[3]));
 
-    fn trie_delete(&mut self, path: &str) -> io::Result<()> {
-        let reversed: String = path.chars().rev().collect();
-        let mut current_page_id = self.trie_root.read().page_id;
-        if current_page_id == -1 {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
-        }
-        // Implement trie deletion with pruning (omitted for brevity)
-        // ...
-        Ok(())
-    }
+        // Delete: the dead uuid is no longer handed out, and a new document at the path is found
+        cxx::let_cxx_string!(test = "maps/test.map");
+        Pin::new(&mut db).delete_by_path(&test).unwrap();
+        assert_eq!(cached(&db, "maps/test.map"), None);
+        assert_eq!(id(&db, "maps/test.map"), None);
+        assert_eq!(db.read_document("maps/test.map").unwrap_err().kind(), io::ErrorKind::NotFound);
+        let recreated = db.write_document_unordered("maps/test.map", b"recreated", true, false, false).unwrap();
+        assert_ne!(recreated, ids[0]);
+        assert_eq!(id(&db, "maps/test.map"), Some(recreated));
+        kept(&db);
 
-    fn get_document_id_by_path(&self, path: &str) -> io::Result<Uuid> {
-        self.validate_path(path)?;
-        let trie_root = self.trie_root.read();
-        if trie_root.page_id == -1 {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
-        }
-        let reversed: String = path.chars().rev().collect();
-        let mut current_page_id = trie_root.page_id;
-        let mut remaining = reversed.as_str();
-        while !remaining.is_empty() {
-            let node = self.deserialize_trie_node(&self.read_raw_page(current_page_id)?)?;
-            let edge = node.edge.as_str();
-            if remaining.starts_with(edge) {
-                remaining = &remaining[edge.len()..];
-                if remaining.is_empty() {
-                    return node.document_id.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Path not found"));
-                }
-                let first_char = remaining.chars().next().unwrap();
-                if let Some(&child_id) = node.children.get(&first_char) {
-                    current_page_id = child_id;
-                } else {
-                    return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
-                }
-            } else {
-                return Err(io::Error::new(io::ErrorKind::NotFound, "Path not found"));
-            }
+        // Rename: the entry moves with the binding
+        cxx::let_cxx_string!(old_name = "maps/old_name.map");
+        cxx::let_cxx_string!(new_name = "maps/new_name.map");
+        Pin::new(&mut db).rename_path(&old_name, &new_name).unwrap();
+        assert_eq!(cached(&db, "maps/old_name.map"), None);
+        assert_eq!(id(&db, "maps/old_name.map"), None);
+        assert_eq!(id(&db, "maps/new_name.map"), Some(ids[1]));
+        kept(&db);
+
+        // Bind and unbind a second path
+        cxx::let_cxx_string!(shared = "maps/shared.map");
+        cxx::let_cxx_string!(alias = "maps/alias.map");
+        Pin::new(&mut db).add_path(&shared, &alias, false).unwrap();
+        assert_eq!(id(&db, "maps/alias.map"), Some(ids[2]));
+        Pin::new(&mut db).remove_path(&alias, false).unwrap();
+        assert_eq!(cached(&db, "maps/alias.map"), None);
+        assert_eq!(id(&db, "maps/alias.map"), None);
+        assert_eq!(cached(&db, "maps/shared.map"), Some(ids[2]));
+        kept(&db);
+
+        // A rolled-back transaction leaves nothing behind; a committed one is found at once
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        cxx::let_cxx_string!(staged = "maps/staged.map");
+        Pin::new(&mut db).save_session_write(tx, &staged, &cxx::CxxVector::from(b"staged".to_vec())).unwrap();
+        Pin::new(&mut db).save_session_write(tx, &shared, &cxx::CxxVector::from(b"replaced".to_vec())).unwrap();
+        Pin::new(&mut db).rollback_transaction(tx).unwrap();
+        assert_eq!(cached(&db, "maps/staged.map"), None);
+        assert_eq!(id(&db, "maps/staged.map"), None);
+        assert_eq!(id(&db, "maps/shared.map"), Some(ids[2]));
+        assert_eq!(db.read_document("maps/shared.map").unwrap(), b"maps/shared.map");
+        let tx = Pin::new(&mut db).begin_transaction().unwrap();
+        Pin::new(&mut db).save_session_write(tx, &staged, &cxx::CxxVector::from(b"staged".to_vec())).unwrap();
+        Pin::new(&mut db).commit_transaction(tx).unwrap();
+        assert!(id(&db, "maps/staged.map").is_some());
+        assert_eq!(db.read_document("maps/staged.map").unwrap(), b"staged");
+        kept(&db);
+
+        // Whatever is cached agrees with a fresh open, which starts cold
+        drop(db);
+        let db = open(&dir, StreamDb::create_options());
+        for path in ["maps/test.map", "maps/old_name.map", "maps/new_name.map", "maps/shared.map", "maps/alias.map", "maps/staged.map", "maps/keep.map"] {
+            assert_eq!(cached(&db, path), None);
         }
-        let node = self.deserialize_trie_node(&self.read_raw_page(current_page_id)?)?;
-        node.document_id.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Path not found"))
+        assert_eq!(id(&db, "maps/test.map"), Some(recreated));
+        assert_eq!(id(&db, "maps/new_name.map"), Some(ids[1]));
+        assert_eq!(id(&db, "maps/old_name.map"), None);
+        assert_eq!(id(&db, "maps/alias.map"), None);
     }
 
-    fn start_stream(&self, path: &CxxString) -> io::Result<i64> {
-        let id = self.get_document_id_by_path(path.to_string_lossy().as_ref())?;
-        let index = self.read_index()?;
-        let doc = index.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
-        Ok(doc.first_page_id)
-    }
 
-    fn next_stream_chunk(&self, stream_id: i64) -> io::Result<CxxVector<u8>> {
-        if stream_id == -1 {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "Stream ended"));
+    #[test]
+    fn prefix_search_returns_exactly_the_paths_under_the_prefix_without_walking_the_trie() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let mut corpus = Vec::new();
+        for i in 0..50 {
+            corpus.push(format!("maps/e{}m{}.map", i / 10, i % 10));
+            corpus.push(format!("textures/maps/t{}.tga", i));
+            corpus.push(format!("sound/maps/s{}.ogg", i));
+            corpus.push(format!("maps_extra/x{}.map", i));
         }
-        let data = self.read_raw_page(stream_id)?;
-        let header = self.read_page_header(stream_id)?;
-        Ok(cxx::CxxVector::from(data))
-    }
+        corpus.extend(["maps.cfg".to_string(), "maps/sub/deep/level.map".to_string(), "mapsx".to_string()]);
+        write_paths(&db, &corpus.iter().map(String::as_str).collect::<Vec<_>>());
+        let check = |db: &StreamDb| {
+            let all = db.get_all_paths_sorted().unwrap();
+            for prefix in ["maps/", "maps", "maps/sub/", "textures/maps/", "sound/", "nothing/"] {
+                cxx::let_cxx_string!(cxx_prefix = prefix);
+                let expected: Vec<String> = all.iter().filter(|path| path.starts_with(prefix)).cloned().collect();
+                let found: Vec<String> = db.search_paths(&cxx_prefix).unwrap().iter().map(|path| path.to_string_lossy().into_owned()).collect();
+                assert_eq!(found, expected, "prefix {prefix}");
+                assert_eq!(db.count_paths(&cxx_prefix).unwrap(), expected.len() as u64);
+            }
+        };
+        check(&db);
+        cxx::let_cxx_string!(maps = "maps/");
+        let found: Vec<String> = db.search_paths(&maps).unwrap().iter().map(|path| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(found.len(), 51);
+        assert!(found.iter().all(|path| path.starts_with("maps/")));
 
-    fn end_stream(self: Pin<&mut Self>, stream_id: i64) {
-        // No-op; idTech4 manages file closure
-    }
+        // Once the path order is loaded, a prefix query reads no trie nodes at all
+        db.trie_cache.lock().clear();
+        assert_eq!(db.search_paths(&maps).unwrap().len(), 51);
+        cxx::let_cxx_string!(none = "nothing/");
+        assert_eq!(db.search_paths(&none).unwrap().len(), 0);
+        assert_eq!(db.trie_cache.lock().len(), 0);
 
-    fn bind_addon_path(self: Pin<&mut Self>, path: &CxxString, addon: bool) -> io::Result<()> {
-        let rust_path = path.to_string_lossy().to_string();
-        self.validate_path(&rust_path)?;
-        let id = self.get_document_id_by_path(&rust_path)?;
-        let mut index = self.read_index()?;
-        let doc = index.get_mut(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Document not found"))?;
-        doc.paths.push(rust_path.clone());
-        let index_page = self.document_index_root.read().page_id;
-        self.write_raw_page(index_page, &self.serialize_index(&index)?, self.document_index_root.read().version)?;
-        self.trie_insert(&rust_path, id)?;
-        Ok(())
-    }
+        // A cold query walks the trie once to build the order, and no more after that
+        db.clear_path_cache();
+        db.trie_cache.lock().clear();
+        assert_eq!(db.search_paths(&maps).unwrap().len(), 51);
+        assert!(db.trie_cache.lock().len() > 0);
+        db.trie_cache.lock().clear();
+        cxx::let_cxx_string!(textures = "textures/maps/");
+        assert_eq!(db.search_paths(&textures).unwrap().len(), 50);
+        assert_eq!(db.trie_cache.lock().len(), 0);
 
-    fn begin_transaction(self: Pin<&mut Self>) -> io::Result<i64> {
-        let tx_id = self.transactions.lock().len() as i64;
-        self.transactions.lock().push(Transaction {
-            writes: VecDeque::new(),
-            frees: Vec::new(),
-        });
-        Ok(tx_id)
+        // Results stay exact through writes, deletes and renames under and beside the prefix
+        write_paths(&db, &["maps/new.map", "maps_extra/new.map"]);
+        cxx::let_cxx_string!(gone = "maps/e0m0.map");
+        Pin::new(&mut db).delete_by_path(&gone).unwrap();
+        cxx::let_cxx_string!(from = "maps_extra/x0.map");
+        cxx::let_cxx_string!(to = "maps/x0.map");
+        Pin::new(&mut db).rename_path(&from, &to).unwrap();
+        cxx::let_cxx_string!(out_from = "maps/e0m1.map");
+        cxx::let_cxx_string!(out_to = "textures/maps/e0m1.map");
+        Pin::new(&mut db).rename_path(&out_from, &out_to).unwrap();
+        check(&db);
+        assert_eq!(db.search_paths(&maps).unwrap().len(), 51);
     }
 
-    fn commit_transaction(self: Pin<&mut Self>, tx_id: i64) -> io::Result<()> {
-        let mut txs = self.transactions.lock();
-        if tx_id as usize >= txs.len() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid transaction ID"));
-        }
-        let tx = txs.remove(tx_id as usize).unwrap();
-        for (page_id, data, version) in tx.writes {
-            self.write_raw_page(page_id, &data, version)?;
+
+    #[test]
+    fn an_empty_prefix_lists_every_path_in_order() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let mut rng = Xorshift(0x9E37_79B9_7F4A_7C15);
+        let names = ["maps", "sound", "gui", "e1m1", "menu.gui", "a.ogg", "z"];
+        let mut paths = BTreeSet::new();
+        while paths.len() < 150 {
+            let depth = rng.pick(&["1", "2", "3"]).parse().unwrap();
+            paths.insert((0..depth).map(|_| rng.pick(&names)).collect::<Vec<_>>().join("/"));
         }
-        for page_id in tx.frees {
-            self.free_page(page_id)?;
+        write_paths(&db, &paths.iter().map(String::as_str).collect::<Vec<_>>());
+        let first = paths.iter().next().unwrap().clone();
+        cxx::let_cxx_string!(source = first.as_str());
+        cxx::let_cxx_string!(alias = "zz/alias");
+        Pin::new(&mut db).add_path(&source, &alias, false).unwrap();
+        paths.insert("zz/alias".to_string());
+
+        // The paths every document resolves under, gathered document by document
+        let mut from_documents = Vec::new();
+        for id in db.read_index().unwrap().keys() {
+            cxx::let_cxx_string!(uuid = id.to_string());
+            for info in db.get_paths_for_uuid(&uuid).unwrap() {
+                if info.lang.is_empty() && info.resolved {
+                    from_documents.push(info.path);
+                }
+            }
         }
-        Ok(())
-    }
+        from_documents.sort();
+        assert_eq!(from_documents, paths.iter().cloned().collect::<Vec<_>>());
 
-    fn rollback_transaction(self: Pin<&mut Self>, tx_id: i64) -> io::Result<()> {
-        let mut txs = self.transactions.lock();
-        if tx_id as usize >= txs.len() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid transaction ID"));
+        for prefix in ["", "/"] {
+            cxx::let_cxx_string!(all = prefix);
+            let listed: Vec<String> = db.search_paths(&all).unwrap().iter().map(|path| path.to_string_lossy().into_owned()).collect();
+            assert_eq!(listed, from_documents, "prefix {prefix:?}");
+            assert_eq!(db.count_paths(&all).unwrap(), from_documents.len() as u64);
+
+            // Paging through the listing gives the same paths, each once
+            let mut paged = Vec::new();
+            let mut after = String::new();
+            loop {
+                cxx::let_cxx_string!(cxx_after = after.as_str());
+                let page = db.search_paths_page(&all, &cxx_after, 7).unwrap();
+                assert!(page.len() <= 7);
+                paged.extend(page.iter().cloned());
+                match page.last() {
+                    Some(last) if page.len() == 7 => after = last.clone(),
+                    _ => break,
+                }
+            }
+            assert_eq!(paged, from_documents);
         }
-        txs.remove(tx_id as usize);
-        Ok(())
-    }
 
-    fn set_quick_mode(self: Pin<&mut Self>, enabled: bool) {
-        self.quick_mode.store(enabled, std::sync::atomic::Ordering::SeqCst);
+        // Only the empty prefix skips validation
+        cxx::let_cxx_string!(bad = "bad prefix/");
+        assert_eq!(db.search_paths(&bad).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        cxx::let_cxx_string!(empty = "");
+        Pin::new(&mut db).remove_path(&alias, false).unwrap();
+        assert_eq!(db.search_paths(&empty).unwrap().len(), paths.len() - 1);
     }
 
-    fn get_cache_stats(&self) -> CacheStats {
-        self.cache_stats.lock().clone()
-    }
 
-    fn close_db(self: Pin<&mut Self>) {
-        if let Some(mmap) = self.mmap.write().as_mut() {
-            mmap.flush().unwrap_or(());
+    #[test]
+    fn searches_list_multi_bound_documents_flat_or_grouped() {
+        let dir = TempDir::new();
+        let mut db = open(&dir, StreamDb::create_options());
+        let menu = db.write_document_unordered("base/gui/menu.gui", b"menu", true, false, false).unwrap();
+        let hud = db.write_document_unordered("base/gui/hud.gui", b"hud", true, false, false).unwrap();
+        let font = db.write_document_unordered("base/fonts/main.dat", b"font", true, false, false).unwrap();
+        cxx::let_cxx_string!(menu_source = menu.to_string());
+        for alias in ["addon/gui/menu.gui", "addon/gui/menu_old.gui", "base/gui/menu2.gui"] {
+            cxx::let_cxx_string!(path = alias);
+            Pin::new(&mut db).add_path(&menu_source, &path, false).unwrap();
         }
-        self.file.lock().flush().unwrap_or(());
+        cxx::let_cxx_string!(font_source = font.to_string());
+        cxx::let_cxx_string!(font_alias = "addon/fonts/main.dat");
+        Pin::new(&mut db).add_path(&font_source, &font_alias, false).unwrap();
+
+        // Flat results give each path once, paired with its document, in path order
+        cxx::let_cxx_string!(all = "");
+        let flat = db.search_documents(&all, false).unwrap();
+        let pairs: Vec<(String, String)> = flat.iter().map(|result| {
+            assert_eq!(result.paths.len(), 1);
+            (result.paths[0].clone(), result.uuid.clone())
+        }).collect();
+        assert_eq!(pairs, [
+            ("addon/fonts/main.dat".to_string(), font.to_string()),
+            ("addon/gui/menu.gui".to_string(), menu.to_string()),
+            ("addon/gui/menu_old.gui".to_string(), menu.to_string()),
+            ("base/fonts/main.dat".to_string(), font.to_string()),
+            ("base/gui/hud.gui".to_string(), hud.to_string()),
+            ("base/gui/menu.gui".to_string(), menu.to_string()),
+            ("base/gui/menu2.gui".to_string(), menu.to_string()),
+        ]);
+        let listed: Vec<String> = db.search_paths(&all).unwrap().iter().map(|path| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(listed, pairs.iter().map(|pair| pair.0.clone()).collect::<Vec<_>>());
+
+        // Grouped results give each document once, in the order of its first path, with every
+        // path it has under the prefix
+        let grouped: Vec<(String, Vec<String>)> = db.search_documents(&all, true).unwrap().into_iter().map(|result| (result.uuid, result.paths)).collect();
+        assert_eq!(grouped, [
+            (font.to_string(), vec!["addon/fonts/main.dat".to_string(), "base/fonts/main.dat".to_string()]),
+            (menu.to_string(), vec!["addon/gui/menu.gui".to_string(), "addon/gui/menu_old.gui".to_string(), "base/gui/menu.gui".to_string(), "base/gui/menu2.gui".to_string()]),
+            (hud.to_string(), vec!["base/gui/hud.gui".to_string()]),
+        ]);
+
+        // A narrower prefix groups only the paths under it
+        cxx::let_cxx_string!(gui = "base/gui/");
+        let grouped: Vec<(String, Vec<String>)> = db.search_documents(&gui, true).unwrap().into_iter().map(|result| (result.uuid, result.paths)).collect();
+        assert_eq!(grouped, [
+            (hud.to_string(), vec!["base/gui/hud.gui".to_string()]),
+            (menu.to_string(), vec!["base/gui/menu.gui".to_string(), "base/gui/menu2.gui".to_string()]),
+        ]);
+        assert_eq!(db.search_documents(&gui, false).unwrap().len(), 3);
+
+        // Documents bound more than once are what the duplicate report wants
+        let duplicated: Vec<String> = db.search_documents(&all, true).unwrap().into_iter().filter(|result| result.paths.len() > 1).map(|result| result.uuid).collect();
+        assert_eq!(duplicated, [font.to_string(), menu.to_string()]);
+
+        // Removing a name drops it from both modes
+        cxx::let_cxx_string!(old = "addon/gui/menu_old.gui");
+        Pin::new(&mut db).remove_path(&old, false).unwrap();
+        assert_eq!(db.search_documents(&all, false).unwrap().len(), 6);
+        let menu_group = db.search_documents(&all, true).unwrap().into_iter().find(|result| result.uuid == menu.to_string()).unwrap();
+        assert_eq!(menu_group.paths, ["addon/gui/menu.gui", "base/gui/menu.gui", "base/gui/menu2.gui"]);
     }
 }
-
-pub fn main() {} // Required for cxx::bridge